@@ -0,0 +1,117 @@
+/// A condition on the flags, as tested by conditional jumps, `setcc` and
+/// `cmovcc`. The names follow the assembler mnemonics; signed comparisons
+/// use less/greater, unsigned comparisons use below/above.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Cond {
+    /// Overflow (OF = 1).
+    O,
+    /// No overflow (OF = 0).
+    NO,
+    /// Unsigned below (CF = 1).
+    B,
+    /// Unsigned above or equal (CF = 0).
+    AE,
+    /// Equal (ZF = 1).
+    E,
+    /// Not equal (ZF = 0).
+    NE,
+    /// Unsigned below or equal (CF = 1 or ZF = 1).
+    BE,
+    /// Unsigned above (CF = 0 and ZF = 0).
+    A,
+    /// Negative (SF = 1).
+    S,
+    /// Not negative (SF = 0).
+    NS,
+    /// Parity even (PF = 1); also set for unordered float compares.
+    P,
+    /// Parity odd (PF = 0).
+    NP,
+    /// Signed less (SF != OF).
+    L,
+    /// Signed greater or equal (SF = OF).
+    GE,
+    /// Signed less or equal (ZF = 1 or SF != OF).
+    LE,
+    /// Signed greater (ZF = 0 and SF = OF).
+    G
+}
+
+impl Cond {
+    /// Zero (ZF = 1), the same condition as `E`.
+    pub const Z: Cond = Cond::E;
+    /// Not zero (ZF = 0), the same condition as `NE`.
+    pub const NZ: Cond = Cond::NE;
+    /// Carry (CF = 1), the same condition as `B`.
+    pub const C: Cond = Cond::B;
+    /// No carry (CF = 0), the same condition as `AE`.
+    pub const NC: Cond = Cond::AE;
+    
+    /// Returns the condition for a signed or unsigned less than compare.
+    pub fn lt(is_signed: bool) -> Cond {
+        if is_signed { Cond::L } else { Cond::B }
+    }
+    
+    /// Returns the condition for a signed or unsigned less or equal compare.
+    pub fn le(is_signed: bool) -> Cond {
+        if is_signed { Cond::LE } else { Cond::BE }
+    }
+    
+    /// Returns the condition for a signed or unsigned greater than compare.
+    pub fn gt(is_signed: bool) -> Cond {
+        if is_signed { Cond::G } else { Cond::A }
+    }
+    
+    /// Returns the condition for a signed or unsigned greater or equal compare.
+    pub fn ge(is_signed: bool) -> Cond {
+        if is_signed { Cond::GE } else { Cond::AE }
+    }
+    
+    /// Returns the condition that holds exactly when this one does not.
+    pub fn negate(self) -> Cond {
+        Cond::from_code(self.code() ^ 1)
+    }
+    
+    /// Returns the condition that holds after swapping the operands of the
+    /// compare that set the flags.
+    pub fn swap(self) -> Cond {
+        match self {
+            Cond::B => Cond::A,
+            Cond::A => Cond::B,
+            Cond::BE => Cond::AE,
+            Cond::AE => Cond::BE,
+            Cond::L => Cond::G,
+            Cond::G => Cond::L,
+            Cond::LE => Cond::GE,
+            Cond::GE => Cond::LE,
+            cond => cond
+        }
+    }
+    
+    /// Returns the four bit condition code that is encoded in the low bits
+    /// of the `jcc`, `setcc` and `cmovcc` opcodes.
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+    
+    fn from_code(code: u8) -> Cond {
+        match code & 0xf {
+            0x0 => Cond::O,
+            0x1 => Cond::NO,
+            0x2 => Cond::B,
+            0x3 => Cond::AE,
+            0x4 => Cond::E,
+            0x5 => Cond::NE,
+            0x6 => Cond::BE,
+            0x7 => Cond::A,
+            0x8 => Cond::S,
+            0x9 => Cond::NS,
+            0xa => Cond::P,
+            0xb => Cond::NP,
+            0xc => Cond::L,
+            0xd => Cond::GE,
+            0xe => Cond::LE,
+            _ => Cond::G
+        }
+    }
+}
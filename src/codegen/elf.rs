@@ -0,0 +1,474 @@
+use std::io;
+use std::io::Write;
+use super::{CodeBuffer, RelocKind, RelocTarget, POOL_ALIGN};
+
+/*
+ * Section indices of the object file, in the order the section headers
+ * are written.
+ */
+const TEXT: u16 = 1;
+const RODATA: u16 = 2;
+const RELA_TEXT: u16 = 3;
+const SYMTAB: u16 = 4;
+const STRTAB: u16 = 5;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+const R_X86_64_64: u32 = 1;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+
+const EHDR_SIZE: usize = 64;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+/// An ELF64 relocatable object file for x86-64 that holds named
+/// functions. The code of the functions goes in `.text` and their
+/// constant pools in `.rodata`. Symbols that are not defined by one of the
+/// functions become undefined symbols for the linker to resolve.
+/// Addresses of constants and RIP relative operands become
+/// `R_X86_64_PC32` relocations and 64 bit addresses `R_X86_64_64`
+/// relocations. Calls become `R_X86_64_PLT32` relocations, which are
+/// applied like `R_X86_64_PC32` but let the linker go through the PLT
+/// when the function is in a shared library.
+///
+/// Code that refers to absolute addresses cannot be written, except as
+/// the operand of a 64 bit `mov`, because the addresses mean nothing
+/// outside of the process that generated the code.
+#[derive(Default)]
+pub struct ObjectFile {
+    functions: Vec<(String, CodeBuffer)>
+}
+
+/*
+ * A symbol table entry; section is the index of the section the symbol
+ * is defined in, or 0 when it is undefined.
+ */
+struct Symbol {
+    name: u32,
+    info: u8,
+    section: u16,
+    value: u64,
+    size: u64
+}
+
+/*
+ * The contents and header fields of a section. The size of a section of
+ * kind SHT_NOBITS is given by size, and of other sections by data.
+ */
+#[derive(Default)]
+struct Section {
+    name: &'static str,
+    kind: u32,
+    flags: u64,
+    address: u64,
+    size: u64,
+    data: Vec<u8>,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64
+}
+
+impl ObjectFile {
+    pub fn new() -> ObjectFile {
+        ObjectFile {
+            functions: Vec::new()
+        }
+    }
+    
+    /// Adds a global function with the given name.
+    pub fn add_function(&mut self, name: &str, code: CodeBuffer) {
+        self.functions.push((name.to_string(), code));
+    }
+    
+    /// Writes the object file to `out`.
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(&self.to_bytes()?)
+    }
+    
+    /// Returns the contents of the object file.
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut strtab = vec![0];
+        let mut text = Vec::new();
+        let mut rodata = Vec::new();
+        let mut offsets = Vec::new();
+        
+        /*
+         * The local symbols come first: the null symbol and the symbols
+         * of the sections, which relocations into the code and the
+         * constant pools refer to.
+         */
+        let mut symbols = vec![
+            Symbol { name: 0, info: 0, section: 0, value: 0, size: 0 },
+            Symbol { name: 0, info: STB_LOCAL << 4 | STT_SECTION, section: TEXT, value: 0, size: 0 },
+            Symbol { name: 0, info: STB_LOCAL << 4 | STT_SECTION, section: RODATA, value: 0, size: 0 }
+        ];
+        let locals = symbols.len();
+        
+        for (name, code) in &self.functions {
+            let align = code.align().max(POOL_ALIGN);
+            
+            text.resize(text.len().next_multiple_of(align), 0);
+            rodata.resize(rodata.len().next_multiple_of(POOL_ALIGN), 0);
+            offsets.push((text.len(), rodata.len()));
+            
+            symbols.push(Symbol {
+                name: string(&mut strtab, name),
+                info: STB_GLOBAL << 4 | STT_FUNC,
+                section: TEXT,
+                value: text.len() as u64,
+                size: code.code().len() as u64
+            });
+            
+            text.extend_from_slice(code.code());
+            rodata.extend_from_slice(code.pool());
+        }
+        
+        let mut rela = Vec::new();
+        let mut externals : Vec<String> = Vec::new();
+        
+        for ((name, code), &(text_offset, rodata_offset)) in self.functions.iter().zip(offsets.iter()) {
+            for reloc in code.relocs() {
+                let (symbol, addend) = match reloc.target {
+                    RelocTarget::Code => (TEXT as usize, text_offset as i64 + reloc.addend),
+                    RelocTarget::Pool => (RODATA as usize, rodata_offset as i64 + reloc.addend),
+                    RelocTarget::Address(address) if reloc.kind == RelocKind::Abs64 => (0, address as i64 + reloc.addend),
+                    RelocTarget::Address(address) => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                            format!("{} refers to the absolute address {:#x}", name, address)));
+                    }
+                    RelocTarget::Symbol(ref target) => {
+                        let symbol = match self.functions.iter().position(|(name, _)| name == target) {
+                            Some(index) => locals + index,
+                            None => match externals.iter().position(|name| name == target) {
+                                Some(index) => locals + self.functions.len() + index,
+                                None => {
+                                    externals.push(target.clone());
+                                    symbols.push(Symbol {
+                                        name: string(&mut strtab, target),
+                                        info: STB_GLOBAL << 4 | STT_NOTYPE,
+                                        section: 0,
+                                        value: 0,
+                                        size: 0
+                                    });
+                                    symbols.len() - 1
+                                }
+                            }
+                        };
+                        (symbol, reloc.addend)
+                    }
+                };
+                
+                let kind = match reloc.kind {
+                    RelocKind::Abs64 => R_X86_64_64,
+                    RelocKind::Rel32 => R_X86_64_PC32,
+                    RelocKind::Call32 => R_X86_64_PLT32
+                };
+                
+                put64(&mut rela, (text_offset + reloc.offset) as u64);
+                put64(&mut rela, (symbol as u64) << 32 | kind as u64);
+                put64(&mut rela, addend as u64);
+            }
+        }
+        
+        let sections = vec![
+            Section {
+                name: ".text",
+                kind: SHT_PROGBITS,
+                flags: SHF_ALLOC | SHF_EXECINSTR,
+                data: text,
+                align: 16,
+                ..Section::default()
+            },
+            Section {
+                name: ".rodata",
+                kind: SHT_PROGBITS,
+                flags: SHF_ALLOC,
+                data: rodata,
+                align: POOL_ALIGN as u64,
+                ..Section::default()
+            },
+            Section {
+                name: ".rela.text",
+                kind: SHT_RELA,
+                flags: SHF_INFO_LINK,
+                data: rela,
+                link: SYMTAB as u32,
+                info: TEXT as u32,
+                align: 8,
+                entry_size: RELA_SIZE as u64,
+                ..Section::default()
+            },
+            Section {
+                name: ".symtab",
+                kind: SHT_SYMTAB,
+                data: symbol_table(&symbols),
+                link: STRTAB as u32,
+                info: locals as u32,
+                align: 8,
+                entry_size: SYM_SIZE as u64,
+                ..Section::default()
+            },
+            Section {
+                name: ".strtab",
+                kind: SHT_STRTAB,
+                data: strtab,
+                align: 1,
+                ..Section::default()
+            },
+            /*
+             * Marks the stack as not executable.
+             */
+            Section {
+                name: ".note.GNU-stack",
+                kind: SHT_PROGBITS,
+                align: 1,
+                ..Section::default()
+            }
+        ];
+        
+        Ok(file(sections))
+    }
+}
+
+/*
+ * Builds the symbol file that describes a function to a debugger: an
+ * object whose .text section is placed at the address of the code, but
+ * holds no data, and a symbol that covers the code.
+ */
+pub(super) fn symbol_file(name: &str, address: u64, size: usize) -> Vec<u8> {
+    let mut strtab = vec![0];
+    let symbols = [
+        Symbol { name: 0, info: 0, section: 0, value: 0, size: 0 },
+        Symbol {
+            name: string(&mut strtab, name),
+            info: STB_GLOBAL << 4 | STT_FUNC,
+            section: TEXT,
+            value: 0,
+            size: size as u64
+        }
+    ];
+    
+    file(vec![
+        Section {
+            name: ".text",
+            kind: SHT_NOBITS,
+            flags: SHF_ALLOC | SHF_EXECINSTR,
+            address,
+            size: size as u64,
+            align: 16,
+            ..Section::default()
+        },
+        Section {
+            name: ".symtab",
+            kind: SHT_SYMTAB,
+            data: symbol_table(&symbols),
+            link: 3,
+            info: 1,
+            align: 8,
+            entry_size: SYM_SIZE as u64,
+            ..Section::default()
+        },
+        Section {
+            name: ".strtab",
+            kind: SHT_STRTAB,
+            data: strtab,
+            align: 1,
+            ..Section::default()
+        }
+    ])
+}
+
+fn symbol_table(symbols: &[Symbol]) -> Vec<u8> {
+    let mut symtab = Vec::with_capacity(symbols.len() * SYM_SIZE);
+    
+    for symbol in symbols {
+        put32(&mut symtab, symbol.name);
+        symtab.push(symbol.info);
+        symtab.push(0);
+        put16(&mut symtab, symbol.section);
+        put64(&mut symtab, symbol.value);
+        put64(&mut symtab, symbol.size);
+    }
+    
+    symtab
+}
+
+/*
+ * Lays out a relocatable file with the given sections, which get indices
+ * from 1, followed by the section name table. The file is the header, the
+ * contents of the sections and the section headers.
+ */
+fn file(mut sections: Vec<Section>) -> Vec<u8> {
+    let mut shstrtab = vec![0];
+    let names : Vec<u32> = sections.iter().map(|section| string(&mut shstrtab, section.name)).collect();
+    let shstrtab_name = string(&mut shstrtab, ".shstrtab");
+    
+    sections.push(Section {
+        kind: SHT_STRTAB,
+        data: shstrtab,
+        align: 1,
+        ..Section::default()
+    });
+    
+    let mut file = vec![0; EHDR_SIZE];
+    let mut headers = vec![0; SHDR_SIZE];
+    
+    for (section, &name) in sections.iter().zip(names.iter().chain(Some(&shstrtab_name))) {
+        let size = if section.kind == SHT_NOBITS { section.size } else { section.data.len() as u64 };
+        
+        file.resize(file.len().next_multiple_of(section.align as usize), 0);
+        
+        put32(&mut headers, name);
+        put32(&mut headers, section.kind);
+        put64(&mut headers, section.flags);
+        put64(&mut headers, section.address);
+        put64(&mut headers, file.len() as u64);
+        put64(&mut headers, size);
+        put32(&mut headers, section.link);
+        put32(&mut headers, section.info);
+        put64(&mut headers, section.align);
+        put64(&mut headers, section.entry_size);
+        
+        file.extend_from_slice(&section.data);
+    }
+    
+    file.resize(file.len().next_multiple_of(8), 0);
+    
+    let headers_offset = file.len();
+    file.extend_from_slice(&headers);
+    
+    let mut header = Vec::with_capacity(EHDR_SIZE);
+    header.extend_from_slice(b"\x7fELF");
+    /* ELFCLASS64, ELFDATA2LSB, EV_CURRENT, ELFOSABI_NONE */
+    header.extend_from_slice(&[2, 1, 1, 0]);
+    header.extend_from_slice(&[0; 8]);
+    /* ET_REL, EM_X86_64 */
+    put16(&mut header, 1);
+    put16(&mut header, 62);
+    put32(&mut header, 1);
+    put64(&mut header, 0);
+    put64(&mut header, 0);
+    put64(&mut header, headers_offset as u64);
+    put32(&mut header, 0);
+    put16(&mut header, EHDR_SIZE as u16);
+    put16(&mut header, 0);
+    put16(&mut header, 0);
+    put16(&mut header, SHDR_SIZE as u16);
+    put16(&mut header, sections.len() as u16 + 1);
+    put16(&mut header, sections.len() as u16);
+    
+    file[..EHDR_SIZE].copy_from_slice(&header);
+    
+    file
+}
+
+/*
+ * Adds a string to a string table and returns its offset.
+ */
+fn string(table: &mut Vec<u8>, string: &str) -> u32 {
+    let offset = table.len() as u32;
+    table.extend_from_slice(string.as_bytes());
+    table.push(0);
+    offset
+}
+
+fn put16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::path::PathBuf;
+    use std::process::Command;
+    use codegen::ObjectFile;
+    use codegen::x86_64::prologue::*;
+    
+    /*
+     * A directory that is removed with its contents when it is dropped,
+     * also when the test fails.
+     */
+    struct TempDir(PathBuf);
+    
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = env::temp_dir().join(format!("{}_{}", name, ::std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+    
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+    
+    /*
+     * Links an object with a main function written with the code
+     * generator, which calls a function in the same object and one in
+     * libc, and runs it.
+     */
+    #[test]
+    fn link_and_run() {
+        let mut twice = Codegen::new();
+        let two = twice.const_f64(2.0);
+        twice.cvtsi2sd(XMM0, RDI).unwrap();
+        twice.mulsd(XMM0, two).unwrap();
+        twice.cvttsd2si(RAX, XMM0).unwrap();
+        twice.ret();
+        
+        let mut main = Codegen::new();
+        main.push(RBX).unwrap();
+        main.mov_symbol(RBX, "twice").unwrap();
+        main.mov(RDI, -21i64).unwrap();
+        main.call(RBX).unwrap();
+        main.mov(RDI, RAX).unwrap();
+        main.call_symbol("labs");
+        main.pop(RBX).unwrap();
+        main.ret();
+        
+        let mut object = ObjectFile::new();
+        object.add_function("twice", twice.finish().unwrap());
+        object.add_function("main", main.finish().unwrap());
+        
+        let dir = TempDir::new("rjs_jit_elf");
+        let path = dir.0.join("main.o");
+        let exe = dir.0.join("main");
+        
+        object.write(&mut File::create(&path).unwrap()).unwrap();
+        
+        let link = Command::new("cc").arg("-no-pie").arg("-o").arg(&exe).arg(&path).status().unwrap();
+        assert!(link.success());
+        
+        let status = Command::new(&exe).status().unwrap();
+        
+        assert_eq!(status.code(), Some(42));
+    }
+}
@@ -0,0 +1,140 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// The kind of an instruction operand.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum OperandKind {
+    Reg,
+    Imm,
+    Mem,
+    MemBase,
+    MemIndex,
+    Xmm
+}
+
+/// Describes an operand of an instruction that could not be encoded.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Operand {
+    pub kind: OperandKind,
+    /// The size of the operand in bytes, if the operand has a size.
+    pub size: Option<i32>
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            OperandKind::Reg => "reg",
+            OperandKind::Imm => "imm",
+            OperandKind::Mem => "mem",
+            OperandKind::MemBase => "membase",
+            OperandKind::MemIndex => "memindex",
+            OperandKind::Xmm => "xmm"
+        };
+        
+        match self.size {
+            Some(size) => write!(f, "{}{}", kind, size * 8),
+            None => write!(f, "{}", kind)
+        }
+    }
+}
+
+/// Why an instruction could not be encoded.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CodegenErrorKind {
+    /// The instruction has no encoding for this combination of operands.
+    UnsupportedOperands,
+    /// The operands have sizes that do not match.
+    SizeMismatch,
+    /// An operand has a size the instruction cannot encode.
+    InvalidSize,
+    /// An immediate does not fit in the encoding of the instruction.
+    ImmOutOfRange,
+    /// A branch or address refers to a label that was never bound.
+    UnboundLabel,
+    /// The code refers to a symbol that is not defined.
+    UndefinedSymbol,
+    /// The target of a RIP relative field is not within 2GB of it.
+    TargetOutOfRange,
+    /// The code heap could not map memory for the code.
+    MapFailed
+}
+
+/// An instruction that could not be encoded, or code that could not be
+/// built. The code generated so far is unaffected; nothing is emitted for
+/// the failing instruction.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CodegenError {
+    pub kind: CodegenErrorKind,
+    /// The mnemonic of the instruction, or `build` for code that could
+    /// not be built.
+    pub instruction: &'static str,
+    pub operands: Vec<Operand>,
+    /// Offset into the instruction stream at which the instruction would
+    /// have been emitted. Branches are not laid out yet, so this is the
+    /// offset before any branch is encoded. For code that could not be
+    /// built, this is the offset of the branch or field at fault. Memory
+    /// that could not be mapped has no location, so the offset is zero.
+    pub offset: usize,
+    /// The error the operating system reported when the code heap could
+    /// not map memory.
+    pub cause: Option<String>
+}
+
+impl CodegenError {
+    /*
+     * An error found when the code is built rather than encoded.
+     */
+    pub(super) fn build(kind: CodegenErrorKind, offset: usize) -> CodegenError {
+        CodegenError {
+            kind,
+            instruction: "build",
+            operands: Vec::new(),
+            offset,
+            cause: None
+        }
+    }
+    
+    /*
+     * An error of the code heap, which could not map memory for the code.
+     */
+    pub(super) fn map_failed(err: &io::Error) -> CodegenError {
+        CodegenError {
+            cause: Some(err.to_string()),
+            ..CodegenError::build(CodegenErrorKind::MapFailed, 0)
+        }
+    }
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self.kind {
+            CodegenErrorKind::UnsupportedOperands => "unsupported operands",
+            CodegenErrorKind::SizeMismatch => "operand size mismatch",
+            CodegenErrorKind::InvalidSize => "invalid operand size",
+            CodegenErrorKind::ImmOutOfRange => "immediate out of range",
+            CodegenErrorKind::UnboundLabel => "unbound label",
+            CodegenErrorKind::UndefinedSymbol => "undefined symbol",
+            CodegenErrorKind::TargetOutOfRange => "target out of range",
+            CodegenErrorKind::MapFailed => "cannot map memory"
+        };
+        
+        if let Some(ref cause) = self.cause {
+            return write!(f, "cannot build code: {}: {}", reason, cause);
+        }
+        
+        if self.instruction == "build" {
+            return write!(f, "cannot build code at offset {}: {}", self.offset, reason);
+        }
+        
+        write!(f, "cannot encode {}", self.instruction)?;
+        
+        for (i, operand) in self.operands.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { " " } else { ", " }, operand)?;
+        }
+        
+        write!(f, " at offset {}: {}", self.offset, reason)
+    }
+}
+
+impl Error for CodegenError {}
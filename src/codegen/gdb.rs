@@ -0,0 +1,235 @@
+use std::ptr;
+use std::sync::Mutex;
+use super::elf;
+
+/*
+ * The GDB JIT compilation interface. GDB sets a breakpoint in
+ * __jit_debug_register_code, and every time it is called reads the
+ * entry that __jit_debug_descriptor points to and loads its symbol file,
+ * or forgets it again.
+ */
+
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+#[repr(C)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64
+}
+
+#[repr(C)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry
+}
+
+#[no_mangle]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: JIT_NOACTION,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut()
+};
+
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    /*
+     * Keeps the call from being optimized away.
+     */
+    unsafe { ptr::read_volatile(ptr::addr_of!(__jit_debug_descriptor.action_flag)); }
+}
+
+/*
+ * Serializes changes to the list of entries, which is shared by all
+ * threads.
+ */
+static LOCK: Mutex<()> = Mutex::new(());
+
+/*
+ * The registration of a function with the debugger, which is undone when
+ * it is dropped.
+ */
+pub(super) struct Registration {
+    entry: *mut JitCodeEntry,
+    symfile: Vec<u8>
+}
+
+/*
+ * The entry is only accessed with LOCK held.
+ */
+unsafe impl Send for Registration {}
+
+impl Registration {
+    pub(super) fn new(name: &str, address: u64, size: usize) -> Registration {
+        let symfile = elf::symbol_file(name, address, size);
+        let entry = Box::into_raw(Box::new(JitCodeEntry {
+            next_entry: ptr::null_mut(),
+            prev_entry: ptr::null_mut(),
+            symfile_addr: symfile.as_ptr(),
+            symfile_size: symfile.len() as u64
+        }));
+        
+        let _lock = LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        
+        unsafe {
+            let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+            
+            (*entry).next_entry = (*descriptor).first_entry;
+            if !(*entry).next_entry.is_null() {
+                (*(*entry).next_entry).prev_entry = entry;
+            }
+            (*descriptor).first_entry = entry;
+            (*descriptor).relevant_entry = entry;
+            (*descriptor).action_flag = JIT_REGISTER_FN;
+            __jit_debug_register_code();
+        }
+        
+        Registration {
+            entry,
+            symfile
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _lock = LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        
+        unsafe {
+            let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+            let entry = self.entry;
+            
+            if (*entry).prev_entry.is_null() {
+                (*descriptor).first_entry = (*entry).next_entry;
+            } else {
+                (*(*entry).prev_entry).next_entry = (*entry).next_entry;
+            }
+            if !(*entry).next_entry.is_null() {
+                (*(*entry).next_entry).prev_entry = (*entry).prev_entry;
+            }
+            (*descriptor).relevant_entry = entry;
+            (*descriptor).action_flag = JIT_UNREGISTER_FN;
+            __jit_debug_register_code();
+            
+            drop(Box::from_raw(entry));
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::convert::TryInto;
+    use std::ptr;
+    use std::slice;
+    use std::str;
+    use codegen::JitFunction;
+    use codegen::x86_64::prologue::*;
+    use super::{LOCK, __jit_debug_descriptor};
+    
+    fn get16(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+    
+    fn get32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+    
+    fn get64(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+    
+    fn get_str(bytes: &[u8], offset: usize) -> &str {
+        let end = offset + bytes[offset..].iter().position(|&b| b == 0).unwrap();
+        str::from_utf8(&bytes[offset..end]).unwrap()
+    }
+    
+    /*
+     * Reads a symbol file the way a debugger does, and returns the address
+     * of its .text section and the name and size of its function symbol.
+     */
+    fn parse(symfile: &[u8]) -> (u64, String, u64) {
+        assert_eq!(&symfile[..4], b"\x7fELF");
+        
+        let shoff = get64(symfile, 0x28) as usize;
+        let shnum = get16(symfile, 0x3c) as usize;
+        let shstrndx = get16(symfile, 0x3e) as usize;
+        let section = |i: usize| &symfile[shoff + i * 64..shoff + (i + 1) * 64];
+        let data = |i: usize| {
+            let header = section(i);
+            let offset = get64(header, 24) as usize;
+            &symfile[offset..offset + get64(header, 32) as usize]
+        };
+        let find = |name: &str| (0..shnum).find(|&i| get_str(data(shstrndx), get32(section(i), 0) as usize) == name).unwrap();
+        
+        let text = find(".text");
+        let symtab = find(".symtab");
+        let strtab = get32(section(symtab), 40) as usize;
+        let symbol = &data(symtab)[24..48];
+        
+        assert_eq!(get16(symbol, 6) as usize, text);
+        (get64(section(text), 16), get_str(data(strtab), get32(symbol, 0) as usize).to_string(), get64(symbol, 16))
+    }
+    
+    /*
+     * The symbol files of the registered functions. The list is walked
+     * with the lock held, as other tests register functions meanwhile.
+     */
+    fn entries() -> Vec<(u64, String, u64)> {
+        let _lock = LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let mut result = Vec::new();
+        
+        unsafe {
+            let mut prev = ptr::null_mut();
+            let mut entry = (*ptr::addr_of!(__jit_debug_descriptor)).first_entry;
+            
+            while !entry.is_null() {
+                assert_eq!((*entry).prev_entry, prev);
+                result.push(parse(slice::from_raw_parts((*entry).symfile_addr, (*entry).symfile_size as usize)));
+                prev = entry;
+                entry = (*entry).next_entry;
+            }
+        }
+        
+        result
+    }
+    
+    fn function(name: &str) -> JitFunction {
+        let mut cg = Codegen::new();
+        cg.mov(EAX, 1).unwrap();
+        cg.ret();
+        
+        cg.build_named(name).unwrap()
+    }
+    
+    fn entry(f: &JitFunction) -> (u64, String, u64) {
+        (unsafe { f.ptr() } as u64, f.name().to_string(), f.size() as u64)
+    }
+    
+    #[test]
+    fn registration() {
+        let first = function("gdb_tests_first");
+        let second = function("gdb_tests_second");
+        let (first_entry, second_entry) = (entry(&first), entry(&second));
+        
+        let registered = entries();
+        assert!(registered.contains(&first_entry));
+        assert!(registered.contains(&second_entry));
+        
+        drop(first);
+        
+        let registered = entries();
+        assert!(!registered.contains(&first_entry));
+        assert!(registered.contains(&second_entry));
+        
+        drop(second);
+        
+        assert!(!entries().contains(&second_entry));
+    }
+}
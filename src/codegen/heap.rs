@@ -0,0 +1,615 @@
+use std::cmp;
+use std::io;
+use std::ptr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use super::os::*;
+
+/*
+ * The default size of the arenas functions are carved out of. Functions
+ * that do not fit get an arena of their own.
+ */
+const ARENA_SIZE: usize = 1 << 20;
+
+/*
+ * The default alignment of functions in the heap.
+ */
+const ALIGNMENT: usize = 16;
+
+/*
+ * How far arenas of a heap with an anchor may be from the anchor. Code in
+ * them reaches everything within NEAR_RANGE of the anchor with a rel32
+ * displacement.
+ */
+const NEAR_RANGE: u64 = 1 << 30;
+
+thread_local! {
+    static LOCAL: CodeHeap = {
+        let heap = CodeHeap::new();
+        heap.set_anchor(Some(CodeHeap::new as fn() -> CodeHeap as usize as u64));
+        heap
+    };
+}
+
+/// Executable memory that functions are allocated from. The heap maps
+/// large arenas and carves the functions out of them, and the space of a
+/// function is reused once its `JitFunction` is dropped. Cloning a
+/// `CodeHeap` gives another handle to the same heap.
+///
+/// Every arena is mapped twice, and code is copied in through a writable
+/// view while it runs from an executable one, so installing a function
+/// never touches the access rights of the code around it. Only a function
+/// that is reopened for patching stops being executable until the patch
+/// is finished. The space of a function is rounded up to the alignment,
+/// so with an alignment of a page every function has pages of its own and
+/// can be reopened.
+///
+/// A heap can be shared between threads, and functions built in it can be
+/// sent to and called from other threads.
+#[derive(Clone)]
+pub struct CodeHeap {
+    heap: Arc<Mutex<Heap>>
+}
+
+/// Statistics of a `CodeHeap`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct CodeHeapStats {
+    /// The number of arenas.
+    pub arenas: usize,
+    /// The number of functions in the heap.
+    pub functions: usize,
+    /// The number of bytes mapped for the arenas.
+    pub reserved: usize,
+    /// The number of bytes taken by functions.
+    pub used: usize,
+    /// The number of bytes available for functions.
+    pub free: usize,
+    /// The size of the largest free block.
+    pub largest_free: usize
+}
+
+impl CodeHeapStats {
+    /// The fraction of the free space that is not part of the largest
+    /// free block, between 0 and 1.
+    pub fn fragmentation(&self) -> f64 {
+        if self.free == 0 {
+            0.0
+        } else {
+            1.0 - self.largest_free as f64 / self.free as f64
+        }
+    }
+}
+
+struct Heap {
+    arenas: Vec<Arena>,
+    arena_size: usize,
+    alignment: usize,
+    anchor: Option<u64>,
+    functions: usize
+}
+
+/*
+ * An arena keeps its free blocks as (offset, size) pairs sorted by offset,
+ * with adjacent blocks merged. writers counts for every page how many
+ * blocks on it are reopened, which leaves the page not executable.
+ */
+struct Arena {
+    memory: DualMemory,
+    free: Vec<(usize, usize)>,
+    writers: Vec<u32>
+}
+
+impl Arena {
+    fn new(size: usize, anchor: Option<u64>) -> io::Result<Arena> {
+        let memory = match anchor {
+            Some(anchor) => DualMemory::alloc_near(anchor, NEAR_RANGE, size).or_else(|_| DualMemory::alloc(size))?,
+            None => DualMemory::alloc(size)?
+        };
+        let size = memory.size();
+        
+        Ok(Arena {
+            memory,
+            free: vec![(0, size)],
+            writers: vec![0; size / page_size()]
+        })
+    }
+    
+    fn contains(&self, ptr: *const u8) -> bool {
+        let start = unsafe { self.memory.ptr() } as usize;
+        
+        (start..start + self.memory.size()).contains(&(ptr as usize))
+    }
+    
+    fn offset(&self, ptr: *const u8) -> usize {
+        ptr as usize - unsafe { self.memory.ptr() } as usize
+    }
+    
+    /*
+     * Takes size bytes at an offset aligned to align from the first free
+     * block they fit in. Pages that a reopened block keeps from being
+     * executable are skipped.
+     */
+    fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+        let page_size = page_size();
+        
+        for i in 0..self.free.len() {
+            let (offset, len) = self.free[i];
+            let mut start = offset.next_multiple_of(align);
+            
+            while start < offset + len {
+                match self.writable_page(start, size) {
+                    Some(page) => start = ((page + 1) * page_size).next_multiple_of(align),
+                    None => break
+                }
+            }
+            
+            if start + size > offset + len {
+                continue;
+            }
+            
+            self.free.remove(i);
+            
+            if start + size < offset + len {
+                self.free.insert(i, (start + size, offset + len - start - size));
+            }
+            if start > offset {
+                self.free.insert(i, (offset, start - offset));
+            }
+            
+            return Some(start);
+        }
+        
+        None
+    }
+    
+    fn free(&mut self, offset: usize, size: usize) {
+        let i = self.free.iter().position(|&(free, _)| free > offset).unwrap_or(self.free.len());
+        
+        self.free.insert(i, (offset, size));
+        
+        if i + 1 < self.free.len() && offset + size == self.free[i + 1].0 {
+            self.free[i].1 += self.free[i + 1].1;
+            self.free.remove(i + 1);
+        }
+        if i > 0 && self.free[i - 1].0 + self.free[i - 1].1 == offset {
+            self.free[i - 1].1 += self.free[i].1;
+            self.free.remove(i);
+        }
+    }
+    
+    fn is_empty(&self) -> bool {
+        self.free == [(0, self.memory.size())]
+    }
+    
+    /*
+     * Returns the last page of a range of the arena that a reopened block
+     * keeps from being executable.
+     */
+    fn writable_page(&self, offset: usize, size: usize) -> Option<usize> {
+        let page_size = page_size();
+        let end = cmp::min((offset + size).div_ceil(page_size), self.writers.len());
+        
+        (offset / page_size..end).rev().find(|&page| self.writers[page] != 0)
+    }
+    
+    /*
+     * Returns whether another block has space on the pages of a block.
+     * Free blocks are merged, so a free range lies in a single one.
+     */
+    fn shares_pages(&self, offset: usize, size: usize) -> bool {
+        let page_size = page_size();
+        let start = offset / page_size * page_size;
+        let end = cmp::min((offset + size).next_multiple_of(page_size), self.memory.size());
+        let is_free = |from: usize, to: usize| {
+            from == to || self.free.iter().any(|&(free, len)| free <= from && to <= free + len)
+        };
+        
+        !is_free(start, offset) || !is_free(offset + size, end)
+    }
+    
+    /*
+     * Takes the execute permission from the pages of a block that is
+     * patched, or gives it back once no other block on them is. The counts
+     * only change once all pages are protected, so a failure leaves them
+     * as they were and can be retried.
+     */
+    fn set_writable(&mut self, offset: usize, size: usize, writable: bool) -> io::Result<()> {
+        let page_size = page_size();
+        let pages = offset / page_size..(offset + size).div_ceil(page_size);
+        let (last, protection) = if writable { (0, Protection::Read) } else { (1, Protection::ReadExecute) };
+        
+        for page in pages.clone() {
+            if self.writers[page] == last {
+                self.memory.protect_range(page * page_size, page_size, protection)?;
+            }
+        }
+        
+        for page in pages {
+            if writable {
+                self.writers[page] += 1;
+            } else {
+                self.writers[page] -= 1;
+            }
+        }
+        
+        Ok(())
+    }
+}
+
+impl CodeHeap {
+    pub fn new() -> CodeHeap {
+        CodeHeap::with_arena_size(ARENA_SIZE)
+    }
+    
+    /// Creates a heap that maps arenas of the given size. The size is
+    /// rounded up to whole pages.
+    pub fn with_arena_size(arena_size: usize) -> CodeHeap {
+        CodeHeap {
+            heap: Arc::new(Mutex::new(Heap {
+                arenas: Vec::new(),
+                arena_size,
+                alignment: ALIGNMENT,
+                anchor: None,
+                functions: 0
+            }))
+        }
+    }
+    
+    /// The heap shared by all code generators of this thread that are
+    /// not given a heap of their own. It is anchored at the code of this
+    /// crate.
+    pub fn local() -> CodeHeap {
+        LOCAL.with(|heap| heap.clone())
+    }
+    
+    /// Sets the alignment of the start of functions, which must be a
+    /// power of two no larger than a page. The default is 16 bytes.
+    pub fn set_alignment(&self, alignment: usize) {
+        assert!(alignment.is_power_of_two() && alignment <= page_size(), "invalid alignment");
+        
+        self.lock().alignment = alignment;
+    }
+    
+    pub fn alignment(&self) -> usize {
+        self.lock().alignment
+    }
+    
+    /// Sets the address that new arenas are placed near, typically an
+    /// address in the text of the binary, so calls from the code to
+    /// functions near it can use a rel32 displacement. Arenas are placed
+    /// anywhere when there is no room near the anchor.
+    pub fn set_anchor(&self, anchor: Option<u64>) {
+        self.lock().anchor = anchor;
+    }
+    
+    pub fn anchor(&self) -> Option<u64> {
+        self.lock().anchor
+    }
+    
+    pub fn stats(&self) -> CodeHeapStats {
+        let heap = self.lock();
+        let reserved = heap.arenas.iter().map(|arena| arena.memory.size()).sum();
+        let free = heap.arenas.iter().flat_map(|arena| arena.free.iter()).map(|&(_, size)| size).sum();
+        let largest_free = heap.arenas.iter().flat_map(|arena| arena.free.iter()).map(|&(_, size)| size).max();
+        
+        CodeHeapStats {
+            arenas: heap.arenas.len(),
+            functions: heap.functions,
+            reserved,
+            used: reserved - free,
+            free,
+            largest_free: largest_free.unwrap_or(0)
+        }
+    }
+    
+    /*
+     * A panic while the heap is locked leaves it consistent, so a poisoned
+     * lock is taken over.
+     */
+    fn lock(&self) -> MutexGuard<'_, Heap> {
+        self.heap.lock().unwrap_or_else(|err| err.into_inner())
+    }
+    
+    /*
+     * Reserves size bytes at an address aligned to at least align, so the
+     * code can be patched for its address before it is copied in with
+     * write.
+     */
+    pub(super) fn alloc(&self, size: usize, align: usize) -> io::Result<Block> {
+        let mut heap = self.lock();
+        let align = cmp::max(align, heap.alignment);
+        let size = cmp::max(size, 1).next_multiple_of(heap.alignment);
+        
+        let found = heap.arenas.iter_mut().enumerate()
+            .filter_map(|(i, arena)| arena.alloc(size, align).map(|offset| (i, offset)))
+            .next();
+        
+        let (arena, offset) = match found {
+            Some(found) => found,
+            None => {
+                let mut arena = Arena::new(cmp::max(heap.arena_size, size), heap.anchor)?;
+                let offset = arena.alloc(size, align).unwrap();
+                heap.arenas.push(arena);
+                (heap.arenas.len() - 1, offset)
+            }
+        };
+        
+        heap.functions += 1;
+        
+        Ok(Block {
+            heap: self.clone(),
+            ptr: unsafe { heap.arenas[arena].memory.ptr().add(offset) },
+            write: unsafe { heap.arenas[arena].memory.write_ptr().add(offset) },
+            size
+        })
+    }
+}
+
+impl Default for CodeHeap {
+    fn default() -> CodeHeap {
+        CodeHeap::new()
+    }
+}
+
+/*
+ * The space of one function in a CodeHeap, which is freed when the block
+ * is dropped. ptr is the address of the code and write the address it is
+ * written at.
+ */
+pub(super) struct Block {
+    heap: CodeHeap,
+    ptr: *mut u8,
+    write: *mut u8,
+    size: usize
+}
+
+/*
+ * The block is only accessed through its heap, which is locked for it.
+ */
+unsafe impl Send for Block {}
+
+impl Block {
+    pub(super) fn ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+    
+    pub(super) fn write_ptr(&self) -> *mut u8 {
+        self.write
+    }
+    
+    pub(super) fn set_writable(&self, writable: bool) -> io::Result<()> {
+        let mut heap = self.heap.lock();
+        let arena = heap.arenas.iter_mut().find(|arena| arena.contains(self.ptr)).unwrap();
+        let offset = arena.offset(self.ptr);
+        
+        arena.set_writable(offset, self.size, writable)
+    }
+    
+    /*
+     * Makes the block not executable for patching. Blocks on the same
+     * pages would stop being executable too, so this fails when the block
+     * shares a page with another one.
+     */
+    pub(super) fn reopen(&self) -> io::Result<()> {
+        {
+            let heap = self.heap.lock();
+            let arena = heap.arenas.iter().find(|arena| arena.contains(self.ptr)).unwrap();
+            
+            if arena.shares_pages(arena.offset(self.ptr), self.size) {
+                return Err(io::Error::other("the code shares a page with other functions"));
+            }
+        }
+        
+        self.set_writable(true)
+    }
+    
+    /*
+     * Gives the end of the block back to the heap.
+     */
+    pub(super) fn shrink(&mut self, size: usize) {
+        let mut heap = self.heap.lock();
+        let size = cmp::max(size, 1).next_multiple_of(heap.alignment);
+        
+        if size < self.size {
+            let arena = heap.arenas.iter_mut().find(|arena| arena.contains(self.ptr)).unwrap();
+            let offset = arena.offset(self.ptr);
+            
+            arena.free(offset + size, self.size - size);
+            self.size = size;
+        }
+    }
+    
+    /*
+     * Copies the code in through the writable view. Other functions on the
+     * same pages keep running while this happens.
+     */
+    pub(super) fn write(&self, code: &[u8]) {
+        assert!(code.len() <= self.size);
+        
+        unsafe { ptr::copy(code.as_ptr(), self.write, code.len()); }
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        let mut heap = self.heap.lock();
+        let i = heap.arenas.iter().position(|arena| arena.contains(self.ptr)).unwrap();
+        let offset = heap.arenas[i].offset(self.ptr);
+        
+        heap.arenas[i].free(offset, self.size);
+        heap.functions -= 1;
+        
+        /*
+         * Empty arenas are unmapped, except for the last one left.
+         */
+        if heap.arenas[i].is_empty() && heap.arenas.len() > 1 {
+            heap.arenas.remove(i);
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+    use codegen::{CodeHeap, CodeHeapStats, JitFunction};
+    use codegen::os::page_size;
+    use codegen::x86_64::prologue::*;
+    
+    fn constant(heap: &CodeHeap, value: i32) -> JitFunction<fn() -> i32> {
+        let mut cg = Codegen::new();
+        cg.set_heap(heap.clone());
+        cg.mov(EAX, value).unwrap();
+        cg.ret();
+        
+        unsafe { cg.build_fn().unwrap() }
+    }
+    
+    #[test]
+    fn reuse() {
+        let size = page_size();
+        let heap = CodeHeap::with_arena_size(size);
+        let a = heap.alloc(100, 1).unwrap();
+        let b = heap.alloc(100, 1).unwrap();
+        let first = a.ptr();
+        
+        assert_eq!(b.ptr() as usize - a.ptr() as usize, 112);
+        assert_eq!(heap.stats(), CodeHeapStats {
+            arenas: 1,
+            functions: 2,
+            reserved: size,
+            used: 224,
+            free: size - 224,
+            largest_free: size - 224
+        });
+        assert_eq!(heap.stats().fragmentation(), 0.0);
+        
+        drop(a);
+        
+        let stats = heap.stats();
+        assert_eq!((stats.functions, stats.used, stats.free, stats.largest_free), (1, 112, size - 112, size - 224));
+        assert_eq!(stats.fragmentation(), 1.0 - (size - 224) as f64 / (size - 112) as f64);
+        
+        let c = heap.alloc(64, 1).unwrap();
+        assert_eq!(c.ptr(), first);
+        
+        let d = heap.alloc(size, 1).unwrap();
+        assert_eq!(heap.stats().arenas, 2);
+        
+        drop((b, c, d));
+        
+        let stats = heap.stats();
+        assert_eq!((stats.arenas, stats.functions, stats.used, stats.free), (1, 0, 0, stats.reserved));
+        assert_eq!(stats.fragmentation(), 0.0);
+    }
+    
+    #[test]
+    fn alignment() {
+        let heap = CodeHeap::new();
+        heap.set_alignment(64);
+        
+        let blocks = (1..8).map(|i| heap.alloc(i * 10, 1).unwrap()).collect::<Vec<_>>();
+        for block in &blocks {
+            assert_eq!(block.ptr() as usize % 64, 0);
+        }
+        
+        let block = heap.alloc(10, 256).unwrap();
+        assert_eq!(block.ptr() as usize % 256, 0);
+    }
+    
+    /*
+     * Reopening a function must not take away the execute permission of
+     * functions on the same page.
+     */
+    #[test]
+    fn reopen() {
+        let heap = CodeHeap::new();
+        let (mut f1, f2) = (constant(&heap, 1), constant(&heap, 2));
+        
+        assert!(f1.reopen().is_err());
+        assert_eq!((f1.call(()), f2.call(())), (1, 2));
+        
+        /*
+         * A function alone on its page can be reopened, and functions
+         * built meanwhile go to other pages.
+         */
+        let heap = CodeHeap::new();
+        let mut f1 = constant(&heap, 1);
+        
+        let mut patch = f1.reopen().unwrap();
+        let f2 = constant(&heap, 2);
+        assert_eq!(f2.call(()), 2);
+        patch[1] = 3;
+        patch.finish().unwrap();
+        
+        assert!(f1.is_sealed());
+        assert_eq!((f1.call(()), f2.call(())), (3, 2));
+        
+        let heap = CodeHeap::new();
+        heap.set_alignment(page_size());
+        let (mut f1, f2) = (constant(&heap, 1), constant(&heap, 2));
+        
+        f1.reopen().unwrap()[1] = 4;
+        assert_eq!((f1.call(()), f2.call(())), (4, 2));
+    }
+    
+    /*
+     * Functions can be called from, and dropped on, another thread than
+     * the one that built them, while that one keeps using the heap.
+     */
+    #[test]
+    fn threads() {
+        let heap = CodeHeap::new();
+        let f = constant(&heap, 5);
+        
+        let thread = {
+            let heap = heap.clone();
+            thread::spawn(move || {
+                let g = constant(&heap, 6);
+                (f.call(()), g.call(()))
+            })
+        };
+        let h = constant(&heap, 7);
+        
+        assert_eq!(thread.join().unwrap(), (5, 6));
+        assert_eq!(h.call(()), 7);
+        assert_eq!(heap.stats().functions, 1);
+    }
+    
+    /*
+     * Installing a function must not take away the execute permission of
+     * the functions on the same pages while another thread runs them.
+     */
+    #[test]
+    fn install_while_running() {
+        let heap = CodeHeap::new();
+        let f = constant(&heap, 8);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+        
+        let thread = {
+            let (calls, done) = (calls.clone(), done.clone());
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    assert_eq!(f.call(()), 8);
+                    calls.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        };
+        
+        while calls.load(Ordering::Relaxed) == 0 {
+            thread::yield_now();
+        }
+        
+        /*
+         * Every function is dropped right away, so the next one goes to the
+         * same page as the one that is running.
+         */
+        for i in 0..10000 {
+            assert_eq!(constant(&heap, i).call(()), i);
+        }
+        
+        done.store(true, Ordering::Relaxed);
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,305 @@
+//! A reference interpreter for the IR, which executes functions directly
+//! in Rust to check the code that `Codegen::lower` generates against.
+
+use std::fmt;
+use std::ptr;
+use super::{BinOp, CastOp, CmpOp, Function, Op, Type, Value};
+
+/// A value of an interpreted function.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Scalar {
+    I32(i32),
+    I64(i64),
+    F64(f64),
+    Ptr(u64)
+}
+
+impl Scalar {
+    pub fn ty(self) -> Type {
+        match self {
+            Scalar::I32(_) => Type::I32,
+            Scalar::I64(_) => Type::I64,
+            Scalar::F64(_) => Type::F64,
+            Scalar::Ptr(_) => Type::Ptr
+        }
+    }
+    
+    /*
+     * The integer sign extended, or the pointer, as an i64.
+     */
+    fn signed(self) -> i64 {
+        match self {
+            Scalar::I32(value) => value as i64,
+            Scalar::I64(value) => value,
+            Scalar::Ptr(value) => value as i64,
+            Scalar::F64(_) => panic!("{:?} is not an integer", self)
+        }
+    }
+    
+    /*
+     * The integer zero extended, or the pointer, as a u64.
+     */
+    fn unsigned(self) -> u64 {
+        match self {
+            Scalar::I32(value) => value as u32 as u64,
+            _ => self.signed() as u64
+        }
+    }
+    
+    fn float(self) -> f64 {
+        match self {
+            Scalar::F64(value) => value,
+            _ => panic!("{:?} is not a float", self)
+        }
+    }
+    
+    /*
+     * The integer or pointer of the type, truncated from an i64.
+     */
+    fn from_int(ty: Type, value: i64) -> Scalar {
+        match ty {
+            Type::I32 => Scalar::I32(value as i32),
+            Type::I64 => Scalar::I64(value),
+            Type::Ptr => Scalar::Ptr(value as u64),
+            Type::F64 => panic!("{} is not an integer type", ty)
+        }
+    }
+}
+
+/// Undefined behavior that stops the interpreter. Generated code may
+/// fault or produce any result instead.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Trap {
+    /// An integer division by zero.
+    DivideByZero,
+    /// A signed division of the smallest integer by -1.
+    Overflow,
+    /// A conversion of NaN or of a float out of range to an integer.
+    InvalidConversion
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Trap::DivideByZero => "division by zero",
+            Trap::Overflow => "division overflow",
+            Trap::InvalidConversion => "invalid float to integer conversion"
+        })
+    }
+}
+
+/// Interprets the function with the arguments and returns its result.
+/// Calls are made through `call`, which gets the target address and the
+/// arguments and returns the result of the native function.
+///
+/// # Safety
+///
+/// Loads and stores access the memory at the addresses the function
+/// computes, as the generated code would.
+pub unsafe fn interpret<F>(func: &Function, args: &[Scalar], mut call: F) -> Result<Option<Scalar>, Trap>
+    where F: FnMut(u64, &[Scalar]) -> Option<Scalar>
+{
+    assert!(args.len() == func.params().len() && args.iter().zip(func.params()).all(|(arg, &ty)| arg.ty() == ty), "arguments {:?} do not match the parameters", args);
+    
+    let mut values = vec![None; func.values().count()];
+    for (i, &arg) in args.iter().enumerate() {
+        values[i] = Some(arg);
+    }
+    
+    let get = |values: &[Option<Scalar>], value: Value| {
+        values[value.index()].unwrap_or_else(|| panic!("{} is used before it is defined", value))
+    };
+    
+    let mut block = func.entry();
+    
+    loop {
+        let mut next = None;
+        
+        for inst in func.insts(block) {
+            let ty = inst.result.map(|result| func.ty(result));
+            
+            let value = match inst.op {
+                Op::Int(value) => Some(Scalar::from_int(ty.unwrap(), value)),
+                Op::Float(value) => Some(Scalar::F64(value)),
+                Op::Binary(op, a, b) => Some(binary(op, get(&values, a), get(&values, b))?),
+                Op::Cmp(op, a, b) => Some(Scalar::I32(cmp(op, get(&values, a), get(&values, b)) as i32)),
+                Op::Cast(op, value) => Some(cast(op, get(&values, value), ty.unwrap())?),
+                Op::Load(addr, disp) => Some(load(ty.unwrap(), address(get(&values, addr), disp))),
+                Op::Store(value, addr, disp) => {
+                    store(get(&values, value), address(get(&values, addr), disp));
+                    None
+                }
+                Op::Call(target, ref args) => {
+                    let args = args.iter().map(|&arg| get(&values, arg)).collect::<Vec<_>>();
+                    let value = call(target, &args);
+                    assert!(value.map(|value| value.ty()) == ty, "call of {:#x} returned {:?} instead of {:?}", target, value, ty);
+                    value
+                }
+                Op::Phi(_) => continue,
+                Op::Jump(target) => {
+                    next = Some(target);
+                    None
+                }
+                Op::Branch(cond, then, otherwise) => {
+                    next = Some(if get(&values, cond) != Scalar::I32(0) { then } else { otherwise });
+                    None
+                }
+                Op::Return(value) => return Ok(value.map(|value| get(&values, value)))
+            };
+            
+            if let Some(result) = inst.result {
+                values[result.index()] = value;
+            }
+        }
+        
+        let target = next.unwrap_or_else(|| panic!("{} does not end with a terminator", block));
+        
+        /*
+         * The phis of the target all take the values that flow in from
+         * this block at once.
+         */
+        let incoming = func.phis(target).map(|inst| {
+            let value = match inst.op {
+                Op::Phi(ref incoming) => incoming.iter().find(|&&(from, _)| from == block).map(|&(_, value)| value),
+                _ => unreachable!()
+            };
+            let phi = inst.result.unwrap();
+            
+            (phi, get(&values, value.unwrap_or_else(|| panic!("{} has no value incoming from {}", phi, block))))
+        }).collect::<Vec<_>>();
+        
+        for (phi, value) in incoming {
+            values[phi.index()] = Some(value);
+        }
+        
+        block = target;
+    }
+}
+
+/*
+ * The arithmetic of integers of both sizes, which wraps like the
+ * machine instructions do.
+ */
+macro_rules! int_binary {
+    ($op:expr, $a:expr, $b:expr, $signed:ty, $unsigned:ty) => {{
+        let (a, b): ($signed, $signed) = ($a, $b);
+        
+        match $op {
+            BinOp::Add => a.wrapping_add(b),
+            BinOp::Sub => a.wrapping_sub(b),
+            BinOp::Mul => a.wrapping_mul(b),
+            BinOp::Div | BinOp::Rem | BinOp::UDiv | BinOp::URem if b == 0 => return Err(Trap::DivideByZero),
+            BinOp::Div | BinOp::Rem if a == <$signed>::MIN && b == -1 => return Err(Trap::Overflow),
+            BinOp::Div => a / b,
+            BinOp::Rem => a % b,
+            BinOp::UDiv => (a as $unsigned / b as $unsigned) as $signed,
+            BinOp::URem => (a as $unsigned % b as $unsigned) as $signed,
+            BinOp::And => a & b,
+            BinOp::Or => a | b,
+            BinOp::Xor => a ^ b,
+            BinOp::Shl => a.wrapping_shl(b as u32),
+            BinOp::Shr => (a as $unsigned).wrapping_shr(b as u32) as $signed,
+            BinOp::Sar => a.wrapping_shr(b as u32)
+        }
+    }}
+}
+
+fn binary(op: BinOp, a: Scalar, b: Scalar) -> Result<Scalar, Trap> {
+    Ok(match (a, b) {
+        (Scalar::I32(a), Scalar::I32(b)) => Scalar::I32(int_binary!(op, a, b, i32, u32)),
+        (Scalar::I64(a), Scalar::I64(b)) => Scalar::I64(int_binary!(op, a, b, i64, u64)),
+        (Scalar::F64(a), Scalar::F64(b)) => Scalar::F64(match op {
+            BinOp::Add => a + b,
+            BinOp::Sub => a - b,
+            BinOp::Mul => a * b,
+            BinOp::Div => a / b,
+            _ => panic!("{} of floats", op.name())
+        }),
+        _ => panic!("{} of {:?} and {:?}", op.name(), a, b)
+    })
+}
+
+fn cmp(op: CmpOp, a: Scalar, b: Scalar) -> bool {
+    if let (Scalar::F64(a), Scalar::F64(b)) = (a, b) {
+        return match op {
+            CmpOp::Eq => a == b,
+            CmpOp::Ne => a != b,
+            CmpOp::Lt => a < b,
+            CmpOp::Le => a <= b,
+            CmpOp::Gt => a > b,
+            CmpOp::Ge => a >= b,
+            _ => panic!("{} compare of floats", op.name())
+        };
+    }
+    
+    let (sa, sb) = (a.signed(), b.signed());
+    let (ua, ub) = (a.unsigned(), b.unsigned());
+    
+    match op {
+        CmpOp::Eq => ua == ub,
+        CmpOp::Ne => ua != ub,
+        CmpOp::Lt => sa < sb,
+        CmpOp::Le => sa <= sb,
+        CmpOp::Gt => sa > sb,
+        CmpOp::Ge => sa >= sb,
+        CmpOp::ULt => ua < ub,
+        CmpOp::ULe => ua <= ub,
+        CmpOp::UGt => ua > ub,
+        CmpOp::UGe => ua >= ub
+    }
+}
+
+fn cast(op: CastOp, value: Scalar, ty: Type) -> Result<Scalar, Trap> {
+    Ok(match op {
+        CastOp::Sext => Scalar::I64(value.signed()),
+        CastOp::Zext => Scalar::I64(value.unsigned() as i64),
+        CastOp::Trunc => Scalar::I32(value.signed() as i32),
+        CastOp::IntToFloat => Scalar::F64(value.signed() as f64),
+        CastOp::FloatToInt => {
+            let value = value.float();
+            let in_range = match ty {
+                Type::I32 => value > i32::MIN as f64 - 1.0 && value < -(i32::MIN as f64),
+                _ => value >= i64::MIN as f64 && value < -(i64::MIN as f64)
+            };
+            
+            if !in_range {
+                return Err(Trap::InvalidConversion);
+            }
+            
+            Scalar::from_int(ty, value as i64)
+        }
+        CastOp::Bitcast => {
+            let bits = match value {
+                Scalar::F64(value) => value.to_bits(),
+                _ => value.unsigned()
+            };
+            
+            match ty {
+                Type::F64 => Scalar::F64(f64::from_bits(bits)),
+                _ => Scalar::from_int(ty, bits as i64)
+            }
+        }
+    })
+}
+
+fn address(addr: Scalar, disp: i32) -> u64 {
+    addr.unsigned().wrapping_add(disp as i64 as u64)
+}
+
+unsafe fn load(ty: Type, addr: u64) -> Scalar {
+    match ty {
+        Type::I32 => Scalar::I32(ptr::read_unaligned(addr as *const i32)),
+        Type::I64 => Scalar::I64(ptr::read_unaligned(addr as *const i64)),
+        Type::F64 => Scalar::F64(ptr::read_unaligned(addr as *const f64)),
+        Type::Ptr => Scalar::Ptr(ptr::read_unaligned(addr as *const u64))
+    }
+}
+
+unsafe fn store(value: Scalar, addr: u64) {
+    match value {
+        Scalar::I32(value) => ptr::write_unaligned(addr as *mut i32, value),
+        Scalar::I64(value) => ptr::write_unaligned(addr as *mut i64, value),
+        Scalar::F64(value) => ptr::write_unaligned(addr as *mut f64, value),
+        Scalar::Ptr(value) => ptr::write_unaligned(addr as *mut u64, value)
+    }
+}
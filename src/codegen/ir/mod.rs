@@ -0,0 +1,564 @@
+//! A small typed SSA intermediate representation, which front ends can
+//! target instead of machine instructions.
+//!
+//! A function is a list of basic blocks. Every value is defined once,
+//! either as a parameter or by an instruction, and phis at the start of a
+//! block select the value that flows in from each predecessor. Every block
+//! ends with a jump, a branch or a return. Functions are created with
+//! `Builder`, compiled for x86_64 with `Codegen::lower`, and can be run
+//! directly with `interpret`.
+
+use std::fmt;
+
+mod interp;
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests;
+
+pub use self::interp::{interpret, Scalar, Trap};
+
+/// The type of a value. Pointers are 64 bits wide.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Type {
+    I32,
+    I64,
+    F64,
+    Ptr
+}
+
+impl Type {
+    /// The size of the type in bytes.
+    pub fn size(self) -> i32 {
+        match self {
+            Type::I32 => 4,
+            Type::I64 | Type::F64 | Type::Ptr => 8
+        }
+    }
+    
+    pub fn is_int(self) -> bool {
+        matches!(self, Type::I32 | Type::I64)
+    }
+    
+    pub fn is_float(self) -> bool {
+        self == Type::F64
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Type::I32 => "i32",
+            Type::I64 => "i64",
+            Type::F64 => "f64",
+            Type::Ptr => "ptr"
+        })
+    }
+}
+
+/// A value of a function. The parameters are the first values.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Value(u32);
+
+impl Value {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+/// A basic block of a function. The entry block is the first block.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Block(u32);
+
+impl Block {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "b{}", self.0)
+    }
+}
+
+/// An arithmetic or bitwise operation on two values of the same type.
+/// Integer arithmetic wraps. Dividing by zero, or the smallest integer
+/// by -1, is undefined. Shift counts are taken modulo the width of the
+/// type. Floats only support `Add`, `Sub`, `Mul` and `Div`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    /// Signed division, rounding towards zero.
+    Div,
+    /// The remainder of signed division, with the sign of the dividend.
+    Rem,
+    UDiv,
+    URem,
+    And,
+    Or,
+    Xor,
+    Shl,
+    /// Logical shift right.
+    Shr,
+    /// Arithmetic shift right.
+    Sar
+}
+
+impl BinOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::Div => "div",
+            BinOp::Rem => "rem",
+            BinOp::UDiv => "udiv",
+            BinOp::URem => "urem",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+            BinOp::Xor => "xor",
+            BinOp::Shl => "shl",
+            BinOp::Shr => "shr",
+            BinOp::Sar => "sar"
+        }
+    }
+    
+    /// Whether the operation is defined on floats.
+    pub fn is_float(self) -> bool {
+        matches!(self, BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div)
+    }
+}
+
+/// A comparison of two values of the same type, which yields an i32 of 1
+/// or 0. Integers compare signed unless the operation is unsigned, and
+/// pointers only compare for equality or unsigned. A float compare with
+/// NaN is false, except for `Ne`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    ULt,
+    ULe,
+    UGt,
+    UGe
+}
+
+impl CmpOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            CmpOp::Eq => "eq",
+            CmpOp::Ne => "ne",
+            CmpOp::Lt => "lt",
+            CmpOp::Le => "le",
+            CmpOp::Gt => "gt",
+            CmpOp::Ge => "ge",
+            CmpOp::ULt => "ult",
+            CmpOp::ULe => "ule",
+            CmpOp::UGt => "ugt",
+            CmpOp::UGe => "uge"
+        }
+    }
+    
+    pub fn is_unsigned(self) -> bool {
+        matches!(self, CmpOp::ULt | CmpOp::ULe | CmpOp::UGt | CmpOp::UGe)
+    }
+}
+
+/// A conversion of a value to another type.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CastOp {
+    /// Sign extends an i32 to an i64.
+    Sext,
+    /// Zero extends an i32 to an i64.
+    Zext,
+    /// Truncates an i64 to an i32.
+    Trunc,
+    /// Converts a signed integer to the nearest f64.
+    IntToFloat,
+    /// Converts an f64 to a signed integer, rounding towards zero. NaN and
+    /// values out of range are undefined.
+    FloatToInt,
+    /// Reinterprets the bits of a value as another type of the same size.
+    Bitcast
+}
+
+impl CastOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            CastOp::Sext => "sext",
+            CastOp::Zext => "zext",
+            CastOp::Trunc => "trunc",
+            CastOp::IntToFloat => "itof",
+            CastOp::FloatToInt => "ftoi",
+            CastOp::Bitcast => "bitcast"
+        }
+    }
+    
+    /// Whether the operation converts a value of type `from` to `to`.
+    pub fn is_valid(self, from: Type, to: Type) -> bool {
+        match self {
+            CastOp::Sext | CastOp::Zext => from == Type::I32 && to == Type::I64,
+            CastOp::Trunc => from == Type::I64 && to == Type::I32,
+            CastOp::IntToFloat => from.is_int() && to == Type::F64,
+            CastOp::FloatToInt => from == Type::F64 && to.is_int(),
+            CastOp::Bitcast => from != to && from.size() == to.size() && from != Type::I32
+        }
+    }
+}
+
+/// The operation of an instruction.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Op {
+    /// An integer or pointer constant, truncated to the type.
+    Int(i64),
+    Float(f64),
+    Binary(BinOp, Value, Value),
+    Cmp(CmpOp, Value, Value),
+    Cast(CastOp, Value),
+    /// Loads a value from the address plus the displacement.
+    Load(Value, i32),
+    /// Stores the value to the address plus the displacement.
+    Store(Value, Value, i32),
+    /// Calls the native function at the address with the arguments.
+    Call(u64, Vec<Value>),
+    /// The value that flows in from each predecessor.
+    Phi(Vec<(Block, Value)>),
+    Jump(Block),
+    /// Branches to the first block if the i32 is not zero, and to the
+    /// second block otherwise.
+    Branch(Value, Block, Block),
+    Return(Option<Value>)
+}
+
+impl Op {
+    pub fn is_terminator(&self) -> bool {
+        matches!(self, Op::Jump(_) | Op::Branch(..) | Op::Return(_))
+    }
+    
+    /// The blocks that a terminator transfers control to.
+    pub fn successors(&self) -> Vec<Block> {
+        match *self {
+            Op::Jump(block) => vec![block],
+            Op::Branch(_, then, otherwise) => vec![then, otherwise],
+            _ => Vec::new()
+        }
+    }
+}
+
+/// An instruction and the value it defines, if any.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Inst {
+    pub result: Option<Value>,
+    pub op: Op
+}
+
+/// A function of the IR.
+#[derive(Clone, Debug)]
+pub struct Function {
+    params: Vec<Type>,
+    ret: Option<Type>,
+    values: Vec<Type>,
+    blocks: Vec<Vec<Inst>>
+}
+
+impl Function {
+    /// Creates a function with the parameter and return types, which has
+    /// an empty entry block.
+    pub fn new(params: &[Type], ret: Option<Type>) -> Function {
+        Function {
+            params: params.to_vec(),
+            ret,
+            values: params.to_vec(),
+            blocks: vec![Vec::new()]
+        }
+    }
+    
+    pub fn params(&self) -> &[Type] {
+        &self.params
+    }
+    
+    pub fn param(&self, index: usize) -> Value {
+        assert!(index < self.params.len(), "no parameter {}", index);
+        
+        Value(index as u32)
+    }
+    
+    pub fn ret(&self) -> Option<Type> {
+        self.ret
+    }
+    
+    pub fn entry(&self) -> Block {
+        Block(0)
+    }
+    
+    pub fn blocks(&self) -> impl Iterator<Item = Block> {
+        (0..self.blocks.len() as u32).map(Block)
+    }
+    
+    pub fn insts(&self, block: Block) -> &[Inst] {
+        &self.blocks[block.index()]
+    }
+    
+    /// All values, starting with the parameters.
+    pub fn values(&self) -> impl Iterator<Item = Value> {
+        (0..self.values.len() as u32).map(Value)
+    }
+    
+    pub fn ty(&self, value: Value) -> Type {
+        self.values[value.index()]
+    }
+    
+    /// The phis at the start of the block.
+    pub fn phis(&self, block: Block) -> impl Iterator<Item = &Inst> {
+        self.insts(block).iter().take_while(|inst| matches!(inst.op, Op::Phi(_)))
+    }
+    
+    fn value(&mut self, ty: Type) -> Value {
+        self.values.push(ty);
+        
+        Value(self.values.len() as u32 - 1)
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("function(")?;
+        for (i, ty) in self.params.iter().enumerate() {
+            write!(f, "{}v{}: {}", if i == 0 { "" } else { ", " }, i, ty)?;
+        }
+        f.write_str(")")?;
+        if let Some(ret) = self.ret {
+            write!(f, " -> {}", ret)?;
+        }
+        f.write_str("\n")?;
+        
+        for block in self.blocks() {
+            writeln!(f, "{}:", block)?;
+            
+            for inst in self.insts(block) {
+                f.write_str("    ")?;
+                if let Some(result) = inst.result {
+                    write!(f, "{}: {} = ", result, self.ty(result))?;
+                }
+                
+                match inst.op {
+                    Op::Int(value) => write!(f, "int {}", value)?,
+                    Op::Float(value) => write!(f, "float {:?}", value)?,
+                    Op::Binary(op, a, b) => write!(f, "{} {}, {}", op.name(), a, b)?,
+                    Op::Cmp(op, a, b) => write!(f, "cmp {} {}, {}", op.name(), a, b)?,
+                    Op::Cast(op, value) => write!(f, "{} {}", op.name(), value)?,
+                    Op::Load(addr, disp) => write!(f, "load [{}{:+}]", addr, disp)?,
+                    Op::Store(value, addr, disp) => write!(f, "store [{}{:+}], {}", addr, disp, value)?,
+                    Op::Call(target, ref args) => {
+                        write!(f, "call {:#x}(", target)?;
+                        for (i, arg) in args.iter().enumerate() {
+                            write!(f, "{}{}", if i == 0 { "" } else { ", " }, arg)?;
+                        }
+                        f.write_str(")")?;
+                    }
+                    Op::Phi(ref incoming) => {
+                        f.write_str("phi")?;
+                        for (i, &(block, value)) in incoming.iter().enumerate() {
+                            write!(f, "{} [{}: {}]", if i == 0 { "" } else { "," }, block, value)?;
+                        }
+                    }
+                    Op::Jump(block) => write!(f, "jump {}", block)?,
+                    Op::Branch(cond, then, otherwise) => write!(f, "branch {}, {}, {}", cond, then, otherwise)?,
+                    Op::Return(Some(value)) => write!(f, "return {}", value)?,
+                    Op::Return(None) => f.write_str("return")?
+                }
+                f.write_str("\n")?;
+            }
+        }
+        
+        Ok(())
+    }
+}
+
+/// Appends instructions to the blocks of a function. The builder starts
+/// out at the entry block. Instructions whose operands have the wrong
+/// types, and instructions after the terminator of a block, panic.
+pub struct Builder<'a> {
+    func: &'a mut Function,
+    block: Block
+}
+
+impl<'a> Builder<'a> {
+    pub fn new(func: &'a mut Function) -> Builder<'a> {
+        let block = func.entry();
+        
+        Builder {
+            func,
+            block
+        }
+    }
+    
+    pub fn func(&self) -> &Function {
+        self.func
+    }
+    
+    /// Creates an empty block, without switching to it.
+    pub fn create_block(&mut self) -> Block {
+        self.func.blocks.push(Vec::new());
+        
+        Block(self.func.blocks.len() as u32 - 1)
+    }
+    
+    /// Appends the instructions that follow to the block.
+    pub fn switch_to(&mut self, block: Block) {
+        assert!(block.index() < self.func.blocks.len(), "no block {}", block);
+        
+        self.block = block;
+    }
+    
+    pub fn current_block(&self) -> Block {
+        self.block
+    }
+    
+    /// Whether the current block ends with a terminator.
+    pub fn is_terminated(&self) -> bool {
+        self.func.insts(self.block).last().is_some_and(|inst| inst.op.is_terminator())
+    }
+    
+    pub fn param(&self, index: usize) -> Value {
+        self.func.param(index)
+    }
+    
+    pub fn ty(&self, value: Value) -> Type {
+        self.func.ty(value)
+    }
+    
+    pub fn int(&mut self, ty: Type, value: i64) -> Value {
+        assert!(ty != Type::F64, "integer constant of type {}", ty);
+        
+        self.append(Some(ty), Op::Int(value)).unwrap()
+    }
+    
+    pub fn float(&mut self, value: f64) -> Value {
+        self.append(Some(Type::F64), Op::Float(value)).unwrap()
+    }
+    
+    pub fn binary(&mut self, op: BinOp, a: Value, b: Value) -> Value {
+        let ty = self.ty(a);
+        assert!(ty == self.ty(b), "{} of {} and {}", op.name(), ty, self.ty(b));
+        assert!(ty.is_int() || (ty.is_float() && op.is_float()), "{} of {}", op.name(), ty);
+        
+        self.append(Some(ty), Op::Binary(op, a, b)).unwrap()
+    }
+    
+    pub fn add(&mut self, a: Value, b: Value) -> Value {
+        self.binary(BinOp::Add, a, b)
+    }
+    
+    pub fn sub(&mut self, a: Value, b: Value) -> Value {
+        self.binary(BinOp::Sub, a, b)
+    }
+    
+    pub fn mul(&mut self, a: Value, b: Value) -> Value {
+        self.binary(BinOp::Mul, a, b)
+    }
+    
+    pub fn cmp(&mut self, op: CmpOp, a: Value, b: Value) -> Value {
+        let ty = self.ty(a);
+        assert!(ty == self.ty(b), "compare of {} and {}", ty, self.ty(b));
+        assert!(match ty {
+            Type::I32 | Type::I64 => true,
+            Type::F64 => !op.is_unsigned(),
+            Type::Ptr => op.is_unsigned() || matches!(op, CmpOp::Eq | CmpOp::Ne)
+        }, "{} compare of {}", op.name(), ty);
+        
+        self.append(Some(Type::I32), Op::Cmp(op, a, b)).unwrap()
+    }
+    
+    pub fn cast(&mut self, op: CastOp, value: Value, ty: Type) -> Value {
+        assert!(op.is_valid(self.ty(value), ty), "{} of {} to {}", op.name(), self.ty(value), ty);
+        
+        self.append(Some(ty), Op::Cast(op, value)).unwrap()
+    }
+    
+    /// Loads a value of the type from the address plus the displacement.
+    pub fn load(&mut self, ty: Type, addr: Value, disp: i32) -> Value {
+        assert!(self.ty(addr) == Type::Ptr, "load from {}", self.ty(addr));
+        
+        self.append(Some(ty), Op::Load(addr, disp)).unwrap()
+    }
+    
+    /// Stores the value to the address plus the displacement.
+    pub fn store(&mut self, value: Value, addr: Value, disp: i32) {
+        assert!(self.ty(addr) == Type::Ptr, "store to {}", self.ty(addr));
+        
+        self.append(None, Op::Store(value, addr, disp));
+    }
+    
+    /// Calls the native function at `target`, which takes the arguments
+    /// and returns a value of type `ret`, with the calling convention the
+    /// function is lowered with.
+    pub fn call(&mut self, target: u64, args: &[Value], ret: Option<Type>) -> Option<Value> {
+        self.append(ret, Op::Call(target, args.to_vec()))
+    }
+    
+    /// Creates a phi at the start of the current block, which must not
+    /// have other instructions yet. The incoming values are added with
+    /// `add_incoming`, so they can be defined later.
+    pub fn phi(&mut self, ty: Type) -> Value {
+        assert!(self.func.insts(self.block).iter().all(|inst| matches!(inst.op, Op::Phi(_))), "phi after instructions in {}", self.block);
+        
+        self.append(Some(ty), Op::Phi(Vec::new())).unwrap()
+    }
+    
+    /// Adds the value that flows into a phi from a predecessor.
+    pub fn add_incoming(&mut self, phi: Value, block: Block, value: Value) {
+        assert!(self.ty(phi) == self.ty(value), "{} incoming to a phi of {}", self.ty(value), self.ty(phi));
+        
+        let inst = self.func.blocks.iter_mut()
+            .flat_map(|insts| insts.iter_mut())
+            .find(|inst| inst.result == Some(phi));
+        
+        match inst {
+            Some(&mut Inst { op: Op::Phi(ref mut incoming), .. }) => incoming.push((block, value)),
+            _ => panic!("{} is not a phi", phi)
+        }
+    }
+    
+    pub fn jump(&mut self, block: Block) {
+        self.append(None, Op::Jump(block));
+    }
+    
+    /// Branches to `then` if the i32 `cond` is not zero, and to
+    /// `otherwise` if it is.
+    pub fn branch(&mut self, cond: Value, then: Block, otherwise: Block) {
+        assert!(self.ty(cond) == Type::I32, "branch on {}", self.ty(cond));
+        
+        self.append(None, Op::Branch(cond, then, otherwise));
+    }
+    
+    pub fn ret(&mut self, value: Option<Value>) {
+        assert!(value.map(|value| self.ty(value)) == self.func.ret, "return of {:?} from a function returning {:?}", value.map(|value| self.ty(value)), self.func.ret);
+        
+        self.append(None, Op::Return(value));
+    }
+    
+    fn append(&mut self, ty: Option<Type>, op: Op) -> Option<Value> {
+        assert!(!self.is_terminated(), "instruction after the terminator of {}", self.block);
+        
+        let result = ty.map(|ty| self.func.value(ty));
+        
+        self.func.blocks[self.block.index()].push(Inst {
+            result,
+            op
+        });
+        
+        result
+    }
+}
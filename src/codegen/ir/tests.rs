@@ -0,0 +1,518 @@
+//! Differential tests of the x86_64 lowering. Random functions are run by
+//! the interpreter and as generated code, and must return the same result
+//! and leave the same memory behind.
+//!
+//! The functions take an i64, an i32, an f64 and a pointer to `SLOTS`
+//! slots of memory, and return an i64. Floats are only loaded from and
+//! stored to the float slots, and integers are only bitcast to finite
+//! floats, so the only NaN that can arise is the default NaN of the
+//! machine, whose bits do not depend on the order of the operands.
+
+use codegen::x86_64::prologue::*;
+use super::*;
+
+const FUNCTIONS: usize = 400;
+const INPUTS: usize = 4;
+const SLOTS: usize = 8;
+const FLOAT_SLOTS: usize = 4;
+const MAX_DEPTH: usize = 3;
+
+/// Clears the top exponent bit of an integer, so it is a finite float.
+const FINITE: i64 = 0x7fef_ffff_ffff_ffff;
+
+const INTS: [i64; 14] = [0, 1, -1, 2, 7, 31, 32, 63, 64, i32::MIN as i64, i32::MAX as i64, u32::MAX as i64, i64::MIN, i64::MAX];
+const FLOATS: [f64; 12] = [0.0, -0.0, 1.0, -1.5, 0.5, 3.25, 1e300, -1e-300, 9007199254740993.0, -2147483648.5, f64::INFINITY, f64::NEG_INFINITY];
+
+type Compiled = fn(i64, i32, f64, *mut u64) -> i64;
+
+extern "C" fn mix(a: i64, b: i32) -> i64 {
+    a.wrapping_mul(31) ^ b as i64
+}
+
+extern "C" fn blend(a: f64, b: i64, c: f64) -> f64 {
+    a * 0.5 + b as f64 - c
+}
+
+/*
+ * Takes more integer arguments than there are argument registers.
+ */
+#[allow(clippy::too_many_arguments)]
+extern "C" fn spread(a: i64, b: f64, c: i32, d: i64, e: i64, f: f64, g: i64, h: i64, i: i32) -> i32 {
+    (a ^ d.rotate_left(7) ^ e.rotate_left(13) ^ g.rotate_left(19) ^ h.rotate_left(29)) as i32 ^ c.wrapping_mul(3) ^ i ^ (b < f) as i32
+}
+
+extern "C" fn record(memory: *mut u64, value: i64) {
+    unsafe { *memory.add(SLOTS - 1) ^= value as u64 }
+}
+
+struct Native {
+    target: u64,
+    params: &'static [Type],
+    ret: Option<Type>
+}
+
+fn natives() -> [Native; 4] {
+    [
+        Native {
+            target: mix as extern "C" fn(i64, i32) -> i64 as usize as u64,
+            params: &[Type::I64, Type::I32],
+            ret: Some(Type::I64)
+        },
+        Native {
+            target: blend as extern "C" fn(f64, i64, f64) -> f64 as usize as u64,
+            params: &[Type::F64, Type::I64, Type::F64],
+            ret: Some(Type::F64)
+        },
+        Native {
+            target: spread as extern "C" fn(i64, f64, i32, i64, i64, f64, i64, i64, i32) -> i32 as usize as u64,
+            params: &[Type::I64, Type::F64, Type::I32, Type::I64, Type::I64, Type::F64, Type::I64, Type::I64, Type::I32],
+            ret: Some(Type::I32)
+        },
+        Native {
+            target: record as extern "C" fn(*mut u64, i64) as usize as u64,
+            params: &[Type::Ptr, Type::I64],
+            ret: None
+        }
+    ]
+}
+
+/*
+ * Calls a native function for the interpreter.
+ */
+fn call(target: u64, args: &[Scalar]) -> Option<Scalar> {
+    let natives = natives();
+    
+    match *args {
+        [Scalar::I64(a), Scalar::I32(b)] if target == natives[0].target => Some(Scalar::I64(mix(a, b))),
+        [Scalar::F64(a), Scalar::I64(b), Scalar::F64(c)] if target == natives[1].target => Some(Scalar::F64(blend(a, b, c))),
+        [Scalar::I64(a), Scalar::F64(b), Scalar::I32(c), Scalar::I64(d), Scalar::I64(e), Scalar::F64(f), Scalar::I64(g), Scalar::I64(h), Scalar::I32(i)] if target == natives[2].target => {
+            Some(Scalar::I32(spread(a, b, c, d, e, f, g, h, i)))
+        }
+        [Scalar::Ptr(memory), Scalar::I64(value)] if target == natives[3].target => {
+            record(memory as *mut u64, value);
+            None
+        }
+        _ => panic!("unknown call of {:#x} with {:?}", target, args)
+    }
+}
+
+/*
+ * A xorshift generator, so failures can be reproduced.
+ */
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+    
+    fn chance(&mut self, percent: u64) -> bool {
+        self.next() % 100 < percent
+    }
+    
+    fn int(&mut self) -> i64 {
+        match self.below(4) {
+            0 => INTS[self.below(INTS.len())],
+            1 => self.below(200) as i64 - 100,
+            _ => self.next() as i64
+        }
+    }
+    
+    fn float(&mut self) -> f64 {
+        match self.below(3) {
+            0 => FLOATS[self.below(FLOATS.len())],
+            1 => (self.below(2000) as f64 - 1000.0) / 8.0,
+            _ => f64::from_bits(self.next() & FINITE as u64)
+        }
+    }
+    
+    fn ty(&mut self) -> Type {
+        [Type::I32, Type::I64, Type::F64][self.below(3)]
+    }
+}
+
+/*
+ * Generates structured control flow, so that the values in scope always
+ * dominate the block being generated.
+ */
+struct Generator<'a> {
+    b: Builder<'a>,
+    rng: &'a mut Rng,
+    memory: Value,
+    scope: Vec<Value>
+}
+
+impl<'a> Generator<'a> {
+    fn pick(&mut self, ty: Type) -> Value {
+        let candidates = self.scope.iter().cloned().filter(|&value| self.b.ty(value) == ty).collect::<Vec<_>>();
+        
+        if candidates.is_empty() || self.rng.chance(10) {
+            self.constant(ty)
+        } else {
+            candidates[self.rng.below(candidates.len())]
+        }
+    }
+    
+    fn picks(&mut self, types: &[Type]) -> Vec<Value> {
+        types.iter().map(|&ty| self.pick(ty)).collect()
+    }
+    
+    fn types(&mut self) -> Vec<Type> {
+        (0..1 + self.rng.below(3)).map(|_| self.rng.ty()).collect()
+    }
+    
+    fn constant(&mut self, ty: Type) -> Value {
+        match ty {
+            Type::F64 => {
+                let value = self.rng.float();
+                self.b.float(value)
+            }
+            Type::Ptr => self.memory,
+            _ => {
+                let value = self.rng.int();
+                self.b.int(ty, value)
+            }
+        }
+    }
+    
+    fn region(&mut self, depth: usize) {
+        for _ in 0..2 + self.rng.below(8) {
+            match self.rng.below(24) {
+                0 | 1 if depth < MAX_DEPTH => self.diamond(depth),
+                2 if depth < MAX_DEPTH => self.skip(depth),
+                3 if depth < MAX_DEPTH => self.while_loop(depth),
+                4 if depth < MAX_DEPTH => self.do_while(depth),
+                _ => self.inst()
+            }
+        }
+    }
+    
+    fn inst(&mut self) {
+        let value = match self.rng.below(16) {
+            0 => {
+                let ty = self.rng.ty();
+                Some(self.constant(ty))
+            }
+            6 | 7 => Some(self.cmp()),
+            8 | 9 => Some(self.cast()),
+            10 | 11 => {
+                let ty = self.rng.ty();
+                let disp = self.disp(ty);
+                Some(self.b.load(ty, self.memory, disp))
+            }
+            12 => {
+                let ty = self.rng.ty();
+                let (value, disp) = (self.pick(ty), self.disp(ty));
+                self.b.store(value, self.memory, disp);
+                None
+            }
+            13 => {
+                let native = &natives()[self.rng.below(4)];
+                let args = self.picks(native.params);
+                self.b.call(native.target, &args, native.ret)
+            }
+            _ => Some(self.binary())
+        };
+        
+        if let Some(value) = value {
+            self.scope.push(value);
+        }
+    }
+    
+    /*
+     * Integer divisors are mostly made odd, so few functions trap.
+     */
+    fn binary(&mut self) -> Value {
+        const INT_OPS: [BinOp; 13] = [
+            BinOp::Add, BinOp::Sub, BinOp::Mul, BinOp::Div, BinOp::Rem, BinOp::UDiv, BinOp::URem,
+            BinOp::And, BinOp::Or, BinOp::Xor, BinOp::Shl, BinOp::Shr, BinOp::Sar
+        ];
+        const FLOAT_OPS: [BinOp; 4] = [BinOp::Add, BinOp::Sub, BinOp::Mul, BinOp::Div];
+        
+        let ty = self.rng.ty();
+        let op = if ty.is_float() { FLOAT_OPS[self.rng.below(4)] } else { INT_OPS[self.rng.below(13)] };
+        let (a, mut b) = (self.pick(ty), self.pick(ty));
+        
+        if matches!(op, BinOp::Div | BinOp::Rem | BinOp::UDiv | BinOp::URem) && ty.is_int() && self.rng.chance(90) {
+            let one = self.b.int(ty, 1);
+            b = self.b.binary(BinOp::Or, b, one);
+        }
+        
+        self.b.binary(op, a, b)
+    }
+    
+    fn cmp(&mut self) -> Value {
+        const OPS: [CmpOp; 10] = [
+            CmpOp::Eq, CmpOp::Ne, CmpOp::Lt, CmpOp::Le, CmpOp::Gt, CmpOp::Ge,
+            CmpOp::ULt, CmpOp::ULe, CmpOp::UGt, CmpOp::UGe
+        ];
+        
+        let ty = self.rng.ty();
+        let op = OPS[self.rng.below(if ty.is_float() { 6 } else { 10 })];
+        let (a, b) = (self.pick(ty), self.pick(ty));
+        
+        self.b.cmp(op, a, b)
+    }
+    
+    fn cast(&mut self) -> Value {
+        let (op, from, to) = match self.rng.below(8) {
+            0 => (CastOp::Sext, Type::I32, Type::I64),
+            1 => (CastOp::Zext, Type::I32, Type::I64),
+            2 => (CastOp::Trunc, Type::I64, Type::I32),
+            3 => (CastOp::IntToFloat, Type::I32, Type::F64),
+            4 => (CastOp::IntToFloat, Type::I64, Type::F64),
+            5 => (CastOp::FloatToInt, Type::F64, [Type::I32, Type::I64][self.rng.below(2)]),
+            6 => (CastOp::Bitcast, Type::F64, Type::I64),
+            _ => (CastOp::Bitcast, Type::I64, Type::F64)
+        };
+        let mut value = self.pick(from);
+        
+        if to == Type::F64 && op == CastOp::Bitcast {
+            let finite = self.b.int(Type::I64, FINITE);
+            value = self.b.binary(BinOp::And, value, finite);
+        }
+        
+        self.b.cast(op, value, to)
+    }
+    
+    /*
+     * The displacement of a random slot for the type; i32s may use either
+     * half of an integer slot.
+     */
+    fn disp(&mut self, ty: Type) -> i32 {
+        match ty {
+            Type::F64 => self.rng.below(FLOAT_SLOTS) as i32 * 8,
+            _ => (FLOAT_SLOTS + self.rng.below(SLOTS - FLOAT_SLOTS)) as i32 * 8 + if ty == Type::I32 { self.rng.below(2) as i32 * 4 } else { 0 }
+        }
+    }
+    
+    fn condition(&mut self) -> Value {
+        if self.rng.chance(30) {
+            self.pick(Type::I32)
+        } else {
+            self.cmp()
+        }
+    }
+    
+    /*
+     * Adds phis of the values that flow in from each predecessor to the
+     * current block.
+     */
+    fn phis(&mut self, types: &[Type], incoming: &[(Block, &[Value])]) {
+        for (i, &ty) in types.iter().enumerate() {
+            let phi = self.b.phi(ty);
+            for &(block, values) in incoming {
+                self.b.add_incoming(phi, block, values[i]);
+            }
+            self.scope.push(phi);
+        }
+    }
+    
+    fn diamond(&mut self, depth: usize) {
+        let cond = self.condition();
+        let (then, otherwise, merge) = (self.b.create_block(), self.b.create_block(), self.b.create_block());
+        let types = self.types();
+        let scope = self.scope.clone();
+        
+        self.b.branch(cond, then, otherwise);
+        
+        let mut incoming = Vec::new();
+        for &block in &[then, otherwise] {
+            self.b.switch_to(block);
+            self.region(depth + 1);
+            incoming.push((self.b.current_block(), self.picks(&types)));
+            self.b.jump(merge);
+            self.scope = scope.clone();
+        }
+        
+        self.b.switch_to(merge);
+        let incoming = incoming.iter().map(|&(block, ref values)| (block, &values[..])).collect::<Vec<_>>();
+        self.phis(&types, &incoming);
+    }
+    
+    /*
+     * Branches straight to a block with phis on one side.
+     */
+    fn skip(&mut self, depth: usize) {
+        let cond = self.condition();
+        let (other, merge) = (self.b.create_block(), self.b.create_block());
+        let types = self.types();
+        let direct = self.picks(&types);
+        let from = self.b.current_block();
+        let scope = self.scope.clone();
+        
+        if self.rng.chance(50) {
+            self.b.branch(cond, merge, other);
+        } else {
+            self.b.branch(cond, other, merge);
+        }
+        
+        self.b.switch_to(other);
+        self.region(depth + 1);
+        let values = self.picks(&types);
+        let end = self.b.current_block();
+        self.b.jump(merge);
+        self.scope = scope;
+        
+        self.b.switch_to(merge);
+        self.phis(&types, &[(from, &direct), (end, &values)]);
+    }
+    
+    /*
+     * A loop that tests its counter at the top, and carries values around
+     * in phis.
+     */
+    fn while_loop(&mut self, depth: usize) {
+        let types = self.types();
+        let initial = self.picks(&types);
+        let (zero, one) = (self.b.int(Type::I64, 0), self.b.int(Type::I64, 1));
+        let limit = self.rng.below(5) as i64;
+        let limit = self.b.int(Type::I64, limit);
+        let (head, body, exit) = (self.b.create_block(), self.b.create_block(), self.b.create_block());
+        let entry = self.b.current_block();
+        
+        self.b.jump(head);
+        
+        self.b.switch_to(head);
+        let counter = self.b.phi(Type::I64);
+        let carried = types.iter().map(|&ty| self.b.phi(ty)).collect::<Vec<_>>();
+        let more = self.b.cmp(CmpOp::Lt, counter, limit);
+        self.b.branch(more, body, exit);
+        self.scope.push(counter);
+        self.scope.extend(carried.iter().cloned());
+        let scope = self.scope.clone();
+        
+        self.b.switch_to(body);
+        self.region(depth + 1);
+        let updated = self.picks(&types);
+        let next = self.b.add(counter, one);
+        let latch = self.b.current_block();
+        self.b.jump(head);
+        self.scope = scope;
+        
+        self.b.add_incoming(counter, entry, zero);
+        self.b.add_incoming(counter, latch, next);
+        for (i, &phi) in carried.iter().enumerate() {
+            self.b.add_incoming(phi, entry, initial[i]);
+            self.b.add_incoming(phi, latch, updated[i]);
+        }
+        
+        self.b.switch_to(exit);
+    }
+    
+    /*
+     * A loop that tests its counter at the bottom, and branches to blocks
+     * with phis on both sides.
+     */
+    fn do_while(&mut self, depth: usize) {
+        let types = self.types();
+        let initial = self.picks(&types);
+        let (zero, one) = (self.b.int(Type::I64, 0), self.b.int(Type::I64, 1));
+        let limit = self.rng.below(5) as i64;
+        let limit = self.b.int(Type::I64, limit);
+        let (body, exit) = (self.b.create_block(), self.b.create_block());
+        let entry = self.b.current_block();
+        
+        self.b.jump(body);
+        
+        self.b.switch_to(body);
+        let counter = self.b.phi(Type::I64);
+        let carried = types.iter().map(|&ty| self.b.phi(ty)).collect::<Vec<_>>();
+        self.scope.push(counter);
+        self.scope.extend(carried.iter().cloned());
+        
+        self.region(depth + 1);
+        let updated = self.picks(&types);
+        let next = self.b.add(counter, one);
+        let more = self.b.cmp(CmpOp::Lt, next, limit);
+        let exit_types = self.types();
+        let exiting = self.picks(&exit_types);
+        let latch = self.b.current_block();
+        self.b.branch(more, body, exit);
+        
+        self.b.add_incoming(counter, entry, zero);
+        self.b.add_incoming(counter, latch, next);
+        for (i, &phi) in carried.iter().enumerate() {
+            self.b.add_incoming(phi, entry, initial[i]);
+            self.b.add_incoming(phi, latch, updated[i]);
+        }
+        
+        self.b.switch_to(exit);
+        self.phis(&exit_types, &[(latch, &exiting)]);
+    }
+}
+
+fn generate(rng: &mut Rng) -> Function {
+    let mut func = Function::new(&[Type::I64, Type::I32, Type::F64, Type::Ptr], Some(Type::I64));
+    
+    {
+        let b = Builder::new(&mut func);
+        let scope = (0..4).map(|i| b.param(i)).collect();
+        let memory = b.param(3);
+        let mut gen = Generator {
+            b,
+            rng,
+            memory,
+            scope
+        };
+        
+        gen.region(0);
+        
+        let ty = gen.rng.ty();
+        let result = gen.pick(ty);
+        let result = match ty {
+            Type::I32 => gen.b.cast(CastOp::Sext, result, Type::I64),
+            Type::F64 => gen.b.cast(CastOp::Bitcast, result, Type::I64),
+            _ => result
+        };
+        gen.b.ret(Some(result));
+    }
+    
+    func
+}
+
+#[test]
+fn differential() {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut runs = 0;
+    
+    for _ in 0..FUNCTIONS {
+        let func = generate(&mut rng);
+        
+        let mut cg = Codegen::new();
+        cg.lower(CallConv::host(), &func).unwrap_or_else(|err| panic!("{}\n{}", err, func));
+        let f = unsafe { cg.build_fn::<Compiled>().unwrap() };
+        
+        for _ in 0..INPUTS {
+            let (a, b, c) = (rng.int(), rng.int() as i32, rng.float());
+            let mut memory = [0u64; SLOTS];
+            for (i, slot) in memory.iter_mut().enumerate() {
+                *slot = if i < FLOAT_SLOTS { rng.float().to_bits() } else { rng.int() as u64 };
+            }
+            
+            let mut expected_memory = memory;
+            let args = [Scalar::I64(a), Scalar::I32(b), Scalar::F64(c), Scalar::Ptr(expected_memory.as_mut_ptr() as u64)];
+            let expected = match unsafe { interpret(&func, &args, call) } {
+                Ok(Some(Scalar::I64(expected))) => expected,
+                Ok(result) => panic!("{:?} returned from\n{}", result, func),
+                Err(_) => continue
+            };
+            
+            let result = f.call((a, b, c, memory.as_mut_ptr()));
+            
+            assert_eq!(result, expected, "result of ({}, {}, {:?}) in\n{}", a, b, c, func);
+            assert_eq!(memory, expected_memory, "memory after ({}, {}, {:?}) in\n{}", a, b, c, func);
+            
+            runs += 1;
+        }
+    }
+    
+    assert!(runs > FUNCTIONS * INPUTS / 2, "only {} runs did not trap", runs);
+}
@@ -1,33 +1,250 @@
 #![allow(unused_variables)]
 #![allow(dead_code)]
 
-use std::ptr;
-use self::os::*;
-
-const PAGE_SIZE : usize = 4 * 1024;
+use std::cmp;
+use std::slice;
+use std::mem;
+use std::io;
+use std::marker::PhantomData;
+use std::any::Any;
+use std::sync::Arc;
+use std::ops::{Deref, DerefMut};
+use self::heap::Block;
+use self::gdb::Registration;
+use self::symbols::Registered;
 
 macro_rules! jit_assert {
     () => {
-        panic!("jit assert");
+        panic!("jit assert")
     };
     ( $expr:expr ) => {
         assert!($expr);
     }
 }
 
+pub mod ir;
 pub mod x86;
 pub mod x86_64;
 mod os;
+mod cond;
+mod elf;
+mod error;
+mod gdb;
+mod heap;
+#[cfg(target_os = "linux")]
+mod perf;
+mod reloc;
+mod signature;
+mod symbols;
+
+pub use self::cond::Cond;
+pub use self::elf::ObjectFile;
+pub use self::error::{CodegenError, CodegenErrorKind, Operand, OperandKind};
+pub use self::heap::{CodeHeap, CodeHeapStats};
+#[cfg(target_os = "linux")]
+pub use self::perf::{PerfMode, perf_mode, set_perf_mode};
+pub use self::reloc::{CodeBuffer, Reloc, RelocKind, RelocTarget, SourceLine};
+pub use self::signature::Signature;
+pub use self::symbols::{JitSymbol, lookup};
+
+/// A position in the code that branches can target. Labels are created
+/// unbound and may be used by branches before they are bound.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Label(usize);
+
+/*
+ * A position in the stream. Branches are not part of the stream, so a
+ * position also records how many branches precede it.
+ */
+#[derive(Copy, Clone)]
+struct Position {
+    offset: usize,
+    branches: usize
+}
+
+/*
+ * A branch to a label. The branch is not part of the stream; its
+ * encoding is chosen when the code is laid out. The short form takes
+ * a rel8 displacement, the long form a rel32 displacement.
+ */
+struct Branch {
+    offset: usize,
+    label: Label,
+    short: Vec<u8>,
+    long: Vec<u8>
+}
+
+impl Branch {
+    fn size(&self, long: bool) -> usize {
+        if long {
+            self.long.len() + 4
+        } else {
+            self.short.len() + 1
+        }
+    }
+}
+
+/*
+ * What a fixup refers to: an absolute address, a symbol that is resolved
+ * when the code is installed, an offset into the constant pool, or a
+ * label.
+ */
+#[derive(Clone, PartialEq)]
+enum FixupTarget {
+    Address(u64),
+    Symbol(String),
+    Pool(usize),
+    Label(Label)
+}
+
+/*
+ * A field in the code that depends on where the code or what it refers to
+ * ends up. Fixups to the pool or to labels relative to the code are
+ * patched when the code is laid out, all others when it is installed. end
+ * is the distance from the field to the end of the instruction, which is
+ * what relative fields are relative to.
+ */
+struct Fixup {
+    at: Position,
+    end: usize,
+    kind: RelocKind,
+    target: FixupTarget
+}
+
+impl Fixup {
+    fn is_relative(&self) -> bool {
+        self.kind != RelocKind::Abs64
+    }
+    
+    /*
+     * Whether the field only depends on the layout of the code, not on
+     * where it is installed.
+     */
+    fn is_local(&self) -> bool {
+        self.is_relative() && matches!(self.target, FixupTarget::Pool(_) | FixupTarget::Label(_))
+    }
+}
+
+/*
+ * The constant pool is placed after the code, aligned to POOL_ALIGN.
+ */
+const POOL_ALIGN: usize = 16;
 
 struct Writer {
-    stream: Vec<u8>
+    stream: Vec<u8>,
+    labels: Vec<Option<Position>>,
+    branches: Vec<Branch>,
+    fixups: Vec<Fixup>,
+    pool: Vec<u8>,
+    lines: Vec<(Position, String, u32)>
 }
 
 impl Writer {
     fn new() -> Writer {
         Writer {
-            stream: Vec::new()
+            stream: Vec::new(),
+            labels: Vec::new(),
+            branches: Vec::new(),
+            fixups: Vec::new(),
+            pool: Vec::new(),
+            lines: Vec::new()
+        }
+    }
+    
+    fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+    
+    fn bind(&mut self, label: Label) {
+        assert!(self.labels[label.0].is_none(), "label is already bound");
+        
+        self.labels[label.0] = Some(self.position());
+    }
+    
+    fn position(&self) -> Position {
+        Position {
+            offset: self.stream.len(),
+            branches: self.branches.len()
+        }
+    }
+    
+    /*
+     * Records a branch to a label at the current position. short and
+     * long are the opcode bytes of the rel8 and rel32 forms.
+     */
+    fn branch(&mut self, label: Label, short: &[u8], long: &[u8]) {
+        self.branches.push(Branch {
+            offset: self.stream.len(),
+            label,
+            short: short.to_vec(),
+            long: long.to_vec()
+        });
+    }
+    
+    /*
+     * Records that the field at offset at of the instruction that was just
+     * written refers to target.
+     */
+    fn fixup(&mut self, at: usize, kind: RelocKind, target: FixupTarget) {
+        self.fixups.push(Fixup {
+            at: Position {
+                offset: at,
+                branches: self.branches.len()
+            },
+            end: self.stream.len() - at,
+            kind,
+            target
+        });
+    }
+    
+    /*
+     * Adds a constant to the pool and returns its offset in the pool. The
+     * constant is aligned to its size, up to POOL_ALIGN. A constant that
+     * is already in the pool with that alignment is shared.
+     */
+    fn constant(&mut self, bytes: &[u8]) -> usize {
+        let align = cmp::min(bytes.len().next_power_of_two(), POOL_ALIGN);
+        
+        if let Some(offset) = (0..self.pool.len()).step_by(align).find(|&offset| self.pool[offset..].starts_with(bytes)) {
+            return offset;
+        }
+        
+        while !self.pool.len().is_multiple_of(align) {
+            self.pool.push(0);
+        }
+        
+        self.pool.extend_from_slice(bytes);
+        self.pool.len() - bytes.len()
+    }
+    
+    /*
+     * Records that the code from the current position on comes from the
+     * given source line. A line recorded at the same position before is
+     * replaced.
+     */
+    fn line(&mut self, file: &str, line: u32) {
+        let pos = self.position();
+        
+        if let Some(&(last, _, _)) = self.lines.last() {
+            if last.offset == pos.offset && last.branches == pos.branches {
+                self.lines.pop();
+            }
         }
+        
+        self.lines.push((pos, file.to_string(), line));
+    }
+    
+    /*
+     * Drops everything written after the position. Labels bound after
+     * the position stay bound. A line recorded at the position is kept,
+     * as it was recorded for the code that follows.
+     */
+    fn truncate(&mut self, pos: Position) {
+        self.stream.truncate(pos.offset);
+        self.branches.truncate(pos.branches);
+        self.fixups.retain(|fixup| fixup.at.offset < pos.offset);
+        self.lines.retain(|line| line.0.offset <= pos.offset);
     }
     
     fn push(&mut self, b: u8) {
@@ -50,15 +267,186 @@ impl Writer {
         self.stream.len()
     }
     
-    fn build(&self) -> JitFunction {
-        let size = (self.stream.len() + (PAGE_SIZE - 1)) & !(PAGE_SIZE - 1);
-        let memory = Memory::alloc(size).unwrap();
+    /*
+     * Returns for every branch the number of bytes added to the stream by
+     * the branches before it. The last entry is the total.
+     */
+    fn branch_shifts(&self, long: &[bool]) -> Vec<usize> {
+        let mut shifts = Vec::with_capacity(self.branches.len() + 1);
+        let mut shift = 0;
         
-        unsafe { ptr::copy(self.stream.as_ptr(), memory.ptr(), self.stream.len()); }
+        shifts.push(0);
         
-        JitFunction {
-            memory: memory
+        for (branch, &long) in self.branches.iter().zip(long) {
+            shift += branch.size(long);
+            shifts.push(shift);
+        }
+        
+        shifts
+    }
+    
+    /*
+     * Returns the offset of a label in the laid out code. `at` is the
+     * offset in the stream of what refers to the label.
+     */
+    fn label_target(&self, label: Label, shifts: &[usize], at: usize) -> Result<usize, CodegenError> {
+        match self.labels[label.0] {
+            Some(pos) => Ok(Writer::resolve(pos, shifts)),
+            None => Err(CodegenError::build(CodegenErrorKind::UnboundLabel, at))
+        }
+    }
+    
+    /*
+     * Returns the offset of a position in the laid out code.
+     */
+    fn resolve(pos: Position, shifts: &[usize]) -> usize {
+        pos.offset + shifts[pos.branches]
+    }
+    
+    /*
+     * Lays out the code with all branches resolved. All branches start
+     * out in their short form; branches whose displacement does not fit
+     * are widened until no more branches change. Widening a branch can
+     * only increase displacements, so this terminates. Returns the code
+     * and the branch shifts needed to resolve positions.
+     */
+    fn layout(&self) -> Result<(Vec<u8>, Vec<usize>), CodegenError> {
+        let mut long = vec![false; self.branches.len()];
+        
+        let shifts = loop {
+            let shifts = self.branch_shifts(&long);
+            let mut changed = false;
+            
+            for (i, branch) in self.branches.iter().enumerate() {
+                if long[i] {
+                    continue;
+                }
+                
+                let end = branch.offset + shifts[i] + branch.size(false);
+                let disp = self.label_target(branch.label, &shifts, branch.offset)? as isize - end as isize;
+                
+                if !(-128..=127).contains(&disp) {
+                    long[i] = true;
+                    changed = true;
+                }
+            }
+            
+            if !changed {
+                break shifts;
+            }
+        };
+        
+        let mut code = Vec::with_capacity(self.stream.len() + shifts[self.branches.len()]);
+        let mut offset = 0;
+        
+        for (i, branch) in self.branches.iter().enumerate() {
+            code.extend_from_slice(&self.stream[offset..branch.offset]);
+            offset = branch.offset;
+            
+            let end = code.len() + branch.size(long[i]);
+            let disp = self.label_target(branch.label, &shifts, branch.offset)? as isize - end as isize;
+            
+            if long[i] {
+                code.extend_from_slice(&branch.long);
+                code.extend_from_slice(&(disp as i32).to_le_bytes());
+            } else {
+                code.extend_from_slice(&branch.short);
+                code.push(disp as i8 as u8);
+            }
         }
+        
+        code.extend_from_slice(&self.stream[offset..]);
+        
+        let pool = Writer::pool_start(code.len());
+        
+        for fixup in self.fixups.iter().filter(|fixup| fixup.is_local()) {
+            let offset = Writer::resolve(fixup.at, &shifts);
+            let target = match fixup.target {
+                FixupTarget::Pool(offset) => pool + offset,
+                FixupTarget::Label(label) => self.label_target(label, &shifts, fixup.at.offset)?,
+                _ => unreachable!()
+            };
+            let disp = target as i64 - (offset + fixup.end) as i64;
+            
+            code[offset..offset + 4].copy_from_slice(&(disp as i32).to_le_bytes());
+        }
+        
+        Ok((code, shifts))
+    }
+    
+    /*
+     * Returns the offset of the constant pool after code of the given length.
+     */
+    fn pool_start(len: usize) -> usize {
+        len.next_multiple_of(POOL_ALIGN)
+    }
+    
+    /*
+     * Lays out the code and resolves the given positions to offsets in
+     * the laid out code.
+     */
+    fn layout_positions(&self, positions: &[Position]) -> Result<(Vec<u8>, Vec<usize>), CodegenError> {
+        let (code, shifts) = self.layout()?;
+        let offsets = positions.iter().map(|&pos| Writer::resolve(pos, &shifts)).collect();
+        
+        Ok((code, offsets))
+    }
+    
+    /*
+     * Lays out the code and the constant pool into a buffer that can be
+     * installed anywhere. Fields that depend on where the code is
+     * installed become relocations.
+     */
+    fn finish(&self) -> Result<CodeBuffer, CodegenError> {
+        let (mut code, shifts) = self.layout()?;
+        let align = if self.pool.is_empty() { 1 } else { POOL_ALIGN };
+        let mut relocs = Vec::new();
+        
+        /*
+         * Only the displacements of labels stay as laid out. Constants
+         * are addressed through relocations, so the pool can be placed
+         * in a section of its own.
+         */
+        for fixup in &self.fixups {
+            if let (true, &FixupTarget::Label(_)) = (fixup.is_relative(), &fixup.target) {
+                continue;
+            }
+            
+            let offset = Writer::resolve(fixup.at, &shifts);
+            let addend = if fixup.is_relative() { -(fixup.end as i64) } else { 0 };
+            
+            let (target, addend) = match fixup.target {
+                FixupTarget::Address(address) => (RelocTarget::Address(address), addend),
+                FixupTarget::Symbol(ref name) => (RelocTarget::Symbol(name.clone()), addend),
+                FixupTarget::Pool(pool) => (RelocTarget::Pool, pool as i64 + addend),
+                FixupTarget::Label(label) => (RelocTarget::Code, self.label_target(label, &shifts, fixup.at.offset)? as i64)
+            };
+            
+            let size = if fixup.kind == RelocKind::Abs64 { 8 } else { 4 };
+            
+            for byte in &mut code[offset..offset + size] {
+                *byte = 0;
+            }
+            
+            relocs.push(Reloc {
+                offset,
+                kind: fixup.kind,
+                target,
+                addend
+            });
+        }
+        
+        let lines = self.lines.iter().map(|&(pos, ref file, line)| SourceLine {
+            offset: Writer::resolve(pos, &shifts),
+            file: file.clone(),
+            line
+        }).collect();
+        
+        Ok(CodeBuffer::new(code, self.pool.clone(), relocs, lines, align))
+    }
+    
+    fn build(&self, heap: &CodeHeap, name: Option<&str>) -> Result<JitFunction, CodegenError> {
+        self.finish()?.install_as(heap, name, |_| None).map_err(|err| err.into_codegen())
     }
 }
 
@@ -70,12 +458,291 @@ impl Jit {
     }
 }
 
-pub struct JitFunction {
-    memory: Memory
+impl Default for Jit {
+    fn default() -> Jit {
+        Jit::new()
+    }
 }
 
-impl JitFunction {
+/// Generated code. The type parameter is the signature of the function,
+/// as in `JitFunction<fn(u64, u64) -> u64>`, or `()` when the code is
+/// untyped.
+///
+/// The function is registered with debuggers through the GDB JIT
+/// interface for as long as it is alive, so it shows up by name in
+/// backtraces and can be disassembled. It can also be found by address
+/// with `lookup`.
+pub struct JitFunction<F = ()> {
+    symbol: Registered,
+    registration: Registration,
+    block: Block,
+    size: usize,
+    sealed: bool,
+    signature: PhantomData<F>
+}
+
+impl<F> JitFunction<F> {
+    /// Returns the start of the generated code.
+    ///
+    /// # Safety
+    ///
+    /// The pointer is only valid for as long as the `JitFunction` is alive.
     pub unsafe fn ptr(&self) -> *const u8 {
-        self.memory.ptr()
+        self.block.ptr()
+    }
+    
+    /// Returns the number of bytes of generated code.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    
+    pub fn name(&self) -> &str {
+        self.symbol.symbol().name()
+    }
+    
+    /// Returns the entry of the function in the registry that `lookup`
+    /// searches.
+    pub fn symbol(&self) -> &JitSymbol {
+        self.symbol.symbol()
+    }
+    
+    /// Attaches data to the function, which `lookup` hands out with its
+    /// symbol, like the source function the code was compiled from.
+    pub fn set_metadata<T: Any + Send + Sync>(&mut self, metadata: T) {
+        self.symbol.set_metadata(Arc::new(metadata));
+    }
+    
+    /// Returns whether the code is currently executable.
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+    
+    /// Gives the code a signature.
+    ///
+    /// # Safety
+    ///
+    /// The code must implement a function with signature `G` in the C
+    /// calling convention of the platform.
+    pub unsafe fn cast<G: Signature>(self) -> JitFunction<G> {
+        JitFunction {
+            symbol: self.symbol,
+            registration: self.registration,
+            block: self.block,
+            size: self.size,
+            sealed: self.sealed,
+            signature: PhantomData
+        }
+    }
+    
+    /// Opens the code for patching. The code is not executable until the
+    /// returned `JitPatch` is finished or dropped. Pages lose the execute
+    /// permission as a whole, so this fails when another function has code
+    /// on the same pages; code that is patched belongs in a heap aligned to
+    /// pages.
+    pub fn reopen(&mut self) -> io::Result<JitPatch<'_, F>> {
+        if self.sealed {
+            self.block.reopen()?;
+            self.sealed = false;
+        }
+        
+        Ok(JitPatch {
+            function: self
+        })
+    }
+    
+    fn seal(&mut self) -> io::Result<()> {
+        if !self.sealed {
+            self.block.set_writable(false)?;
+            self.sealed = true;
+        }
+        
+        Ok(())
+    }
+}
+
+impl<F: Signature> JitFunction<F> {
+    /// Returns the function. It borrows the `JitFunction`, so it cannot
+    /// be called after the code is freed.
+    ///
+    /// # Panics
+    ///
+    /// Panics when the code is not executable, because a patch could not
+    /// make it executable again when it was dropped.
+    pub fn get(&self) -> JitFn<'_, F> {
+        assert!(self.sealed, "the code is not executable");
+        
+        JitFn {
+            ptr: self.block.ptr(),
+            function: PhantomData
+        }
+    }
+    
+    /// Calls the function with a tuple of arguments.
+    pub fn call(&self, args: F::Args) -> F::Output {
+        self.get().call(args)
+    }
+}
+
+/// A function in a `JitFunction`, which it borrows.
+pub struct JitFn<'a, F: 'a> {
+    ptr: *const u8,
+    function: PhantomData<&'a JitFunction<F>>
+}
+
+impl<'a, F: Signature> JitFn<'a, F> {
+    /// Calls the function with a tuple of arguments.
+    pub fn call(&self, args: F::Args) -> F::Output {
+        unsafe { F::call(self.ptr, args) }
+    }
+}
+
+impl<'a, F> Clone for JitFn<'a, F> {
+    fn clone(&self) -> JitFn<'a, F> {
+        *self
+    }
+}
+
+impl<'a, F> Copy for JitFn<'a, F> {}
+
+/// Writable view of the code of a `JitFunction`. The function is made
+/// executable again by `finish`, or when the patch is dropped.
+///
+/// Dropping the patch cannot report an error, so when the code cannot be
+/// made executable again it is left as it is: `is_sealed` returns false
+/// and calling the function panics until it is reopened and finished.
+pub struct JitPatch<'a, F: 'a = ()> {
+    function: &'a mut JitFunction<F>
+}
+
+impl<'a, F> JitPatch<'a, F> {
+    /// Makes the code executable again, reporting any error from the
+    /// operating system. The code stays writable when this fails.
+    pub fn finish(self) -> io::Result<()> {
+        let result = self.function.seal();
+        mem::forget(self);
+        result
+    }
+}
+
+impl<'a, F> Deref for JitPatch<'a, F> {
+    type Target = [u8];
+    
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.function.block.write_ptr(), self.function.size) }
+    }
+}
+
+impl<'a, F> DerefMut for JitPatch<'a, F> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.function.block.write_ptr(), self.function.size) }
+    }
+}
+
+impl<'a, F> Drop for JitPatch<'a, F> {
+    fn drop(&mut self) {
+        let _ = self.function.seal();
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::io;
+    use codegen::{CodegenError, CodegenErrorKind};
+    use codegen::x86_64::prologue::*;
+    
+    /*
+     * Fills `bytes` bytes with increments of EAX and a cdq if the count is
+     * odd, and appends their encoding to `code`. Returns the number of
+     * increments.
+     */
+    fn fill(cg: &mut Codegen, code: &mut Vec<u8>, bytes: usize) -> i64 {
+        for _ in 0..bytes / 2 {
+            cg.inc(EAX).unwrap();
+            code.extend_from_slice(&[0xff, 0xc0]);
+        }
+        if bytes % 2 == 1 {
+            cg.cdq();
+            code.push(0x99);
+        }
+        
+        (bytes / 2) as i64
+    }
+    
+    /*
+     * Loops three times back over `back` bytes that count in EAX, and then
+     * jumps forward over `forward` bytes that would count too. A rel8
+     * displacement is relative to the end of the two byte jcc, a rel32
+     * displacement to the end of the six byte jcc.
+     */
+    fn straddle(back: usize, forward: usize) {
+        let mut cg = Codegen::new();
+        let mut expected = vec![0xb9, 3, 0, 0, 0, 0xb8, 0, 0, 0, 0];
+        let (top, skip) = (cg.new_label(), cg.new_label());
+        
+        cg.mov(ECX, 3).unwrap();
+        cg.mov(EAX, 0).unwrap();
+        cg.bind(top);
+        let count = fill(&mut cg, &mut expected, back - 2);
+        cg.dec(ECX).unwrap();
+        expected.extend_from_slice(&[0xff, 0xc9]);
+        cg.jcc(Cond::NE, top);
+        cg.jcc(Cond::E, skip);
+        
+        let disp = -(back as i32) - 2;
+        if disp >= -128 {
+            expected.extend_from_slice(&[0x75, disp as u8]);
+        } else {
+            expected.extend_from_slice(&[0x0f, 0x85]);
+            expected.extend_from_slice(&(disp - 4).to_le_bytes());
+        }
+        if forward <= 127 {
+            expected.extend_from_slice(&[0x74, forward as u8]);
+        } else {
+            expected.extend_from_slice(&[0x0f, 0x84]);
+            expected.extend_from_slice(&(forward as i32).to_le_bytes());
+        }
+        
+        fill(&mut cg, &mut expected, forward);
+        cg.bind(skip);
+        cg.ret();
+        expected.push(0xc3);
+        
+        assert_eq!(cg.finish().unwrap().code(), &expected[..], "back {}, forward {}", back, forward);
+        
+        let f = unsafe { cg.build_fn::<fn() -> i64>().unwrap() };
+        assert_eq!(f.call(()), 3 * count, "back {}, forward {}", back, forward);
+    }
+    
+    #[test]
+    fn branch_boundaries() {
+        for &back in &[126, 127] {
+            for &forward in &[127, 128] {
+                straddle(back, forward);
+            }
+        }
+    }
+    
+    #[test]
+    fn build_errors() {
+        let mut cg = Codegen::new();
+        let label = cg.new_label();
+        cg.mov(EAX, 0).unwrap();
+        cg.jmp(label);
+        
+        let err = cg.build().err().unwrap();
+        assert_eq!((err.kind, err.offset), (CodegenErrorKind::UnboundLabel, 5));
+        assert_eq!(err.to_string(), "cannot build code at offset 5: unbound label");
+        assert_eq!(cg.finish().err().unwrap(), err);
+        assert_eq!(cg.listing().err().unwrap(), err);
+        
+        let mut cg = Codegen::new();
+        cg.call_symbol("undefined");
+        
+        let err = cg.build().err().unwrap();
+        assert_eq!((err.kind, err.offset), (CodegenErrorKind::UndefinedSymbol, 1));
+        
+        let err = CodegenError::map_failed(&io::Error::new(io::ErrorKind::OutOfMemory, "out of address space"));
+        assert_eq!(err.kind, CodegenErrorKind::MapFailed);
+        assert_eq!(err.to_string(), "cannot build code: cannot map memory: out of address space");
     }
 }
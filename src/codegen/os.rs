@@ -3,91 +3,442 @@
 extern crate libc;
 
 use self::libc::*;
+use std::cmp;
+#[cfg(target_os = "linux")]
+use std::fs;
+#[cfg(all(unix, not(target_os = "linux")))]
+use std::process;
+#[cfg(all(unix, not(target_os = "linux")))]
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::ptr;
-use std::mem;
+use std::io;
+
+/// Access rights of a mapped region.
+#[allow(clippy::enum_variant_names)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Protection {
+    Read,
+    ReadWrite,
+    ReadExecute,
+    ReadWriteExecute
+}
+
+#[cfg(target_os = "windows")]
+pub fn page_size() -> usize {
+    unsafe {
+        let mut info = ::std::mem::zeroed::<SYSTEM_INFO>();
+        GetSystemInfo(&mut info);
+        info.dwPageSize as usize
+    }
+}
 
 #[cfg(target_os = "windows")]
-unsafe fn map(addr: *const u8, size: usize) -> *mut u8 {
-    assert!(size != 0);
+fn protection_flags(protection: Protection) -> DWORD {
+    match protection {
+        Protection::Read => PAGE_READONLY,
+        Protection::ReadWrite => PAGE_READWRITE,
+        Protection::ReadExecute => PAGE_EXECUTE_READ,
+        Protection::ReadWriteExecute => PAGE_EXECUTE_READWRITE
+    }
+}
+
+fn check_size(size: usize) -> io::Result<()> {
+    if size == 0 {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot map zero bytes"))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "windows")]
+unsafe fn map(addr: *const u8, size: usize, protection: Protection) -> io::Result<*mut u8> {
+    check_size(size)?;
     
     /*
      * If VirtualAlloc can't allocate at the given address when one is
      * given, it fails and returns NULL.
      */
-    let ret = VirtualAlloc(ptr::null_mut(), size as size_t, MEM_COMMIT | MEM_RESERVE, PAGE_EXECUTE_READWRITE);
+    let ret = VirtualAlloc(addr as LPVOID, size as size_t, MEM_COMMIT | MEM_RESERVE, protection_flags(protection));
     
-    assert!(
-        ret == ptr::null_mut() ||
-        (addr == ptr::null() && ret as *const u8 != addr) ||
-        (addr != ptr::null() && ret as *const u8 == addr)
-    );
+    if ret.is_null() {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as *mut u8)
+    }
+}
+
+#[cfg(target_os = "windows")]
+unsafe fn unmap(addr: *const u8, _: usize) -> io::Result<()> {
+    if VirtualFree(addr as LPVOID, 0, MEM_RELEASE) == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "windows")]
+unsafe fn protect(addr: *const u8, size: usize, protection: Protection) -> io::Result<()> {
+    let mut old = 0;
+    if VirtualProtect(addr as LPVOID, size as size_t, protection_flags(protection), &mut old) == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "windows")]
+type Shared = HANDLE;
+
+#[cfg(target_os = "windows")]
+extern "system" {
+    fn MapViewOfFileEx(mapping: HANDLE, access: DWORD, offset_high: DWORD, offset_low: DWORD, size: SIZE_T, addr: LPVOID) -> LPVOID;
+}
+
+/*
+ * Creates a section of size bytes backed by the paging file, which can be
+ * mapped several times.
+ */
+#[cfg(target_os = "windows")]
+unsafe fn shared(size: usize) -> io::Result<Shared> {
+    let size = size as u64;
+    let handle = CreateFileMappingW(INVALID_HANDLE_VALUE, ptr::null_mut(), PAGE_EXECUTE_READWRITE, (size >> 32) as DWORD, size as DWORD, ptr::null());
     
-    mem::transmute(ret)
+    if handle.is_null() {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(handle)
+    }
 }
 
-/* LINUX VERSION
-static void *
-pages_map(void *addr, size_t size)
-{
-    void *ret;
+#[cfg(target_os = "windows")]
+unsafe fn close_shared(shared: Shared) {
+    CloseHandle(shared);
+}
+
+#[cfg(target_os = "windows")]
+unsafe fn map_view(shared: Shared, addr: *const u8, size: usize, protection: Protection) -> io::Result<*mut u8> {
+    check_size(size)?;
+    
+    let access = match protection {
+        Protection::Read => FILE_MAP_READ,
+        Protection::ReadWrite => FILE_MAP_WRITE,
+        Protection::ReadExecute => FILE_MAP_READ | FILE_MAP_EXECUTE,
+        Protection::ReadWriteExecute => FILE_MAP_WRITE | FILE_MAP_EXECUTE
+    };
+    let ret = MapViewOfFileEx(shared, access, 0, 0, size as SIZE_T, addr as LPVOID);
+    
+    if ret.is_null() {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as *mut u8)
+    }
+}
 
-    assert(size != 0);
+#[cfg(target_os = "windows")]
+unsafe fn unmap_view(addr: *const u8, _: usize) -> io::Result<()> {
+    if UnmapViewOfFile(addr as LPCVOID) == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
 
-    / *
+#[cfg(unix)]
+pub fn page_size() -> usize {
+    unsafe { sysconf(_SC_PAGESIZE) as usize }
+}
+
+#[cfg(unix)]
+fn protection_flags(protection: Protection) -> c_int {
+    match protection {
+        Protection::Read => PROT_READ,
+        Protection::ReadWrite => PROT_READ | PROT_WRITE,
+        Protection::ReadExecute => PROT_READ | PROT_EXEC,
+        Protection::ReadWriteExecute => PROT_READ | PROT_WRITE | PROT_EXEC
+    }
+}
+
+/*
+ * Linux 4.17 and later fail a mapping with this flag when it overlaps an
+ * existing one; older kernels take the address as a hint.
+ */
+#[cfg(target_os = "linux")]
+const MAP_FIXED_NOREPLACE: c_int = 0x100000;
+
+#[cfg(all(unix, not(target_os = "linux")))]
+const MAP_FIXED_NOREPLACE: c_int = 0;
+
+#[cfg(unix)]
+unsafe fn map(addr: *const u8, size: usize, protection: Protection) -> io::Result<*mut u8> {
+    map_fd(addr, size, protection, MAP_PRIVATE | MAP_ANON, -1)
+}
+
+#[cfg(unix)]
+unsafe fn map_fd(addr: *const u8, size: usize, protection: Protection, flags: c_int, fd: c_int) -> io::Result<*mut u8> {
+    check_size(size)?;
+    
+    /*
      * We don't use MAP_FIXED here, because it can cause the *replacement*
      * of existing mappings, and we only want to create new mappings.
-     * /
-    ret = mmap(addr, size, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANON,
-        -1, 0);
-    assert(ret != NULL);
-
-    if (ret == MAP_FAILED)
-        ret = NULL;
-    else if (addr != NULL && ret != addr) {
-        / *
+     * MAP_FIXED_NOREPLACE fails instead of mapping somewhere else, which
+     * saves unmapping the memory again.
+     */
+    let flags = if addr.is_null() { flags } else { flags | MAP_FIXED_NOREPLACE };
+    let ret = mmap(addr as *mut c_void, size as size_t, protection_flags(protection), flags, fd, 0);
+    
+    if ret == MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    
+    if !addr.is_null() && ret as *const u8 != addr {
+        /*
          * We succeeded in mapping memory, but not in the right place.
-         * /
-        pages_unmap(ret, size);
-        ret = NULL;
+         */
+        unmap(ret as *const u8, size)?;
+        return Err(io::Error::new(io::ErrorKind::AddrInUse, "could not map memory at the requested address"));
     }
     
-    assert(ret == NULL || (addr == NULL && ret != addr)
-        || (addr != NULL && ret == addr));
-    return (ret);
+    Ok(ret as *mut u8)
 }
-*/
 
-#[cfg(target_os = "windows")]
-unsafe fn unmap(addr: *const u8, _: usize) {
-    if VirtualFree(mem::transmute(addr), 0, MEM_RELEASE) == 0 {
-        panic!("error in VirtualFree");
+#[cfg(unix)]
+unsafe fn unmap(addr: *const u8, size: usize) -> io::Result<()> {
+    if munmap(addr as *mut c_void, size as size_t) == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
     }
 }
 
-/* LINUX VERSION
-static void
-pages_unmap(void *addr, size_t size)
-{
+#[cfg(unix)]
+type Shared = c_int;
+
+/*
+ * The number of the memfd_create system call, which libc 0.1 does not
+ * declare either.
+ */
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const SYS_MEMFD_CREATE: c_long = 319;
+
+#[cfg(all(target_os = "linux", target_arch = "x86"))]
+const SYS_MEMFD_CREATE: c_long = 356;
+
+#[cfg(all(target_os = "linux", target_arch = "arm"))]
+const SYS_MEMFD_CREATE: c_long = 385;
+
+#[cfg(all(target_os = "linux", any(target_arch = "aarch64", target_arch = "riscv64")))]
+const SYS_MEMFD_CREATE: c_long = 279;
+
+/*
+ * Creates an anonymous file of size bytes in memory, which can be mapped
+ * several times.
+ */
+#[cfg(target_os = "linux")]
+unsafe fn shared(size: usize) -> io::Result<Shared> {
+    const MFD_CLOEXEC: c_uint = 1;
+    
+    let fd = syscall(SYS_MEMFD_CREATE, b"rjs_jit\0".as_ptr(), MFD_CLOEXEC) as c_int;
+    
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    
+    truncate(fd, size)
+}
+
+/*
+ * Other systems do not have anonymous files, so a shared memory object is
+ * created under a unique name and unlinked right away.
+ */
+#[cfg(all(unix, not(target_os = "linux")))]
+unsafe fn shared(size: usize) -> io::Result<Shared> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    
+    let name = format!("/rjs_jit_{}_{}\0", process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+    let fd = shm_open(name.as_ptr() as *const c_char, O_RDWR | O_CREAT | O_EXCL, 0o600);
+    
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    
+    shm_unlink(name.as_ptr() as *const c_char);
+    truncate(fd, size)
+}
+
+#[cfg(unix)]
+unsafe fn truncate(fd: c_int, size: usize) -> io::Result<Shared> {
+    if ftruncate(fd, size as off_t) == -1 {
+        let err = io::Error::last_os_error();
+        close(fd);
+        Err(err)
+    } else {
+        Ok(fd)
+    }
+}
+
+#[cfg(unix)]
+unsafe fn close_shared(shared: Shared) {
+    close(shared);
+}
+
+#[cfg(unix)]
+unsafe fn map_view(shared: Shared, addr: *const u8, size: usize, protection: Protection) -> io::Result<*mut u8> {
+    map_fd(addr, size, protection, MAP_SHARED, shared)
+}
+
+#[cfg(unix)]
+unsafe fn unmap_view(addr: *const u8, size: usize) -> io::Result<()> {
+    unmap(addr, size)
+}
 
-    if (munmap(addr, size) == -1)
+#[cfg(unix)]
+unsafe fn protect(addr: *const u8, size: usize, protection: Protection) -> io::Result<()> {
+    if mprotect(addr as *mut c_void, size as size_t, protection_flags(protection)) == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn clock_gettime(clock: c_int, time: *mut timespec) -> c_int;
+    fn syscall(number: c_long, ...) -> c_long;
+}
+
+/// The time of `CLOCK_MONOTONIC` in nanoseconds.
+#[cfg(target_os = "linux")]
+pub fn monotonic_time() -> u64 {
+    let mut time = timespec { tv_sec: 0, tv_nsec: 0 };
+    
+    unsafe { clock_gettime(CLOCK_MONOTONIC, &mut time); }
+    
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+/*
+ * Returns the address nearest to the anchor at which size bytes fit in a
+ * gap between the mappings listed in /proc/self/maps. Addresses below
+ * 64K and above the 47 bit user address space are never mapped.
+ */
+#[cfg(target_os = "linux")]
+fn free_near(anchor: u64, size: u64) -> Option<u64> {
+    const LOW: u64 = 1 << 16;
+    const HIGH: u64 = 1 << 47;
+    
+    let maps = fs::read_to_string("/proc/self/maps").ok()?;
+    let anchor = anchor & !(page_size() as u64 - 1);
+    let ranges = maps.lines().filter_map(|line| {
+        let (start, end) = line.split(' ').next()?.split_once('-')?;
+        Some((u64::from_str_radix(start, 16).ok()?, u64::from_str_radix(end, 16).ok()?))
+    });
+    
+    let mut best: Option<u64> = None;
+    let mut end = LOW;
+    
+    for (start, next) in ranges.chain(Some((HIGH, HIGH))) {
+        let start = cmp::min(start, HIGH);
+        
+        if start >= end + size {
+            let addr = cmp::min(cmp::max(anchor, end), start - size);
+            
+            if best.is_none_or(|best| addr.abs_diff(anchor) < best.abs_diff(anchor)) {
+                best = Some(addr);
+            }
+        }
+        
+        end = cmp::max(end, next);
+    }
+    
+    best
+}
+
+/*
+ * The number of the gettid system call, which libc 0.1 does not declare.
+ */
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const SYS_GETTID: c_long = 186;
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "arm")))]
+const SYS_GETTID: c_long = 224;
+
+#[cfg(all(target_os = "linux", any(target_arch = "aarch64", target_arch = "riscv64")))]
+const SYS_GETTID: c_long = 178;
+
+/// The id of the calling thread.
+#[cfg(target_os = "linux")]
+pub fn thread_id() -> u32 {
+    unsafe { syscall(SYS_GETTID) as u32 }
+}
+
+/*
+ * Maps size bytes with the map function at an address within range bytes
+ * of the anchor.
+ */
+fn map_near<M: FnMut(*const u8) -> io::Result<*mut u8>>(anchor: u64, range: u64, size: usize, mut map: M) -> io::Result<*mut u8> {
+    let near = |addr: u64| addr.abs_diff(anchor) + size as u64 <= range;
+    
+    #[cfg(target_os = "linux")]
     {
-        char buf[BUFERROR_BUF];
+        if let Some(addr) = free_near(anchor, size as u64).filter(|&addr| near(addr)) {
+            if let Ok(ptr) = map(addr as *const u8) {
+                return Ok(ptr);
+            }
+        }
+    }
+    
+    /*
+     * Windows reserves memory in 64K units, so that is the smallest
+     * step between two addresses worth trying. The step is a power of
+     * two, so the anchor can be rounded down to it.
+     */
+    let step = cmp::max(size, 1 << 16).next_power_of_two() as u64;
+    let start = anchor & !(step - 1);
+    let mut i = 0;
+    
+    while i * step <= range {
+        for &addr in &[start.checked_add(i * step), start.checked_sub((i + 1) * step)] {
+            if let Some(addr) = addr.filter(|&addr| near(addr)) {
+                if let Ok(ptr) = map(addr as *const u8) {
+                    return Ok(ptr);
+                }
+            }
+        }
+        
+        i += 1;
+    }
+    
+    Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "could not map memory near the anchor"))
+}
 
-        buferror(get_errno(), buf, sizeof(buf));
-        malloc_printf("<jemalloc>: Error in "
-                      "munmap"
-                      "(): %s\n", buf);
-        if (opt_abort)
-            abort();
+/*
+ * Changes the access rights of the pages of a mapping at ptr of len bytes
+ * that hold size bytes starting at offset.
+ */
+fn protect_pages(ptr: *mut u8, len: usize, offset: usize, size: usize, protection: Protection) -> io::Result<()> {
+    let page_size = page_size();
+    let start = offset & !(page_size - 1);
+    let end = (offset + size + (page_size - 1)) & !(page_size - 1);
+    
+    assert!(end <= len, "range outside of the mapping");
+    
+    if start == end {
+        return Ok(());
     }
+    
+    unsafe { protect(ptr.add(start), end - start, protection) }
 }
-*/
 
 pub struct Memory {
     ptr: *mut u8,
     size: usize
 }
 
+/*
+ * A Memory owns its mapping, so it can be moved to another thread.
+ */
+unsafe impl Send for Memory {}
+
 impl Memory {
     pub fn empty() -> Memory {
         Memory {
@@ -96,16 +447,66 @@ impl Memory {
         }
     }
     
-    pub fn alloc(size: usize) -> Option<Memory> {
-        let ptr = unsafe { map(ptr::null(), size) };
-        if ptr.is_null() {
-            None
-        } else {
-            Some(Memory {
-                ptr: ptr,
-                size: size
-            })
+    /// Maps `size` bytes of readable and writable memory. The size is
+    /// rounded up to whole pages. Use `protect` to make the memory
+    /// executable once the code has been written.
+    pub fn alloc(size: usize) -> io::Result<Memory> {
+        let page_size = page_size();
+        let size = (size + (page_size - 1)) & !(page_size - 1);
+        
+        let ptr = unsafe { map(ptr::null(), size, Protection::ReadWrite)? };
+        
+        Ok(Memory {
+            ptr,
+            size
+        })
+    }
+    
+    /// Maps `size` bytes of readable and writable memory that lie within
+    /// `range` bytes of `anchor`. On Linux the gap between the mappings
+    /// of the process nearest to the anchor is tried first; otherwise
+    /// addresses on both sides of the anchor are tried.
+    pub fn alloc_near(anchor: u64, range: u64, size: usize) -> io::Result<Memory> {
+        check_size(size)?;
+        
+        let page_size = page_size();
+        let size = (size + (page_size - 1)) & !(page_size - 1);
+        let ptr = map_near(anchor, range, size, |addr| unsafe { map(addr, size, Protection::ReadWrite) })?;
+        
+        Ok(Memory {
+            ptr,
+            size
+        })
+    }
+    
+    /// Maps the first `size` bytes of a file as readable and executable.
+    #[cfg(unix)]
+    pub fn map_file(fd: c_int, size: usize) -> io::Result<Memory> {
+        let ptr = unsafe { mmap(ptr::null_mut(), size as size_t, PROT_READ | PROT_EXEC, MAP_PRIVATE, fd, 0) };
+        
+        if ptr == MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        
+        Ok(Memory {
+            ptr: ptr as *mut u8,
+            size
+        })
+    }
+    
+    /// Changes the access rights of the whole mapping.
+    pub fn protect(&self, protection: Protection) -> io::Result<()> {
+        if self.size == 0 {
+            return Ok(());
         }
+        
+        unsafe { protect(self.ptr, self.size, protection) }
+    }
+    
+    /// Changes the access rights of the pages that hold `size` bytes
+    /// starting at `offset`.
+    pub fn protect_range(&self, offset: usize, size: usize, protection: Protection) -> io::Result<()> {
+        protect_pages(self.ptr, self.size, offset, size, protection)
     }
     
     pub unsafe fn ptr(&self) -> *mut u8 {
@@ -119,8 +520,130 @@ impl Memory {
 
 impl Drop for Memory {
     fn drop(&mut self) {
+        /*
+         * Drop cannot report an error, and a mapping that cannot be
+         * unmapped is merely leaked.
+         */
         if self.size > 0 {
-            unsafe { unmap(self.ptr, self.size) };
+            let _ = unsafe { unmap(self.ptr, self.size) };
         }
     }
 }
+
+/// Memory that is mapped twice, as a readable and executable view and as
+/// a readable and writable view of the same pages. Code is written
+/// through the one view while it runs from the other, so the pages it
+/// runs from never have to be made writable.
+pub struct DualMemory {
+    exec: *mut u8,
+    write: *mut u8,
+    size: usize
+}
+
+/*
+ * A DualMemory owns both of its mappings.
+ */
+unsafe impl Send for DualMemory {}
+
+impl DualMemory {
+    /// Maps `size` bytes twice. The size is rounded up to whole pages.
+    pub fn alloc(size: usize) -> io::Result<DualMemory> {
+        DualMemory::map(size, |shared, size| unsafe { map_view(shared, ptr::null(), size, Protection::ReadExecute) })
+    }
+    
+    /// Maps `size` bytes twice, with the executable view within `range`
+    /// bytes of `anchor` like `Memory::alloc_near`.
+    pub fn alloc_near(anchor: u64, range: u64, size: usize) -> io::Result<DualMemory> {
+        DualMemory::map(size, |shared, size| {
+            map_near(anchor, range, size, |addr| unsafe { map_view(shared, addr, size, Protection::ReadExecute) })
+        })
+    }
+    
+    fn map<M: FnOnce(Shared, usize) -> io::Result<*mut u8>>(size: usize, map_exec: M) -> io::Result<DualMemory> {
+        check_size(size)?;
+        
+        let page_size = page_size();
+        let size = (size + (page_size - 1)) & !(page_size - 1);
+        
+        unsafe {
+            let shared = shared(size)?;
+            let views = map_view(shared, ptr::null(), size, Protection::ReadWrite).and_then(|write| {
+                match map_exec(shared, size) {
+                    Ok(exec) => Ok((exec, write)),
+                    Err(err) => {
+                        let _ = unmap_view(write, size);
+                        Err(err)
+                    }
+                }
+            });
+            
+            /*
+             * The mappings keep the memory alive once the handle is closed.
+             */
+            close_shared(shared);
+            
+            let (exec, write) = views?;
+            
+            Ok(DualMemory {
+                exec,
+                write,
+                size
+            })
+        }
+    }
+    
+    /// Changes the access rights of the pages of the executable view that
+    /// hold `size` bytes starting at `offset`.
+    pub fn protect_range(&self, offset: usize, size: usize, protection: Protection) -> io::Result<()> {
+        protect_pages(self.exec, self.size, offset, size, protection)
+    }
+    
+    /// Returns the start of the executable view.
+    pub unsafe fn ptr(&self) -> *mut u8 {
+        self.exec
+    }
+    
+    /// Returns the start of the writable view.
+    pub unsafe fn write_ptr(&self) -> *mut u8 {
+        self.write
+    }
+    
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for DualMemory {
+    fn drop(&mut self) {
+        let _ = unsafe { unmap_view(self.exec, self.size) };
+        let _ = unsafe { unmap_view(self.write, self.size) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use codegen::os::Memory;
+    
+    #[test]
+    fn alloc_near() {
+        const RANGE: u64 = 1 << 30;
+        const SIZE: usize = 1 << 20;
+        
+        let anchor = alloc_near as fn() as usize as u64;
+        let memories = (0..4).map(|_| Memory::alloc_near(anchor, RANGE, SIZE).unwrap()).collect::<Vec<_>>();
+        
+        for memory in &memories {
+            let addr = unsafe { memory.ptr() } as u64;
+            assert!(addr.abs_diff(anchor) + SIZE as u64 <= RANGE, "{:#x} is not near {:#x}", addr, anchor);
+        }
+        
+        assert!(Memory::alloc_near(anchor, SIZE as u64 / 2, SIZE).is_err());
+    }
+    
+    #[test]
+    fn alloc_empty() {
+        assert_eq!(Memory::alloc(0).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(Memory::alloc_near(0x1000_0000, 1 << 30, 0).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,304 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::process;
+use std::sync::Mutex;
+use super::SourceLine;
+use super::os::{Memory, monotonic_time, page_size, thread_id};
+
+/*
+ * The jitdump format, as described in
+ * tools/perf/Documentation/jitdump-specification.txt of the kernel.
+ */
+const JITDUMP_MAGIC: u32 = 0x4a69_5444;
+const JITDUMP_VERSION: u32 = 1;
+const JITDUMP_HEADER_SIZE: u32 = 40;
+const RECORD_HEADER_SIZE: usize = 16;
+const EM_X86_64: u32 = 62;
+
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_DEBUG_INFO: u32 = 2;
+const JIT_CODE_CLOSE: u32 = 3;
+
+/// How generated functions are reported to the Linux `perf` profiler.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PerfMode {
+    /// Nothing is reported. This is the default.
+    Off,
+    /// Functions are appended to `/tmp/perf-<pid>.map`, which `perf
+    /// report` uses to name the samples in generated code. Entries stay
+    /// when functions are dropped, so samples at an address the heap has
+    /// reused may be named after the function that was there before.
+    Map,
+    /// Besides the map, the code of functions and their source lines are
+    /// written to `/tmp/jit-<pid>.dump` in the jitdump format. After
+    /// `perf record -k mono`, `perf inject --jit` turns the dump into
+    /// objects, so `perf report` and `perf annotate` can also
+    /// disassemble the code.
+    JitDump
+}
+
+static PERF: Mutex<Option<Perf>> = Mutex::new(None);
+
+struct Perf {
+    map: File,
+    dump: Option<JitDump>
+}
+
+/*
+ * perf finds the dump through a mapping of it that is executable, which
+ * marker keeps alive until the dump is closed.
+ */
+struct JitDump {
+    file: File,
+    marker: Memory,
+    index: u64
+}
+
+/// Sets how functions that are created from now on are reported to
+/// `perf`. Functions are reported for the whole process. The dump is
+/// started over when it is opened again after being closed.
+pub fn set_perf_mode(mode: PerfMode) -> io::Result<()> {
+    let mut perf = PERF.lock().unwrap_or_else(|err| err.into_inner());
+    
+    let (map, dump) = match perf.take() {
+        Some(Perf { map, dump }) => (map, dump),
+        None if mode == PerfMode::Off => return Ok(()),
+        None => (OpenOptions::new().create(true).append(true).open(format!("/tmp/perf-{}.map", process::id()))?, None)
+    };
+    
+    let dump = match (mode, dump) {
+        (PerfMode::JitDump, Some(dump)) => Some(dump),
+        (PerfMode::JitDump, None) => Some(JitDump::open()?),
+        (_, Some(mut dump)) => {
+            dump.close()?;
+            None
+        }
+        (_, None) => None
+    };
+    
+    if mode != PerfMode::Off {
+        *perf = Some(Perf {
+            map,
+            dump
+        });
+    }
+    
+    Ok(())
+}
+
+pub fn perf_mode() -> PerfMode {
+    match *PERF.lock().unwrap_or_else(|err| err.into_inner()) {
+        None => PerfMode::Off,
+        Some(Perf { dump: None, .. }) => PerfMode::Map,
+        Some(Perf { dump: Some(_), .. }) => PerfMode::JitDump
+    }
+}
+
+/*
+ * Reports a function that was installed at address. Errors are ignored,
+ * so profiling never gets in the way of running the code.
+ *
+ * Functions are not reported when they are dropped, as neither format
+ * has a record for that. The heap reuses the space of dropped functions,
+ * so the map can have stale entries that overlap newer ones, and perf
+ * may name samples in the newer function after the older one. Code load
+ * records in the dump are timestamped, so `perf inject --jit` attributes
+ * every sample to the function that was at its address at the time.
+ */
+pub(super) fn code_load(name: &str, address: u64, code: &[u8], lines: &[SourceLine]) {
+    let mut perf = PERF.lock().unwrap_or_else(|err| err.into_inner());
+    
+    if let Some(ref mut perf) = *perf {
+        let _ = writeln!(perf.map, "{:x} {:x} {}", address, code.len(), name);
+        
+        if let Some(ref mut dump) = perf.dump {
+            let _ = dump.code_load(name, address, code, lines);
+        }
+    }
+}
+
+impl JitDump {
+    fn open() -> io::Result<JitDump> {
+        let path = format!("/tmp/jit-{}.dump", process::id());
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        let marker = Memory::map_file(file.as_raw_fd(), page_size())?;
+        
+        let mut header = Vec::with_capacity(JITDUMP_HEADER_SIZE as usize);
+        put32(&mut header, JITDUMP_MAGIC);
+        put32(&mut header, JITDUMP_VERSION);
+        put32(&mut header, JITDUMP_HEADER_SIZE);
+        put32(&mut header, EM_X86_64);
+        put32(&mut header, 0);
+        put32(&mut header, process::id());
+        put64(&mut header, monotonic_time());
+        put64(&mut header, 0);
+        file.write_all(&header)?;
+        
+        Ok(JitDump {
+            file,
+            marker,
+            index: 0
+        })
+    }
+    
+    fn code_load(&mut self, name: &str, address: u64, code: &[u8], lines: &[SourceLine]) -> io::Result<()> {
+        let timestamp = monotonic_time();
+        
+        /*
+         * The lines of a function must come before the function itself.
+         */
+        if !lines.is_empty() {
+            let mut record = record(JIT_CODE_DEBUG_INFO, timestamp);
+            put64(&mut record, address);
+            put64(&mut record, lines.len() as u64);
+            for line in lines {
+                put64(&mut record, address + line.offset as u64);
+                put32(&mut record, line.line);
+                put32(&mut record, 0);
+                record.extend_from_slice(line.file.as_bytes());
+                record.push(0);
+            }
+            self.write(record)?;
+        }
+        
+        let mut record = record(JIT_CODE_LOAD, timestamp);
+        put32(&mut record, process::id());
+        put32(&mut record, thread_id());
+        put64(&mut record, address);
+        put64(&mut record, address);
+        put64(&mut record, code.len() as u64);
+        put64(&mut record, self.index);
+        record.extend_from_slice(name.as_bytes());
+        record.push(0);
+        record.extend_from_slice(code);
+        self.write(record)?;
+        
+        self.index += 1;
+        
+        Ok(())
+    }
+    
+    fn close(&mut self) -> io::Result<()> {
+        self.write(record(JIT_CODE_CLOSE, monotonic_time()))
+    }
+    
+    /*
+     * Fills in the size of a record and appends it to the dump.
+     */
+    fn write(&mut self, mut record: Vec<u8>) -> io::Result<()> {
+        let size = record.len() as u32;
+        record[4..8].copy_from_slice(&size.to_le_bytes());
+        self.file.write_all(&record)
+    }
+}
+
+/*
+ * Starts a record with its header.
+ */
+fn record(id: u32, timestamp: u64) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE);
+    put32(&mut record, id);
+    put32(&mut record, 0);
+    put64(&mut record, timestamp);
+    record
+}
+
+fn put32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(all(test, target_arch = "x86_64", target_os = "linux"))]
+mod tests {
+    use std::convert::TryInto;
+    use std::fs;
+    use std::process;
+    use codegen::{PerfMode, set_perf_mode};
+    use codegen::x86_64::prologue::*;
+    use super::{JITDUMP_MAGIC, JITDUMP_VERSION, JITDUMP_HEADER_SIZE, EM_X86_64, JIT_CODE_LOAD, JIT_CODE_DEBUG_INFO, JIT_CODE_CLOSE};
+    
+    fn get32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+    
+    fn get64(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+    
+    /*
+     * Reads a zero terminated string, and returns it with the offset
+     * after the terminator.
+     */
+    fn get_str(bytes: &[u8], offset: usize) -> (&str, usize) {
+        let end = offset + bytes[offset..].iter().position(|&b| b == 0).unwrap();
+        (::std::str::from_utf8(&bytes[offset..end]).unwrap(), end + 1)
+    }
+    
+    /*
+     * Builds a function with source lines while the dump is open, and
+     * checks the header and the records written for it. Other tests may
+     * build functions meanwhile, so the records of this one are found by
+     * name.
+     */
+    #[test]
+    fn jitdump() {
+        const NAME: &str = "perf_tests_jitdump";
+        
+        set_perf_mode(PerfMode::JitDump).unwrap();
+        
+        let mut cg = Codegen::new();
+        cg.set_line("test.js", 3);
+        cg.mov(EAX, 1).unwrap();
+        cg.set_line("test.js", 4);
+        cg.ret();
+        let f = cg.build_named(NAME).unwrap();
+        
+        set_perf_mode(PerfMode::Off).unwrap();
+        
+        let address = unsafe { f.ptr() } as u64;
+        let dump = fs::read(format!("/tmp/jit-{}.dump", process::id())).unwrap();
+        
+        assert_eq!(get32(&dump, 0), JITDUMP_MAGIC);
+        assert_eq!(get32(&dump, 4), JITDUMP_VERSION);
+        assert_eq!(get32(&dump, 8), JITDUMP_HEADER_SIZE);
+        assert_eq!(get32(&dump, 12), EM_X86_64);
+        assert_eq!(get32(&dump, 20), process::id());
+        
+        let mut records = Vec::new();
+        let mut offset = JITDUMP_HEADER_SIZE as usize;
+        while offset < dump.len() {
+            let size = get32(&dump, offset + 4) as usize;
+            records.push((get32(&dump, offset), &dump[offset + 16..offset + size]));
+            offset += size;
+        }
+        assert_eq!(offset, dump.len());
+        assert_eq!(records.last().map(|&(id, body)| (id, body.len())), Some((JIT_CODE_CLOSE, 0)));
+        
+        let load = records.iter().position(|&(id, body)| id == JIT_CODE_LOAD && get_str(body, 40).0 == NAME).unwrap();
+        let (_, body) = records[load];
+        assert_eq!(get32(body, 0), process::id());
+        assert_eq!((get64(body, 8), get64(body, 16), get64(body, 24)), (address, address, 6));
+        assert_eq!(&body[get_str(body, 40).1..], &[0xb8, 0x01, 0x00, 0x00, 0x00, 0xc3]);
+        
+        let (id, body) = records[load - 1];
+        assert_eq!(id, JIT_CODE_DEBUG_INFO);
+        assert_eq!((get64(body, 0), get64(body, 8)), (address, 2));
+        
+        let mut offset = 16;
+        for &(line_offset, line) in &[(0, 3), (5, 4)] {
+            assert_eq!((get64(body, offset), get32(body, offset + 8), get32(body, offset + 12)), (address + line_offset, line, 0));
+            let (file, next) = get_str(body, offset + 16);
+            assert_eq!(file, "test.js");
+            offset = next;
+        }
+        assert_eq!(offset, body.len());
+        
+        let map = fs::read_to_string(format!("/tmp/perf-{}.map", process::id())).unwrap();
+        assert!(map.lines().any(|line| line == format!("{:x} 6 {}", address, NAME)));
+    }
+}
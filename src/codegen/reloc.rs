@@ -0,0 +1,335 @@
+use std::io;
+use std::marker::PhantomData;
+use super::{CodeHeap, JitFunction, CodegenError, CodegenErrorKind, POOL_ALIGN};
+use super::gdb::Registration;
+use super::symbols::Registered;
+#[cfg(target_os = "linux")]
+use super::perf;
+
+/*
+ * A veneer is jmp qword ptr [rip], followed by the address to jump to.
+ */
+const VENEER: [u8; 6] = [0xff, 0x25, 0, 0, 0, 0];
+const VENEER_SIZE: usize = 14;
+
+/// How a relocation is applied to its field.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RelocKind {
+    /// A 64 bit field that holds the target plus the addend.
+    Abs64,
+    /// A 32 bit field that holds the target plus the addend, relative to
+    /// the address of the field. The result must fit in 32 bits.
+    Rel32,
+    /// The displacement of a `call` or `jmp`, applied like `Rel32`. When
+    /// the target is out of reach, the field is pointed at a veneer that
+    /// jumps to it.
+    Call32
+}
+
+/// What a relocation refers to.
+#[derive(Clone, PartialEq, Debug)]
+pub enum RelocTarget {
+    /// An absolute address.
+    Address(u64),
+    /// A symbol that is resolved when the code is installed.
+    Symbol(String),
+    /// The start of the code itself.
+    Code,
+    /// The start of the constant pool.
+    Pool
+}
+
+/// A field in a `CodeBuffer` that is filled in when the code is installed.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Reloc {
+    /// The offset of the field in the code.
+    pub offset: usize,
+    pub kind: RelocKind,
+    pub target: RelocTarget,
+    pub addend: i64
+}
+
+/// The source line that the code from `offset` on was generated from.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SourceLine {
+    pub offset: usize,
+    pub file: String,
+    pub line: u32
+}
+
+/// Code that is laid out but not yet installed at an address. Everything
+/// that depends on the address is kept as relocations, so the buffer can
+/// be installed any number of times, in any heap.
+#[derive(Clone, Debug)]
+pub struct CodeBuffer {
+    code: Vec<u8>,
+    pool: Vec<u8>,
+    relocs: Vec<Reloc>,
+    lines: Vec<SourceLine>,
+    align: usize
+}
+
+impl CodeBuffer {
+    pub(super) fn new(code: Vec<u8>, pool: Vec<u8>, relocs: Vec<Reloc>, lines: Vec<SourceLine>, align: usize) -> CodeBuffer {
+        CodeBuffer {
+            code,
+            pool,
+            relocs,
+            lines,
+            align
+        }
+    }
+    
+    /// The code with the relocated fields left as zero.
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+    
+    /// The constant pool, which the code addresses RIP relative. It is
+    /// aligned to 16 bytes.
+    pub fn pool(&self) -> &[u8] {
+        &self.pool
+    }
+    
+    pub fn relocs(&self) -> &[Reloc] {
+        &self.relocs
+    }
+    
+    /// The source lines of the code, in order of their offsets.
+    pub fn lines(&self) -> &[SourceLine] {
+        &self.lines
+    }
+    
+    /// The alignment the code needs.
+    pub fn align(&self) -> usize {
+        self.align
+    }
+    
+    /// Copies the code into the heap, followed by the constant pool, and
+    /// applies the relocations.
+    /// `resolve` returns the address of a symbol; an unknown symbol is
+    /// an error of kind `NotFound`, and a `Rel32` target out of reach
+    /// one of kind `InvalidData`. The function is named after its
+    /// address.
+    pub fn install<R: Fn(&str) -> Option<u64>>(&self, heap: &CodeHeap, resolve: R) -> io::Result<JitFunction> {
+        self.install_as(heap, None, resolve).map_err(|err| err.into_io())
+    }
+    
+    /// Installs the code like `install`, as a function with the given
+    /// name.
+    pub fn install_named<R: Fn(&str) -> Option<u64>>(&self, heap: &CodeHeap, name: &str, resolve: R) -> io::Result<JitFunction> {
+        self.install_as(heap, Some(name), resolve).map_err(|err| err.into_io())
+    }
+    
+    pub(super) fn install_as<R: Fn(&str) -> Option<u64>>(&self, heap: &CodeHeap, name: Option<&str>, resolve: R) -> Result<JitFunction, InstallError> {
+        let mut code = self.code.clone();
+        
+        if !self.pool.is_empty() {
+            code.resize(self.pool_start(), 0);
+            code.extend_from_slice(&self.pool);
+        }
+        
+        /*
+         * External targets are resolved first. Targets in the code are
+         * offsets from its start until the address of the code is known.
+         */
+        
+        let mut targets = Vec::with_capacity(self.relocs.len());
+        
+        for reloc in &self.relocs {
+            targets.push(match reloc.target {
+                RelocTarget::Address(address) => Some(address),
+                RelocTarget::Symbol(ref name) => match resolve(name) {
+                    Some(address) => Some(address),
+                    None => return Err(InstallError::Undefined(name.clone(), reloc.offset))
+                },
+                RelocTarget::Code | RelocTarget::Pool => None
+            });
+        }
+        
+        /*
+         * Room for a veneer for every function that is called is
+         * allocated, and what is not used is given back to the heap.
+         */
+        
+        let mut calls = Vec::new();
+        
+        for (reloc, &target) in self.relocs.iter().zip(targets.iter()) {
+            if let (RelocKind::Call32, Some(target)) = (reloc.kind, target) {
+                if !calls.contains(&target) {
+                    calls.push(target);
+                }
+            }
+        }
+        
+        let mut block = heap.alloc(code.len() + calls.len() * VENEER_SIZE, self.align)?;
+        let base = block.ptr() as u64;
+        let mut veneers : Vec<(u64, u64)> = Vec::new();
+        
+        for (reloc, &target) in self.relocs.iter().zip(targets.iter()) {
+            let offset = reloc.offset;
+            let value = match reloc.target {
+                RelocTarget::Pool => base + self.pool_start() as u64,
+                _ => target.unwrap_or(base)
+            }.wrapping_add(reloc.addend as u64);
+            
+            if reloc.kind == RelocKind::Abs64 {
+                code[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+                continue;
+            }
+            
+            let field = base + offset as u64;
+            let mut disp = value.wrapping_sub(field) as i64;
+            
+            if let (RelocKind::Call32, Some(target), true) = (reloc.kind, target, disp != disp as i32 as i64) {
+                let veneer = match veneers.iter().find(|&&(address, _)| address == target) {
+                    Some(&(_, veneer)) => veneer,
+                    None => {
+                        let veneer = base + code.len() as u64;
+                        veneers.push((target, veneer));
+                        code.extend_from_slice(&VENEER);
+                        code.extend_from_slice(&target.to_le_bytes());
+                        veneer
+                    }
+                };
+                
+                disp = veneer.wrapping_add(reloc.addend as u64).wrapping_sub(field) as i64;
+            }
+            
+            if disp != disp as i32 as i64 {
+                return Err(InstallError::OutOfRange(offset));
+            }
+            
+            code[offset..offset + 4].copy_from_slice(&(disp as i32).to_le_bytes());
+        }
+        
+        block.shrink(code.len());
+        block.write(&code);
+        
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => format!("jit_{:x}", base)
+        };
+        
+        #[cfg(target_os = "linux")]
+        perf::code_load(&name, base, &code, &self.lines);
+        
+        Ok(JitFunction {
+            symbol: Registered::new(&name, base, code.len()),
+            registration: Registration::new(&name, base, code.len()),
+            block,
+            size: code.len(),
+            sealed: true,
+            signature: PhantomData
+        })
+    }
+    
+    /*
+     * The offset of the constant pool from the start of the code.
+     */
+    fn pool_start(&self) -> usize {
+        self.code.len().next_multiple_of(POOL_ALIGN)
+    }
+}
+
+/*
+ * Why code could not be installed. `install` reports it as an I/O error,
+ * `Codegen::build` as a `CodegenError`.
+ */
+pub(super) enum InstallError {
+    /* A symbol that does not resolve, and the offset that refers to it. */
+    Undefined(String, usize),
+    /* The offset of a RIP relative field whose target is out of reach. */
+    OutOfRange(usize),
+    Io(io::Error)
+}
+
+impl InstallError {
+    fn into_io(self) -> io::Error {
+        match self {
+            InstallError::Undefined(name, _) => io::Error::new(io::ErrorKind::NotFound, format!("undefined symbol {}", name)),
+            InstallError::OutOfRange(offset) => io::Error::new(io::ErrorKind::InvalidData, format!("RIP relative address at offset {} out of range", offset)),
+            InstallError::Io(err) => err
+        }
+    }
+    
+    pub(super) fn into_codegen(self) -> CodegenError {
+        match self {
+            InstallError::Undefined(_, offset) => CodegenError::build(CodegenErrorKind::UndefinedSymbol, offset),
+            InstallError::OutOfRange(offset) => CodegenError::build(CodegenErrorKind::TargetOutOfRange, offset),
+            InstallError::Io(err) => CodegenError::map_failed(&err)
+        }
+    }
+}
+
+impl From<io::Error> for InstallError {
+    fn from(err: io::Error) -> InstallError {
+        InstallError::Io(err)
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use codegen::{CodeHeap, RelocKind, RelocTarget};
+    use codegen::x86_64::prologue::*;
+    
+    static VALUE: i64 = 5;
+    static OTHER: i64 = 11;
+    
+    extern "C" fn seven() -> i64 {
+        7
+    }
+    
+    /*
+     * Installs one buffer into two heaps. Every copy must get its own
+     * relocated addresses of labels and of the constant pool, and the same
+     * addresses of symbols, statics and native functions.
+     */
+    #[test]
+    fn install_twice() {
+        let mut cg = Codegen::new();
+        let here = cg.new_label();
+        let hundred = cg.const_f64(100.0);
+        
+        cg.set_scratch(None);
+        cg.sub(RSP, 8).unwrap();
+        cg.call(seven as extern "C" fn() -> i64 as usize as u64).unwrap();
+        cg.add(RSP, 8).unwrap();
+        cg.mov_symbol(RCX, "value").unwrap();
+        cg.add(RAX, MemBase(RCX, 0)).unwrap();
+        cg.add(RAX, MemSize(&OTHER as *const i64 as u64, 8)).unwrap();
+        cg.bind(here);
+        cg.mov_label(RCX, here).unwrap();
+        cg.lea_label(RDX, here).unwrap();
+        cg.sub(RCX, RDX).unwrap();
+        cg.add(RAX, RCX).unwrap();
+        cg.cvttsd2si(RCX, hundred).unwrap();
+        cg.add(RAX, RCX).unwrap();
+        cg.ret();
+        
+        let buffer = cg.finish().unwrap();
+        let kinds = buffer.relocs().iter().map(|reloc| (reloc.kind, reloc.target.clone())).collect::<Vec<_>>();
+        assert!(kinds.contains(&(RelocKind::Abs64, RelocTarget::Code)));
+        assert!(kinds.contains(&(RelocKind::Abs64, RelocTarget::Symbol("value".to_string()))));
+        assert!(kinds.contains(&(RelocKind::Rel32, RelocTarget::Pool)));
+        assert!(kinds.iter().any(|&(kind, _)| kind == RelocKind::Call32));
+        
+        let resolve = |name: &str| if name == "value" { Some(&VALUE as *const i64 as u64) } else { None };
+        let heaps = [CodeHeap::new(), CodeHeap::new()];
+        let mut functions = Vec::new();
+        
+        for heap in &heaps {
+            heap.set_anchor(Some(&OTHER as *const i64 as u64));
+            functions.push(unsafe { buffer.install(heap, resolve).unwrap().cast::<fn() -> i64>() });
+        }
+        
+        assert!(unsafe { functions[0].ptr() != functions[1].ptr() });
+        
+        for function in &functions {
+            assert_eq!(function.call(()), 123);
+        }
+        
+        drop(functions.remove(0));
+        assert_eq!(functions[0].call(()), 123);
+    }
+}
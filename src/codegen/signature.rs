@@ -0,0 +1,45 @@
+use std::mem;
+
+/// The signature of a JIT compiled function, written as a Rust function
+/// pointer type like `fn(u64, u64) -> u64`. The function is called with
+/// the C calling convention of the platform.
+pub trait Signature {
+    /// The arguments of the function as a tuple.
+    type Args;
+    /// The return type of the function.
+    type Output;
+    
+    /// Calls the function at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to executable code that implements a function
+    /// with this signature.
+    unsafe fn call(ptr: *const u8, args: Self::Args) -> Self::Output;
+}
+
+macro_rules! signature {
+    ( $( $arg:ident ),* ) => {
+        impl<R, $( $arg ),*> Signature for fn($( $arg ),*) -> R {
+            type Args = ($( $arg, )*);
+            type Output = R;
+            
+            #[allow(non_snake_case)]
+            unsafe fn call(ptr: *const u8, args: Self::Args) -> R {
+                let ($( $arg, )*) = args;
+                let function : extern "C" fn($( $arg ),*) -> R = mem::transmute(ptr);
+                function($( $arg ),*)
+            }
+        }
+    }
+}
+
+signature!();
+signature!(A);
+signature!(A, B);
+signature!(A, B, C);
+signature!(A, B, C, D);
+signature!(A, B, C, D, E);
+signature!(A, B, C, D, E, F);
+signature!(A, B, C, D, E, F, G);
+signature!(A, B, C, D, E, F, G, H);
@@ -0,0 +1,322 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::fmt;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::thread;
+
+/// The name, address range and metadata of a generated function, as kept
+/// in the registry that `lookup` searches.
+pub struct JitSymbol {
+    name: String,
+    start: u64,
+    size: usize,
+    metadata: Option<Arc<dyn Any + Send + Sync>>
+}
+
+impl JitSymbol {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    
+    /// The address of the first byte of the code.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+    
+    /// The number of bytes of code, including the constant pool.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.start && address - self.start < self.size as u64
+    }
+    
+    /// The metadata of the function, if it is of type `T`.
+    pub fn metadata<T: Any>(&self) -> Option<&T> {
+        self.metadata.as_ref().and_then(|metadata| (**metadata).downcast_ref())
+    }
+}
+
+impl fmt::Debug for JitSymbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [{:#x}, {:#x})", self.name, self.start, self.start + self.size as u64)
+    }
+}
+
+/*
+ * The registry is a sorted list of the symbols that is replaced as a
+ * whole when a function is added or removed, so readers neither lock nor
+ * allocate. Readers announce themselves in the counter of the current
+ * EPOCH in READERS before they load SNAPSHOT. An update replaces the
+ * snapshot and then moves on to the next epoch, so only the readers
+ * counted in the previous epoch can still see the old snapshot, and it
+ * is freed once they are done. Readers that arrive meanwhile are counted
+ * in the other counter and do not hold the update up.
+ */
+type Snapshot = Vec<Arc<JitSymbol>>;
+
+static SNAPSHOT: AtomicPtr<Snapshot> = AtomicPtr::new(ptr::null_mut());
+static EPOCH: AtomicUsize = AtomicUsize::new(0);
+static READERS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+static WRITER: Mutex<()> = Mutex::new(());
+
+thread_local! {
+    /*
+     * The number of lookups the thread is in. An update would wait for
+     * them to finish, so it is refused instead.
+     */
+    static LOOKUPS: Cell<usize> = const { Cell::new(0) };
+}
+
+/*
+ * A reader announced in the counter of an epoch, which leaves again when
+ * it is dropped, also when the callback of lookup panics. When an update
+ * moves on to the next epoch before the reader is counted, the update
+ * may not wait for it, so it announces itself again in the new epoch.
+ */
+struct Reader {
+    readers: &'static AtomicUsize
+}
+
+impl Reader {
+    fn new() -> Reader {
+        LOOKUPS.with(|lookups| lookups.set(lookups.get() + 1));
+        
+        loop {
+            let epoch = EPOCH.load(Ordering::SeqCst);
+            let readers = &READERS[epoch % 2];
+            
+            readers.fetch_add(1, Ordering::SeqCst);
+            
+            if EPOCH.load(Ordering::SeqCst) == epoch {
+                return Reader {
+                    readers
+                };
+            }
+            
+            readers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        self.readers.fetch_sub(1, Ordering::SeqCst);
+        LOOKUPS.with(|lookups| lookups.set(lookups.get() - 1));
+    }
+}
+
+/// Finds the generated function that contains `address` and calls `f`
+/// with its symbol. The lookup takes no locks and does not allocate, so
+/// it can be used from signal handlers and panic hooks, as long as `f`
+/// can.
+///
+/// # Panics
+///
+/// `f` must not change the registry: building or dropping a
+/// `JitFunction` or setting its metadata from `f` panics, as the change
+/// would wait for the lookup to finish.
+pub fn lookup<R, F: FnOnce(&JitSymbol) -> R>(address: u64, f: F) -> Option<R> {
+    let reader = Reader::new();
+    
+    let snapshot = SNAPSHOT.load(Ordering::SeqCst);
+    let result = if snapshot.is_null() {
+        None
+    } else {
+        let symbols = unsafe { &*snapshot };
+        let i = symbols.partition_point(|symbol| symbol.start <= address);
+        
+        if i > 0 && symbols[i - 1].contains(address) {
+            Some(f(&symbols[i - 1]))
+        } else {
+            None
+        }
+    };
+    
+    drop(reader);
+    
+    result
+}
+
+/*
+ * Replaces the snapshot with a changed copy, and frees the old one once
+ * the readers that may have seen it are done.
+ */
+fn update<F: FnOnce(&mut Snapshot)>(f: F) {
+    assert!(LOOKUPS.with(|lookups| lookups.get()) == 0, "the registry cannot be changed during a lookup");
+    
+    let _lock = WRITER.lock().unwrap_or_else(|err| err.into_inner());
+    
+    let old = SNAPSHOT.load(Ordering::SeqCst);
+    let mut symbols = if old.is_null() { Vec::new() } else { unsafe { (*old).clone() } };
+    
+    f(&mut symbols);
+    
+    SNAPSHOT.store(Box::into_raw(Box::new(symbols)), Ordering::SeqCst);
+    
+    let readers = &READERS[EPOCH.fetch_add(1, Ordering::SeqCst) % 2];
+    
+    while readers.load(Ordering::SeqCst) != 0 {
+        thread::yield_now();
+    }
+    
+    if !old.is_null() {
+        drop(unsafe { Box::from_raw(old) });
+    }
+}
+
+/*
+ * The entry of a function in the registry, which is removed when it is
+ * dropped.
+ */
+pub(super) struct Registered {
+    symbol: Arc<JitSymbol>
+}
+
+impl Registered {
+    pub(super) fn new(name: &str, start: u64, size: usize) -> Registered {
+        let symbol = Arc::new(JitSymbol {
+            name: name.to_string(),
+            start,
+            size,
+            metadata: None
+        });
+        
+        update(|symbols| {
+            let i = symbols.partition_point(|other| other.start < start);
+            symbols.insert(i, symbol.clone());
+        });
+        
+        Registered {
+            symbol
+        }
+    }
+    
+    pub(super) fn symbol(&self) -> &JitSymbol {
+        &self.symbol
+    }
+    
+    pub(super) fn set_metadata(&mut self, metadata: Arc<dyn Any + Send + Sync>) {
+        let symbol = Arc::new(JitSymbol {
+            name: self.symbol.name.clone(),
+            start: self.symbol.start,
+            size: self.symbol.size,
+            metadata: Some(metadata)
+        });
+        let old = &self.symbol;
+        
+        update(|symbols| {
+            if let Some(entry) = symbols.iter_mut().find(|entry| Arc::ptr_eq(entry, old)) {
+                *entry = symbol.clone();
+            }
+        });
+        
+        self.symbol = symbol;
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        let symbol = &self.symbol;
+        
+        update(|symbols| symbols.retain(|entry| !Arc::ptr_eq(entry, symbol)));
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use codegen::{CodeHeap, JitFunction, lookup};
+    use codegen::x86_64::prologue::*;
+    
+    /*
+     * Builds a function of 33 bytes in a heap of its own, so no other
+     * test puts code next to it.
+     */
+    fn function(name: &str) -> JitFunction {
+        let mut cg = Codegen::new();
+        cg.set_heap(CodeHeap::new());
+        for _ in 0..16 {
+            cg.inc(EAX).unwrap();
+        }
+        cg.ret();
+        
+        cg.build_named(name).unwrap()
+    }
+    
+    #[test]
+    fn registry() {
+        let mut f = function("registry");
+        let start = unsafe { f.ptr() } as u64;
+        let ours = |address: u64| lookup(address, |symbol| symbol.start()) == Some(start);
+        
+        assert_eq!(f.size(), 33);
+        assert_eq!((f.name(), f.symbol().start(), f.symbol().size()), ("registry", start, 33));
+        assert_eq!(lookup(start, |symbol| symbol.name().to_string()), Some("registry".to_string()));
+        assert!(ours(start + 32));
+        assert!(!ours(start - 1));
+        assert!(!ours(start + 33));
+        assert_eq!(lookup(start, |symbol| symbol.metadata::<u32>().is_none()), Some(true));
+        
+        f.set_metadata(42u32);
+        assert_eq!(lookup(start + 10, |symbol| symbol.metadata::<u32>().copied()), Some(Some(42)));
+        assert_eq!(lookup(start, |symbol| symbol.metadata::<String>().is_none()), Some(true));
+        
+        f.set_metadata("source".to_string());
+        assert_eq!(lookup(start, |symbol| symbol.metadata::<String>().cloned()), Some(Some("source".to_string())));
+        assert_eq!(lookup(start, |symbol| symbol.metadata::<u32>().is_none()), Some(true));
+        assert_eq!(f.symbol().metadata::<String>().map(|source| &source[..]), Some("source"));
+        
+        drop(f);
+        assert!(!ours(start));
+    }
+    
+    #[test]
+    #[should_panic(expected = "the registry cannot be changed during a lookup")]
+    fn change_during_lookup() {
+        let f = function("change_during_lookup");
+        let start = unsafe { f.ptr() } as u64;
+        
+        lookup(start, |_| function("inner"));
+    }
+    
+    /*
+     * Lookups on other threads that keep overlapping must not hold up
+     * changes to the registry.
+     */
+    #[test]
+    fn change_during_lookups() {
+        let f = function("change_during_lookups");
+        let start = unsafe { f.ptr() } as u64;
+        let done = Arc::new(AtomicBool::new(false));
+        
+        let threads = (0..4).map(|_| {
+            let done = done.clone();
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    let found = lookup(start, |symbol| {
+                        for _ in 0..1000 {
+                            std::hint::spin_loop();
+                        }
+                        symbol.start()
+                    });
+                    assert_eq!(found, Some(start));
+                }
+            })
+        }).collect::<Vec<_>>();
+        
+        for i in 0..20 {
+            drop(function(&format!("change_during_lookups_{}", i)));
+        }
+        
+        done.store(true, Ordering::Relaxed);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
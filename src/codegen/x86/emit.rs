@@ -3,6 +3,9 @@
 #![allow(non_camel_case_types)]
 #![allow(non_upper_case_globals)]
 #![allow(non_snake_case)]
+#![allow(clippy::double_parens)]
+#![allow(clippy::too_many_arguments)]
+#![allow(clippy::upper_case_acronyms)]
 
 /*;
  * jit-gen-x86.h: Macros for generating x86 code
@@ -21,7 +24,7 @@
  * be redistributed under the terms of the Lesser General Public License.
  */
 
-use codegen::Writer;
+use codegen::{Writer, Cond};
 use std::mem::transmute;
 
 /*
@@ -130,33 +133,6 @@ impl FpOp {
     }
 }
 
-/*
-// integer conditions codes
-*/
-
-#[repr(usize)]
-#[derive(Copy, Clone)]
-pub enum Cond {
-    EQ = 0,
-    NE = 1,
-    LT = 2,
-    LE = 3,
-    GT = 4,
-    GE = 5,
-    LZ = 6,
-    GEZ = 7,
-    P = 8,
-    NP = 9,
-    O = 10,
-    NO = 11
-}
-
-impl Cond {
-    fn value(&self) -> usize {
-        unsafe { transmute(*self) }
-    }
-}
-
 /* FP status */
 
 // These constants are not used.
@@ -184,12 +160,12 @@ impl Cond {
 //pub const X86_FPCW_PRECC_MASK   : X86_FP_ControlWord = 0x300;
 //pub const X86_FPCW_ROUNDC_MASK  : X86_FP_ControlWord = 0xc00;
 //
-///* values for precision control */
+// /* values for precision control */
 //pub const X86_FPCW_PREC_SINGLE    : X86_FP_ControlWord = 0;
 //pub const X86_FPCW_PREC_DOUBLE    : X86_FP_ControlWord = 0x200;
 //pub const X86_FPCW_PREC_EXTENDED  : X86_FP_ControlWord = 0x300;
 //
-///* values for rounding control */
+// /* values for rounding control */
 //pub const X86_FPCW_ROUND_NEAREST  : X86_FP_ControlWord = 0;
 //pub const X86_FPCW_ROUND_DOWN     : X86_FP_ControlWord = 0x400;
 //pub const X86_FPCW_ROUND_UP       : X86_FP_ControlWord = 0x800;
@@ -226,36 +202,6 @@ impl Prefix {
     }
 }
 
-const x86_cc_unsigned_map : [u8; 12] = [
-	0x74, /* eq  */
-	0x75, /* ne  */
-	0x72, /* lt  */
-	0x76, /* le  */
-	0x77, /* gt  */
-	0x73, /* ge  */
-	0x78, /* lz  */
-	0x79, /* gez */
-	0x7a, /* p   */
-	0x7b, /* np  */
-	0x70, /* o  */
-	0x71, /* no  */
-];
-
-const x86_cc_signed_map : [u8; 12] = [
-	0x74, /* eq  */
-	0x75, /* ne  */
-	0x7c, /* lt  */
-	0x7e, /* le  */
-	0x7f, /* gt  */
-	0x7d, /* ge  */
-	0x78, /* lz  */
-	0x79, /* gez */
-	0x7a, /* p   */
-	0x7b, /* np  */
-	0x70, /* o  */
-	0x71, /* no  */
-];
-
 const x86_prefix_map : [u8; 14] = [
     0xF0,
     0xF2,
@@ -377,7 +323,7 @@ impl Emit {
     }
     
     fn imm_emit32(&mut self, imm: i32) {
-        let imb = unsafe { transmute::<_, [u8; 4]>(imm) };
+        let imb = imm.to_le_bytes();
         self.inst.push(imb [0]);
         self.inst.push(imb [1]);
         self.inst.push(imb [2]);
@@ -385,16 +331,15 @@ impl Emit {
     }
     
     fn imm_emit32_at(&mut self, pos: usize, imm: i32) {
-        let imb = unsafe { transmute::<_, [u8; 4]>(imm) };
+        let imb = imm.to_le_bytes();
         self.inst.set_at(imb [0], pos);
         self.inst.set_at(imb [1], pos + 1);
         self.inst.set_at(imb [2], pos + 2);
         self.inst.set_at(imb [3], pos + 3);
     }
     
-    // TODO: inst is the offset into the stream!
     fn imm_emit16(&mut self, imm: i32) {
-        let imb = unsafe { transmute::<_, [u8; 2]>(imm as i16) };
+        let imb = (imm as i16).to_le_bytes();
         self.inst.push(imb [0]);
         self.inst.push(imb [1]);
     }
@@ -408,11 +353,7 @@ impl Emit {
     }
     
     fn is_imm8(imm: i32) -> bool {
-        ((imm) >= -128 && (imm) <= 127)
-    }
-    
-    fn is_imm16(imm: i32) -> bool {
-        ((imm) >= -(1<<16) && (imm) <= ((1<<16)-1))
+        (-128..=127).contains(&imm)
     }
     
     fn reg_emit(&mut self, r: u8, regno: Reg) {
@@ -500,7 +441,6 @@ impl Emit {
      */
     pub fn patch(&mut self, offset: usize, target: usize) {
         let mut pos = offset + 1;
-        let disp;
         let mut size = 0;
         
         match self.inst.get() {
@@ -523,7 +463,7 @@ impl Emit {
             _ => jit_assert! ()
         }
         
-        disp = (target as isize - pos as isize) as i32;
+        let disp = (target as isize - pos as isize) as i32;
         if (size != 0) {
             self.imm_emit32_at (pos, disp - 4);
         } else if (Self::is_imm8 (disp - 1)) {
@@ -1665,73 +1605,53 @@ impl Emit {
         }
     }
     
-    pub fn branch8(&mut self, cond: Cond, imm: i32, is_signed: bool) {
-        if ((is_signed)) {
-            self.inst.push(x86_cc_signed_map [cond.value()]);
-        } else {
-            self.inst.push(x86_cc_unsigned_map [cond.value()]);
-        }
+    pub fn branch8(&mut self, cond: Cond, imm: i32) {
+        self.inst.push(0x70 | cond.code());
         self.imm_emit8 ((imm));
     }
     
-    pub fn branch32(&mut self, cond: Cond, imm: i32, is_signed: bool) {
+    pub fn branch32(&mut self, cond: Cond, imm: i32) {
         self.inst.push(0x0f);
-        if ((is_signed)) {
-            self.inst.push(x86_cc_signed_map [cond.value()] + 0x10);
-        } else {
-            self.inst.push(x86_cc_unsigned_map [cond.value()] + 0x10);
-        }
+        self.inst.push(0x80 | cond.code());
         self.imm_emit32 ((imm));
     }
     
-    pub fn branch(&mut self, cond: Cond, target: i32, is_signed: bool) {
+    pub fn branch(&mut self, cond: Cond, target: i32) {
         let mut offset = (target) - 2;
         if (Self::is_imm8 ((offset))) {
-            self.branch8 ((cond), offset, (is_signed));
+            self.branch8 ((cond), offset);
         } else {
             offset -= 4;
-            self.branch32 ((cond), offset, (is_signed));
+            self.branch32 ((cond), offset);
         }
     }
     
-    pub fn branch_disp(&mut self, cond: Cond, disp: i32, is_signed: bool) {
+    pub fn branch_disp(&mut self, cond: Cond, disp: i32) {
         let mut offset = (disp) - 2;
         if (Self::is_imm8 ((offset))) {
-            self.branch8 ((cond), offset, (is_signed));
+            self.branch8 ((cond), offset);
         } else {
             offset -= 4;
-            self.branch32 ((cond), offset, (is_signed));
+            self.branch32 ((cond), offset);
         }
     }
     
-    pub fn set_reg(&mut self, cond: Cond, reg: Reg, is_signed: bool) {
+    pub fn set_reg(&mut self, cond: Cond, reg: Reg) {
         jit_assert! (IS_BYTE_REG (reg));
         self.inst.push(0x0f);
-        if ((is_signed)) {
-            self.inst.push(x86_cc_signed_map [cond.value()] + 0x20);
-        } else {
-            self.inst.push(x86_cc_unsigned_map [cond.value()] + 0x20);
-        }
+        self.inst.push(0x90 | cond.code());
         self.reg_emit (0, (reg));
     }
     
-    pub fn set_mem(&mut self, cond: Cond, mem: i32, is_signed: bool) {
+    pub fn set_mem(&mut self, cond: Cond, mem: i32) {
         self.inst.push(0x0f);
-        if ((is_signed)) {
-            self.inst.push(x86_cc_signed_map [cond.value()] + 0x20);
-        } else {
-            self.inst.push(x86_cc_unsigned_map [cond.value()] + 0x20);
-        }
+        self.inst.push(0x90 | cond.code());
         self.mem_emit (0, (mem));
     }
     
-    pub fn set_membase(&mut self, cond: Cond, basereg: Reg, disp: i32, is_signed: bool) {
+    pub fn set_membase(&mut self, cond: Cond, basereg: Reg, disp: i32) {
         self.inst.push(0x0f);
-        if ((is_signed)) {
-            self.inst.push(x86_cc_signed_map [cond.value()] + 0x20);
-        } else {
-            self.inst.push(x86_cc_unsigned_map [cond.value()] + 0x20);
-        }
+        self.inst.push(0x90 | cond.code());
         self.membase_emit (0, (basereg), (disp));
     }
     
@@ -1774,33 +1694,21 @@ impl Emit {
         }
     }
     
-    pub fn cmov_reg(&mut self, cond: Cond, is_signed: bool, dreg: Reg, reg: Reg) {
+    pub fn cmov_reg(&mut self, cond: Cond, dreg: Reg, reg: Reg) {
         self.inst.push( 0x0f);
-        if ((is_signed)) {
-            self.inst.push(x86_cc_signed_map [cond.value()] - 0x30);
-        } else {
-            self.inst.push(x86_cc_unsigned_map [cond.value()] - 0x30);
-        }
+        self.inst.push(0x40 | cond.code());
         self.reg_emit ((dreg.value()), (reg));
     }
     
-    pub fn cmov_mem(&mut self, cond: Cond, is_signed: bool, reg: Reg, mem: i32) {
+    pub fn cmov_mem(&mut self, cond: Cond, reg: Reg, mem: i32) {
         self.inst.push( 0x0f);
-        if ((is_signed)) {
-            self.inst.push(x86_cc_signed_map [cond.value()] - 0x30);
-        }  else {
-            self.inst.push(x86_cc_unsigned_map [cond.value()] - 0x30);
-        }
+        self.inst.push(0x40 | cond.code());
         self.mem_emit ((reg.value()), (mem));
     }
     
-    pub fn cmov_membase(&mut self, cond: Cond, is_signed: bool, reg: Reg, basereg: Reg, disp: i32) {
+    pub fn cmov_membase(&mut self, cond: Cond, reg: Reg, basereg: Reg, disp: i32) {
         self.inst.push( 0x0f);
-        if ((is_signed)) {
-            self.inst.push(x86_cc_signed_map [cond.value()] - 0x30);
-        } else {
-            self.inst.push(x86_cc_unsigned_map [cond.value()] - 0x30);
-        }
+        self.inst.push(0x40 | cond.code());
         self.membase_emit ((reg.value()), (basereg), (disp));
     }
     
@@ -1939,7 +1847,7 @@ impl Emit {
                 self.pop_reg (Reg::from_u8(i));
             }
             i -= 1;
-            m=m>>1;
+            m >>= 1;
         }
         self.leave ();
         self.ret ();
@@ -14,3 +14,9 @@ impl Codegen {
         }
     }
 }
+
+impl Default for Codegen {
+    fn default() -> Codegen {
+        Codegen::new()
+    }
+}
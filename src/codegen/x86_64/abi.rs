@@ -0,0 +1,552 @@
+//! Calling conventions: function frames and calls to native functions.
+
+use codegen::{CodegenError, CodegenErrorKind};
+use super::{Codegen, Arg, Imm, Reg, SizedReg};
+use super::SizedReg::*;
+
+/// A calling convention of the platform ABI.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CallConv {
+    /// The System V AMD64 ABI used by Linux, macOS and the BSDs.
+    SysV,
+    /// The Microsoft x64 calling convention.
+    Win64
+}
+
+const SYSV_INT_ARGS : [SizedReg; 6] = [RDI, RSI, RDX, RCX, R8, R9];
+const SYSV_FLOAT_ARGS : [SizedReg; 8] = [XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7];
+const SYSV_CALLEE_SAVED : [SizedReg; 6] = [RBX, RBP, R12, R13, R14, R15];
+const SYSV_TEMPS : [SizedReg; 11] = [RAX, R10, R11, XMM8, XMM9, XMM10, XMM11, XMM12, XMM13, XMM14, XMM15];
+
+const WIN64_INT_ARGS : [SizedReg; 4] = [RCX, RDX, R8, R9];
+const WIN64_FLOAT_ARGS : [SizedReg; 4] = [XMM0, XMM1, XMM2, XMM3];
+const WIN64_CALLEE_SAVED : [SizedReg; 18] = [
+    RBX, RBP, RDI, RSI, R12, R13, R14, R15,
+    XMM6, XMM7, XMM8, XMM9, XMM10, XMM11, XMM12, XMM13, XMM14, XMM15
+];
+const WIN64_TEMPS : [SizedReg; 5] = [RAX, R10, R11, XMM4, XMM5];
+
+const GPRS : [[SizedReg; 4]; 16] = [
+    [AL, AX, EAX, RAX], [CL, CX, ECX, RCX], [DL, DX, EDX, RDX], [BL, BX, EBX, RBX],
+    [SPL, SP, ESP, RSP], [BPL, BP, EBP, RBP], [SIL, SI, ESI, RSI], [DIL, DI, EDI, RDI],
+    [R8B, R8W, R8D, R8], [R9B, R9W, R9D, R9], [R10B, R10W, R10D, R10], [R11B, R11W, R11D, R11],
+    [R12B, R12W, R12D, R12], [R13B, R13W, R13D, R13], [R14B, R14W, R14D, R14], [R15B, R15W, R15D, R15]
+];
+
+/*
+ * The sized register of a general purpose register.
+ */
+pub(super) fn gpr(reg: Reg, size: i32) -> SizedReg {
+    let sizes = &GPRS[reg as usize];
+    
+    match size {
+        1 => sizes[0],
+        2 => sizes[1],
+        4 => sizes[2],
+        _ => sizes[3]
+    }
+}
+
+impl CallConv {
+    /// The calling convention of the platform the crate is compiled for.
+    pub fn host() -> CallConv {
+        if cfg!(windows) {
+            CallConv::Win64
+        } else {
+            CallConv::SysV
+        }
+    }
+    
+    /// The registers that pass integer and pointer arguments, in order.
+    pub fn int_args(self) -> &'static [SizedReg] {
+        match self {
+            CallConv::SysV => &SYSV_INT_ARGS,
+            CallConv::Win64 => &WIN64_INT_ARGS
+        }
+    }
+    
+    /// The registers that pass floating point arguments, in order.
+    pub fn float_args(self) -> &'static [SizedReg] {
+        match self {
+            CallConv::SysV => &SYSV_FLOAT_ARGS,
+            CallConv::Win64 => &WIN64_FLOAT_ARGS
+        }
+    }
+    
+    /// The registers a function must preserve for its caller.
+    pub fn callee_saved(self) -> &'static [SizedReg] {
+        match self {
+            CallConv::SysV => &SYSV_CALLEE_SAVED,
+            CallConv::Win64 => &WIN64_CALLEE_SAVED
+        }
+    }
+    
+    /*
+     * The caller saved registers that pass no arguments, which calls use
+     * to break cycles between the argument moves.
+     */
+    fn temps(self) -> &'static [SizedReg] {
+        match self {
+            CallConv::SysV => &SYSV_TEMPS,
+            CallConv::Win64 => &WIN64_TEMPS
+        }
+    }
+    
+    /*
+     * The space the caller reserves below the stack arguments for the
+     * callee to spill its register arguments to.
+     */
+    fn shadow_space(self) -> i32 {
+        match self {
+            CallConv::SysV => 0,
+            CallConv::Win64 => 0x20
+        }
+    }
+}
+
+/// The frame set up by `Codegen::prologue`, which `Codegen::epilogue`
+/// tears down again.
+///
+/// RBP points at the saved RBP of the caller. Below it are the saved
+/// general purpose registers, the saved XMM registers and then the
+/// locals, and RSP is 16 byte aligned.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    conv: CallConv,
+    gprs: Vec<SizedReg>,
+    xmms: Vec<SizedReg>,
+    locals: i32
+}
+
+impl Frame {
+    pub fn conv(&self) -> CallConv {
+        self.conv
+    }
+    
+    /// The displacement from RBP of the byte at `offset` in the locals.
+    pub fn local(&self, offset: i32) -> i32 {
+        -self.saved_size() - self.locals + offset
+    }
+    
+    /// The displacement from RBP of the stack argument at `index`, where
+    /// the first stack argument has index 0. On Win64 the shadow space of
+    /// the register arguments precedes the stack arguments.
+    pub fn stack_arg(&self, index: i32) -> i32 {
+        16 + self.conv.shadow_space() + index * 8
+    }
+    
+    /*
+     * The size of the saved registers below the saved RBP.
+     */
+    fn saved_size(&self) -> i32 {
+        self.gprs.len() as i32 * 8 + self.xmms.len() as i32 * 16
+    }
+    
+    fn xmm_offset(&self, index: usize) -> i32 {
+        -(self.gprs.len() as i32 * 8) - (index as i32 + 1) * 16
+    }
+}
+
+/*
+ * Where a call passes an argument.
+ */
+#[derive(Copy, Clone)]
+enum Slot {
+    Reg(Reg),
+    Stack(i32)
+}
+
+fn align16(size: i32) -> i32 {
+    (size + 15) & !15
+}
+
+fn is_float(arg: Arg) -> bool {
+    match arg {
+        Arg::VReg(vreg) => vreg.is_xmm(),
+        _ => matches!(arg, Arg::Xmm(_) | Arg::Imm(Imm::F32(_)) | Arg::Imm(Imm::F64(_)))
+    }
+}
+
+/*
+ * The operand with every use of register from replaced by register to.
+ */
+fn replace(arg: Arg, from: Reg, to: Reg) -> Arg {
+    let map = |reg: Reg| if reg == from { to } else { reg };
+    
+    match arg {
+        Arg::Reg(reg) if reg.reg() == from => Arg::Reg(gpr(to, reg.size())),
+        Arg::Xmm(reg) => Arg::Xmm(map(reg)),
+        Arg::MemBase(basereg, disp, size) => Arg::MemBase(map(basereg), disp, size),
+        Arg::MemIndex(basereg, disp, indexreg, shift, size) => Arg::MemIndex(map(basereg), disp, map(indexreg), shift, size),
+        arg => arg
+    }
+}
+
+impl Codegen {
+    /// Sets up a frame for a function with the given calling convention.
+    /// The callee saved registers among `clobbers` are saved, RBP is
+    /// always saved, and `locals` bytes are reserved for locals.
+    pub fn prologue(&mut self, conv: CallConv, clobbers: &[SizedReg], locals: i32) -> Result<Frame, CodegenError> {
+        assert!(locals >= 0, "negative locals size");
+        
+        let mut frame = Frame {
+            conv,
+            gprs: Vec::new(),
+            xmms: Vec::new(),
+            locals
+        };
+        
+        for &reg in conv.callee_saved() {
+            if reg.reg() != Reg::RBP && clobbers.iter().any(|clobber| clobber.reg() == reg.reg()) {
+                if reg.is_xmm() {
+                    frame.xmms.push(reg);
+                } else {
+                    frame.gprs.push(reg);
+                }
+            }
+        }
+        
+        self.push(RBP)?;
+        self.mov(RBP, RSP)?;
+        
+        for &reg in frame.gprs.iter() {
+            self.push(reg)?;
+        }
+        
+        /*
+         * RSP is 16 byte aligned after pushing RBP, so the rest of the
+         * frame is rounded up to a multiple of 16.
+         */
+        
+        let size = align16(frame.saved_size() + locals) - frame.gprs.len() as i32 * 8;
+        
+        if size != 0 {
+            self.sub(RSP, size)?;
+        }
+        
+        for (i, &reg) in frame.xmms.iter().enumerate() {
+            self.movups(Arg::MemBase(Reg::RBP, frame.xmm_offset(i), 16), reg)?;
+        }
+        
+        Ok(frame)
+    }
+    
+    /// Restores the registers saved by `prologue`, tears down the frame
+    /// and returns.
+    pub fn epilogue(&mut self, frame: &Frame) -> Result<(), CodegenError> {
+        for (i, &reg) in frame.xmms.iter().enumerate() {
+            self.movups(reg, Arg::MemBase(Reg::RBP, frame.xmm_offset(i), 16))?;
+        }
+        
+        if frame.gprs.is_empty() {
+            self.mov(RSP, RBP)?;
+        } else {
+            self.lea(RSP, Arg::MemBase(Reg::RBP, -(frame.gprs.len() as i32 * 8), 8))?;
+        }
+        
+        for &reg in frame.gprs.iter().rev() {
+            self.pop(reg)?;
+        }
+        
+        self.pop(RBP)?;
+        self.ret();
+        
+        Ok(())
+    }
+    
+    /// Calls the native function at `target` with the given calling
+    /// convention. XMM registers and f32 and f64 immediates are passed as
+    /// floating point arguments; registers, immediates and memory operands
+    /// as integer or pointer arguments. The result is moved from RAX or
+    /// XMM0 to `ret`.
+    ///
+    /// RSP must be 16 byte aligned, as it is in a frame set up by
+    /// `prologue`. Memory operands relative to RSP are evaluated after the
+    /// stack arguments are reserved. The caller saved registers are
+    /// clobbered. XMM virtual registers are passed as floating point
+    /// arguments too.
+    pub fn call_native(&mut self, conv: CallConv, target: u64, args: &[Arg], ret: Option<Arg>) -> Result<(), CodegenError> {
+        self.native_call(conv, target, args, false, ret)
+    }
+    
+    /// Calls a native function that takes a variable number of
+    /// arguments, like `call_native`. On SysV, AL is set to the number
+    /// of XMM registers used; on Win64, floating point register
+    /// arguments are also passed in the matching integer registers.
+    pub fn call_native_varargs(&mut self, conv: CallConv, target: u64, args: &[Arg], ret: Option<Arg>) -> Result<(), CodegenError> {
+        self.native_call(conv, target, args, true, ret)
+    }
+    
+    fn native_call(&mut self, conv: CallConv, target: u64, args: &[Arg], varargs: bool, ret: Option<Arg>) -> Result<(), CodegenError> {
+        let in_xmm = args.iter().map(|&arg| is_float(arg)).collect::<Vec<_>>();
+        let args = &self.call_args(args)?[..];
+        let slots = Codegen::slots(conv, &in_xmm);
+        let stack = slots.iter().filter(|slot| matches!(slot, Slot::Stack(_))).count() as i32;
+        let size = align16(conv.shadow_space() + stack * 8);
+        
+        if size != 0 {
+            self.sub(RSP, size)?;
+        }
+        
+        /*
+         * The stack arguments go first, while none of the argument
+         * registers have been overwritten yet.
+         */
+        
+        let mut gprs = Vec::new();
+        let mut xmms = Vec::new();
+        
+        for ((&arg, &slot), &float) in args.iter().zip(slots.iter()).zip(in_xmm.iter()) {
+            match slot {
+                Slot::Reg(reg) if float => xmms.push((reg, arg)),
+                Slot::Reg(reg) => gprs.push((reg, arg)),
+                Slot::Stack(offset) => self.store_arg(conv, args, offset, arg)?
+            }
+        }
+        
+        /*
+         * The XMM arguments go before the integer arguments, because
+         * their memory operands may use the integer argument registers.
+         */
+        
+        let floats = xmms.len() as i32;
+        
+        self.parallel_move(conv, args, xmms, true)?;
+        self.parallel_move(conv, args, gprs, false)?;
+        
+        if varargs {
+            match conv {
+                CallConv::SysV => self.mov(EAX, floats)?,
+                CallConv::Win64 => {
+                    for (&slot, &float) in slots.iter().zip(in_xmm.iter()) {
+                        if let Slot::Reg(reg) = slot {
+                            if float {
+                                let index = WIN64_FLOAT_ARGS.iter().position(|xmm| xmm.reg() == reg).unwrap();
+                                self.movq(WIN64_INT_ARGS[index], Arg::Xmm(reg))?;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        
+        if !varargs {
+            self.note_call_args(slots.iter().filter_map(|&slot| match slot {
+                Slot::Reg(reg) => Some(reg),
+                Slot::Stack(_) => None
+            }).collect());
+        }
+        
+        self.call(target)?;
+        
+        if size != 0 {
+            self.add(RSP, size)?;
+        }
+        
+        match ret {
+            None => {}
+            Some(Arg::Xmm(Reg::XMM0)) => {}
+            Some(Arg::Xmm(reg)) => self.movaps(Arg::Xmm(reg), XMM0)?,
+            Some(Arg::Reg(reg)) if reg.reg() == Reg::RAX => {}
+            Some(Arg::VReg(vreg)) if vreg.is_xmm() => self.movaps(vreg, XMM0)?,
+            Some(dst) => self.mov(dst, gpr(Reg::RAX, dst.operand().size.unwrap_or(8)))?
+        }
+        
+        Ok(())
+    }
+    
+    /*
+     * Assigns the arguments to registers or to offsets from RSP.
+     */
+    fn slots(conv: CallConv, floats: &[bool]) -> Vec<Slot> {
+        let mut ints = 0;
+        let mut xmms = 0;
+        let mut offset = conv.shadow_space();
+        
+        floats.iter().enumerate().map(|(i, &float)| {
+            let (regs, next) = if float {
+                (conv.float_args(), &mut xmms)
+            } else {
+                (conv.int_args(), &mut ints)
+            };
+            
+            /*
+             * Win64 assigns the argument registers by position, SysV
+             * takes the next free register of the right kind.
+             */
+            
+            let index = match conv {
+                CallConv::SysV => *next,
+                CallConv::Win64 => i
+            };
+            
+            if index < regs.len() {
+                *next += 1;
+                Slot::Reg(regs[index].reg())
+            } else {
+                offset += 8;
+                Slot::Stack(offset - 8)
+            }
+        }).collect()
+    }
+    
+    /*
+     * Returns a caller saved register that passes no arguments and is not
+     * used by any of the operands.
+     */
+    fn temp(&self, conv: CallConv, args: &[Arg], used: &[Arg], float: bool) -> Result<Reg, CodegenError> {
+        conv.temps().iter()
+            .filter(|reg| reg.is_xmm() == float && Some(reg.reg()) != self.scratch)
+            .map(|reg| reg.reg())
+            .find(|&reg| !used.iter().any(|arg| arg.uses(reg)))
+            .ok_or_else(|| self.error(CodegenErrorKind::UnsupportedOperands, "call_native", args))
+    }
+    
+    /*
+     * Stores an argument into its 8 byte stack slot. An immediate that
+     * does not survive the sign extension of a 32 bit immediate is
+     * loaded into a temporary register first.
+     */
+    fn store_arg(&mut self, conv: CallConv, args: &[Arg], offset: i32, arg: Arg) -> Result<(), CodegenError> {
+        let dst = Arg::MemBase(Reg::RSP, offset, 8);
+        
+        match arg {
+            Arg::Reg(reg) if reg.size() == 8 => self.mov(dst, reg),
+            Arg::Xmm(_) => self.movsd(dst, arg),
+            Arg::Imm(imm) if imm.float_bits().unwrap_or(imm).as_i64().is_some_and(|value| value == value as i32 as i64) => {
+                self.mov(dst, imm)
+            }
+            _ => {
+                let temp = self.temp(conv, args, args, false)?;
+                self.load_int(temp, arg)?;
+                self.mov(dst, gpr(temp, 8))
+            }
+        }
+    }
+    
+    /*
+     * Moves the operands into the registers. A register is only written
+     * once no other operand uses it; when every register is still used,
+     * one is first copied to a temporary register.
+     */
+    fn parallel_move(&mut self, conv: CallConv, args: &[Arg], mut moves: Vec<(Reg, Arg)>, float: bool) -> Result<(), CodegenError> {
+        while !moves.is_empty() {
+            let ready = (0..moves.len()).find(|&i| {
+                !moves.iter().enumerate().any(|(j, &(_, src))| j != i && src.uses(moves[i].0))
+            });
+            
+            if let Some(i) = ready {
+                let (dst, src) = moves.remove(i);
+                self.move_arg(dst, src, float)?;
+            } else {
+                let reg = moves[0].0;
+                let used = moves.iter().map(|&(_, src)| src).collect::<Vec<_>>();
+                let temp = self.temp(conv, args, &used, float)?;
+                
+                if float {
+                    self.move_arg(temp, Arg::Xmm(reg), true)?;
+                } else {
+                    self.move_arg(temp, Arg::Reg(gpr(reg, 8)), false)?;
+                }
+                
+                for &mut (_, ref mut src) in moves.iter_mut() {
+                    *src = replace(*src, reg, temp);
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn move_arg(&mut self, dst: Reg, src: Arg, float: bool) -> Result<(), CodegenError> {
+        if !float {
+            return self.load_int(dst, src);
+        }
+        
+        match src {
+            Arg::Xmm(reg) if reg == dst => Ok(()),
+            Arg::Xmm(_) => self.movaps(Arg::Xmm(dst), src),
+            _ if src.is_mem() => self.movsd(Arg::Xmm(dst), src),
+            _ => self.mov(Arg::Xmm(dst), src)
+        }
+    }
+    
+    /*
+     * Loads an integer argument into a register. Arguments smaller than
+     * 32 bits are zero extended, and immediates are extended to 64 bits.
+     */
+    fn load_int(&mut self, dst: Reg, src: Arg) -> Result<(), CodegenError> {
+        match src {
+            Arg::Reg(reg) if reg.reg() == dst && reg.size() >= 4 => Ok(()),
+            Arg::Reg(reg) if reg.size() < 4 => self.movzx(gpr(dst, 4), reg),
+            Arg::Reg(reg) => self.mov(gpr(dst, reg.size()), reg),
+            Arg::Imm(imm) => self.mov(gpr(dst, 8), imm.float_bits().unwrap_or(imm)),
+            Arg::Xmm(_) => self.movq(gpr(dst, 8), src),
+            _ => {
+                let size = src.operand().size.unwrap_or(8);
+                
+                if size < 4 {
+                    self.movzx(gpr(dst, 4), src)
+                } else {
+                    self.mov(gpr(dst, size), src)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use codegen::x86_64::prologue::*;
+    
+    #[allow(clippy::too_many_arguments)]
+    extern "C" fn store(out: *mut i64, a: i64, b: i64, c: i64, d: i64, e: i64, f: i64, g: i64, h: i64, i: i64) {
+        for (k, &value) in [a, b, c, d, e, f, g, h, i].iter().enumerate() {
+            unsafe { *out.add(k) = value; }
+        }
+    }
+    
+    /*
+     * Passes immediates of every size in registers and in stack slots.
+     * The registers and slots are filled with garbage first, so bits an
+     * immediate leaves unwritten show up in the result.
+     */
+    #[test]
+    fn immediate_args() {
+        const GARBAGE: i64 = 0x5a5a_5a5a_5a5a_5a5a;
+        
+        let conv = CallConv::host();
+        let target = store as extern "C" fn(*mut i64, i64, i64, i64, i64, i64, i64, i64, i64, i64) as usize as u64;
+        let args = [
+            RBX.as_arg(),
+            0xffu8.as_arg(),
+            (-1i8).as_arg(),
+            0xffffu16.as_arg(),
+            (-2i16).as_arg(),
+            0x1234_5678_9abc_def0u64.as_arg(),
+            0x80u8.as_arg(),
+            (-3i32).as_arg(),
+            0x8000_0000u32.as_arg(),
+            0xfedc_ba98_7654_3210u64.as_arg()
+        ];
+        
+        let mut garbage = [GARBAGE.as_arg(); 10];
+        garbage[0] = RBX.as_arg();
+        
+        let mut cg = Codegen::new();
+        let frame = cg.prologue(conv, &[RBX], 0).unwrap();
+        cg.mov(RBX, conv.int_args()[0]).unwrap();
+        cg.call_native(conv, target, &garbage, None).unwrap();
+        for &reg in conv.int_args() {
+            cg.mov(reg, GARBAGE).unwrap();
+        }
+        cg.call_native(conv, target, &args, None).unwrap();
+        cg.epilogue(&frame).unwrap();
+        
+        let f = unsafe { cg.build_fn::<fn(*mut i64)>().unwrap() };
+        let mut out = [0i64; 9];
+        f.call((out.as_mut_ptr(),));
+        
+        assert_eq!(out, [0xff, -1, 0xffff, -2, 0x1234_5678_9abc_def0, 0x80, -3, 0x8000_0000, 0xfedc_ba98_7654_3210u64 as i64]);
+    }
+}
@@ -0,0 +1,884 @@
+//! A disassembler for the x86_64 instructions the code generator emits.
+//!
+//! The decoder covers the general purpose instructions, the SSE scalar
+//! and logical instructions and the x87 instructions `Emit` can produce,
+//! plus the common instructions found around them. Anything it does not
+//! recognize is rendered as a single `db` byte, after which decoding
+//! continues with the next byte.
+
+use std::fmt;
+
+/// A single decoded instruction.
+pub struct Instruction {
+    /// Offset of the instruction from the start of the code.
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+    /// The instruction in Intel syntax.
+    pub text: String
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut bytes = String::new();
+        
+        for b in &self.bytes {
+            bytes.push_str(&format!("{:02x} ", b));
+        }
+        
+        write!(f, "{:6x}:  {:<30} {}", self.offset, bytes, self.text)
+    }
+}
+
+/// Decodes all instructions in `code`.
+pub fn disassemble(code: &[u8]) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    
+    while offset < code.len() {
+        let instruction = decode(code, offset);
+        offset += instruction.bytes.len();
+        instructions.push(instruction);
+    }
+    
+    instructions
+}
+
+/// Renders `code` as a listing with one instruction per line.
+pub fn listing(code: &[u8]) -> String {
+    let mut result = String::new();
+    
+    for instruction in disassemble(code) {
+        result.push_str(&format!("{}\n", instruction));
+    }
+    
+    result
+}
+
+/// Decodes the instruction at `offset`. Bytes that do not start a known
+/// instruction are returned as a one byte `db` pseudo instruction.
+pub fn decode(code: &[u8], offset: usize) -> Instruction {
+    let mut decoder = Decoder {
+        code,
+        pos: offset,
+        rex: 0,
+        opsize: false,
+        rep: None,
+        rip_disp: None
+    };
+    
+    match decoder.decode() {
+        Some(mut text) => {
+            if let Some(disp) = decoder.rip_disp {
+                let target = decoder.pos as i64 + disp as i64;
+                text.push_str(&format!("  # {:#x}", target));
+            }
+            
+            Instruction {
+                offset,
+                bytes: code[offset..decoder.pos].to_vec(),
+                text
+            }
+        }
+        None => Instruction {
+            offset,
+            bytes: vec![code[offset]],
+            text: format!("db {:#04x}", code[offset])
+        }
+    }
+}
+
+const REX_W: u8 = 8;
+const REX_R: u8 = 4;
+const REX_X: u8 = 2;
+const REX_B: u8 = 1;
+
+const GPR64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi",
+    "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"
+];
+
+const GPR32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi",
+    "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d"
+];
+
+const GPR16: [&str; 16] = [
+    "ax", "cx", "dx", "bx", "sp", "bp", "si", "di",
+    "r8w", "r9w", "r10w", "r11w", "r12w", "r13w", "r14w", "r15w"
+];
+
+const GPR8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil",
+    "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b"
+];
+
+/*
+ * Without a REX prefix, byte registers 4 to 7 are the high bytes of the
+ * first four registers.
+ */
+const GPR8_LEGACY: [&str; 8] = [
+    "al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"
+];
+
+const CC: [&str; 16] = [
+    "o", "no", "b", "ae", "e", "ne", "be", "a",
+    "s", "ns", "p", "np", "l", "ge", "le", "g"
+];
+
+const ALU: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+
+const SHIFT: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"];
+
+/*
+ * The operand of the r/m field of a ModRM byte.
+ */
+enum Rm {
+    Reg(u8),
+    Mem(String)
+}
+
+struct ModRm {
+    /// The reg field, extended with REX.R.
+    reg: u8,
+    /// The reg field without the REX extension, used as opcode extension.
+    ext: u8,
+    rm: Rm
+}
+
+impl ModRm {
+    fn is_reg(&self) -> bool {
+        match self.rm {
+            Rm::Reg(_) => true,
+            Rm::Mem(_) => false
+        }
+    }
+}
+
+/*
+ * Formats an immediate of the given operand size. Immediates of 64 bit
+ * operations are sign extended and printed signed; all others are
+ * printed as unsigned values of the operand size.
+ */
+fn imm(value: i64, size: i32) -> String {
+    match size {
+        1 => format!("{:#x}", value as u8),
+        2 => format!("{:#x}", value as u16),
+        4 => format!("{:#x}", value as u32),
+        _ => signed(value)
+    }
+}
+
+fn signed(value: i64) -> String {
+    if value < 0 {
+        format!("-{:#x}", value.unsigned_abs())
+    } else {
+        format!("{:#x}", value)
+    }
+}
+
+fn ptr(size: i32) -> &'static str {
+    match size {
+        1 => "byte ptr ",
+        2 => "word ptr ",
+        4 => "dword ptr ",
+        8 => "qword ptr ",
+        10 => "tbyte ptr ",
+        16 => "xmmword ptr ",
+        _ => ""
+    }
+}
+
+struct Decoder<'a> {
+    code: &'a [u8],
+    pos: usize,
+    rex: u8,
+    opsize: bool,
+    rep: Option<u8>,
+    rip_disp: Option<i32>
+}
+
+impl<'a> Decoder<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        let b = *self.code.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+    
+    fn i8(&mut self) -> Option<i64> {
+        Some(self.byte()? as i8 as i64)
+    }
+    
+    fn i16(&mut self) -> Option<i64> {
+        let lo = self.byte()? as u16;
+        let hi = self.byte()? as u16;
+        Some((lo | (hi << 8)) as i16 as i64)
+    }
+    
+    fn i32(&mut self) -> Option<i64> {
+        let mut value = 0u32;
+        for i in 0..4 {
+            value |= (self.byte()? as u32) << (i * 8);
+        }
+        Some(value as i32 as i64)
+    }
+    
+    fn i64(&mut self) -> Option<i64> {
+        let mut value = 0u64;
+        for i in 0..8 {
+            value |= (self.byte()? as u64) << (i * 8);
+        }
+        Some(value as i64)
+    }
+    
+    /*
+     * Reads an immediate of the operand size; 64 bit operations take a
+     * sign extended 32 bit immediate.
+     */
+    fn imm_z(&mut self, size: i32) -> Option<i64> {
+        if size == 2 { self.i16() } else { self.i32() }
+    }
+    
+    /*
+     * The operand size of instructions that default to 32 bits.
+     */
+    fn size(&self) -> i32 {
+        if (self.rex & REX_W) != 0 {
+            8
+        } else if self.opsize {
+            2
+        } else {
+            4
+        }
+    }
+    
+    /*
+     * The operand size of instructions that default to 64 bits, like
+     * push and pop.
+     */
+    fn size64(&self) -> i32 {
+        if self.opsize { 2 } else { 8 }
+    }
+    
+    fn gpr(&self, reg: u8, size: i32) -> &'static str {
+        let reg = reg as usize;
+        match size {
+            1 if self.rex == 0 && reg < 8 => GPR8_LEGACY[reg],
+            1 => GPR8[reg],
+            2 => GPR16[reg],
+            4 => GPR32[reg],
+            _ => GPR64[reg]
+        }
+    }
+    
+    fn branch_target(&self, disp: i64) -> String {
+        format!("{:#x}", self.pos as i64 + disp)
+    }
+    
+    fn modrm(&mut self) -> Option<ModRm> {
+        let b = self.byte()?;
+        let md = b >> 6;
+        let ext = (b >> 3) & 7;
+        let reg = ext | if (self.rex & REX_R) != 0 { 8 } else { 0 };
+        let rm = b & 7;
+        
+        if md == 3 {
+            let rm = rm | if (self.rex & REX_B) != 0 { 8 } else { 0 };
+            return Some(ModRm { reg, ext, rm: Rm::Reg(rm) });
+        }
+        
+        let mut base = None;
+        let mut index = None;
+        let mut disp = 0;
+        let mut rip = false;
+        
+        if rm == 4 {
+            let sib = self.byte()?;
+            let scale = 1 << (sib >> 6);
+            let idx = ((sib >> 3) & 7) | if (self.rex & REX_X) != 0 { 8 } else { 0 };
+            let b = sib & 7;
+            
+            if idx != 4 {
+                index = Some((idx, scale));
+            }
+            
+            if b == 5 && md == 0 {
+                disp = self.i32()?;
+            } else {
+                base = Some(b | if (self.rex & REX_B) != 0 { 8 } else { 0 });
+            }
+        } else if rm == 5 && md == 0 {
+            rip = true;
+            disp = self.i32()?;
+        } else {
+            base = Some(rm | if (self.rex & REX_B) != 0 { 8 } else { 0 });
+        }
+        
+        match md {
+            1 => disp = self.i8()?,
+            2 => disp = self.i32()?,
+            _ => {}
+        }
+        
+        let mut address = String::from("[");
+        
+        if rip {
+            address.push_str("rip");
+            self.rip_disp = Some(disp as i32);
+        }
+        if let Some(base) = base {
+            address.push_str(GPR64[base as usize]);
+        }
+        if let Some((index, scale)) = index {
+            if address.len() > 1 {
+                address.push('+');
+            }
+            address.push_str(&format!("{}*{}", GPR64[index as usize], scale));
+        }
+        if address.len() == 1 {
+            address.push_str(&format!("{:#x}", disp));
+        } else if disp < 0 {
+            address.push_str(&format!("-{:#x}", -disp));
+        } else if disp > 0 {
+            address.push_str(&format!("+{:#x}", disp));
+        }
+        address.push(']');
+        
+        Some(ModRm { reg, ext, rm: Rm::Mem(address) })
+    }
+    
+    /*
+     * Formats the r/m operand as a general purpose register or memory
+     * operand of the given size.
+     */
+    fn e(&self, m: &ModRm, size: i32) -> String {
+        match m.rm {
+            Rm::Reg(reg) => self.gpr(reg, size).to_string(),
+            Rm::Mem(ref address) => format!("{}{}", ptr(size), address)
+        }
+    }
+    
+    /*
+     * Formats the r/m operand as an XMM register or memory operand of the
+     * given size.
+     */
+    fn x(&self, m: &ModRm, size: i32) -> String {
+        match m.rm {
+            Rm::Reg(reg) => format!("xmm{}", reg),
+            Rm::Mem(ref address) => format!("{}{}", ptr(size), address)
+        }
+    }
+    
+    fn g(&self, m: &ModRm, size: i32) -> String {
+        self.gpr(m.reg, size).to_string()
+    }
+    
+    fn decode(&mut self) -> Option<String> {
+        loop {
+            match *self.code.get(self.pos)? {
+                0x66 => self.opsize = true,
+                0xf2 | 0xf3 => self.rep = Some(self.code[self.pos]),
+                _ => break
+            }
+            self.pos += 1;
+        }
+        
+        let mut op = self.byte()?;
+        
+        if (op & 0xf0) == 0x40 {
+            self.rex = op;
+            op = self.byte()?;
+        }
+        
+        match op {
+            0x0f => self.decode_0f(),
+            0xd8..=0xdf => self.decode_x87(op),
+            _ => self.decode_1(op)
+        }
+    }
+    
+    fn decode_1(&mut self, op: u8) -> Option<String> {
+        let size = self.size();
+        
+        let text = match op {
+            0x00..=0x3f if (op & 7) < 6 => {
+                let name = ALU[(op >> 3) as usize];
+                match op & 7 {
+                    0 => { let m = self.modrm()?; format!("{} {}, {}", name, self.e(&m, 1), self.g(&m, 1)) }
+                    1 => { let m = self.modrm()?; format!("{} {}, {}", name, self.e(&m, size), self.g(&m, size)) }
+                    2 => { let m = self.modrm()?; format!("{} {}, {}", name, self.g(&m, 1), self.e(&m, 1)) }
+                    3 => { let m = self.modrm()?; format!("{} {}, {}", name, self.g(&m, size), self.e(&m, size)) }
+                    4 => { let value = self.i8()?; format!("{} al, {}", name, imm(value, 1)) }
+                    _ => { let value = self.imm_z(size)?; format!("{} {}, {}", name, self.gpr(0, size), imm(value, size)) }
+                }
+            }
+            0x50..=0x5f => {
+                let reg = (op & 7) | if (self.rex & REX_B) != 0 { 8 } else { 0 };
+                let name = if op < 0x58 { "push" } else { "pop" };
+                format!("{} {}", name, self.gpr(reg, self.size64()))
+            }
+            0x63 => {
+                let m = self.modrm()?;
+                format!("movsxd {}, {}", self.g(&m, size), self.e(&m, 4))
+            }
+            0x68 => {
+                let size = self.size64();
+                let name = if size == 2 { "pushw" } else { "push" };
+                format!("{} {}", name, imm(self.imm_z(size)?, size))
+            }
+            0x6a => format!("push {}", imm(self.i8()?, 8)),
+            0x69 | 0x6b => {
+                let m = self.modrm()?;
+                let value = if op == 0x69 { self.imm_z(size)? } else { self.i8()? };
+                format!("imul {}, {}, {}", self.g(&m, size), self.e(&m, size), imm(value, size))
+            }
+            0x70..=0x7f => {
+                let disp = self.i8()?;
+                format!("j{} {}", CC[(op & 0xf) as usize], self.branch_target(disp))
+            }
+            0x80 | 0x81 | 0x83 => {
+                let m = self.modrm()?;
+                let size = if op == 0x80 { 1 } else { size };
+                let value = match op {
+                    0x81 => self.imm_z(size)?,
+                    _ => self.i8()?
+                };
+                format!("{} {}, {}", ALU[m.ext as usize], self.e(&m, size), imm(value, size))
+            }
+            0x84..=0x8b => {
+                let m = self.modrm()?;
+                let size = if (op & 1) == 0 { 1 } else { size };
+                let name = match op {
+                    0x84 | 0x85 => "test",
+                    0x86 | 0x87 => "xchg",
+                    _ => "mov"
+                };
+                if op >= 0x8a {
+                    format!("{} {}, {}", name, self.g(&m, size), self.e(&m, size))
+                } else {
+                    format!("{} {}, {}", name, self.e(&m, size), self.g(&m, size))
+                }
+            }
+            0x8d => {
+                let m = self.modrm()?;
+                if m.is_reg() {
+                    return None;
+                }
+                format!("lea {}, {}", self.g(&m, size), self.e(&m, 0))
+            }
+            0x8f => {
+                let m = self.modrm()?;
+                if m.ext != 0 {
+                    return None;
+                }
+                format!("pop {}", self.e(&m, self.size64()))
+            }
+            0x90 if (self.rex & REX_B) == 0 && !self.opsize => {
+                if self.rep == Some(0xf3) { "pause".to_string() } else { "nop".to_string() }
+            }
+            0x90..=0x97 => {
+                let reg = (op & 7) | if (self.rex & REX_B) != 0 { 8 } else { 0 };
+                format!("xchg {}, {}", self.gpr(reg, size), self.gpr(0, size))
+            }
+            0x98 => match size { 2 => "cbw", 4 => "cwde", _ => "cdqe" }.to_string(),
+            0x99 => match size { 2 => "cwd", 4 => "cdq", _ => "cqo" }.to_string(),
+            0xa0..=0xa3 => {
+                let size = if (op & 1) == 0 { 1 } else { size };
+                let address = format!("{}[{:#x}]", ptr(size), self.i64()? as u64);
+                if op < 0xa2 {
+                    format!("movabs {}, {}", self.gpr(0, size), address)
+                } else {
+                    format!("movabs {}, {}", address, self.gpr(0, size))
+                }
+            }
+            0xa8 => format!("test al, {}", imm(self.i8()?, 1)),
+            0xa9 => {
+                let value = self.imm_z(size)?;
+                format!("test {}, {}", self.gpr(0, size), imm(value, size))
+            }
+            0xb0..=0xb7 => {
+                let reg = (op & 7) | if (self.rex & REX_B) != 0 { 8 } else { 0 };
+                format!("mov {}, {}", self.gpr(reg, 1), imm(self.i8()?, 1))
+            }
+            0xb8..=0xbf => {
+                let reg = (op & 7) | if (self.rex & REX_B) != 0 { 8 } else { 0 };
+                let value = match size {
+                    8 => self.i64()?,
+                    2 => self.i16()?,
+                    _ => self.i32()?
+                };
+                if size == 8 {
+                    format!("movabs {}, {:#x}", self.gpr(reg, size), value)
+                } else {
+                    format!("mov {}, {}", self.gpr(reg, size), imm(value, size))
+                }
+            }
+            0xc0 | 0xc1 | 0xd0..=0xd3 => {
+                let m = self.modrm()?;
+                let size = if (op & 1) == 0 { 1 } else { size };
+                let count = match op {
+                    0xc0 | 0xc1 => imm(self.i8()?, 1),
+                    0xd0 | 0xd1 => "1".to_string(),
+                    _ => "cl".to_string()
+                };
+                format!("{} {}, {}", SHIFT[m.ext as usize], self.e(&m, size), count)
+            }
+            0xc2 => format!("ret {}", imm(self.i16()?, 2)),
+            0xc3 => "ret".to_string(),
+            0xc6 | 0xc7 => {
+                let m = self.modrm()?;
+                if m.ext != 0 {
+                    return None;
+                }
+                let size = if op == 0xc6 { 1 } else { size };
+                let value = if op == 0xc6 { self.i8()? } else { self.imm_z(size)? };
+                format!("mov {}, {}", self.e(&m, size), imm(value, size))
+            }
+            0xc8 => {
+                let frame = self.i16()?;
+                let level = self.i8()?;
+                format!("enter {}, {}", imm(frame, 2), imm(level, 1))
+            }
+            0xc9 => "leave".to_string(),
+            0xcc => "int3".to_string(),
+            0xe8 => {
+                let disp = self.i32()?;
+                format!("call {}", self.branch_target(disp))
+            }
+            0xe9 => {
+                let disp = self.i32()?;
+                format!("jmp {}", self.branch_target(disp))
+            }
+            0xeb => {
+                let disp = self.i8()?;
+                format!("jmp {}", self.branch_target(disp))
+            }
+            0xf4 => "hlt".to_string(),
+            0xf6 | 0xf7 => {
+                let m = self.modrm()?;
+                let size = if op == 0xf6 { 1 } else { size };
+                match m.ext {
+                    0 | 1 => {
+                        let value = if op == 0xf6 { self.i8()? } else { self.imm_z(size)? };
+                        format!("test {}, {}", self.e(&m, size), imm(value, size))
+                    }
+                    ext => {
+                        let name = ["", "", "not", "neg", "mul", "imul", "div", "idiv"][ext as usize];
+                        format!("{} {}", name, self.e(&m, size))
+                    }
+                }
+            }
+            0xfe => {
+                let m = self.modrm()?;
+                match m.ext {
+                    0 => format!("inc {}", self.e(&m, 1)),
+                    1 => format!("dec {}", self.e(&m, 1)),
+                    _ => return None
+                }
+            }
+            0xff => {
+                let m = self.modrm()?;
+                match m.ext {
+                    0 => format!("inc {}", self.e(&m, size)),
+                    1 => format!("dec {}", self.e(&m, size)),
+                    2 => format!("call {}", self.e(&m, 8)),
+                    4 => format!("jmp {}", self.e(&m, 8)),
+                    6 => format!("push {}", self.e(&m, self.size64())),
+                    _ => return None
+                }
+            }
+            _ => return None
+        };
+        
+        Some(text)
+    }
+    
+    /*
+     * Selects the mnemonic of an SSE instruction from its mandatory
+     * prefix: none, 0x66, 0xf3 or 0xf2.
+     */
+    fn sse_name(&self, names: [&'static str; 4]) -> &'static str {
+        match self.rep {
+            Some(0xf3) => names[2],
+            Some(0xf2) => names[3],
+            _ if self.opsize => names[1],
+            _ => names[0]
+        }
+    }
+    
+    /*
+     * The size of the memory operand of an SSE instruction, which is
+     * scalar for the 0xf3 and 0xf2 prefixes and packed otherwise.
+     */
+    fn sse_size(&self) -> i32 {
+        match self.rep {
+            Some(0xf3) => 4,
+            Some(0xf2) => 8,
+            _ => 16
+        }
+    }
+    
+    fn decode_0f(&mut self) -> Option<String> {
+        let op = self.byte()?;
+        let size = self.size();
+        
+        let text = match op {
+            0x05 => "syscall".to_string(),
+            0x0b => "ud2".to_string(),
+            0x10 | 0x11 => {
+                let m = self.modrm()?;
+                let name = self.sse_name(["movups", "movupd", "movss", "movsd"]);
+                let (x, g) = (self.x(&m, self.sse_size()), format!("xmm{}", m.reg));
+                if op == 0x10 { format!("{} {}, {}", name, g, x) } else { format!("{} {}, {}", name, x, g) }
+            }
+            0x1f => {
+                let m = self.modrm()?;
+                format!("nop {}", self.e(&m, size))
+            }
+            0x28 | 0x29 => {
+                let m = self.modrm()?;
+                let name = if self.opsize { "movapd" } else { "movaps" };
+                let (x, g) = (self.x(&m, 16), format!("xmm{}", m.reg));
+                if op == 0x28 { format!("{} {}, {}", name, g, x) } else { format!("{} {}, {}", name, x, g) }
+            }
+            0x2a => {
+                let m = self.modrm()?;
+                let name = self.sse_name(["cvtpi2ps", "cvtpi2pd", "cvtsi2ss", "cvtsi2sd"]);
+                self.rep?;
+                let size = if (self.rex & REX_W) != 0 { 8 } else { 4 };
+                format!("{} xmm{}, {}", name, m.reg, self.e(&m, size))
+            }
+            0x2c | 0x2d => {
+                let m = self.modrm()?;
+                let name = if op == 0x2c {
+                    self.sse_name(["cvttps2pi", "cvttpd2pi", "cvttss2si", "cvttsd2si"])
+                } else {
+                    self.sse_name(["cvtps2pi", "cvtpd2pi", "cvtss2si", "cvtsd2si"])
+                };
+                self.rep?;
+                let size = if (self.rex & REX_W) != 0 { 8 } else { 4 };
+                format!("{} {}, {}", name, self.g(&m, size), self.x(&m, self.sse_size()))
+            }
+            0x2e | 0x2f => {
+                let m = self.modrm()?;
+                let name = match (op, self.opsize) {
+                    (0x2e, false) => "ucomiss",
+                    (0x2e, true) => "ucomisd",
+                    (_, false) => "comiss",
+                    (_, true) => "comisd"
+                };
+                let size = if self.opsize { 8 } else { 4 };
+                format!("{} xmm{}, {}", name, m.reg, self.x(&m, size))
+            }
+            0x3a => {
+                let op = self.byte()?;
+                let m = self.modrm()?;
+                let (name, size) = match op {
+                    0x0a if self.opsize => ("roundss", 4),
+                    0x0b if self.opsize => ("roundsd", 8),
+                    _ => return None
+                };
+                let mode = self.i8()?;
+                format!("{} xmm{}, {}, {}", name, m.reg, self.x(&m, size), imm(mode, 1))
+            }
+            0x40..=0x4f => {
+                let m = self.modrm()?;
+                format!("cmov{} {}, {}", CC[(op & 0xf) as usize], self.g(&m, size), self.e(&m, size))
+            }
+            0x51 | 0x58 | 0x59 | 0x5c..=0x5f => {
+                let m = self.modrm()?;
+                let name = match op {
+                    0x51 => self.sse_name(["sqrtps", "sqrtpd", "sqrtss", "sqrtsd"]),
+                    0x58 => self.sse_name(["addps", "addpd", "addss", "addsd"]),
+                    0x59 => self.sse_name(["mulps", "mulpd", "mulss", "mulsd"]),
+                    0x5c => self.sse_name(["subps", "subpd", "subss", "subsd"]),
+                    0x5d => self.sse_name(["minps", "minpd", "minss", "minsd"]),
+                    0x5e => self.sse_name(["divps", "divpd", "divss", "divsd"]),
+                    _ => self.sse_name(["maxps", "maxpd", "maxss", "maxsd"])
+                };
+                format!("{} xmm{}, {}", name, m.reg, self.x(&m, self.sse_size()))
+            }
+            0x54..=0x57 => {
+                let m = self.modrm()?;
+                if self.rep.is_some() {
+                    return None;
+                }
+                let name = match (op, self.opsize) {
+                    (0x54, false) => "andps",
+                    (0x54, true) => "andpd",
+                    (0x55, false) => "andnps",
+                    (0x55, true) => "andnpd",
+                    (0x56, false) => "orps",
+                    (0x56, true) => "orpd",
+                    (_, false) => "xorps",
+                    (_, true) => "xorpd"
+                };
+                format!("{} xmm{}, {}", name, m.reg, self.x(&m, 16))
+            }
+            0x5a => {
+                let m = self.modrm()?;
+                let name = self.sse_name(["cvtps2pd", "cvtpd2ps", "cvtss2sd", "cvtsd2ss"]);
+                let size = match self.rep {
+                    Some(0xf3) => 4,
+                    Some(_) => 8,
+                    None if self.opsize => 16,
+                    None => 8
+                };
+                format!("{} xmm{}, {}", name, m.reg, self.x(&m, size))
+            }
+            0x6e | 0x7e if self.opsize => {
+                let m = self.modrm()?;
+                let (name, size) = if (self.rex & REX_W) != 0 { ("movq", 8) } else { ("movd", 4) };
+                if op == 0x6e {
+                    format!("{} xmm{}, {}", name, m.reg, self.e(&m, size))
+                } else {
+                    format!("{} {}, xmm{}", name, self.e(&m, size), m.reg)
+                }
+            }
+            0x7e if self.rep == Some(0xf3) => {
+                let m = self.modrm()?;
+                format!("movq xmm{}, {}", m.reg, self.x(&m, 8))
+            }
+            0xd6 if self.opsize => {
+                let m = self.modrm()?;
+                format!("movq {}, xmm{}", self.x(&m, 8), m.reg)
+            }
+            0x80..=0x8f => {
+                let disp = self.i32()?;
+                format!("j{} {}", CC[(op & 0xf) as usize], self.branch_target(disp))
+            }
+            0x90..=0x9f => {
+                let m = self.modrm()?;
+                format!("set{} {}", CC[(op & 0xf) as usize], self.e(&m, 1))
+            }
+            0xa2 => "cpuid".to_string(),
+            0xa3 | 0xab | 0xb3 | 0xbb => {
+                let m = self.modrm()?;
+                let name = match op { 0xa3 => "bt", 0xab => "bts", 0xb3 => "btr", _ => "btc" };
+                format!("{} {}, {}", name, self.e(&m, size), self.g(&m, size))
+            }
+            0xa4 | 0xa5 | 0xac | 0xad => {
+                let m = self.modrm()?;
+                let name = if op < 0xac { "shld" } else { "shrd" };
+                let count = if (op & 1) == 0 { imm(self.i8()?, 1) } else { "cl".to_string() };
+                format!("{} {}, {}, {}", name, self.e(&m, size), self.g(&m, size), count)
+            }
+            0xae => {
+                let m = self.modrm()?;
+                if m.is_reg() {
+                    return None;
+                }
+                match m.ext {
+                    2 => format!("ldmxcsr {}", self.e(&m, 4)),
+                    3 => format!("stmxcsr {}", self.e(&m, 4)),
+                    _ => return None
+                }
+            }
+            0xaf => {
+                let m = self.modrm()?;
+                format!("imul {}, {}", self.g(&m, size), self.e(&m, size))
+            }
+            0xb6 | 0xb7 | 0xbe | 0xbf => {
+                let m = self.modrm()?;
+                let name = if op < 0xbe { "movzx" } else { "movsx" };
+                let src = if (op & 1) == 0 { 1 } else { 2 };
+                format!("{} {}, {}", name, self.g(&m, size), self.e(&m, src))
+            }
+            0xc8..=0xcf => {
+                let reg = (op & 7) | if (self.rex & REX_B) != 0 { 8 } else { 0 };
+                format!("bswap {}", self.gpr(reg, size))
+            }
+            _ => return None
+        };
+        
+        Some(text)
+    }
+    
+    fn decode_x87(&mut self, op: u8) -> Option<String> {
+        let m = self.modrm()?;
+        let ext = m.ext as usize;
+        
+        if let Rm::Reg(reg) = m.rm {
+            let i = reg & 7;
+            let text = match (op, ext) {
+                (0xd8, _) | (0xdc, _) | (0xde, _) => {
+                    let name = ["fadd", "fmul", "fcom", "fcomp", "fsub", "fsubr", "fdiv", "fdivr"][ext];
+                    match op {
+                        0xd8 => format!("{} st, st({})", name, i),
+                        0xdc => format!("{} st({}), st", name, i),
+                        _ if ext == 3 && i == 1 => "fcompp".to_string(),
+                        _ => format!("{}p st({}), st", name, i)
+                    }
+                }
+                (0xd9, 0) => format!("fld st({})", i),
+                (0xd9, 1) => format!("fxch st({})", i),
+                (0xd9, _) => {
+                    match 0xc0 | (m.ext << 3) | i {
+                        0xd0 => "fnop",
+                        0xe0 => "fchs",
+                        0xe1 => "fabs",
+                        0xe4 => "ftst",
+                        0xe5 => "fxam",
+                        0xe8 => "fld1",
+                        0xee => "fldz",
+                        0xfa => "fsqrt",
+                        0xfc => "frndint",
+                        0xfe => "fsin",
+                        0xff => "fcos",
+                        _ => return None
+                    }.to_string()
+                }
+                (0xdb, 5) => format!("fucomi st, st({})", i),
+                (0xdb, 6) => format!("fcomi st, st({})", i),
+                (0xdd, 0) => format!("ffree st({})", i),
+                (0xdd, 2) => format!("fst st({})", i),
+                (0xdd, 3) => format!("fstp st({})", i),
+                (0xdd, 4) => format!("fucom st({})", i),
+                (0xdd, 5) => format!("fucomp st({})", i),
+                (0xdf, 4) if i == 0 => "fnstsw ax".to_string(),
+                (0xdf, 5) => format!("fucomip st, st({})", i),
+                (0xdf, 6) => format!("fcomip st, st({})", i),
+                _ => return None
+            };
+            
+            return Some(text);
+        }
+        
+        let (name, size) = match (op, ext) {
+            (0xd8, _) => (["fadd", "fmul", "fcom", "fcomp", "fsub", "fsubr", "fdiv", "fdivr"][ext], 4),
+            (0xdc, _) => (["fadd", "fmul", "fcom", "fcomp", "fsub", "fsubr", "fdiv", "fdivr"][ext], 8),
+            (0xda, _) => (["fiadd", "fimul", "ficom", "ficomp", "fisub", "fisubr", "fidiv", "fidivr"][ext], 4),
+            (0xde, _) => (["fiadd", "fimul", "ficom", "ficomp", "fisub", "fisubr", "fidiv", "fidivr"][ext], 2),
+            (0xd9, 0) => ("fld", 4),
+            (0xd9, 2) => ("fst", 4),
+            (0xd9, 3) => ("fstp", 4),
+            (0xd9, 4) => ("fldenv", 0),
+            (0xd9, 5) => ("fldcw", 2),
+            (0xd9, 6) => ("fnstenv", 0),
+            (0xd9, 7) => ("fnstcw", 2),
+            (0xdb, 0) => ("fild", 4),
+            (0xdb, 1) => ("fisttp", 4),
+            (0xdb, 2) => ("fist", 4),
+            (0xdb, 3) => ("fistp", 4),
+            (0xdb, 5) => ("fld", 10),
+            (0xdb, 7) => ("fstp", 10),
+            (0xdd, 0) => ("fld", 8),
+            (0xdd, 1) => ("fisttp", 8),
+            (0xdd, 2) => ("fst", 8),
+            (0xdd, 3) => ("fstp", 8),
+            (0xdd, 4) => ("frstor", 0),
+            (0xdd, 6) => ("fnsave", 0),
+            (0xdd, 7) => ("fnstsw", 2),
+            (0xdf, 0) => ("fild", 2),
+            (0xdf, 1) => ("fisttp", 2),
+            (0xdf, 2) => ("fist", 2),
+            (0xdf, 3) => ("fistp", 2),
+            (0xdf, 4) => ("fbld", 10),
+            (0xdf, 5) => ("fild", 8),
+            (0xdf, 6) => ("fbstp", 10),
+            (0xdf, 7) => ("fistp", 8),
+            _ => return None
+        };
+        
+        Some(format!("{} {}", name, self.e(&m, size)))
+    }
+}
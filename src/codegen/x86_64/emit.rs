@@ -3,6 +3,8 @@
 #![allow(non_camel_case_types)]
 #![allow(non_upper_case_globals)]
 #![allow(non_snake_case)]
+#![allow(clippy::double_parens)]
+#![allow(clippy::too_many_arguments)]
 
 /*
  * jit-gen-x86-64.h - Macros for generating x86_64 code.
@@ -26,9 +28,8 @@
  * <http://www.gnu.org/licenses/>.
  */
 
-use codegen::{Writer, JitFunction};
+use codegen::{Writer, Position, FixupTarget, RelocKind, CodeBuffer, JitFunction, CodeHeap, Label, Cond, CodegenError};
 use std::mem::transmute;
-use std::i32;
 
 /*
  * X86_64 64 bit general purpose integer registers.
@@ -77,7 +78,7 @@ const X86_64_XMM14 : u8 = 14;
 const X86_64_XMM15 : u8 = 15;
 
 #[repr(usize)]
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Reg {
     RAX = 0,
     RCX = 1,
@@ -118,7 +119,7 @@ pub enum Reg {
 
 impl Reg {
     pub fn value(self) -> u8 {
-        x86_64_reg_map[unsafe { transmute::<_, usize>(self) }]
+        x86_64_reg_map[unsafe { transmute::<Reg, usize>(self) }]
     }
 }
 
@@ -195,6 +196,10 @@ impl AluOp {
 #[repr(u8)]
 #[derive(Copy, Clone)]
 pub enum ShiftOp {
+    Rol = 0,
+    Ror = 1,
+    Rcl = 2,
+    Rcr = 3,
     Shl = 4,
     Shr = 5,
     Sar = 7,
@@ -206,35 +211,18 @@ impl ShiftOp {
     }
 }
 
-const x86_cc_unsigned_map : [u8; 12] = [
-	0x74, /* eq  */
-	0x75, /* ne  */
-	0x72, /* lt  */
-	0x76, /* le  */
-	0x77, /* gt  */
-	0x73, /* ge  */
-	0x78, /* lz  */
-	0x79, /* gez */
-	0x7a, /* p   */
-	0x7b, /* np  */
-	0x70, /* o  */
-	0x71, /* no  */
-];
+#[repr(u8)]
+#[derive(Copy, Clone)]
+pub enum DoubleShiftOp {
+    Shld = 0xa4,
+    Shrd = 0xac,
+}
 
-const x86_cc_signed_map : [u8; 12] = [
-	0x74, /* eq  */
-	0x75, /* ne  */
-	0x7c, /* lt  */
-	0x7e, /* le  */
-	0x7f, /* gt  */
-	0x7d, /* ge  */
-	0x78, /* lz  */
-	0x79, /* gez */
-	0x7a, /* p   */
-	0x7b, /* np  */
-	0x70, /* o  */
-	0x71, /* no  */
-];
+impl DoubleShiftOp {
+    fn value(self) -> u8 {
+        unsafe { transmute(self) }
+    }
+}
 
 const x86_64_reg_map : [u8; 35] = [
     X86_64_RAX,
@@ -275,18 +263,68 @@ const x86_64_reg_map : [u8; 35] = [
 ];
 
 pub struct Emit {
-    inst: Writer
+    inst: Writer,
+    /* Offset of the displacement of the last RIP relative operand */
+    rip_disp: usize
 }
 
 impl Emit {
     pub fn new() -> Emit {
         Emit {
-            inst: Writer::new()
+            inst: Writer::new(),
+            rip_disp: 0
         }
     }
     
-    pub fn build(&mut self) -> JitFunction {
-        self.inst.build()
+    pub fn build(&mut self, heap: &CodeHeap, name: Option<&str>) -> Result<JitFunction, CodegenError> {
+        self.inst.build(heap, name)
+    }
+    
+    pub fn finish(&self) -> Result<CodeBuffer, CodegenError> {
+        self.inst.finish()
+    }
+    
+    pub fn new_label(&mut self) -> Label {
+        self.inst.new_label()
+    }
+    
+    pub fn bind(&mut self, label: Label) {
+        self.inst.bind(label);
+    }
+    
+    pub fn line(&mut self, file: &str, line: u32) {
+        self.inst.line(file, line);
+    }
+    
+    pub fn offset(&self) -> usize {
+        self.inst.len()
+    }
+    
+    pub(super) fn position(&self) -> Position {
+        self.inst.position()
+    }
+    
+    pub(super) fn layout(&self, positions: &[Position]) -> Result<(Vec<u8>, Vec<usize>), CodegenError> {
+        self.inst.layout_positions(positions)
+    }
+    
+    /*
+     * Makes the RIP relative operand of the instruction that was just
+     * emitted address target once the code is built.
+     */
+    pub(super) fn rip_fixup(&mut self, kind: RelocKind, target: FixupTarget) {
+        self.inst.fixup(self.rip_disp, kind, target);
+    }
+    
+    /*
+     * Adds a constant to the constant pool and returns its offset there.
+     */
+    pub(super) fn constant(&mut self, bytes: &[u8]) -> usize {
+        self.inst.constant(bytes)
+    }
+    
+    pub(super) fn truncate(&mut self, pos: Position) {
+        self.inst.truncate(pos);
     }
     
     fn address_byte(&mut self, m: u8, o: u8, r: u8) {
@@ -294,16 +332,15 @@ impl Emit {
     }
 
     fn imm_emit32(&mut self, imm: i32) {
-        let imb = unsafe { transmute::<_, [u8; 4]>(imm) };
+        let imb = imm.to_le_bytes();
         self.inst.push(imb [0]);
         self.inst.push(imb [1]);
         self.inst.push(imb [2]);
         self.inst.push(imb [3]);
     }
 
-    // TODO: inst is the offset into the stream!
     fn imm_emit16(&mut self, imm: i32) {
-        let imb = unsafe { transmute::<_, [u8; 2]>(imm as i16) };
+        let imb = (imm as i16).to_le_bytes();
         self.inst.push(imb [0]);
         self.inst.push(imb [1]);
     }
@@ -313,7 +350,7 @@ impl Emit {
     }
 
     fn imm_emit64(&mut self, imm: i64) {
-        let imb = unsafe { transmute::<_, [u8; 8]>(imm) };
+        let imb = imm.to_le_bytes();
         self.inst.push(imb[0]);
         self.inst.push(imb[1]);
         self.inst.push(imb[2]);
@@ -358,11 +395,7 @@ impl Emit {
     }
     
     fn is_imm8(imm: i32) -> bool {
-        ((imm) >= -128 && (imm) <= 127)
-    }
-    
-    fn is_imm16(imm: i32) -> bool {
-        ((imm) >= -(1<<16) && (imm) <= ((1<<16)-1))
+        (-128..=127).contains(&imm)
     }
     
     /*
@@ -377,14 +410,29 @@ impl Emit {
     }
     
     fn rex_emit(&mut self, width: i32, modrm_reg: Reg, index_reg: Reg, rm_base_opcode_reg: Reg) {
+        let low_byte = ((width) & 1) != 0 && ((modrm_reg.value() & 4) != 0 || (rm_base_opcode_reg.value() & 4) != 0);
+        self.rex_emit_low_byte((width), (modrm_reg), (index_reg), (rm_base_opcode_reg), low_byte);
+    }
+    
+    /*
+     * Emit the Rex prefix for an instruction with a memory operand.
+     * The base and index registers are never byte registers, so only a low
+     * byte register in the modrm reg field needs the Rex prefix 0x40.
+     */
+    fn rex_emit_mem(&mut self, width: i32, modrm_reg: Reg, index_reg: Reg, basereg: Reg) {
+        let low_byte = ((width) & 1) != 0 && (modrm_reg.value() & 4) != 0;
+        self.rex_emit_low_byte((width), (modrm_reg), (index_reg), (basereg), low_byte);
+    }
+    
+    fn rex_emit_low_byte(&mut self, width: i32, modrm_reg: Reg, index_reg: Reg, rm_base_opcode_reg: Reg, low_byte: bool) {
         let __rex_bits =
             if ((width) & 8) != 0 { X86_64_REX_W } else { 0 } |
             if ((modrm_reg.value()) & 8) != 0 { X86_64_REX_R } else { 0 } |
             if ((index_reg.value()) & 8) != 0 { X86_64_REX_X } else { 0 } |
-            if ((rm_base_opcode_reg.value()) & 8) != 0 { X86_64_REX_B } else { 0 };
+            if (rm_base_opcode_reg) != Reg::NOBASEREG && ((rm_base_opcode_reg.value()) & 8) != 0 { X86_64_REX_B } else { 0 };
         if((__rex_bits != 0)) {
              self.inst.push(Self::rex(__rex_bits));
-        } else if(((width) & 1) != 0 && ((modrm_reg.value() & 4) != 0 || (rm_base_opcode_reg.value() & 4) != 0)) {
+        } else if(low_byte) {
              self.inst.push(Self::rex(0));
         }
     }
@@ -417,13 +465,14 @@ impl Emit {
         
         if((basereg) == X86_64_RIP) {
             self.address_byte(0, ((r) & 0x7), 5);
+            self.rip_disp = self.inst.len();
             self.imm_emit32((disp));
             return;
         }
         
         let r = r & 0x7;
 
-        if ((basereg) == X86_64_RSP) {
+        if ((basereg & 0x7) == X86_64_RSP) {
             if ((disp) == 0) {
                 self.address_byte (0, (r), X86_64_RSP);
                 self.address_byte (0, X86_64_RSP, X86_64_RSP);
@@ -439,7 +488,7 @@ impl Emit {
             return;
         }
         
-        if ((disp) == 0 && (basereg) != X86_64_RBP) {
+        if ((disp) == 0 && (basereg & 0x7) != X86_64_RBP) {
             self.address_byte (0, (r), (basereg));
             return;
         }
@@ -455,14 +504,18 @@ impl Emit {
     
     fn memindex_emit(&mut self, r: u8, basereg: Reg, disp: i32, indexreg: Reg, shift: u8) {
         let r = r & 0x7;
-        let basereg = basereg.value() & 0x7;
         let indexreg = indexreg.value() & 0x7;
         
-        if ((basereg) == X86_64_NOBASEREG) {
+        if ((basereg.value()) == X86_64_NOBASEREG) {
             self.address_byte (0, (r), 4);
             self.address_byte ((shift), (indexreg), 5);
             self.imm_emit32 ((disp));
-        } else if ((disp) == 0 && (basereg) != X86_64_RBP) {
+            return;
+        }
+        
+        let basereg = basereg.value() & 0x7;
+        
+        if ((disp) == 0 && (basereg) != X86_64_RBP) {
             self.address_byte (0, (r), 4);
             self.address_byte ((shift), (indexreg), (basereg));
         } else if (Self::is_imm8((disp))) {
@@ -536,7 +589,7 @@ impl Emit {
     pub fn alu_regp_reg_size(&mut self, opc: AluOp, dregp: Reg, sreg: Reg, size: i32) {
         match size {
             1 => {
-                self.rex_emit(size, (sreg), Reg::NONE, (dregp));
+                self.rex_emit_mem(size, (sreg), Reg::NONE, (dregp));
                 self.inst.push((((opc.value())) << 3));
                 self.regp_emit((sreg.value()), (dregp));
             }
@@ -544,7 +597,7 @@ impl Emit {
                 if size == 2 {
                     self.inst.push(0x66);
                 }
-                self.rex_emit(size, (sreg), Reg::NONE, (dregp));
+                self.rex_emit_mem(size, (sreg), Reg::NONE, (dregp));
                 self.inst.push((((opc.value())) << 3) + 1);
                 self.regp_emit((sreg.value()), (dregp));
             }
@@ -555,7 +608,7 @@ impl Emit {
     pub fn alu_mem_reg_size(&mut self, opc: AluOp, mem: i32, sreg: Reg, size: i32) {
         match size {
             1 => {
-                self.rex_emit(size, (sreg), Reg::NONE, Reg::NONE);
+                self.rex_emit_mem(size, (sreg), Reg::NONE, Reg::NONE);
                 self.inst.push((((opc.value())) << 3));
                 self.mem_emit((sreg.value()), (mem));
             }
@@ -563,7 +616,7 @@ impl Emit {
                 if size == 2 {
                     self.inst.push(0x66);
                 }
-                self.rex_emit(size, (sreg), Reg::NONE, Reg::NONE);
+                self.rex_emit_mem(size, (sreg), Reg::NONE, Reg::NONE);
                 self.inst.push((((opc.value())) << 3) + 1);
                 self.mem_emit((sreg.value()), (mem));
             }
@@ -574,7 +627,7 @@ impl Emit {
     pub fn alu_membase_reg_size(&mut self, opc: AluOp, basereg: Reg, disp: i32, sreg: Reg, size: i32) {
         match size {
             1 => {
-                self.rex_emit(size, (sreg), Reg::NONE, (basereg));
+                self.rex_emit_mem(size, (sreg), Reg::NONE, (basereg));
                 self.inst.push((((opc.value())) << 3));
                 self.membase_emit((sreg.value()), (basereg), (disp));
             }
@@ -582,7 +635,7 @@ impl Emit {
                 if size == 2 {
                     self.inst.push(0x66);
                 }
-                self.rex_emit(size, (sreg), Reg::NONE, (basereg));
+                self.rex_emit_mem(size, (sreg), Reg::NONE, (basereg));
                 self.inst.push((((opc.value())) << 3) + 1);
                 self.membase_emit((sreg.value()), (basereg), (disp));
             }
@@ -593,7 +646,7 @@ impl Emit {
     pub fn alu_memindex_reg_size(&mut self, opc: AluOp, basereg: Reg, disp: i32, indexreg: Reg, shift: u8, sreg: Reg, size: i32) {
         match size {
             1 => {
-                self.rex_emit(size, (sreg), (indexreg), (basereg));
+                self.rex_emit_mem(size, (sreg), (indexreg), (basereg));
                 self.inst.push((((opc.value())) << 3));
                 self.memindex_emit((sreg.value()), (basereg), (disp), (indexreg), (shift));
             }
@@ -601,7 +654,7 @@ impl Emit {
                 if size == 2 {
                     self.inst.push(0x66);
                 }
-                self.rex_emit(size, (sreg), (indexreg), (basereg));
+                self.rex_emit_mem(size, (sreg), (indexreg), (basereg));
                 self.inst.push((((opc.value())) << 3) + 1);
                 self.memindex_emit((sreg.value()), (basereg), (disp), (indexreg), (shift));
             }
@@ -612,7 +665,7 @@ impl Emit {
     pub fn alu_reg_regp_size(&mut self, opc: AluOp, dreg: Reg, sregp: Reg, size: i32) {
         match size {
             1 => {
-                self.rex_emit(size, (dreg), Reg::NONE, (sregp));
+                self.rex_emit_mem(size, (dreg), Reg::NONE, (sregp));
                 self.inst.push((((opc.value())) << 3) + 2);
                 self.regp_emit((dreg.value()), (sregp));
             }
@@ -620,7 +673,7 @@ impl Emit {
                 if size == 2 {
                     self.inst.push(0x66);
                 }
-                self.rex_emit(size, (dreg), Reg::NONE, (sregp));
+                self.rex_emit_mem(size, (dreg), Reg::NONE, (sregp));
                 self.inst.push((((opc.value())) << 3) + 3);
                 self.regp_emit((dreg.value()), (sregp));
             }
@@ -631,7 +684,7 @@ impl Emit {
     pub fn alu_reg_mem_size(&mut self, opc: AluOp, dreg: Reg, mem: i32, size: i32) {
         match size {
             1 => {
-                self.rex_emit(size, (dreg), Reg::NONE, Reg::NONE);
+                self.rex_emit_mem(size, (dreg), Reg::NONE, Reg::NONE);
                 self.inst.push((((opc.value())) << 3) + 2);
                 self.mem_emit((dreg.value()), (mem));
             }
@@ -639,7 +692,7 @@ impl Emit {
                 if size == 2 {
                     self.inst.push(0x66);
                 }
-                self.rex_emit(size, (dreg), Reg::NONE, Reg::NONE);
+                self.rex_emit_mem(size, (dreg), Reg::NONE, Reg::NONE);
                 self.inst.push((((opc.value())) << 3) + 3);
                 self.mem_emit((dreg.value()), (mem));
             }
@@ -650,7 +703,7 @@ impl Emit {
     pub fn alu_reg_membase_size(&mut self, opc: AluOp, dreg: Reg, basereg: Reg, disp: i32, size: i32) {
         match size {
             1 => {
-                self.rex_emit(size, (dreg), Reg::NONE, (basereg));
+                self.rex_emit_mem(size, (dreg), Reg::NONE, (basereg));
                 self.inst.push((((opc.value())) << 3) + 2);
                 self.membase_emit((dreg.value()), (basereg), (disp));
             }
@@ -658,7 +711,7 @@ impl Emit {
                 if size == 2 {
                     self.inst.push(0x66);
                 }
-                self.rex_emit(size, (dreg), Reg::NONE, (basereg));
+                self.rex_emit_mem(size, (dreg), Reg::NONE, (basereg));
                 self.inst.push((((opc.value())) << 3) + 3);
                 self.membase_emit((dreg.value()), (basereg), (disp));
             }
@@ -669,7 +722,7 @@ impl Emit {
     pub fn alu_reg_memindex_size(&mut self, opc: AluOp, dreg: Reg, basereg: Reg, disp: i32, indexreg: Reg, shift: u8, size: i32) {
         match size {
             1 => {
-                self.rex_emit(size, (dreg), (indexreg), (basereg));
+                self.rex_emit_mem(size, (dreg), (indexreg), (basereg));
                 self.inst.push((((opc.value())) << 3) + 2);
                 self.memindex_emit((dreg.value()), (basereg), (disp), (indexreg), (shift));
             }
@@ -677,7 +730,7 @@ impl Emit {
                 if size == 2 {
                     self.inst.push(0x66);
                 }
-                self.rex_emit(size, (dreg), (indexreg), (basereg));
+                self.rex_emit_mem(size, (dreg), (indexreg), (basereg));
                 self.inst.push((((opc.value())) << 3) + 3);
                 self.memindex_emit((dreg.value()), (basereg), (disp), (indexreg), (shift));
             }
@@ -689,7 +742,7 @@ impl Emit {
      * The immediate value has to be at most 32 bit wide.
      */
     pub fn alu_reg_imm_size(&mut self, opc: AluOp, dreg: Reg, imm: i32, size: i32) {
-        if((dreg) == Reg::RAX) {
+        if((dreg) == Reg::RAX && ((size) == 1 || !Self::is_imm8((imm)))) {
             match size {
                 1 => {
                     self.inst.push((((opc.value())) << 3) + 4);
@@ -755,14 +808,14 @@ impl Emit {
         if(Self::is_imm8((imm))) {
             match size {
                 1 => {
-                    self.rex_emit(size, Reg::NONE, Reg::NONE, (reg));
+                    self.rex_emit_mem(size, Reg::NONE, Reg::NONE, (reg));
                     self.inst.push(0x80);
                 }
                 2 | 4 | 8 => {
                     if size == 2 {
                         self.inst.push(0x66);
                     }
-                    self.rex_emit(size, Reg::NONE, Reg::NONE, (reg));
+                    self.rex_emit_mem(size, Reg::NONE, Reg::NONE, (reg));
                     self.inst.push(0x83);
                 }
                 _ => {}
@@ -772,7 +825,7 @@ impl Emit {
         } else {
             match size {
                 1 => {
-                    self.rex_emit(size, Reg::NONE, Reg::NONE, (reg));
+                    self.rex_emit_mem(size, Reg::NONE, Reg::NONE, (reg));
                     self.inst.push(0x80);
                     self.regp_emit((opc.value()), (reg));
                     self.imm_emit8((imm));
@@ -780,13 +833,13 @@ impl Emit {
                 }
                 2 => {
                     self.inst.push(0x66);
-                    self.rex_emit(size, Reg::NONE, Reg::NONE, (reg));
+                    self.rex_emit_mem(size, Reg::NONE, Reg::NONE, (reg));
                     self.inst.push(0x81);
                     self.regp_emit((opc.value()), (reg));
                     self.imm_emit16((imm));
                 }
                 4 | 8 => {
-                    self.rex_emit(size, Reg::NONE, Reg::NONE, (reg));
+                    self.rex_emit_mem(size, Reg::NONE, Reg::NONE, (reg));
                     self.inst.push(0x81);
                     self.regp_emit((opc.value()), (reg));
                     self.imm_emit32((imm));
@@ -800,14 +853,14 @@ impl Emit {
         if (Self::is_imm8((imm))) {
             match size {
                 1 => {
-                    self.rex_emit((size), Reg::NONE, Reg::NONE, Reg::NONE);
+                    self.rex_emit_mem((size), Reg::NONE, Reg::NONE, Reg::NONE);
                     self.inst.push(0x80);
                 }
                 2 | 4 | 8 => {
                     if size == 2 {
                         self.inst.push(0x66);
                     }
-                    self.rex_emit((size), Reg::NONE, Reg::NONE, Reg::NONE);
+                    self.rex_emit_mem((size), Reg::NONE, Reg::NONE, Reg::NONE);
                     self.inst.push(0x83);
                 }
                 _ => {}
@@ -817,7 +870,7 @@ impl Emit {
         } else {
             match size {
                 1 => {
-                    self.rex_emit((size), Reg::NONE, Reg::NONE, Reg::NONE);
+                    self.rex_emit_mem((size), Reg::NONE, Reg::NONE, Reg::NONE);
                     self.inst.push(0x80);
                     self.mem_emit((opc.value()), (mem));
                     self.imm_emit8((imm));
@@ -825,13 +878,13 @@ impl Emit {
                 }
                 2 => {
                     self.inst.push(0x66);
-                    self.rex_emit((size), Reg::NONE, Reg::NONE, Reg::NONE);
+                    self.rex_emit_mem((size), Reg::NONE, Reg::NONE, Reg::NONE);
                     self.inst.push(0x81);
                     self.mem_emit((opc.value()), (mem));
                     self.imm_emit16((imm));
                 }
                 4 | 8 => {
-                    self.rex_emit((size), Reg::NONE, Reg::NONE, Reg::NONE);
+                    self.rex_emit_mem((size), Reg::NONE, Reg::NONE, Reg::NONE);
                     self.inst.push(0x81);
                     self.mem_emit((opc.value()), (mem));
                     self.imm_emit32((imm));
@@ -845,14 +898,14 @@ impl Emit {
         if (Self::is_imm8((imm))) {
             match size {
                 1 => {
-                    self.rex_emit((size), Reg::NONE, Reg::NONE, (basereg));
+                    self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (basereg));
                     self.inst.push(0x80);
                 }
                 2 | 4 | 8 => {
                     if size == 2 {
                         self.inst.push(0x66);
                     }
-                    self.rex_emit((size), Reg::NONE, Reg::NONE, (basereg));
+                    self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (basereg));
                     self.inst.push(0x83);
                 }
                 _ => {}
@@ -862,7 +915,7 @@ impl Emit {
         } else {
             match size {
                 1 => {
-                    self.rex_emit((size), Reg::NONE, Reg::NONE, (basereg));
+                    self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (basereg));
                     self.inst.push(0x80);
                     self.membase_emit((opc.value()), (basereg), (disp));
                     self.imm_emit8((imm));
@@ -870,13 +923,13 @@ impl Emit {
                 }
                 2 => {
                     self.inst.push(0x66);
-                    self.rex_emit((size), Reg::NONE, Reg::NONE, (basereg));
+                    self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (basereg));
                     self.inst.push(0x81);
                     self.membase_emit((opc.value()), (basereg), (disp));
                     self.imm_emit16((imm));
                 }
                 4 | 8 => {
-                    self.rex_emit((size), Reg::NONE, Reg::NONE, (basereg));
+                    self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (basereg));
                     self.inst.push(0x81);
                     self.membase_emit((opc.value()), (basereg), (disp));
                     self.imm_emit32((imm));
@@ -890,14 +943,14 @@ impl Emit {
         if (Self::is_imm8((imm))) {
             match size {
                 1 => {
-                    self.rex_emit((size), Reg::NONE, (indexreg), (basereg));
+                    self.rex_emit_mem((size), Reg::NONE, (indexreg), (basereg));
                     self.inst.push(0x80);
                 }
                 2 | 4 | 8 => {
                     if size == 2 {
                         self.inst.push(0x66);
                     }
-                    self.rex_emit((size), Reg::NONE, (indexreg), (basereg));
+                    self.rex_emit_mem((size), Reg::NONE, (indexreg), (basereg));
                     self.inst.push(0x83);
                 }
                 _ => {}
@@ -907,7 +960,7 @@ impl Emit {
         } else {
             match size {
                 1 => {
-                    self.rex_emit((size), Reg::NONE, (indexreg), (basereg));
+                    self.rex_emit_mem((size), Reg::NONE, (indexreg), (basereg));
                     self.inst.push(0x80);
                     self.memindex_emit((opc.value()), (basereg), (disp), (indexreg), (shift));
                     self.imm_emit8((imm));
@@ -915,13 +968,13 @@ impl Emit {
                 }
                 2 => {
                     self.inst.push(0x66);
-                    self.rex_emit((size), Reg::NONE, (indexreg), (basereg));
+                    self.rex_emit_mem((size), Reg::NONE, (indexreg), (basereg));
                     self.inst.push(0x81);
                     self.memindex_emit((opc.value()), (basereg), (disp), (indexreg), (shift));
                     self.imm_emit16((imm));
                 }
                 4 | 8 => {
-                    self.rex_emit((size), Reg::NONE, (indexreg), (basereg));
+                    self.rex_emit_mem((size), Reg::NONE, (indexreg), (basereg));
                     self.inst.push(0x81);
                     self.memindex_emit((opc.value()), (basereg), (disp), (indexreg), (shift));
                     self.imm_emit32((imm));
@@ -945,7 +998,7 @@ impl Emit {
     }
     
     pub fn alu1_regp(&mut self, opc1: u8, r: u8, regp: Reg) {
-        self.rex_emit(0, Reg::NONE, Reg::NONE, (regp));
+        self.rex_emit_mem(0, Reg::NONE, Reg::NONE, (regp));
         self.inst.push((opc1));
         self.regp_emit((r), (regp));
     }
@@ -956,13 +1009,13 @@ impl Emit {
     }
     
     pub fn alu1_membase(&mut self, opc1: u8, r: u8, basereg: Reg, disp: i32) {
-        self.rex_emit(0, Reg::NONE, Reg::NONE, (basereg));
+        self.rex_emit_mem(0, Reg::NONE, Reg::NONE, (basereg));
         self.inst.push((opc1));
         self.membase_emit((r), (basereg), (disp));
     }
     
     pub fn alu1_memindex(&mut self, opc1: u8, r: u8, basereg: Reg, disp: i32, indexreg: Reg, shift: u8) {
-        self.rex_emit(0, Reg::NONE, (indexreg), (basereg));
+        self.rex_emit_mem(0, Reg::NONE, (indexreg), (basereg));
         self.inst.push((opc1));
         self.memindex_emit((r), (basereg), (disp), (indexreg), (shift));
     }
@@ -980,7 +1033,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, Reg::NONE, (regp));
+        self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (regp));
         self.opcode1_emit((opc1), (size));
         self.regp_emit((r), (regp));
     }
@@ -989,7 +1042,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, Reg::NONE, Reg::NONE);
+        self.rex_emit_mem((size), Reg::NONE, Reg::NONE, Reg::NONE);
         self.opcode1_emit((opc1), (size));
         self.mem_emit((r), (mem));
     }
@@ -998,7 +1051,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, Reg::NONE, (basereg));
+        self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (basereg));
         self.opcode1_emit((opc1), (size));
         self.membase_emit((r), (basereg), (disp));
     }
@@ -1007,7 +1060,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, (indexreg), (basereg));
+        self.rex_emit_mem((size), Reg::NONE, (indexreg), (basereg));
         self.opcode1_emit((opc1), (size));
         self.memindex_emit((r), (basereg), (disp), (indexreg), (shift));
     }
@@ -1025,7 +1078,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, (sregp));
+        self.rex_emit_mem((size), (dreg), Reg::NONE, (sregp));
         self.inst.push((opc1));
         self.regp_emit((dreg.value()), (sregp));
     }
@@ -1034,7 +1087,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, Reg::NONE);
+        self.rex_emit_mem((size), (dreg), Reg::NONE, Reg::NONE);
         self.inst.push((opc1));
         self.mem_emit((dreg.value()), (mem));
     }
@@ -1043,7 +1096,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, (basereg));
+        self.rex_emit_mem((size), (dreg), Reg::NONE, (basereg));
         self.inst.push((opc1));
         self.membase_emit((dreg.value()), (basereg), (disp));
     }
@@ -1052,7 +1105,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), (indexreg), (basereg));
+        self.rex_emit_mem((size), (dreg), (indexreg), (basereg));
         self.inst.push((opc1));
         self.memindex_emit((dreg.value()), (basereg), (disp), (indexreg), (shift));
     }
@@ -1071,7 +1124,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, (sregp));
+        self.rex_emit_mem((size), (dreg), Reg::NONE, (sregp));
         self.inst.push((opc1));
         self.inst.push((opc2));
         self.regp_emit((dreg.value()), (sregp));
@@ -1081,7 +1134,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, Reg::NONE);
+        self.rex_emit_mem((size), (dreg), Reg::NONE, Reg::NONE);
         self.inst.push((opc1));
         self.inst.push((opc2));
         self.mem_emit((dreg.value()), (mem));
@@ -1091,7 +1144,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, (basereg));
+        self.rex_emit_mem((size), (dreg), Reg::NONE, (basereg));
         self.inst.push((opc1));
         self.inst.push((opc2));
         self.membase_emit((dreg.value()), (basereg), (disp));
@@ -1101,7 +1154,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), (indexreg), (basereg));
+        self.rex_emit_mem((size), (dreg), (indexreg), (basereg));
         self.inst.push((opc1));
         self.inst.push((opc2));
         self.memindex_emit((dreg.value()), (basereg), (disp), (indexreg), (shift));
@@ -1315,14 +1368,14 @@ impl Emit {
             if ((size) == 2) {
                 self.inst.push(0x66);
             }
-            self.rex_emit((size), Reg::NONE, Reg::NONE, Reg::NONE);
+            self.rex_emit_mem((size), Reg::NONE, Reg::NONE, Reg::NONE);
             self.opcode1_emit(0xd0, (size));
             self.mem_emit((opc.value()), (mem));
         } else {
             if ((size) == 2) {
                 self.inst.push(0x66);
             }
-            self.rex_emit((size), Reg::NONE, Reg::NONE, Reg::NONE);
+            self.rex_emit_mem((size), Reg::NONE, Reg::NONE, Reg::NONE);
             self.opcode1_emit(0xc0, (size));
             self.mem_emit((opc.value()), (mem));
             self.imm_emit8((imm));
@@ -1334,14 +1387,14 @@ impl Emit {
             if ((size) == 2) {
                 self.inst.push(0x66);
             }
-            self.rex_emit((size), Reg::NONE, Reg::NONE, (dregp));
+            self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (dregp));
             self.opcode1_emit(0xd0, (size));
             self.regp_emit((opc.value()), (dregp));
         } else {
             if ((size) == 2) {
                 self.inst.push(0x66);
             }
-            self.rex_emit((size), Reg::NONE, Reg::NONE, (dregp));
+            self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (dregp));
             self.opcode1_emit(0xc0, (size));
             self.regp_emit((opc.value()), (dregp));
             self.imm_emit8((imm));
@@ -1353,14 +1406,14 @@ impl Emit {
             if ((size) == 2) {
                 self.inst.push(0x66);
             }
-            self.rex_emit((size), Reg::NONE, Reg::NONE, (basereg));
+            self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (basereg));
             self.opcode1_emit(0xd0, (size));
             self.membase_emit((opc.value()), (basereg), (disp));
         } else {
             if ((size) == 2) {
                 self.inst.push(0x66);
             }
-            self.rex_emit((size), Reg::NONE, Reg::NONE, (basereg));
+            self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (basereg));
             self.opcode1_emit(0xc0, (size));
             self.membase_emit((opc.value()), (basereg), (disp));
             self.imm_emit8((imm));
@@ -1372,14 +1425,14 @@ impl Emit {
             if ((size) == 2) {
                 self.inst.push(0x66);
             }
-            self.rex_emit((size), Reg::NONE, (indexreg), (basereg));
+            self.rex_emit_mem((size), Reg::NONE, (indexreg), (basereg));
             self.opcode1_emit(0xd0, (size));
             self.memindex_emit((opc.value()), (basereg), (disp), (indexreg), (shift));
         } else {
             if ((size) == 2) {
                 self.inst.push(0x66);
             }
-            self.rex_emit((size), Reg::NONE, (indexreg), (basereg));
+            self.rex_emit_mem((size), Reg::NONE, (indexreg), (basereg));
             self.opcode1_emit(0xc0, (size));
             self.memindex_emit((opc.value()), (basereg), (disp), (indexreg), (shift));
             self.imm_emit8((imm));
@@ -1402,7 +1455,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, Reg::NONE, Reg::NONE);
+        self.rex_emit_mem((size), Reg::NONE, Reg::NONE, Reg::NONE);
         self.opcode1_emit(0xd2, (size));
         self.mem_emit((opc.value()), (mem));
     }
@@ -1411,7 +1464,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, Reg::NONE, (dregp));
+        self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (dregp));
         self.opcode1_emit(0xd2, (size));
         self.regp_emit((opc.value()), (dregp));
     }
@@ -1420,7 +1473,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, Reg::NONE, (basereg));
+        self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (basereg));
         self.opcode1_emit(0xd2, (size));
         self.membase_emit((opc.value()), (basereg), (disp));
     }
@@ -1429,11 +1482,119 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, (indexreg), (basereg));
+        self.rex_emit_mem((size), Reg::NONE, (indexreg), (basereg));
         self.opcode1_emit(0xd2, (size));
         self.memindex_emit((opc.value()), (basereg), (disp), (indexreg), (shift));
     }
     
+    /*
+     * shld, shrd: double precision shift, filling the vacated bits from sreg
+     */
+    pub fn double_shift_reg_reg_imm_size(&mut self, opc: DoubleShiftOp, dreg: Reg, sreg: Reg, imm: i32, size: i32) {
+        if ((size) == 2) {
+            self.inst.push(0x66);
+        }
+        self.rex_emit((size), (sreg), Reg::NONE, (dreg));
+        self.inst.push(0x0f);
+        self.inst.push(opc.value());
+        self.reg_emit((sreg.value()), (dreg));
+        self.imm_emit8((imm));
+    }
+    
+    pub fn double_shift_mem_reg_imm_size(&mut self, opc: DoubleShiftOp, mem: i32, sreg: Reg, imm: i32, size: i32) {
+        if ((size) == 2) {
+            self.inst.push(0x66);
+        }
+        self.rex_emit_mem((size), (sreg), Reg::NONE, Reg::NONE);
+        self.inst.push(0x0f);
+        self.inst.push(opc.value());
+        self.mem_emit((sreg.value()), (mem));
+        self.imm_emit8((imm));
+    }
+    
+    pub fn double_shift_regp_reg_imm_size(&mut self, opc: DoubleShiftOp, dregp: Reg, sreg: Reg, imm: i32, size: i32) {
+        if ((size) == 2) {
+            self.inst.push(0x66);
+        }
+        self.rex_emit_mem((size), (sreg), Reg::NONE, (dregp));
+        self.inst.push(0x0f);
+        self.inst.push(opc.value());
+        self.regp_emit((sreg.value()), (dregp));
+        self.imm_emit8((imm));
+    }
+    
+    pub fn double_shift_membase_reg_imm_size(&mut self, opc: DoubleShiftOp, basereg: Reg, disp: i32, sreg: Reg, imm: i32, size: i32) {
+        if ((size) == 2) {
+            self.inst.push(0x66);
+        }
+        self.rex_emit_mem((size), (sreg), Reg::NONE, (basereg));
+        self.inst.push(0x0f);
+        self.inst.push(opc.value());
+        self.membase_emit((sreg.value()), (basereg), (disp));
+        self.imm_emit8((imm));
+    }
+    
+    pub fn double_shift_memindex_reg_imm_size(&mut self, opc: DoubleShiftOp, basereg: Reg, disp: i32, indexreg: Reg, shift: u8, sreg: Reg, imm: i32, size: i32) {
+        if ((size) == 2) {
+            self.inst.push(0x66);
+        }
+        self.rex_emit_mem((size), (sreg), (indexreg), (basereg));
+        self.inst.push(0x0f);
+        self.inst.push(opc.value());
+        self.memindex_emit((sreg.value()), (basereg), (disp), (indexreg), (shift));
+        self.imm_emit8((imm));
+    }
+    
+    pub fn double_shift_reg_reg_size(&mut self, opc: DoubleShiftOp, dreg: Reg, sreg: Reg, size: i32) {
+        if ((size) == 2) {
+            self.inst.push(0x66);
+        }
+        self.rex_emit((size), (sreg), Reg::NONE, (dreg));
+        self.inst.push(0x0f);
+        self.inst.push(opc.value() + 1);
+        self.reg_emit((sreg.value()), (dreg));
+    }
+    
+    pub fn double_shift_mem_reg_size(&mut self, opc: DoubleShiftOp, mem: i32, sreg: Reg, size: i32) {
+        if ((size) == 2) {
+            self.inst.push(0x66);
+        }
+        self.rex_emit_mem((size), (sreg), Reg::NONE, Reg::NONE);
+        self.inst.push(0x0f);
+        self.inst.push(opc.value() + 1);
+        self.mem_emit((sreg.value()), (mem));
+    }
+    
+    pub fn double_shift_regp_reg_size(&mut self, opc: DoubleShiftOp, dregp: Reg, sreg: Reg, size: i32) {
+        if ((size) == 2) {
+            self.inst.push(0x66);
+        }
+        self.rex_emit_mem((size), (sreg), Reg::NONE, (dregp));
+        self.inst.push(0x0f);
+        self.inst.push(opc.value() + 1);
+        self.regp_emit((sreg.value()), (dregp));
+    }
+    
+    pub fn double_shift_membase_reg_size(&mut self, opc: DoubleShiftOp, basereg: Reg, disp: i32, sreg: Reg, size: i32) {
+        if ((size) == 2) {
+            self.inst.push(0x66);
+        }
+        self.rex_emit_mem((size), (sreg), Reg::NONE, (basereg));
+        self.inst.push(0x0f);
+        self.inst.push(opc.value() + 1);
+        self.membase_emit((sreg.value()), (basereg), (disp));
+    }
+    
+    pub fn double_shift_memindex_reg_size(&mut self, opc: DoubleShiftOp, basereg: Reg, disp: i32, indexreg: Reg, shift: u8, sreg: Reg, size: i32) {
+        if ((size) == 2) {
+            self.inst.push(0x66);
+        }
+        self.rex_emit_mem((size), (sreg), (indexreg), (basereg));
+        self.inst.push(0x0f);
+        self.inst.push(opc.value() + 1);
+        self.memindex_emit((sreg.value()), (basereg), (disp), (indexreg), (shift));
+    }
+    
     /*
      * test: and tha values and set sf, zf and pf according to the result
      */
@@ -1455,7 +1616,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, Reg::NONE, (regp));
+        self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (regp));
         self.opcode1_emit(0xf6, (size));
         self.regp_emit(0, (regp));
         self.imm_emit_max32((imm), (size));
@@ -1465,7 +1626,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, Reg::NONE, Reg::NONE);
+        self.rex_emit_mem((size), Reg::NONE, Reg::NONE, Reg::NONE);
         self.opcode1_emit(0xf6, (size));
         self.mem_emit(0, (mem));
         self.imm_emit_max32((imm), (size));
@@ -1475,7 +1636,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, Reg::NONE, (basereg));
+        self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (basereg));
         self.opcode1_emit(0xf6, (size));
         self.membase_emit(0, (basereg), (disp));
         self.imm_emit_max32((imm), (size));
@@ -1485,7 +1646,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, (indexreg), (basereg));
+        self.rex_emit_mem((size), Reg::NONE, (indexreg), (basereg));
         self.opcode1_emit(0xf6, (size));
         self.memindex_emit(0, (basereg), (disp), (indexreg), (shift));
         self.imm_emit_max32((imm), (size));
@@ -1504,7 +1665,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (sreg), Reg::NONE, (dregp));
+        self.rex_emit_mem((size), (sreg), Reg::NONE, (dregp));
         self.opcode1_emit(0x84, (size));
         self.regp_emit((sreg.value()), (dregp));
     }
@@ -1513,7 +1674,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (sreg), Reg::NONE, Reg::NONE);
+        self.rex_emit_mem((size), (sreg), Reg::NONE, Reg::NONE);
         self.opcode1_emit(0x84, (size));
         self.mem_emit((sreg.value()), (mem));
     }
@@ -1522,7 +1683,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (sreg), Reg::NONE, (basereg));
+        self.rex_emit_mem((size), (sreg), Reg::NONE, (basereg));
         self.opcode1_emit(0x84, (size));
         self.membase_emit((sreg.value()), (basereg), (disp));
     }
@@ -1531,7 +1692,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (sreg), (indexreg), (basereg));
+        self.rex_emit_mem((size), (sreg), (indexreg), (basereg));
         self.opcode1_emit(0x84, (size));
         self.memindex_emit((sreg.value()), (basereg), (disp), (indexreg), (shift));
     }
@@ -1567,7 +1728,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, (sregp));
+        self.rex_emit_mem((size), (dreg), Reg::NONE, (sregp));
         if (Self::is_imm8((imm))) {
             self.inst.push(0x6b);
             self.regp_emit((dreg.value()), (sregp));
@@ -1591,7 +1752,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, Reg::NONE);
+        self.rex_emit_mem((size), (dreg), Reg::NONE, Reg::NONE);
         if (Self::is_imm8((imm))) {
             self.inst.push(0x6b);
             self.mem_emit((dreg.value()), (mem));
@@ -1615,7 +1776,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, (basereg));
+        self.rex_emit_mem((size), (dreg), Reg::NONE, (basereg));
         if (Self::is_imm8((imm))) {
             self.inst.push(0x6b);
             self.membase_emit((dreg.value()), (basereg), (disp));
@@ -1639,7 +1800,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), (indexreg), (basereg));
+        self.rex_emit_mem((size), (dreg), (indexreg), (basereg));
         if (Self::is_imm8((imm))) {
             self.inst.push(0x6b);
             self.memindex_emit((dreg.value()), (basereg), (disp), (indexreg), (shift));
@@ -1673,7 +1834,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, (sregp));
+        self.rex_emit_mem((size), (dreg), Reg::NONE, (sregp));
         self.inst.push(0x0F);
         self.inst.push(0xAF);
         self.regp_emit((dreg.value()), (sregp));
@@ -1683,7 +1844,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, Reg::NONE);
+        self.rex_emit_mem((size), (dreg), Reg::NONE, Reg::NONE);
         self.inst.push(0x0F);
         self.inst.push(0xAF);
         self.mem_emit((dreg.value()), (mem));
@@ -1693,7 +1854,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, (basereg));
+        self.rex_emit_mem((size), (dreg), Reg::NONE, (basereg));
         self.inst.push(0x0F);
         self.inst.push(0xAF);
         self.membase_emit((dreg.value()), (basereg), (disp));
@@ -1703,7 +1864,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), (indexreg), (basereg));
+        self.rex_emit_mem((size), (dreg), (indexreg), (basereg));
         self.inst.push(0x0F);
         self.inst.push(0xAF);
         self.memindex_emit((dreg.value()), (basereg), (disp), (indexreg), (shift));
@@ -1738,7 +1899,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, Reg::NONE, (dreg));
+        self.rex_emit_mem((size), (dreg), Reg::NONE, Reg::NONE);
         self.lea_mem((dreg /* & 0x7 */), (mem));
     }
     
@@ -1746,7 +1907,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, (basereg));
+        self.rex_emit_mem((size), (dreg), Reg::NONE, (basereg));
         self.inst.push(0x8d);
         self.membase_emit((dreg.value()), (basereg), (disp));
     }
@@ -1755,7 +1916,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), (indexreg), (basereg));
+        self.rex_emit_mem((size), (dreg), (indexreg), (basereg));
         self.inst.push(0x8d);
         self.memindex_emit ((dreg.value()), (basereg), (disp), (indexreg), (shift));
     }
@@ -1776,7 +1937,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (sreg), Reg::NONE, (regp));
+        self.rex_emit_mem((size), (sreg), Reg::NONE, (regp));
         self.opcode1_emit(0x88, (size));
         self.regp_emit((sreg.value()), (regp));
     }
@@ -1785,7 +1946,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (sreg), Reg::NONE, (basereg));
+        self.rex_emit_mem((size), (sreg), Reg::NONE, (basereg));
         self.opcode1_emit(0x88, (size));
         self.membase_emit((sreg.value()), (basereg), (disp));
     }
@@ -1794,7 +1955,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (sreg), (indexreg), (basereg));
+        self.rex_emit_mem((size), (sreg), (indexreg), (basereg));
         self.opcode1_emit(0x88, (size));
         self.memindex_emit((sreg.value()), (basereg), (disp), (indexreg), (shift));
     }
@@ -1807,7 +1968,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (sreg), Reg::NONE, Reg::NONE);
+        self.rex_emit_mem((size), (sreg), Reg::NONE, Reg::NONE);
         if ((sreg) == Reg::RAX) {
             self.opcode1_emit(0xa2, (size));
             self.imm_emit64((mem));
@@ -1860,7 +2021,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, Reg::NONE);
+        self.rex_emit_mem((size), (dreg), Reg::NONE, Reg::NONE);
         if ((dreg) == Reg::RAX) {
             self.opcode1_emit(0xa0, (size));
             self.imm_emit64((mem));
@@ -1876,7 +2037,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, (sregp));
+        self.rex_emit_mem((size), (dreg), Reg::NONE, (sregp));
         self.opcode1_emit(0x8a, (size));
         self.regp_emit((dreg.value()), (sregp));
     }
@@ -1885,7 +2046,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, (basereg));
+        self.rex_emit_mem((size), (dreg), Reg::NONE, (basereg));
         self.opcode1_emit(0x8a, (size));
         self.membase_emit((dreg.value()), (basereg), (disp));
     }
@@ -1895,7 +2056,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), (indexreg), (basereg));
+        self.rex_emit_mem((size), (dreg), (indexreg), (basereg));
         self.opcode1_emit(0x8a, (size));
         self.memindex_emit((dreg.value()), (basereg), (disp), (indexreg), (shift));
     }
@@ -1909,7 +2070,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, Reg::NONE, Reg::NONE);
+        self.rex_emit_mem((size), Reg::NONE, Reg::NONE, Reg::NONE);
         self.opcode1_emit(0xc6, (size));
         self.mem_emit(0, (mem));
         self.imm_emit_max32((imm), (size));
@@ -1919,7 +2080,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, Reg::NONE, (dregp));
+        self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (dregp));
         self.opcode1_emit(0xc6, (size));
         self.regp_emit(0, (dregp));
         self.imm_emit_max32((imm), (size));
@@ -1929,7 +2090,7 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, Reg::NONE, (basereg));
+        self.rex_emit_mem((size), Reg::NONE, Reg::NONE, (basereg));
         self.opcode1_emit(0xc6, (size));
         self.membase_emit(0, (basereg), (disp));
         self.imm_emit_max32((imm), (size));
@@ -1939,17 +2100,31 @@ impl Emit {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), Reg::NONE, (indexreg), (basereg));
+        self.rex_emit_mem((size), Reg::NONE, (indexreg), (basereg));
         self.opcode1_emit(0xc6, (size));
         self.memindex_emit(0, (basereg), (disp), (indexreg), (shift));
         self.imm_emit_max32((imm), (size));
     }
     
+    /*
+     * Only the source register of movsx8 and movzx8 is a byte register,
+     * so only SPL, BPL, SIL and DIL as source need the Rex prefix 0x40.
+     */
+    fn movx8_reg_reg_size(&mut self, opc: u8, dreg: Reg, sreg: Reg, size: i32) {
+        if ((size) == 2) {
+            self.inst.push(0x66);
+        }
+        self.rex_emit_low_byte((size), (dreg), Reg::NONE, (sreg), (sreg.value() & 4) != 0);
+        self.inst.push(0x0f);
+        self.inst.push((opc));
+        self.reg_emit((dreg.value()), (sreg));
+    }
+    
     /*
      * Move with sign extension to the given size (signed)
      */
     pub fn movsx8_reg_reg_size(&mut self, dreg: Reg, sreg: Reg, size: i32) {
-        self.alu2_reg_reg_size(0x0f, 0xbe, (dreg), (sreg), (size) | 1);
+        self.movx8_reg_reg_size(0xbe, (dreg), (sreg), (size));
     }
     
     pub fn movsx8_reg_regp_size(&mut self, dreg: Reg, sregp: Reg, size: i32) {
@@ -2012,7 +2187,7 @@ impl Emit {
      * Move with zero extension to the given size (unsigned)
      */
     pub fn movzx8_reg_reg_size(&mut self, dreg: Reg, sreg: Reg, size: i32) {
-        self.alu2_reg_reg_size(0x0f, 0xb6, (dreg), (sreg), (size) | 1);
+        self.movx8_reg_reg_size(0xb6, (dreg), (sreg), (size));
     }
     
     pub fn movzx8_reg_regp_size(&mut self, dreg: Reg, sregp: Reg, size: i32) {
@@ -2054,73 +2229,53 @@ impl Emit {
     /*
      * cmov: conditional move
      */
-    pub fn cmov_reg_reg_size(&mut self, cond: i32, dreg: Reg, sreg: Reg, is_signed: bool, size: i32) {
+    pub fn cmov_reg_reg_size(&mut self, cond: Cond, dreg: Reg, sreg: Reg, size: i32) {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
         self.rex_emit((size), (dreg), Reg::NONE, (sreg));
         self.inst.push(0x0f);
-        if ((is_signed)) {
-            self.inst.push(x86_cc_signed_map[(cond) as usize] - 0x30);
-        } else {
-            self.inst.push(x86_cc_unsigned_map[(cond) as usize] - 0x30);
-        }
+        self.inst.push(0x40 | cond.code());
         self.reg_emit((dreg.value()), (sreg));
     }
     
-    pub fn cmov_reg_regp_size(&mut self, cond: i32, dreg: Reg, sregp: Reg, is_signed: bool, size: i32) {
+    pub fn cmov_reg_regp_size(&mut self, cond: Cond, dreg: Reg, sregp: Reg, size: i32) {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, (sregp));
+        self.rex_emit_mem((size), (dreg), Reg::NONE, (sregp));
         self.inst.push(0x0f);
-        if ((is_signed)) {
-            self.inst.push(x86_cc_signed_map[(cond) as usize] - 0x30);
-        } else {
-            self.inst.push(x86_cc_unsigned_map[(cond) as usize] - 0x30);
-        }
+        self.inst.push(0x40 | cond.code());
         self.regp_emit((dreg.value()), (sregp));
     }
     
-    pub fn cmov_reg_mem_size(&mut self, cond: i32, dreg: Reg, mem: i32, is_signed: bool, size: i32) {
+    pub fn cmov_reg_mem_size(&mut self, cond: Cond, dreg: Reg, mem: i32, size: i32) {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, Reg::NONE);
+        self.rex_emit_mem((size), (dreg), Reg::NONE, Reg::NONE);
         self.inst.push(0x0f);
-        if ((is_signed)) {
-            self.inst.push(x86_cc_signed_map[(cond) as usize] - 0x30);
-        } else {
-            self.inst.push(x86_cc_unsigned_map[(cond) as usize] - 0x30);
-        }
+        self.inst.push(0x40 | cond.code());
         self.mem_emit((dreg.value()), (mem));
     }
     
-    pub fn cmov_reg_membase_size(&mut self, cond: i32, dreg: Reg, basereg: Reg, disp: i32, is_signed: bool, size: i32) {
+    pub fn cmov_reg_membase_size(&mut self, cond: Cond, dreg: Reg, basereg: Reg, disp: i32, size: i32) {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), Reg::NONE, (basereg));
+        self.rex_emit_mem((size), (dreg), Reg::NONE, (basereg));
         self.inst.push(0x0f);
-        if ((is_signed)) {
-            self.inst.push(x86_cc_signed_map[(cond) as usize] - 0x30);
-        } else {
-            self.inst.push(x86_cc_unsigned_map[(cond) as usize] - 0x30);
-        }
+        self.inst.push(0x40 | cond.code());
         self.membase_emit((dreg.value()), (basereg), (disp));
     }
     
-    pub fn cmov_reg_memindex_size(&mut self, cond: i32, dreg: Reg, basereg: Reg, disp: i32, indexreg: Reg, shift: u8, is_signed: bool, size: i32) {
+    pub fn cmov_reg_memindex_size(&mut self, cond: Cond, dreg: Reg, basereg: Reg, disp: i32, indexreg: Reg, shift: u8, size: i32) {
         if ((size) == 2) {
             self.inst.push(0x66);
         }
-        self.rex_emit((size), (dreg), (indexreg), (basereg));
+        self.rex_emit_mem((size), (dreg), (indexreg), (basereg));
         self.inst.push(0x0f);
-        if ((is_signed)) {
-            self.inst.push(x86_cc_signed_map[(cond) as usize] - 0x30);
-        } else {
-            self.inst.push(x86_cc_unsigned_map[(cond) as usize] - 0x30);
-        }
+        self.inst.push(0x40 | cond.code());
         self.memindex_emit((dreg.value()), (basereg), (disp), (indexreg), (shift));
     }
     
@@ -2284,6 +2439,28 @@ impl Emit {
         self.imm_emit32 ((disp));
     }
     
+    /*
+     * call rel32 to target, which is resolved when the code is built.
+     */
+    pub(super) fn call_target(&mut self, target: FixupTarget) {
+        self.inst.push(0xe8);
+        self.rip_disp = self.inst.len();
+        self.imm_emit32(0);
+        self.rip_fixup(RelocKind::Call32, target);
+    }
+    
+    /*
+     * mov reg, imm64 with the address of target, which is filled in when
+     * the code is installed.
+     */
+    pub(super) fn mov_reg_target(&mut self, dreg: Reg, target: FixupTarget) {
+        self.rex_emit(8, Reg::NONE, Reg::NONE, (dreg));
+        self.inst.push(0xb8 + ((dreg.value()) & 0x7));
+        let at = self.inst.len();
+        self.imm_emit64(0);
+        self.inst.fixup(at, RelocKind::Abs64, target);
+    }
+    
     pub fn call_reg(&mut self, reg: Reg) {
         self.alu1_reg(0xff, 2, (reg));
     }
@@ -2327,6 +2504,14 @@ impl Emit {
         self.imm_emit32((disp));
     }
     
+    /*
+     * unconditional relative jump to a label.
+     * The rel8 or rel32 form is chosen when the code is built.
+     */
+    pub fn jmp_label(&mut self, label: Label) {
+        self.inst.branch(label, &[0xEB], &[0xE9]);
+    }
+    
     /*
      * unconditional indirect jumps
      */
@@ -2350,42 +2535,60 @@ impl Emit {
         self.alu1_memindex(0xff, 4, (basereg), (disp), (indexreg), (shift));
     }
     
+    /*
+     * conditional relative jumps
+     */
+    pub fn branch_imm8(&mut self, cond: Cond, disp: i32) {
+        self.inst.push(0x70 | cond.code());
+        self.imm_emit8((disp));
+    }
+    
+    pub fn branch_imm(&mut self, cond: Cond, disp: i32) {
+        self.inst.push(0x0f);
+        self.inst.push(0x80 | cond.code());
+        self.imm_emit32((disp));
+    }
+    
+    /*
+     * conditional relative jump to a label.
+     * The rel8 or rel32 form is chosen when the code is built.
+     */
+    pub fn branch_label(&mut self, cond: Cond, label: Label) {
+        let opc = 0x70 | cond.code();
+        self.inst.branch(label, &[opc], &[0x0f, opc + 0x10]);
+    }
+    
     /*
      * Set the low byte in a register to 0x01 if a condition is met
      * or 0x00 otherwise.
      */
-    pub fn set_reg(&mut self, cond: i32, dreg: Reg, is_signed: bool) {
+    pub fn set_reg(&mut self, cond: Cond, dreg: Reg) {
         self.rex_emit(1, Reg::NONE, Reg::NONE, (dreg));
         self.inst.push(0x0f);
-        if ((is_signed)) {
-            self.inst.push(x86_cc_signed_map[(cond) as usize] + 0x20);
-        } else {
-            self.inst.push(x86_cc_unsigned_map[(cond) as usize] + 0x20);
-        }
+        self.inst.push(0x90 | cond.code());
         self.reg_emit(0, (dreg));
     }
     
-    pub fn set_mem(&mut self, cond: i32, mem: i32, is_signed: bool) {
+    pub fn set_mem(&mut self, cond: Cond, mem: i32) {
         self.inst.push(0x0f);
-        if ((is_signed)) {
-            self.inst.push(x86_cc_signed_map[(cond) as usize] + 0x20);
-        } else {
-            self.inst.push(x86_cc_unsigned_map[(cond) as usize] + 0x20);
-        }
+        self.inst.push(0x90 | cond.code());
         self.mem_emit(0, (mem));
     }
     
-    pub fn set_membase(&mut self, cond: i32, basereg: Reg, disp: i32, is_signed: bool) {
-        self.rex_emit(4, Reg::NONE, Reg::NONE, (basereg));
+    pub fn set_membase(&mut self, cond: Cond, basereg: Reg, disp: i32) {
+        self.rex_emit_mem(4, Reg::NONE, Reg::NONE, (basereg));
         self.inst.push(0x0f);
-        if ((is_signed)) {
-            self.inst.push(x86_cc_signed_map[(cond) as usize] + 0x20);
-        } else {
-            self.inst.push(x86_cc_unsigned_map[(cond) as usize] + 0x20);
-        }
+        self.inst.push(0x90 | cond.code());
         self.membase_emit(0, (basereg), (disp));
     }
     
+    pub fn set_memindex(&mut self, cond: Cond, basereg: Reg, disp: i32, indexreg: Reg, shift: u8) {
+        self.rex_emit_mem(4, Reg::NONE, (indexreg), (basereg));
+        self.inst.push(0x0f);
+        self.inst.push(0x90 | cond.code());
+        self.memindex_emit(0, (basereg), (disp), (indexreg), (shift));
+    }
+    
     /*
      * ret
      */
@@ -2397,7 +2600,10 @@ impl Emit {
      * xchg: Exchange values
      */
     pub fn xchg_reg_reg_size(&mut self, dreg: Reg, sreg: Reg, size: i32) {
-        if (((size) > 1) && ((dreg) == Reg::RAX || (sreg) == Reg::RAX)) {
+        /*
+         * 0x90 is nop for eax, which does not clear the upper half of rax.
+         */
+        if (((size) > 1) && ((dreg) == Reg::RAX || (sreg) == Reg::RAX) && !((size) == 4 && (dreg) == (sreg))) {
             if ((size) == 2) {
                 self.inst.push(0x66);
             }
@@ -2432,28 +2638,28 @@ impl Emit {
     }
     
     pub fn xmm2_reg_regp(&mut self, opc1: u8, opc2: u8, r: Reg, regp: Reg) {
-        self.rex_emit(0, (r), Reg::NONE, (regp));
+        self.rex_emit_mem(0, (r), Reg::NONE, (regp));
         self.inst.push((opc1));
         self.inst.push((opc2));
         self.regp_emit((r.value()), (regp));
     }
     
     pub fn xmm2_reg_mem(&mut self, opc1: u8, opc2: u8, r: Reg, mem: i32) {
-        self.rex_emit(0, (r), Reg::NONE, Reg::NONE);
+        self.rex_emit_mem(0, (r), Reg::NONE, Reg::NONE);
         self.inst.push((opc1));
         self.inst.push((opc2));
         self.mem_emit((r.value()), (mem));
     }
     
     pub fn xmm2_reg_membase(&mut self, opc1: u8, opc2: u8, r: Reg, basereg: Reg, disp: i32) {
-        self.rex_emit(0, (r), Reg::NONE, (basereg));
+        self.rex_emit_mem(0, (r), Reg::NONE, (basereg));
         self.inst.push((opc1));
         self.inst.push((opc2));
         self.membase_emit((r.value()), (basereg), (disp));
     }
     
     pub fn xmm2_reg_memindex(&mut self, opc1: u8, opc2: u8, r: Reg, basereg: Reg, disp: i32, indexreg: Reg, shift: u8) {
-        self.rex_emit(0, (r), (indexreg), (basereg));
+        self.rex_emit_mem(0, (r), (indexreg), (basereg));
         self.inst.push((opc1));
         self.inst.push((opc2));
         self.memindex_emit((r.value()), (basereg), (disp), (indexreg), (shift));
@@ -2472,7 +2678,7 @@ impl Emit {
     
     pub fn p1_xmm2_reg_regp_size(&mut self, p1: u8, opc1: u8, opc2: u8, r: Reg, regp: Reg, size: i32) {
         self.inst.push((p1));
-        self.rex_emit((size), (r), Reg::NONE, (regp));
+        self.rex_emit_mem((size), (r), Reg::NONE, (regp));
         self.inst.push((opc1));
         self.inst.push((opc2));
         self.regp_emit((r.value()), (regp));
@@ -2480,7 +2686,7 @@ impl Emit {
     
     pub fn p1_xmm2_reg_mem_size(&mut self, p1: u8, opc1: u8, opc2: u8, r: Reg, mem: i32, size: i32) {
         self.inst.push((p1));
-        self.rex_emit((size), (r), Reg::NONE, Reg::NONE);
+        self.rex_emit_mem((size), (r), Reg::NONE, Reg::NONE);
         self.inst.push((opc1));
         self.inst.push((opc2));
         self.mem_emit((r.value()), (mem));
@@ -2488,7 +2694,7 @@ impl Emit {
     
     pub fn p1_xmm2_reg_membase_size(&mut self, p1: u8, opc1: u8, opc2: u8, r: Reg, basereg: Reg, disp: i32, size: i32) {
         self.inst.push((p1));
-        self.rex_emit((size), (r), Reg::NONE, (basereg));
+        self.rex_emit_mem((size), (r), Reg::NONE, (basereg));
         self.inst.push((opc1));
         self.inst.push((opc2));
         self.membase_emit((r.value()), (basereg), (disp));
@@ -2496,7 +2702,7 @@ impl Emit {
     
     pub fn p1_xmm2_reg_memindex_size(&mut self, p1: u8, opc1: u8, opc2: u8, r: Reg, basereg: Reg, disp: i32, indexreg: Reg, shift: u8, size: i32) {
         self.inst.push((p1));
-        self.rex_emit((size), (r), (indexreg), (basereg));
+        self.rex_emit_mem((size), (r), (indexreg), (basereg));
         self.inst.push((opc1));
         self.inst.push((opc2));
         self.memindex_emit((r.value()), (basereg), (disp), (indexreg), (shift));
@@ -2516,7 +2722,7 @@ impl Emit {
     
     pub fn p1_xmm3_reg_regp_size(&mut self, p1: u8, opc1: u8, opc2: u8, opc3: u8, r: Reg, regp: Reg, size: i32) {
         self.inst.push((p1));
-        self.rex_emit((size), (r), Reg::NONE, (regp));
+        self.rex_emit_mem((size), (r), Reg::NONE, (regp));
         self.inst.push((opc1));
         self.inst.push((opc2));
         self.inst.push((opc3));
@@ -2525,7 +2731,7 @@ impl Emit {
     
     pub fn p1_xmm3_reg_mem_size(&mut self, p1: u8, opc1: u8, opc2: u8, opc3: u8, r: Reg, mem: i32, size: i32) {
         self.inst.push((p1));
-        self.rex_emit((size), (r), Reg::NONE, Reg::NONE);
+        self.rex_emit_mem((size), (r), Reg::NONE, Reg::NONE);
         self.inst.push((opc1));
         self.inst.push((opc2));
         self.inst.push((opc3));
@@ -2534,7 +2740,7 @@ impl Emit {
     
     pub fn p1_xmm3_reg_membase_size(&mut self, p1: u8, opc1: u8, opc2: u8, opc3: u8, r: Reg, basereg: Reg, disp: i32, size: i32) {
         self.inst.push((p1));
-        self.rex_emit((size), (r), Reg::NONE, (basereg));
+        self.rex_emit_mem((size), (r), Reg::NONE, (basereg));
         self.inst.push((opc1));
         self.inst.push((opc2));
         self.inst.push((opc3));
@@ -2543,7 +2749,7 @@ impl Emit {
     
     pub fn p1_xmm3_reg_memindex_size(&mut self, p1: u8, opc1: u8, opc2: u8, opc3: u8, r: Reg, basereg: Reg, disp: i32, indexreg: Reg, shift: u8, size: i32) {
         self.inst.push((p1));
-        self.rex_emit((size), (r), (indexreg), (basereg));
+        self.rex_emit_mem((size), (r), (indexreg), (basereg));
         self.inst.push((opc1));
         self.inst.push((opc2));
         self.inst.push((opc3));
@@ -3740,7 +3946,7 @@ impl Emit {
      */
     
     pub fn fld_regp_size(&mut self, sregp: Reg, size: i32) {
-        self.rex_emit(0, Reg::NONE, Reg::NONE, (sregp));
+        self.rex_emit_mem(0, Reg::NONE, Reg::NONE, (sregp));
         match size {
             4 => {
                 self.inst.push(0xd9);
@@ -3777,7 +3983,7 @@ impl Emit {
     }
     
     pub fn fld_membase_size(&mut self, basereg: Reg, disp: i32, size: i32) {
-        self.rex_emit(0, Reg::NONE, Reg::NONE, (basereg));
+        self.rex_emit_mem(0, Reg::NONE, Reg::NONE, (basereg));
         match size {
             4 => {
                 self.inst.push(0xd9);
@@ -3796,7 +4002,7 @@ impl Emit {
     }
     
     pub fn fld_memindex_size(&mut self, basereg: Reg, disp: i32, indexreg: Reg, shift: u8, size: i32) {
-        self.rex_emit(0, Reg::NONE, (indexreg), (basereg));
+        self.rex_emit_mem(0, Reg::NONE, (indexreg), (basereg));
         match size {
             4 => {
                 self.inst.push(0xd9);
@@ -3836,7 +4042,7 @@ impl Emit {
     }
     
     pub fn fild_membase_size(&mut self, basereg: Reg, disp: i32, size: i32) {
-        self.rex_emit(0, Reg::NONE, Reg::NONE, (basereg));
+        self.rex_emit_mem(0, Reg::NONE, Reg::NONE, (basereg));
         match size {
             2 => {
                 self.inst.push(0xdf);
@@ -3859,7 +4065,7 @@ impl Emit {
      */
     
     pub fn fst_regp_size(&mut self, sregp: Reg, size: i32) {
-        self.rex_emit(0, Reg::NONE, Reg::NONE, (sregp));
+        self.rex_emit_mem(0, Reg::NONE, Reg::NONE, (sregp));
         match size {
             4 => {
                 self.inst.push(0xd9);
@@ -3888,7 +4094,7 @@ impl Emit {
     }
     
     pub fn fst_membase_size(&mut self, basereg: Reg, disp: i32, size: i32) {
-        self.rex_emit(0, Reg::NONE, Reg::NONE, (basereg));
+        self.rex_emit_mem(0, Reg::NONE, Reg::NONE, (basereg));
         match size {
             4 => {
                 self.inst.push(0xd9);
@@ -3903,7 +4109,7 @@ impl Emit {
     }
     
     pub fn fst_memindex_size(&mut self, basereg: Reg, disp: i32, indexreg: Reg, shift: u8, size: i32) {
-        self.rex_emit(0, Reg::NONE, (indexreg), (basereg));
+        self.rex_emit_mem(0, Reg::NONE, (indexreg), (basereg));
         match size {
             4 => {
                 self.inst.push(0xd9);
@@ -3921,7 +4127,7 @@ impl Emit {
      * fstp: store top fpu register to memory and pop it from the fpu stack
      */
     pub fn fstp_regp_size(&mut self, sregp: Reg, size: i32) {
-        self.rex_emit(0, Reg::NONE, Reg::NONE, (sregp));
+        self.rex_emit_mem(0, Reg::NONE, Reg::NONE, (sregp));
         match size {
             4 => {
                 self.inst.push(0xd9);
@@ -3958,7 +4164,7 @@ impl Emit {
     }
     
     pub fn fstp_membase_size(&mut self, basereg: Reg, disp: i32, size: i32) {
-        self.rex_emit(0, Reg::NONE, Reg::NONE, (basereg));
+        self.rex_emit_mem(0, Reg::NONE, Reg::NONE, (basereg));
         match size {
             4 => {
                 self.inst.push(0xd9);
@@ -3977,7 +4183,7 @@ impl Emit {
     }
     
     pub fn fstp_memindex_size(&mut self, basereg: Reg, disp: i32, indexreg: Reg, shift: u8, size: i32) {
-        self.rex_emit(0, Reg::NONE, (indexreg), (basereg));
+        self.rex_emit_mem(0, Reg::NONE, (indexreg), (basereg));
         match size {
             4 => {
                 self.inst.push(0xd9);
@@ -4017,7 +4223,7 @@ impl Emit {
     }
     
     pub fn fistp_regp_size(&mut self, dregp: Reg, size: i32) {
-        self.rex_emit(0, Reg::NONE, Reg::NONE, (dregp));
+        self.rex_emit_mem(0, Reg::NONE, Reg::NONE, (dregp));
         match size {
             2 => {
                 self.inst.push(0xdf);
@@ -4036,7 +4242,7 @@ impl Emit {
     }
     
     pub fn fistp_membase_size(&mut self, basereg: Reg, disp: i32, size: i32) {
-        self.rex_emit(0, Reg::NONE, Reg::NONE, (basereg));
+        self.rex_emit_mem(0, Reg::NONE, Reg::NONE, (basereg));
         match size {
             2 => {
                 self.inst.push(0xdf);
@@ -4066,7 +4272,7 @@ impl Emit {
      * fisttp: Convert long double to integer using truncation as rounding mode Available in SSE 3 only
      */
     pub fn fisttp_regp_size(&mut self, dregp: Reg, size: i32) {
-        self.rex_emit(0, Reg::NONE, Reg::NONE, (dregp));
+        self.rex_emit_mem(0, Reg::NONE, Reg::NONE, (dregp));
         match size {
             2 => {
                 self.inst.push(0xdf);
@@ -4103,7 +4309,7 @@ impl Emit {
     }
     
     pub fn fisttp_membase_size(&mut self, basereg: Reg, disp: i32, size: i32) {
-        self.rex_emit(0, Reg::NONE, Reg::NONE, (basereg));
+        self.rex_emit_mem(0, Reg::NONE, Reg::NONE, (basereg));
         match size {
             2 => {
                 self.inst.push(0xdf);
@@ -4140,6 +4346,7 @@ impl Emit {
     }
     
     pub fn fnstcw_membase(&mut self, basereg: Reg, disp: i32) {
+        self.rex_emit_mem(0, Reg::NONE, Reg::NONE, (basereg));
         self.inst.push(0xd9);
         self.membase_emit(7, (basereg), (disp));
     }
@@ -4153,6 +4360,7 @@ impl Emit {
     }
     
     pub fn fldcw_membase(&mut self, basereg: Reg, disp: i32) {
+        self.rex_emit_mem(0, Reg::NONE, Reg::NONE, (basereg));
         self.inst.push(0xd9);
         self.membase_emit (5, (basereg), (disp));
     }
@@ -0,0 +1,459 @@
+//! Instruction selection for the IR: lowers functions onto virtual
+//! registers, which `Codegen::function` assigns.
+
+use codegen::{CodegenError, Cond, Label};
+use codegen::ir::{BinOp, Block, CastOp, CmpOp, Function, Op, Type, Value};
+use super::{Codegen, AsArg, Imm, MemBase, Reg, CallConv, Frame, VReg, VMemBase};
+use super::abi::gpr;
+use super::SizedReg::*;
+
+impl Codegen {
+    /// Generates the code of an IR function, which takes its parameters
+    /// and returns its result with the calling convention. Calls in the
+    /// function use the same convention. Each value is held in a virtual
+    /// register, and phis are resolved with moves on the edges into their
+    /// block.
+    pub fn lower(&mut self, conv: CallConv, func: &Function) -> Result<(), CodegenError> {
+        self.function(conv, 0, |cg, frame| {
+            let mut lowering = Lowering::new(cg, conv, func, frame);
+            
+            lowering.params()?;
+            
+            for block in func.blocks() {
+                lowering.block(block)?;
+            }
+            
+            Ok(())
+        })
+    }
+}
+
+struct Lowering<'a> {
+    cg: &'a mut Codegen,
+    conv: CallConv,
+    func: &'a Function,
+    frame: &'a Frame,
+    values: Vec<VReg>,
+    labels: Vec<Label>
+}
+
+impl<'a> Lowering<'a> {
+    fn new(cg: &'a mut Codegen, conv: CallConv, func: &'a Function, frame: &'a Frame) -> Lowering<'a> {
+        let values = func.values().map(|value| {
+            match func.ty(value) {
+                Type::F64 => cg.new_vreg_xmm(),
+                ty => cg.new_vreg(ty.size())
+            }
+        }).collect();
+        let labels = func.blocks().map(|_| cg.new_label()).collect();
+        
+        Lowering {
+            cg,
+            conv,
+            func,
+            frame,
+            values,
+            labels
+        }
+    }
+    
+    fn vreg(&self, value: Value) -> VReg {
+        self.values[value.index()]
+    }
+    
+    /*
+     * Moves the parameters from their registers or stack slots into their
+     * virtual registers, assigned as native_call assigns arguments.
+     */
+    fn params(&mut self) -> Result<(), CodegenError> {
+        let mut ints = 0;
+        let mut floats = 0;
+        let mut stack = 0;
+        
+        for (i, &ty) in self.func.params().iter().enumerate() {
+            let dst = self.vreg(self.func.param(i));
+            let (regs, next) = if ty.is_float() {
+                (self.conv.float_args(), &mut floats)
+            } else {
+                (self.conv.int_args(), &mut ints)
+            };
+            let index = match self.conv {
+                CallConv::SysV => *next,
+                CallConv::Win64 => i
+            };
+            *next += 1;
+            
+            if index < regs.len() {
+                let reg = regs[index];
+                if ty.is_float() {
+                    self.cg.movaps(dst, reg)?;
+                } else {
+                    self.cg.mov(dst, gpr(reg.reg(), ty.size()))?;
+                }
+            } else {
+                let src = MemBase(gpr(Reg::RBP, ty.size()), self.frame.stack_arg(stack));
+                stack += 1;
+                if ty.is_float() {
+                    self.cg.movsd(dst, src)?;
+                } else {
+                    self.cg.mov(dst, src)?;
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn block(&mut self, block: Block) -> Result<(), CodegenError> {
+        let func = self.func;
+        
+        self.cg.bind(self.labels[block.index()]);
+        
+        assert!(func.insts(block).last().is_some_and(|inst| inst.op.is_terminator()), "{} does not end with a terminator", block);
+        
+        for inst in func.insts(block) {
+            let dst = inst.result.map(|result| self.vreg(result));
+            
+            match inst.op {
+                Op::Int(value) => {
+                    let dst = dst.unwrap();
+                    let value = if dst.size() == 4 { Imm::I32(value as i32) } else { Imm::I64(value) };
+                    self.cg.mov(dst, value)?;
+                }
+                Op::Float(value) => self.cg.mov(dst.unwrap(), value)?,
+                Op::Binary(op, a, b) => self.binary(dst.unwrap(), op, a, b)?,
+                Op::Cmp(op, a, b) => self.cmp(dst.unwrap(), op, a, b)?,
+                Op::Cast(op, value) => self.cast(dst.unwrap(), op, value)?,
+                Op::Load(addr, disp) => {
+                    let dst = dst.unwrap();
+                    let addr = self.vreg(addr);
+                    if dst.is_xmm() {
+                        self.cg.movsd(dst, VMemBase(addr, disp))?;
+                    } else {
+                        self.cg.mov(dst, VMemBase(addr.sized(dst.size()), disp))?;
+                    }
+                }
+                Op::Store(value, addr, disp) => {
+                    let value = self.vreg(value);
+                    let addr = self.vreg(addr);
+                    if value.is_xmm() {
+                        self.cg.movsd(VMemBase(addr, disp), value)?;
+                    } else {
+                        self.cg.mov(VMemBase(addr.sized(value.size()), disp), value)?;
+                    }
+                }
+                Op::Call(target, ref args) => {
+                    let args = args.iter().map(|&arg| self.vreg(arg).as_arg()).collect::<Vec<_>>();
+                    self.cg.call_native(self.conv, target, &args, dst.map(|dst| dst.as_arg()))?;
+                }
+                Op::Phi(_) => {}
+                Op::Jump(target) => self.jump(block, target)?,
+                Op::Branch(cond, then, otherwise) => self.branch(block, cond, then, otherwise)?,
+                Op::Return(value) => {
+                    if let Some(value) = value {
+                        let value = self.vreg(value);
+                        if value.is_xmm() {
+                            self.cg.movaps(XMM0, value)?;
+                        } else {
+                            self.cg.mov(gpr(Reg::RAX, value.size()), value)?;
+                        }
+                    }
+                    self.cg.epilogue(self.frame)?;
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn binary(&mut self, dst: VReg, op: BinOp, a: Value, b: Value) -> Result<(), CodegenError> {
+        let (a, b) = (self.vreg(a), self.vreg(b));
+        let cg = &mut *self.cg;
+        
+        if dst.is_xmm() {
+            cg.movaps(dst, a)?;
+            return match op {
+                BinOp::Add => cg.addsd(dst, b),
+                BinOp::Sub => cg.subsd(dst, b),
+                BinOp::Mul => cg.mulsd(dst, b),
+                BinOp::Div => cg.divsd(dst, b),
+                _ => unreachable!()
+            };
+        }
+        
+        match op {
+            BinOp::Div | BinOp::Rem | BinOp::UDiv | BinOp::URem => {
+                let size = dst.size();
+                
+                cg.mov(gpr(Reg::RAX, size), a)?;
+                if matches!(op, BinOp::Div | BinOp::Rem) {
+                    if size == 4 { cg.cdq() } else { cg.cqo() }
+                    cg.idiv(b)?;
+                } else {
+                    cg.mov(gpr(Reg::RDX, size), 0)?;
+                    cg.div(b)?;
+                }
+                
+                let result = if matches!(op, BinOp::Div | BinOp::UDiv) { Reg::RAX } else { Reg::RDX };
+                cg.mov(dst, gpr(result, size))
+            }
+            _ => {
+                cg.mov(dst, a)?;
+                match op {
+                    BinOp::Add => cg.add(dst, b),
+                    BinOp::Sub => cg.sub(dst, b),
+                    BinOp::Mul => cg.imul(dst, b),
+                    BinOp::And => cg.and(dst, b),
+                    BinOp::Or => cg.or(dst, b),
+                    BinOp::Xor => cg.xor(dst, b),
+                    BinOp::Shl => cg.shl(dst, b.sized(1)),
+                    BinOp::Shr => cg.shr(dst, b.sized(1)),
+                    BinOp::Sar => cg.sar(dst, b.sized(1)),
+                    _ => unreachable!()
+                }
+            }
+        }
+    }
+    
+    /*
+     * The result is cleared before the compare, because setcc only writes
+     * its low byte. A float compare is unordered when either operand is
+     * NaN, which sets ZF, PF and CF; less than is tested as greater than
+     * with the operands swapped, so unordered compares fail on CF.
+     */
+    fn cmp(&mut self, dst: VReg, op: CmpOp, a: Value, b: Value) -> Result<(), CodegenError> {
+        let (a, b) = (self.vreg(a), self.vreg(b));
+        let cg = &mut *self.cg;
+        
+        cg.mov(dst, 0)?;
+        
+        if !a.is_xmm() {
+            let cond = match op {
+                CmpOp::Eq => Cond::E,
+                CmpOp::Ne => Cond::NE,
+                CmpOp::Lt => Cond::L,
+                CmpOp::Le => Cond::LE,
+                CmpOp::Gt => Cond::G,
+                CmpOp::Ge => Cond::GE,
+                CmpOp::ULt => Cond::B,
+                CmpOp::ULe => Cond::BE,
+                CmpOp::UGt => Cond::A,
+                CmpOp::UGe => Cond::AE
+            };
+            
+            cg.cmp(a, b)?;
+            return cg.setcc(cond, dst.sized(1));
+        }
+        
+        match op {
+            CmpOp::Eq | CmpOp::Ne => {
+                let parity = cg.new_vreg(4);
+                cg.mov(parity, 0)?;
+                cg.ucomisd(a, b)?;
+                if op == CmpOp::Eq {
+                    cg.setcc(Cond::E, dst.sized(1))?;
+                    cg.setcc(Cond::NP, parity.sized(1))?;
+                    cg.and(dst, parity)
+                } else {
+                    cg.setcc(Cond::NE, dst.sized(1))?;
+                    cg.setcc(Cond::P, parity.sized(1))?;
+                    cg.or(dst, parity)
+                }
+            }
+            CmpOp::Gt | CmpOp::Ge => {
+                cg.ucomisd(a, b)?;
+                cg.setcc(if op == CmpOp::Gt { Cond::A } else { Cond::AE }, dst.sized(1))
+            }
+            CmpOp::Lt | CmpOp::Le => {
+                cg.ucomisd(b, a)?;
+                cg.setcc(if op == CmpOp::Lt { Cond::A } else { Cond::AE }, dst.sized(1))
+            }
+            _ => unreachable!()
+        }
+    }
+    
+    fn cast(&mut self, dst: VReg, op: CastOp, value: Value) -> Result<(), CodegenError> {
+        let src = self.vreg(value);
+        let cg = &mut *self.cg;
+        
+        match op {
+            CastOp::Sext => cg.movsx(dst, src),
+            CastOp::Zext => cg.movzx(dst, src),
+            CastOp::Trunc => cg.mov(dst, src.sized(4)),
+            CastOp::IntToFloat => cg.cvtsi2sd(dst, src),
+            CastOp::FloatToInt => cg.cvttsd2si(dst, src),
+            CastOp::Bitcast if dst.is_xmm() || src.is_xmm() => cg.movq(dst, src),
+            CastOp::Bitcast => cg.mov(dst, src)
+        }
+    }
+    
+    fn has_phis(&self, block: Block) -> bool {
+        self.func.phis(block).next().is_some()
+    }
+    
+    /*
+     * Moves the values that flow from one block into the phis of another
+     * and jumps there, unless it is the next block.
+     */
+    fn jump(&mut self, from: Block, to: Block) -> Result<(), CodegenError> {
+        self.edge(from, to)?;
+        
+        if to.index() != from.index() + 1 {
+            self.cg.jmp(self.labels[to.index()]);
+        }
+        
+        Ok(())
+    }
+    
+    fn branch(&mut self, from: Block, cond: Value, then: Block, otherwise: Block) -> Result<(), CodegenError> {
+        let cond = self.vreg(cond);
+        
+        self.cg.test(cond, cond)?;
+        
+        if !self.has_phis(then) {
+            self.cg.jcc(Cond::NE, self.labels[then.index()]);
+            self.jump(from, otherwise)
+        } else if !self.has_phis(otherwise) {
+            self.cg.jcc(Cond::E, self.labels[otherwise.index()]);
+            self.jump(from, then)
+        } else {
+            let label = self.cg.new_label();
+            self.cg.jcc(Cond::E, label);
+            self.edge(from, then)?;
+            self.cg.jmp(self.labels[then.index()]);
+            self.cg.bind(label);
+            self.jump(from, otherwise)
+        }
+    }
+    
+    /*
+     * The phis of a block all take their value at once, so when one of
+     * them flows into another, the values are copied to temporaries
+     * first.
+     */
+    fn edge(&mut self, from: Block, to: Block) -> Result<(), CodegenError> {
+        let moves = self.func.phis(to).map(|inst| {
+            let incoming = match inst.op {
+                Op::Phi(ref incoming) => incoming,
+                _ => unreachable!()
+            };
+            let phi = inst.result.unwrap();
+            let value = incoming.iter().find(|&&(block, _)| block == from).map(|&(_, value)| value);
+            
+            (phi, value.unwrap_or_else(|| panic!("{} has no value incoming from {}", phi, from)))
+        }).collect::<Vec<_>>();
+        
+        let overlap = moves.iter().any(|&(_, value)| moves.iter().any(|&(phi, _)| phi == value));
+        
+        if overlap {
+            let temps = moves.iter().map(|&(_, value)| {
+                let src = self.vreg(value);
+                let temp = if src.is_xmm() { self.cg.new_vreg_xmm() } else { self.cg.new_vreg(src.size()) };
+                self.copy(temp, src).map(|_| temp)
+            }).collect::<Result<Vec<_>, _>>()?;
+            
+            for (&(phi, _), &temp) in moves.iter().zip(temps.iter()) {
+                let dst = self.vreg(phi);
+                self.copy(dst, temp)?;
+            }
+        } else {
+            for &(phi, value) in &moves {
+                let (dst, src) = (self.vreg(phi), self.vreg(value));
+                self.copy(dst, src)?;
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn copy(&mut self, dst: VReg, src: VReg) -> Result<(), CodegenError> {
+        if dst.is_xmm() {
+            self.cg.movaps(dst, src)
+        } else {
+            self.cg.mov(dst, src)
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use codegen::ir::*;
+    use codegen::x86_64::prologue::*;
+    
+    extern "C" fn scale(x: f64, n: i32) -> f64 {
+        x * n as f64
+    }
+    
+    /*
+     * Swaps two phis in a loop, divides, compares floats, and stores,
+     * loads and calls across the loop.
+     */
+    #[test]
+    fn lower() {
+        let mut func = Function::new(&[Type::I64, Type::Ptr, Type::F64], Some(Type::I64));
+        
+        {
+            let mut b = Builder::new(&mut func);
+            let (n, p, x) = (b.param(0), b.param(1), b.param(2));
+            let (head, body, done) = (b.create_block(), b.create_block(), b.create_block());
+            let entry = b.current_block();
+            
+            let zero = b.int(Type::I64, 0);
+            let one = b.int(Type::I64, 1);
+            b.jump(head);
+            
+            b.switch_to(head);
+            let (i, fa, fb) = (b.phi(Type::I64), b.phi(Type::I64), b.phi(Type::I64));
+            let more = b.cmp(CmpOp::Lt, i, n);
+            b.branch(more, body, done);
+            
+            b.switch_to(body);
+            let next = b.add(fa, fb);
+            let i1 = b.add(i, one);
+            b.jump(head);
+            
+            for &(phi, first, later) in &[(i, zero, i1), (fa, zero, fb), (fb, one, next)] {
+                b.add_incoming(phi, entry, first);
+                b.add_incoming(phi, body, later);
+            }
+            
+            b.switch_to(done);
+            b.store(fa, p, 8);
+            let three = b.int(Type::I32, 3);
+            let scaled = b.call(scale as extern "C" fn(f64, i32) -> f64 as usize as u64, &[x, three], Some(Type::F64)).unwrap();
+            let half = b.float(0.5);
+            let big = b.cmp(CmpOp::Gt, scaled, half);
+            let big = b.cast(CastOp::Sext, big, Type::I64);
+            let loaded = b.load(Type::I64, p, 8);
+            let seven = b.int(Type::I64, -7);
+            let quotient = b.binary(BinOp::Div, loaded, seven);
+            let remainder = b.binary(BinOp::Rem, loaded, seven);
+            let shifted = b.binary(BinOp::Shl, quotient, big);
+            let sum = b.add(shifted, remainder);
+            let truncated = b.cast(CastOp::FloatToInt, scaled, Type::I64);
+            let sum = b.mul(sum, truncated);
+            b.ret(Some(sum));
+        }
+        
+        let mut cg = Codegen::new();
+        cg.lower(CallConv::host(), &func).unwrap();
+        let f = unsafe { cg.build_fn::<fn(i64, *mut i64, f64) -> i64>().unwrap() };
+        
+        for &(n, x) in &[(0i64, 1.0f64), (10, 0.1), (40, 2.5), (1, -4.0)] {
+            let (mut fa, mut fb) = (0i64, 1i64);
+            for _ in 0..n {
+                let next = fa + fb;
+                fa = fb;
+                fb = next;
+            }
+            let scaled = scale(x, 3);
+            let big = (scaled > 0.5) as i64;
+            let expected = ((fa / -7) << big) + fa % -7;
+            let expected = expected.wrapping_mul(scaled as i64);
+            
+            let mut memory = [0i64; 2];
+            assert_eq!(f.call((n, memory.as_mut_ptr(), x)), expected, "{}", func);
+            assert_eq!(memory[1], fa);
+        }
+    }
+}
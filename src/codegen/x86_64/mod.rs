@@ -1,186 +1,1965 @@
 mod emit;
+mod abi;
+mod regalloc;
+mod lower;
+pub mod disasm;
+#[cfg(test)]
+mod tests;
 
-use codegen::JitFunction;
-use self::emit::{Emit, AluOp, ShiftOp};
+use std::fmt;
+use codegen::{Position, FixupTarget, RelocKind, CodeBuffer, JitFunction, CodeHeap, Signature, Label, Cond, CodegenError, CodegenErrorKind, Operand, OperandKind};
+use self::emit::{Emit, AluOp, ShiftOp, DoubleShiftOp};
 pub use self::emit::Reg;
+pub use self::abi::{CallConv, Frame};
+pub use self::regalloc::{VReg, VMemBase};
+use self::regalloc::{Alloc, Note};
 
 pub struct Codegen {
-    emit: Emit
+    emit: Emit,
+    calls: Option<Vec<(Position, String)>>,
+    scratch: Option<Reg>,
+    heap: CodeHeap,
+    vregs: u32,
+    alloc: Option<Box<Alloc>>
 }
 
 impl Codegen {
     pub fn new() -> Codegen {
         Codegen {
-            emit: Emit::new()
+            emit: Emit::new(),
+            calls: None,
+            scratch: Some(Reg::R11),
+            heap: CodeHeap::local(),
+            vregs: 0,
+            alloc: None
         }
     }
     
-    pub fn build(&mut self) -> JitFunction {
-        self.emit.build()
+    /// Copies the code into the code heap. Code that refers to symbols
+    /// must be installed with `finish` and `CodeBuffer::install` instead.
+    /// Fails when a label that is referred to was never bound, when the
+    /// code refers to a symbol, when an address the code refers to RIP
+    /// relative is out of reach, or when the heap cannot map memory.
+    pub fn build(&mut self) -> Result<JitFunction, CodegenError> {
+        self.emit.build(&self.heap, None)
     }
     
-    pub fn push<A: AsArg>(&mut self, arg: A) {
-        match arg.as_arg() {
-            Arg::Imm(imm) => self.emit.push_imm_size(imm.as_i32(), imm.size()),
-            Arg::MemBase(basereg, disp, size) => self.emit.push_membase_size(basereg, disp, size),
-            Arg::MemIndex(basereg, disp, indexreg, shift, size) => self.emit.push_memindex_size(basereg, disp, indexreg, shift, size),
-            Arg::Reg(reg) => self.emit.push_reg_size(reg.reg(), reg.size()),
-            _ => jit_assert!()
+    /// Copies the code into the code heap as a function with the given
+    /// name, which debuggers, profilers and `lookup` report for it.
+    pub fn build_named(&mut self, name: &str) -> Result<JitFunction, CodegenError> {
+        self.emit.build(&self.heap, Some(name))
+    }
+    
+    /// Lays out the code without installing it. Addresses of symbols,
+    /// absolute addresses the code calls, and labels whose absolute
+    /// address is taken are left as relocations, so the buffer can be
+    /// installed in any heap or written to an object file. Fails when a
+    /// label that is referred to was never bound.
+    pub fn finish(&self) -> Result<CodeBuffer, CodegenError> {
+        self.emit.finish()
+    }
+    
+    /// Builds the code as a function with signature `F`, like
+    /// `fn(u64, u64) -> u64`, which can then be called through `call`.
+    ///
+    /// # Safety
+    ///
+    /// The code must implement a function with signature `F` in the C
+    /// calling convention of the platform.
+    pub unsafe fn build_fn<F: Signature>(&mut self) -> Result<JitFunction<F>, CodegenError> {
+        Ok(self.build()?.cast())
+    }
+    
+    /// Starts or stops recording the front-end calls, so `listing` can
+    /// show which call produced each instruction. Recording is off by
+    /// default.
+    pub fn record_calls(&mut self, record: bool) {
+        if !record {
+            self.calls = None;
+        } else if self.calls.is_none() {
+            self.calls = Some(Vec::new());
         }
     }
     
-    pub fn pop<A: AsArg>(&mut self, arg: A) {
-        match arg.as_arg() {
-            Arg::MemBase(basereg, disp, size) => self.emit.pop_membase_size(basereg, disp, size),
-            Arg::MemIndex(basereg, disp, indexreg, shift, size) => self.emit.pop_memindex_size(basereg, disp, indexreg, shift, size),
-            Arg::Reg(reg) => self.emit.pop_reg_size(reg.reg(), reg.size()),
-            _ => jit_assert!()
+    /// Sets the register that absolute addresses are loaded into when
+    /// they do not fit in a sign extended 32 bit displacement. The
+    /// register is clobbered by such instructions. Without a scratch
+    /// register, or when the instruction itself uses it, the address is
+    /// reached RIP relative instead, and `build` fails with
+    /// `TargetOutOfRange` if the code ends up more than 2GB away from it.
+    /// The default is R11.
+    pub fn set_scratch(&mut self, reg: Option<SizedReg>) {
+        self.scratch = reg.map(|reg| reg.reg());
+    }
+    
+    /// Sets the heap that `build` copies the code into. The default is
+    /// the heap shared by the code generators of this thread.
+    pub fn set_heap(&mut self, heap: CodeHeap) {
+        self.heap = heap;
+    }
+    
+    /// Adds a constant to the constant pool, which is placed after the
+    /// code. The returned operand addresses the constant RIP relative.
+    pub fn const_f64(&mut self, value: f64) -> Const {
+        self.const_bytes(&value.to_bits().to_le_bytes())
+    }
+    
+    /// Adds a constant to the constant pool, which is placed after the
+    /// code. The returned operand addresses the constant RIP relative.
+    pub fn const_u64(&mut self, value: u64) -> Const {
+        self.const_bytes(&value.to_le_bytes())
+    }
+    
+    /// Adds a constant to the constant pool, which is placed after the
+    /// code. The returned operand addresses the constant RIP relative; its
+    /// size is the number of bytes. Constants are aligned to their size,
+    /// up to 16 bytes, and identical constants are shared.
+    pub fn const_bytes(&mut self, bytes: &[u8]) -> Const {
+        assert!(!bytes.is_empty(), "empty constant");
+        
+        Const(self.emit.constant(bytes), bytes.len() as i32)
+    }
+    
+    fn record(&mut self, pos: Position, call: fmt::Arguments) {
+        if let Some(ref mut calls) = self.calls {
+            calls.push((pos, call.to_string()));
         }
     }
     
-    pub fn mov<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) {
-        match (arg1.as_arg(), arg2.as_arg()) {
-            (Arg::Reg(dreg), Arg::Reg(sreg)) => {
-                assert!(dreg.size() == sreg.size());
-                self.emit.mov_reg_reg_size(dreg.reg(), sreg.reg(), dreg.size());
+    /// Disassembles the code generated so far. When calls are being
+    /// recorded, every front-end call is listed as a comment above the
+    /// instructions it produced. Fails when a label that is branched to
+    /// was never bound.
+    pub fn listing(&self) -> Result<String, CodegenError> {
+        let calls = match self.calls {
+            Some(ref calls) => &calls[..],
+            None => &[]
+        };
+        let positions = calls.iter().map(|&(pos, _)| pos).collect::<Vec<_>>();
+        let (code, offsets) = self.emit.layout(&positions)?;
+        
+        let mut result = String::new();
+        let mut next = 0;
+        
+        for instruction in disasm::disassemble(&code) {
+            while next < calls.len() && offsets[next] <= instruction.offset {
+                result.push_str(&format!("; {}\n", calls[next].1));
+                next += 1;
             }
-            (Arg::MemBase(basereg, disp, _), Arg::Reg(sreg)) => self.emit.mov_membase_reg_size(basereg, disp, sreg.reg(), sreg.size()),
-            (Arg::MemIndex(basereg, disp, indexreg, shift, _), Arg::Reg(sreg)) => self.emit.mov_memindex_reg_size(basereg, disp, indexreg, shift, sreg.reg(), sreg.size()),
-            (Arg::Mem(mem), Arg::Reg(sreg)) => self.emit.mov_mem_reg_size(mem, sreg.reg(), sreg.size()),
-            (Arg::Reg(dreg), Arg::Imm(imm)) => self.emit.mov_reg_imm_size(dreg.reg(), imm.as_i64(), imm.size()),
-            (Arg::Reg(dreg), Arg::Mem(mem)) => self.emit.mov_reg_mem_size(dreg.reg(), mem, dreg.size()),
-            (Arg::Reg(dreg), Arg::MemBase(basereg, disp, _)) => self.emit.mov_reg_membase_size(dreg.reg(), basereg, disp, dreg.size()),
-            (Arg::Reg(dreg), Arg::MemIndex(basereg, disp, indexreg, shift, _)) => self.emit.mov_reg_memindex_size(dreg.reg(), basereg, disp, indexreg, shift, dreg.size()),
-            (Arg::MemBase(basereg, disp, _), Arg::Imm(imm)) => self.emit.mov_membase_imm_size(basereg, disp, imm.as_i32(), imm.size()),
-            (Arg::MemIndex(basereg, disp, indexreg, shift, _), Arg::Imm(imm)) => self.emit.mov_memindex_imm_size(basereg, disp, indexreg, shift, imm.as_i32(), imm.size()),
-            _ => jit_assert!()
+            
+            result.push_str(&format!("{}\n", instruction));
+        }
+        
+        for call in &calls[next..] {
+            result.push_str(&format!("; {}\n", call.1));
+        }
+        
+        Ok(result)
+    }
+    
+    fn error(&self, kind: CodegenErrorKind, instruction: &'static str, args: &[Arg]) -> CodegenError {
+        CodegenError {
+            kind,
+            instruction,
+            operands: args.iter().map(|arg| arg.operand()).collect(),
+            offset: self.emit.offset(),
+            cause: None
         }
     }
     
-    fn alu<A1: AsArg, A2: AsArg>(&mut self, opc: AluOp, arg1: A1, arg2: A2) {
-        match (arg1.as_arg(), arg2.as_arg()) {
-            (Arg::Reg(dreg), Arg::Reg(sreg)) => {
-                assert_eq!(dreg.size(), sreg.size());
-                self.emit.alu_reg_reg_size(opc, dreg.reg(), sreg.reg(), dreg.size());
+    /*
+     * Checks the sizes of all operands. Only MemSize operands carry a
+     * size given by the caller; all other sizes are implied by the
+     * operand itself. A size of 16 is only accepted next to an XMM
+     * register, for the packed SSE instructions; every instruction that
+     * takes an XMM register checks the size of its memory operand.
+     */
+    fn check_sizes(&self, instruction: &'static str, args: &[Arg]) -> Result<(), CodegenError> {
+        let xmm = args.iter().any(|arg| matches!(*arg, Arg::Xmm(_)));
+        
+        for arg in args {
+            if let Arg::MemSize(_, size) = *arg {
+                if !(size == 1 || size == 2 || size == 4 || size == 8 || (size == 16 && xmm)) {
+                    return Err(self.error(CodegenErrorKind::InvalidSize, instruction, args));
+                }
             }
-            (Arg::MemBase(basereg, disp, size), Arg::Reg(sreg)) => {
-                assert_eq!(size, sreg.size());
-                self.emit.alu_membase_reg_size(opc, basereg, disp, sreg.reg(), sreg.size());
+        }
+        
+        Ok(())
+    }
+    
+    fn check_same_size(&self, instruction: &'static str, args: &[Arg], size1: i32, size2: i32) -> Result<(), CodegenError> {
+        if size1 == size2 {
+            Ok(())
+        } else {
+            Err(self.error(CodegenErrorKind::SizeMismatch, instruction, args))
+        }
+    }
+    
+    /*
+     * Returns the value of an immediate that is encoded in at most 32 bits
+     * and sign extended to the size of the destination. A 64 bit
+     * destination only keeps values that survive the sign extension, so
+     * an unsigned immediate must fit a signed 32 bit one.
+     */
+    fn imm32(&self, instruction: &'static str, args: &[Arg], imm: Imm, size: i32) -> Result<i32, CodegenError> {
+        if imm.size() > size {
+            return Err(self.error(CodegenErrorKind::SizeMismatch, instruction, args));
+        }
+        
+        let value = if size == 8 {
+            imm.as_i64().filter(|&value| value == value as i32 as i64).map(|value| value as i32)
+        } else {
+            imm.as_i32()
+        };
+        
+        match value {
+            Some(value) => Ok(value),
+            None => Err(self.error(CodegenErrorKind::ImmOutOfRange, instruction, args))
+        }
+    }
+    
+    /*
+     * Returns the immediate of an ALU instruction. An immediate narrower
+     * than the destination is sign extended, which only keeps the value of
+     * signed immediates.
+     */
+    fn alu_imm(&self, instruction: &'static str, args: &[Arg], imm: Imm, size: i32) -> Result<i32, CodegenError> {
+        if imm.size() < size && matches!(imm, Imm::U8(_) | Imm::U16(_) | Imm::U32(_)) {
+            return Err(self.error(CodegenErrorKind::SizeMismatch, instruction, args));
+        }
+        
+        self.imm32(instruction, args, imm, size)
+    }
+    
+    fn shift_count(&self, instruction: &'static str, args: &[Arg], imm: Imm) -> Result<i32, CodegenError> {
+        match imm.as_i32() {
+            Some(value) if (0..=255).contains(&value) => Ok(value),
+            _ => Err(self.error(CodegenErrorKind::ImmOutOfRange, instruction, args))
+        }
+    }
+    
+    fn unsupported(&self, instruction: &'static str, args: &[Arg]) -> CodegenError {
+        self.error(CodegenErrorKind::UnsupportedOperands, instruction, args)
+    }
+    
+    /*
+     * Encodes an instruction. Virtual registers are only accepted in the
+     * body of `function`, which replaces them before encode_args.
+     */
+    fn encode<F>(&mut self, instruction: &'static str, args: &[Arg], size: Option<i32>, far: bool, emit: F) -> Result<(), CodegenError>
+        where F: Fn(&mut Codegen, &[Arg]) -> Result<(), CodegenError>
+    {
+        if self.alloc.is_some() {
+            return self.encode_virtual(instruction, args, size, far, &emit);
+        }
+        
+        if args.iter().any(|arg| arg.is_virtual()) {
+            return Err(self.unsupported(instruction, args));
+        }
+        
+        self.encode_args(instruction, args, size, far, &emit)
+    }
+    
+    /*
+     * Checks the operands and passes them to emit with all absolute
+     * addresses and constants turned into operands that can be encoded:
+     * Mem becomes MemSize of the given size, an address that does not fit
+     * in a sign extended 32 bit displacement becomes MemBase of the
+     * scratch register or of RIP, and a constant becomes MemBase of RIP.
+     * Only when far is set are such addresses passed on unchanged. If emit fails, everything written for the instruction
+     * is dropped again and the error refers to the operands as given.
+     */
+    fn encode_args<F>(&mut self, instruction: &'static str, args: &[Arg], size: Option<i32>, far: bool, emit: &F) -> Result<(), CodegenError>
+        where F: Fn(&mut Codegen, &[Arg]) -> Result<(), CodegenError>
+    {
+        let pos = self.emit.position();
+        self.check_sizes(instruction, args)?;
+        
+        let mut ops = args.to_vec();
+        let mut rip = None;
+        
+        for op in &mut ops {
+            let (mem, size) = match *op {
+                Arg::Mem(mem) => match size {
+                    Some(size) => (mem, size),
+                    None => return Err(self.error(CodegenErrorKind::InvalidSize, instruction, args))
+                },
+                Arg::MemSize(mem, size) => (mem, size),
+                Arg::Const(offset, size) => {
+                    rip = Some(FixupTarget::Pool(offset));
+                    *op = Arg::MemBase(Reg::RIP, 0, size);
+                    continue;
+                }
+                _ => continue
+            };
+            
+            *op = if far || mem == mem as i32 as i64 {
+                Arg::MemSize(mem, size)
+            } else {
+                match self.scratch {
+                    Some(scratch) if !args.iter().any(|arg| arg.uses(scratch)) => {
+                        self.emit.mov_reg_imm_size(scratch, mem, 8);
+                        Arg::MemBase(scratch, 0, size)
+                    }
+                    _ => {
+                        rip = Some(FixupTarget::Address(mem as u64));
+                        Arg::MemBase(Reg::RIP, 0, size)
+                    }
+                }
+            };
+        }
+        
+        match emit(self, &ops) {
+            Ok(()) => {
+                if let Some(target) = rip {
+                    self.emit.rip_fixup(RelocKind::Rel32, target);
+                }
+                Ok(())
             }
-            (Arg::MemIndex(basereg, disp, indexreg, shift, size), Arg::Reg(sreg)) => {
-                assert_eq!(size, sreg.size());
-                self.emit.alu_memindex_reg_size(opc, basereg, disp, indexreg, shift, sreg.reg(), sreg.size());
+            Err(err) => {
+                self.emit.truncate(pos);
+                Err(CodegenError {
+                    operands: args.iter().map(|arg| arg.operand()).collect(),
+                    offset: self.emit.offset(),
+                    ..err
+                })
             }
-            (Arg::Reg(dreg), Arg::Imm(imm)) => {
-                assert_eq!(dreg.size(), imm.size());
-                self.emit.alu_reg_imm_size(opc, dreg.reg(), imm.as_i32(), imm.size());
+        }
+    }
+    
+    /*
+     * The size of an unsized memory operand, implied by the other operands.
+     */
+    fn implied_size(args: &[Arg]) -> Option<i32> {
+        args.iter().filter_map(|arg| arg.operand().size).next()
+    }
+    
+    /// Pushes 8 bytes. An immediate is sign extended to 64 bits, so an
+    /// unsigned immediate must fit a signed 32 bit one, and 16 bit
+    /// immediates are rejected as they would only push 2 bytes.
+    pub fn push<A: AsArg>(&mut self, arg: A) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg.as_arg()];
+        
+        self.encode("push", &args, Some(8), false, |cg, ops| {
+            match ops[0] {
+                Arg::Imm(imm) if imm.size() == 2 => {
+                    return Err(cg.error(CodegenErrorKind::InvalidSize, "push", ops));
+                }
+                Arg::Imm(imm) => {
+                    let value = cg.imm32("push", ops, imm, 8)?;
+                    cg.emit.push_imm(value);
+                }
+                Arg::MemSize(_, size) | Arg::MemBase(_, _, size) if !(size == 2 || size == 8) => {
+                    return Err(cg.error(CodegenErrorKind::InvalidSize, "push", ops));
+                }
+                Arg::MemSize(mem, size) => cg.emit.push_mem_size(mem as i32, size),
+                Arg::MemBase(basereg, disp, size) => cg.emit.push_membase_size(basereg, disp, size),
+                Arg::MemIndex(basereg, disp, indexreg, shift, size) => cg.emit.push_memindex_size(basereg, disp, indexreg, shift, size),
+                Arg::Reg(reg) => cg.emit.push_reg_size(reg.reg(), reg.size()),
+                _ => return Err(cg.unsupported("push", ops))
             }
-            (Arg::Reg(dreg), Arg::MemBase(basereg, disp, size)) => {
-                assert_eq!(dreg.size(), size);
-                self.emit.alu_reg_membase_size(opc, dreg.reg(), basereg, disp, dreg.size());
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("push({:?})", arg));
+        
+        Ok(())
+    }
+    
+    pub fn pop<A: AsArg>(&mut self, arg: A) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg.as_arg()];
+        
+        self.encode("pop", &args, Some(8), false, |cg, ops| {
+            match ops[0] {
+                Arg::MemSize(_, size) | Arg::MemBase(_, _, size) if !(size == 2 || size == 8) => {
+                    return Err(cg.error(CodegenErrorKind::InvalidSize, "pop", ops));
+                }
+                Arg::MemSize(mem, size) => cg.emit.pop_mem_size(mem as i32, size),
+                Arg::MemBase(basereg, disp, size) => cg.emit.pop_membase_size(basereg, disp, size),
+                Arg::MemIndex(basereg, disp, indexreg, shift, size) => cg.emit.pop_memindex_size(basereg, disp, indexreg, shift, size),
+                Arg::Reg(reg) => cg.emit.pop_reg_size(reg.reg(), reg.size()),
+                _ => return Err(cg.unsupported("pop", ops))
             }
-            (Arg::Reg(dreg), Arg::MemIndex(basereg, disp, indexreg, shift, size)) => {
-                assert_eq!(dreg.size(), size);
-                self.emit.alu_reg_memindex_size(opc, dreg.reg(), basereg, disp, indexreg, shift, dreg.size());
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("pop({:?})", arg));
+        
+        Ok(())
+    }
+    
+    /// Moves the source into the destination. Absolute addresses are only
+    /// encoded in full when the other operand is the accumulator; see
+    /// `set_scratch` for all other cases. Integer immediates are moved at
+    /// the size of the destination, sign extended when they are signed
+    /// and zero extended when they are not, and may not be wider than the
+    /// destination. Floating point immediates are moved as their bit
+    /// pattern. An XMM destination is cleared with
+    /// `xorps` for positive zero and loaded from the constant pool
+    /// otherwise. A 64 bit pattern that does not fit in a sign extended 32
+    /// bit immediate is stored to 8 bytes of memory as two 32 bit halves.
+    pub fn mov<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg()];
+        
+        match self.split_f64(&args)? {
+            Some([low, high]) => {
+                if let Err(err) = self.mov_args(&low).and_then(|()| self.mov_args(&high)) {
+                    self.emit.truncate(pos);
+                    return Err(CodegenError {
+                        operands: args.iter().map(|arg| arg.operand()).collect(),
+                        ..err
+                    });
+                }
             }
-            (Arg::MemBase(basereg, disp, size), Arg::Imm(imm)) => {
-                assert_eq!(size, imm.size());
-                self.emit.alu_membase_imm_size(opc, basereg, disp, imm.as_i32(), imm.size());
+            None => self.mov_args(&args)?
+        }
+        
+        self.record(pos, format_args!("mov({:?}, {:?})", arg1, arg2));
+        
+        Ok(())
+    }
+    
+    /*
+     * Splits a move of an f64 immediate to memory into moves of the two
+     * 32 bit halves, unless the bit pattern fits in a sign extended 32 bit
+     * immediate. The memory operand must be 8 bytes, and the address of
+     * the high half must not overflow.
+     */
+    fn split_f64(&self, args: &[Arg]) -> Result<Option<[[Arg; 2]; 2]>, CodegenError> {
+        let bits = match args[1] {
+            Arg::Imm(Imm::F64(value)) if value.to_bits() as i64 != value.to_bits() as i32 as i64 => value.to_bits(),
+            _ => return Ok(None)
+        };
+        
+        match args[0] {
+            Arg::MemSize(_, size) | Arg::MemBase(_, _, size) | Arg::MemIndex(_, _, _, _, size) if size != 8 => {
+                return Err(self.error(CodegenErrorKind::SizeMismatch, "mov", args));
             }
-            (Arg::MemIndex(basereg, disp, indexreg, shift, size), Arg::Imm(imm)) => {
-                assert_eq!(size, imm.size());
-                self.emit.alu_memindex_imm_size(opc, basereg, disp, indexreg, shift, imm.as_i32(), imm.size());
+            _ => {}
+        }
+        
+        let overflow = || self.error(CodegenErrorKind::UnsupportedOperands, "mov", args);
+        let (low, high) = match args[0] {
+            Arg::Mem(mem) | Arg::MemSize(mem, _) => (
+                Arg::MemSize(mem, 4),
+                Arg::MemSize(mem.checked_add(4).ok_or_else(overflow)?, 4)
+            ),
+            Arg::MemBase(basereg, disp, _) => (
+                Arg::MemBase(basereg, disp, 4),
+                Arg::MemBase(basereg, disp.checked_add(4).ok_or_else(overflow)?, 4)
+            ),
+            Arg::MemIndex(basereg, disp, indexreg, shift, _) => (
+                Arg::MemIndex(basereg, disp, indexreg, shift, 4),
+                Arg::MemIndex(basereg, disp.checked_add(4).ok_or_else(overflow)?, indexreg, shift, 4)
+            ),
+            _ => return Ok(None)
+        };
+        
+        Ok(Some([
+            [low, Arg::Imm(Imm::U32(bits as u32))],
+            [high, Arg::Imm(Imm::U32((bits >> 32) as u32))]
+        ]))
+    }
+    
+    fn mov_args(&mut self, args: &[Arg]) -> Result<(), CodegenError> {
+        let far = args.iter().any(|arg| match *arg {
+            Arg::Reg(reg) => reg.reg() == Reg::RAX,
+            _ => false
+        });
+        
+        self.encode("mov", args, Codegen::implied_size(args), far, |cg, ops| {
+            let src = match (ops[0], ops[1]) {
+                (Arg::Xmm(dreg), Arg::Imm(imm)) => return cg.mov_xmm_imm(dreg, imm, ops),
+                (Arg::Reg(dreg), Arg::Imm(imm)) if imm.float_bits().is_some() && dreg.size() != imm.size() => {
+                    return Err(cg.error(CodegenErrorKind::SizeMismatch, "mov", ops));
+                }
+                (_, Arg::Imm(imm)) => Arg::Imm(imm.float_bits().unwrap_or(imm)),
+                (_, src) => src
+            };
+            
+            match (ops[0], src) {
+                (Arg::Reg(dreg), Arg::Reg(sreg)) => {
+                    cg.check_same_size("mov", ops, dreg.size(), sreg.size())?;
+                    cg.emit.mov_reg_reg_size(dreg.reg(), sreg.reg(), dreg.size());
+                }
+                (Arg::MemSize(mem, size), Arg::Reg(sreg)) => {
+                    cg.check_same_size("mov", ops, size, sreg.size())?;
+                    cg.emit.mov_mem_reg_size(mem, sreg.reg(), sreg.size());
+                }
+                (Arg::MemBase(basereg, disp, size), Arg::Reg(sreg)) => {
+                    cg.check_same_size("mov", ops, size, sreg.size())?;
+                    cg.emit.mov_membase_reg_size(basereg, disp, sreg.reg(), sreg.size());
+                }
+                (Arg::MemIndex(basereg, disp, indexreg, shift, size), Arg::Reg(sreg)) => {
+                    cg.check_same_size("mov", ops, size, sreg.size())?;
+                    cg.emit.mov_memindex_reg_size(basereg, disp, indexreg, shift, sreg.reg(), sreg.size());
+                }
+                (Arg::Reg(dreg), Arg::Imm(imm)) => {
+                    if imm.size() > dreg.size() {
+                        return Err(cg.error(CodegenErrorKind::SizeMismatch, "mov", ops));
+                    }
+                    match imm.as_i64() {
+                        Some(value) => cg.emit.mov_reg_imm_size(dreg.reg(), value, dreg.size()),
+                        None => return Err(cg.error(CodegenErrorKind::ImmOutOfRange, "mov", ops))
+                    }
+                }
+                (Arg::Reg(dreg), Arg::MemSize(mem, size)) => {
+                    cg.check_same_size("mov", ops, dreg.size(), size)?;
+                    cg.emit.mov_reg_mem_size(dreg.reg(), mem, dreg.size());
+                }
+                (Arg::Reg(dreg), Arg::MemBase(basereg, disp, size)) => {
+                    cg.check_same_size("mov", ops, dreg.size(), size)?;
+                    cg.emit.mov_reg_membase_size(dreg.reg(), basereg, disp, dreg.size());
+                }
+                (Arg::Reg(dreg), Arg::MemIndex(basereg, disp, indexreg, shift, size)) => {
+                    cg.check_same_size("mov", ops, dreg.size(), size)?;
+                    cg.emit.mov_reg_memindex_size(dreg.reg(), basereg, disp, indexreg, shift, dreg.size());
+                }
+                (Arg::MemSize(mem, size), Arg::Imm(imm)) => {
+                    let value = cg.imm32("mov", ops, imm, size)?;
+                    cg.emit.mov_mem_imm_size(mem as i32, value, size);
+                }
+                (Arg::MemBase(basereg, disp, size), Arg::Imm(imm)) => {
+                    let value = cg.imm32("mov", ops, imm, size)?;
+                    cg.emit.mov_membase_imm_size(basereg, disp, value, size);
+                }
+                (Arg::MemIndex(basereg, disp, indexreg, shift, size), Arg::Imm(imm)) => {
+                    let value = cg.imm32("mov", ops, imm, size)?;
+                    cg.emit.mov_memindex_imm_size(basereg, disp, indexreg, shift, value, size);
+                }
+                _ => return Err(cg.unsupported("mov", ops))
             }
-            _ => jit_assert!()
+            
+            Ok(())
+        })
+    }
+    
+    /*
+     * Loads a floating point immediate into an XMM register.
+     */
+    fn mov_xmm_imm(&mut self, dreg: Reg, imm: Imm, args: &[Arg]) -> Result<(), CodegenError> {
+        let bits = match imm {
+            Imm::F32(value) => value.to_bits() as u64,
+            Imm::F64(value) => value.to_bits(),
+            _ => return Err(self.unsupported("mov", args))
+        };
+        
+        if bits == 0 {
+            self.emit.xorps_reg_reg(dreg, dreg);
+            return Ok(());
+        }
+        
+        let offset = self.emit.constant(&bits.to_le_bytes()[..imm.size() as usize]);
+        
+        if imm.size() == 4 {
+            self.emit.movss_reg_membase(dreg, Reg::RIP, 0);
+        } else {
+            self.emit.movsd_reg_membase(dreg, Reg::RIP, 0);
         }
+        self.emit.rip_fixup(RelocKind::Rel32, FixupTarget::Pool(offset));
+        
+        Ok(())
     }
     
-    pub fn add<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) {
-        self.alu(AluOp::Add, arg1, arg2);
+    fn alu<A1: AsArg, A2: AsArg>(&mut self, instruction: &'static str, opc: AluOp, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg()];
+        
+        self.encode(instruction, &args, Codegen::implied_size(&args), false, |cg, ops| {
+            match (ops[0], ops[1]) {
+                (Arg::Reg(dreg), Arg::Reg(sreg)) => {
+                    cg.check_same_size(instruction, ops, dreg.size(), sreg.size())?;
+                    cg.emit.alu_reg_reg_size(opc, dreg.reg(), sreg.reg(), dreg.size());
+                }
+                (Arg::MemSize(mem, size), Arg::Reg(sreg)) => {
+                    cg.check_same_size(instruction, ops, size, sreg.size())?;
+                    cg.emit.alu_mem_reg_size(opc, mem as i32, sreg.reg(), sreg.size());
+                }
+                (Arg::MemBase(basereg, disp, size), Arg::Reg(sreg)) => {
+                    cg.check_same_size(instruction, ops, size, sreg.size())?;
+                    cg.emit.alu_membase_reg_size(opc, basereg, disp, sreg.reg(), sreg.size());
+                }
+                (Arg::MemIndex(basereg, disp, indexreg, shift, size), Arg::Reg(sreg)) => {
+                    cg.check_same_size(instruction, ops, size, sreg.size())?;
+                    cg.emit.alu_memindex_reg_size(opc, basereg, disp, indexreg, shift, sreg.reg(), sreg.size());
+                }
+                (Arg::Reg(dreg), Arg::Imm(imm)) => {
+                    let value = cg.alu_imm(instruction, ops, imm, dreg.size())?;
+                    cg.emit.alu_reg_imm_size(opc, dreg.reg(), value, dreg.size());
+                }
+                (Arg::Reg(dreg), Arg::MemSize(mem, size)) => {
+                    cg.check_same_size(instruction, ops, dreg.size(), size)?;
+                    cg.emit.alu_reg_mem_size(opc, dreg.reg(), mem as i32, dreg.size());
+                }
+                (Arg::Reg(dreg), Arg::MemBase(basereg, disp, size)) => {
+                    cg.check_same_size(instruction, ops, dreg.size(), size)?;
+                    cg.emit.alu_reg_membase_size(opc, dreg.reg(), basereg, disp, dreg.size());
+                }
+                (Arg::Reg(dreg), Arg::MemIndex(basereg, disp, indexreg, shift, size)) => {
+                    cg.check_same_size(instruction, ops, dreg.size(), size)?;
+                    cg.emit.alu_reg_memindex_size(opc, dreg.reg(), basereg, disp, indexreg, shift, dreg.size());
+                }
+                (Arg::MemSize(mem, size), Arg::Imm(imm)) => {
+                    let value = cg.alu_imm(instruction, ops, imm, size)?;
+                    cg.emit.alu_mem_imm_size(opc, mem as i32, value, size);
+                }
+                (Arg::MemBase(basereg, disp, size), Arg::Imm(imm)) => {
+                    let value = cg.alu_imm(instruction, ops, imm, size)?;
+                    cg.emit.alu_membase_imm_size(opc, basereg, disp, value, size);
+                }
+                (Arg::MemIndex(basereg, disp, indexreg, shift, size), Arg::Imm(imm)) => {
+                    let value = cg.alu_imm(instruction, ops, imm, size)?;
+                    cg.emit.alu_memindex_imm_size(opc, basereg, disp, indexreg, shift, value, size);
+                }
+                _ => return Err(cg.unsupported(instruction, ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("{}({:?}, {:?})", instruction, arg1, arg2));
+        
+        Ok(())
     }
     
-    pub fn or<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) {
-        self.alu(AluOp::Or, arg1, arg2);
+    pub fn add<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.alu("add", AluOp::Add, arg1, arg2)
     }
     
-    pub fn adc<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) {
-        self.alu(AluOp::Adc, arg1, arg2);
+    pub fn or<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.alu("or", AluOp::Or, arg1, arg2)
     }
     
-    pub fn sbb<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) {
-        self.alu(AluOp::Sbb, arg1, arg2);
+    pub fn adc<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.alu("adc", AluOp::Adc, arg1, arg2)
     }
     
-    pub fn and<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) {
-        self.alu(AluOp::And, arg1, arg2);
+    pub fn sbb<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.alu("sbb", AluOp::Sbb, arg1, arg2)
     }
     
-    pub fn sub<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) {
-        self.alu(AluOp::Sub, arg1, arg2);
+    pub fn and<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.alu("and", AluOp::And, arg1, arg2)
     }
     
-    pub fn xor<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) {
-        self.alu(AluOp::XOr, arg1, arg2);
+    pub fn sub<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.alu("sub", AluOp::Sub, arg1, arg2)
     }
     
-    pub fn cmp<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) {
-        self.alu(AluOp::Cmp, arg1, arg2);
+    pub fn xor<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.alu("xor", AluOp::XOr, arg1, arg2)
     }
     
-    fn shift<A1: AsArg, A2: AsArg>(&mut self, opc: ShiftOp, arg1: A1, arg2: A2) {
-        match (arg1.as_arg(), arg2.as_arg()) {
-            (Arg::Reg(dreg), Arg::Imm(imm))
-                => self.emit.shift_reg_imm_size(opc, dreg.reg(), imm.as_i32(), dreg.size()),
-            (Arg::MemBase(basereg, disp, size), Arg::Imm(imm))
-                => self.emit.shift_membase_imm_size(opc, basereg, disp, imm.as_i32(), size),
-            (Arg::MemIndex(basereg, disp, indexreg, shift, size), Arg::Imm(imm))
-                => self.emit.shift_memindex_imm_size(opc, basereg, disp, indexreg, shift, imm.as_i32(), size),
-            _ => jit_assert!()
-        }
+    pub fn cmp<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.alu("cmp", AluOp::Cmp, arg1, arg2)
     }
     
-    fn shl<A1: AsArg, A2: AsArg>(&mut self, opc: ShiftOp, arg1: A1, arg2: A2) {
-        self.shift(ShiftOp::Shl, arg1, arg2);
+    fn shift<A1: AsArg, A2: AsArg>(&mut self, instruction: &'static str, opc: ShiftOp, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg()];
+        
+        self.encode(instruction, &args, None, false, |cg, ops| {
+            match (ops[0], ops[1]) {
+                (Arg::Reg(dreg), Arg::Imm(imm)) => {
+                    let value = cg.shift_count(instruction, ops, imm)?;
+                    cg.emit.shift_reg_imm_size(opc, dreg.reg(), value, dreg.size());
+                }
+                (Arg::MemSize(mem, size), Arg::Imm(imm)) => {
+                    let value = cg.shift_count(instruction, ops, imm)?;
+                    cg.emit.shift_mem_imm_size(opc, mem as i32, value, size);
+                }
+                (Arg::MemBase(basereg, disp, size), Arg::Imm(imm)) => {
+                    let value = cg.shift_count(instruction, ops, imm)?;
+                    cg.emit.shift_membase_imm_size(opc, basereg, disp, value, size);
+                }
+                (Arg::MemIndex(basereg, disp, indexreg, shift, size), Arg::Imm(imm)) => {
+                    let value = cg.shift_count(instruction, ops, imm)?;
+                    cg.emit.shift_memindex_imm_size(opc, basereg, disp, indexreg, shift, value, size);
+                }
+                (Arg::Reg(dreg), Arg::Reg(SizedReg::CL)) => {
+                    cg.emit.shift_reg_size(opc, dreg.reg(), dreg.size());
+                }
+                (Arg::MemSize(mem, size), Arg::Reg(SizedReg::CL)) => {
+                    cg.emit.shift_mem_size(opc, mem as i32, size);
+                }
+                (Arg::MemBase(basereg, disp, size), Arg::Reg(SizedReg::CL)) => {
+                    cg.emit.shift_membase_size(opc, basereg, disp, size);
+                }
+                (Arg::MemIndex(basereg, disp, indexreg, shift, size), Arg::Reg(SizedReg::CL)) => {
+                    cg.emit.shift_memindex_size(opc, basereg, disp, indexreg, shift, size);
+                }
+                _ => return Err(cg.unsupported(instruction, ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("{}({:?}, {:?})", instruction, arg1, arg2));
+        
+        Ok(())
     }
     
-    fn shr<A1: AsArg, A2: AsArg>(&mut self, opc: ShiftOp, arg1: A1, arg2: A2) {
-        self.shift(ShiftOp::Shr, arg1, arg2);
+    /// Shifts the operand left. The count is either an immediate or `CL`.
+    pub fn shl<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.shift("shl", ShiftOp::Shl, arg1, arg2)
     }
     
-    fn sar<A1: AsArg, A2: AsArg>(&mut self, opc: ShiftOp, arg1: A1, arg2: A2) {
-        self.shift(ShiftOp::Sar, arg1, arg2);
+    /// Shifts the operand right, filling in zero bits.
+    pub fn shr<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.shift("shr", ShiftOp::Shr, arg1, arg2)
     }
     
-    pub fn call<A: AsArg>(&mut self, arg: A) {
-        match arg.as_arg() {
-            Arg::Imm(imm) => self.emit.call_imm(imm.as_i32()),
-            Arg::MemBase(basereg, disp, _) => self.emit.call_membase(basereg, disp),
-            Arg::MemIndex(basereg, disp, indexreg, shift, _) => self.emit.call_memindex(basereg, disp, indexreg, shift),
-            Arg::Reg(reg) => self.emit.call_reg(reg.reg()),
-            _ => jit_assert!()
-        }
+    /// Shifts the operand right, filling in copies of the sign bit.
+    pub fn sar<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.shift("sar", ShiftOp::Sar, arg1, arg2)
+    }
+    
+    /// Rotates the operand left.
+    pub fn rol<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.shift("rol", ShiftOp::Rol, arg1, arg2)
+    }
+    
+    /// Rotates the operand right.
+    pub fn ror<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.shift("ror", ShiftOp::Ror, arg1, arg2)
+    }
+    
+    /// Rotates the operand and the carry flag left.
+    pub fn rcl<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.shift("rcl", ShiftOp::Rcl, arg1, arg2)
+    }
+    
+    /// Rotates the operand and the carry flag right.
+    pub fn rcr<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.shift("rcr", ShiftOp::Rcr, arg1, arg2)
+    }
+    
+    fn double_shift<A1: AsArg, A2: AsArg, A3: AsArg>(&mut self, instruction: &'static str, opc: DoubleShiftOp, arg1: A1, arg2: A2, arg3: A3) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg(), arg3.as_arg()];
+        
+        self.encode(instruction, &args, Codegen::implied_size(&args), false, |cg, ops| {
+            let sreg = match ops[1] {
+                Arg::Reg(sreg) if sreg.size() != 1 => sreg,
+                Arg::Reg(_) => return Err(cg.error(CodegenErrorKind::InvalidSize, instruction, ops)),
+                _ => return Err(cg.unsupported(instruction, ops))
+            };
+            let count = match ops[2] {
+                Arg::Imm(imm) => Some(cg.shift_count(instruction, ops, imm)?),
+                Arg::Reg(SizedReg::CL) => None,
+                _ => return Err(cg.unsupported(instruction, ops))
+            };
+            
+            match (ops[0], count) {
+                (Arg::Reg(dreg), Some(value)) => {
+                    cg.check_same_size(instruction, ops, dreg.size(), sreg.size())?;
+                    cg.emit.double_shift_reg_reg_imm_size(opc, dreg.reg(), sreg.reg(), value, sreg.size());
+                }
+                (Arg::MemSize(mem, size), Some(value)) => {
+                    cg.check_same_size(instruction, ops, size, sreg.size())?;
+                    cg.emit.double_shift_mem_reg_imm_size(opc, mem as i32, sreg.reg(), value, sreg.size());
+                }
+                (Arg::MemBase(basereg, disp, size), Some(value)) => {
+                    cg.check_same_size(instruction, ops, size, sreg.size())?;
+                    cg.emit.double_shift_membase_reg_imm_size(opc, basereg, disp, sreg.reg(), value, sreg.size());
+                }
+                (Arg::MemIndex(basereg, disp, indexreg, shift, size), Some(value)) => {
+                    cg.check_same_size(instruction, ops, size, sreg.size())?;
+                    cg.emit.double_shift_memindex_reg_imm_size(opc, basereg, disp, indexreg, shift, sreg.reg(), value, sreg.size());
+                }
+                (Arg::Reg(dreg), None) => {
+                    cg.check_same_size(instruction, ops, dreg.size(), sreg.size())?;
+                    cg.emit.double_shift_reg_reg_size(opc, dreg.reg(), sreg.reg(), sreg.size());
+                }
+                (Arg::MemSize(mem, size), None) => {
+                    cg.check_same_size(instruction, ops, size, sreg.size())?;
+                    cg.emit.double_shift_mem_reg_size(opc, mem as i32, sreg.reg(), sreg.size());
+                }
+                (Arg::MemBase(basereg, disp, size), None) => {
+                    cg.check_same_size(instruction, ops, size, sreg.size())?;
+                    cg.emit.double_shift_membase_reg_size(opc, basereg, disp, sreg.reg(), sreg.size());
+                }
+                (Arg::MemIndex(basereg, disp, indexreg, shift, size), None) => {
+                    cg.check_same_size(instruction, ops, size, sreg.size())?;
+                    cg.emit.double_shift_memindex_reg_size(opc, basereg, disp, indexreg, shift, sreg.reg(), sreg.size());
+                }
+                _ => return Err(cg.unsupported(instruction, ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("{}({:?}, {:?}, {:?})", instruction, arg1, arg2, arg3));
+        
+        Ok(())
+    }
+    
+    /// Shifts the first operand left, filling in the high bits of the
+    /// register in the second operand. The count is either an immediate or
+    /// `CL`.
+    pub fn shld<A1: AsArg, A2: AsArg, A3: AsArg>(&mut self, arg1: A1, arg2: A2, arg3: A3) -> Result<(), CodegenError> {
+        self.double_shift("shld", DoubleShiftOp::Shld, arg1, arg2, arg3)
+    }
+    
+    /// Shifts the first operand right, filling in the low bits of the
+    /// register in the second operand.
+    pub fn shrd<A1: AsArg, A2: AsArg, A3: AsArg>(&mut self, arg1: A1, arg2: A2, arg3: A3) -> Result<(), CodegenError> {
+        self.double_shift("shrd", DoubleShiftOp::Shrd, arg1, arg2, arg3)
+    }
+    
+    fn unary<A: AsArg>(&mut self, instruction: &'static str, unary: &Unary, arg: A) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg.as_arg()];
+        
+        self.encode(instruction, &args, None, false, |cg, ops| {
+            match ops[0] {
+                Arg::Reg(reg) => (unary.reg)(&mut cg.emit, reg.reg(), reg.size()),
+                Arg::MemSize(mem, size) => (unary.mem)(&mut cg.emit, mem as i32, size),
+                Arg::MemBase(basereg, disp, size) => (unary.membase)(&mut cg.emit, basereg, disp, size),
+                Arg::MemIndex(basereg, disp, indexreg, shift, size) => (unary.memindex)(&mut cg.emit, basereg, disp, indexreg, shift, size),
+                _ => return Err(cg.unsupported(instruction, ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("{}({:?})", instruction, arg));
+        
+        Ok(())
+    }
+    
+    pub fn inc<A: AsArg>(&mut self, arg: A) -> Result<(), CodegenError> {
+        self.unary("inc", &INC, arg)
+    }
+    
+    pub fn dec<A: AsArg>(&mut self, arg: A) -> Result<(), CodegenError> {
+        self.unary("dec", &DEC, arg)
+    }
+    
+    pub fn neg<A: AsArg>(&mut self, arg: A) -> Result<(), CodegenError> {
+        self.unary("neg", &NEG, arg)
+    }
+    
+    pub fn not<A: AsArg>(&mut self, arg: A) -> Result<(), CodegenError> {
+        self.unary("not", &NOT, arg)
+    }
+    
+    /// Unsigned multiply of the accumulator by the operand. The result is
+    /// twice the operand size and is stored in AX, DX:AX, EDX:EAX or
+    /// RDX:RAX.
+    pub fn mul<A: AsArg>(&mut self, arg: A) -> Result<(), CodegenError> {
+        self.unary("mul", &MUL, arg)
+    }
+    
+    /// Signed multiply with a result of twice the operand size, as `mul`.
+    pub fn imul_wide<A: AsArg>(&mut self, arg: A) -> Result<(), CodegenError> {
+        self.unary("imul_wide", &IMUL_WIDE, arg)
+    }
+    
+    /// Unsigned divide of AX, DX:AX, EDX:EAX or RDX:RAX by the operand. The
+    /// quotient is stored in the accumulator and the remainder in DX, or AH
+    /// for byte operands.
+    pub fn div<A: AsArg>(&mut self, arg: A) -> Result<(), CodegenError> {
+        self.unary("div", &DIV, arg)
+    }
+    
+    /// Signed divide, as `div`. The dividend is usually sign extended with
+    /// `cwd`, `cdq` or `cqo` first.
+    pub fn idiv<A: AsArg>(&mut self, arg: A) -> Result<(), CodegenError> {
+        self.unary("idiv", &IDIV, arg)
+    }
+    
+    /// Signed multiply of the destination register by the source; the
+    /// result is truncated to the operand size. Byte sized operands are
+    /// not supported.
+    pub fn imul<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg()];
+        
+        self.encode("imul", &args, Codegen::implied_size(&args), false, |cg, ops| {
+            if let Arg::Reg(dreg) = ops[0] {
+                if dreg.size() == 1 {
+                    return Err(cg.error(CodegenErrorKind::InvalidSize, "imul", ops));
+                }
+            }
+            
+            match (ops[0], ops[1]) {
+                (Arg::Reg(dreg), Arg::Reg(sreg)) => {
+                    cg.check_same_size("imul", ops, dreg.size(), sreg.size())?;
+                    cg.emit.imul_reg_reg_size(dreg.reg(), sreg.reg(), dreg.size());
+                }
+                (Arg::Reg(dreg), Arg::Imm(imm)) => {
+                    let value = cg.imm32("imul", ops, imm, dreg.size())?;
+                    cg.emit.imul_reg_reg_imm_size(dreg.reg(), dreg.reg(), value, dreg.size());
+                }
+                (Arg::Reg(dreg), Arg::MemSize(mem, size)) => {
+                    cg.check_same_size("imul", ops, dreg.size(), size)?;
+                    cg.emit.imul_reg_mem_size(dreg.reg(), mem as i32, dreg.size());
+                }
+                (Arg::Reg(dreg), Arg::MemBase(basereg, disp, size)) => {
+                    cg.check_same_size("imul", ops, dreg.size(), size)?;
+                    cg.emit.imul_reg_membase_size(dreg.reg(), basereg, disp, dreg.size());
+                }
+                (Arg::Reg(dreg), Arg::MemIndex(basereg, disp, indexreg, shift, size)) => {
+                    cg.check_same_size("imul", ops, dreg.size(), size)?;
+                    cg.emit.imul_reg_memindex_size(dreg.reg(), basereg, disp, indexreg, shift, dreg.size());
+                }
+                _ => return Err(cg.unsupported("imul", ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("imul({:?}, {:?})", arg1, arg2));
+        
+        Ok(())
+    }
+    
+    /// Signed multiply of the source by an immediate into the destination
+    /// register. Byte sized operands are not supported.
+    pub fn imul_imm<A1: AsArg, A2: AsArg, A3: AsArg>(&mut self, arg1: A1, arg2: A2, arg3: A3) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg(), arg3.as_arg()];
+        
+        self.encode("imul", &args, Codegen::implied_size(&args), false, |cg, ops| {
+            let (dreg, imm) = match (ops[0], ops[2]) {
+                (Arg::Reg(dreg), Arg::Imm(imm)) if dreg.size() != 1 => (dreg, imm),
+                (Arg::Reg(_), Arg::Imm(_)) => return Err(cg.error(CodegenErrorKind::InvalidSize, "imul", ops)),
+                _ => return Err(cg.unsupported("imul", ops))
+            };
+            let value = cg.imm32("imul", ops, imm, dreg.size())?;
+            
+            match ops[1] {
+                Arg::Reg(sreg) => {
+                    cg.check_same_size("imul", ops, dreg.size(), sreg.size())?;
+                    cg.emit.imul_reg_reg_imm_size(dreg.reg(), sreg.reg(), value, dreg.size());
+                }
+                Arg::MemSize(mem, size) => {
+                    cg.check_same_size("imul", ops, dreg.size(), size)?;
+                    cg.emit.imul_reg_mem_imm_size(dreg.reg(), mem as i32, value, dreg.size());
+                }
+                Arg::MemBase(basereg, disp, size) => {
+                    cg.check_same_size("imul", ops, dreg.size(), size)?;
+                    cg.emit.imul_reg_membase_imm_size(dreg.reg(), basereg, disp, value, dreg.size());
+                }
+                Arg::MemIndex(basereg, disp, indexreg, shift, size) => {
+                    cg.check_same_size("imul", ops, dreg.size(), size)?;
+                    cg.emit.imul_reg_memindex_imm_size(dreg.reg(), basereg, disp, indexreg, shift, value, dreg.size());
+                }
+                _ => return Err(cg.unsupported("imul", ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("imul_imm({:?}, {:?}, {:?})", arg1, arg2, arg3));
+        
+        Ok(())
+    }
+    
+    /// Sets the flags from the bitwise and of the operands, like `and`
+    /// without storing the result.
+    pub fn test<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg()];
+        
+        self.encode("test", &args, Codegen::implied_size(&args), false, |cg, ops| {
+            let (dst, src) = match (ops[0], ops[1]) {
+                (Arg::Reg(reg), mem) if mem.is_mem() => (mem, Arg::Reg(reg)),
+                (dst, src) => (dst, src)
+            };
+            
+            match (dst, src) {
+                (Arg::Reg(dreg), Arg::Reg(sreg)) => {
+                    cg.check_same_size("test", ops, dreg.size(), sreg.size())?;
+                    cg.emit.test_reg_reg_size(dreg.reg(), sreg.reg(), dreg.size());
+                }
+                (Arg::MemSize(mem, size), Arg::Reg(sreg)) => {
+                    cg.check_same_size("test", ops, size, sreg.size())?;
+                    cg.emit.test_mem_reg_size(mem as i32, sreg.reg(), sreg.size());
+                }
+                (Arg::MemBase(basereg, disp, size), Arg::Reg(sreg)) => {
+                    cg.check_same_size("test", ops, size, sreg.size())?;
+                    cg.emit.test_membase_reg_size(basereg, disp, sreg.reg(), sreg.size());
+                }
+                (Arg::MemIndex(basereg, disp, indexreg, shift, size), Arg::Reg(sreg)) => {
+                    cg.check_same_size("test", ops, size, sreg.size())?;
+                    cg.emit.test_memindex_reg_size(basereg, disp, indexreg, shift, sreg.reg(), sreg.size());
+                }
+                (Arg::Reg(dreg), Arg::Imm(imm)) => {
+                    let value = cg.imm32("test", ops, imm, dreg.size())?;
+                    cg.emit.test_reg_imm_size(dreg.reg(), value, dreg.size());
+                }
+                (Arg::MemSize(mem, size), Arg::Imm(imm)) => {
+                    let value = cg.imm32("test", ops, imm, size)?;
+                    cg.emit.test_mem_imm_size(mem as i32, value, size);
+                }
+                (Arg::MemBase(basereg, disp, size), Arg::Imm(imm)) => {
+                    let value = cg.imm32("test", ops, imm, size)?;
+                    cg.emit.test_membase_imm_size(basereg, disp, value, size);
+                }
+                (Arg::MemIndex(basereg, disp, indexreg, shift, size), Arg::Imm(imm)) => {
+                    let value = cg.imm32("test", ops, imm, size)?;
+                    cg.emit.test_memindex_imm_size(basereg, disp, indexreg, shift, value, size);
+                }
+                _ => return Err(cg.unsupported("test", ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("test({:?}, {:?})", arg1, arg2));
+        
+        Ok(())
+    }
+    
+    /// Loads the address of the memory operand into the register. Byte
+    /// sized registers are not supported.
+    pub fn lea<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg()];
+        
+        self.encode("lea", &args, Codegen::implied_size(&args), false, |cg, ops| {
+            let dreg = match ops[0] {
+                Arg::Reg(dreg) if dreg.size() != 1 => dreg,
+                Arg::Reg(_) => return Err(cg.error(CodegenErrorKind::InvalidSize, "lea", ops)),
+                _ => return Err(cg.unsupported("lea", ops))
+            };
+            
+            match ops[1] {
+                Arg::MemSize(mem, _) => cg.emit.lea_mem_size(dreg.reg(), mem as i32, dreg.size()),
+                Arg::MemBase(basereg, disp, _) => cg.emit.lea_membase_size(dreg.reg(), basereg, disp, dreg.size()),
+                Arg::MemIndex(basereg, disp, indexreg, shift, _) => cg.emit.lea_memindex_size(dreg.reg(), basereg, disp, indexreg, shift, dreg.size()),
+                _ => return Err(cg.unsupported("lea", ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("lea({:?}, {:?})", arg1, arg2));
+        
+        Ok(())
+    }
+    
+    /*
+     * movsx and movzx. The emitters are chosen by the size of the source;
+     * extend returns None for source sizes the instruction cannot encode.
+     */
+    fn extend<A1: AsArg, A2: AsArg>(&mut self, instruction: &'static str, extend: fn(i32) -> Option<&'static Unary2>, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg()];
+        
+        self.encode(instruction, &args, None, false, |cg, ops| {
+            let (dreg, size) = match (ops[0], ops[1].operand().size) {
+                (Arg::Reg(dreg), Some(size)) => (dreg, size),
+                (Arg::Reg(_), None) => return Err(cg.error(CodegenErrorKind::InvalidSize, instruction, ops)),
+                _ => return Err(cg.unsupported(instruction, ops))
+            };
+            let ext = match extend(size) {
+                Some(ext) if size < dreg.size() => ext,
+                _ => return Err(cg.error(CodegenErrorKind::InvalidSize, instruction, ops))
+            };
+            
+            match ops[1] {
+                Arg::Reg(sreg) => (ext.reg)(&mut cg.emit, dreg.reg(), sreg.reg(), dreg.size()),
+                Arg::MemSize(mem, _) => (ext.mem)(&mut cg.emit, dreg.reg(), mem as i32, dreg.size()),
+                Arg::MemBase(basereg, disp, _) => (ext.membase)(&mut cg.emit, dreg.reg(), basereg, disp, dreg.size()),
+                Arg::MemIndex(basereg, disp, indexreg, shift, _) => {
+                    (ext.memindex)(&mut cg.emit, dreg.reg(), basereg, disp, indexreg, shift, dreg.size());
+                }
+                _ => return Err(cg.unsupported(instruction, ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("{}({:?}, {:?})", instruction, arg1, arg2));
+        
+        Ok(())
+    }
+    
+    /// Sign extends the source into the larger destination register. The
+    /// size of a memory source must be given with MemSize, or is the size
+    /// of the base register of MemBase and MemIndex.
+    pub fn movsx<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.extend("movsx", |size| match size {
+            1 => Some(&MOVSX8),
+            2 => Some(&MOVSX16),
+            4 => Some(&MOVSX32),
+            _ => None
+        }, arg1, arg2)
+    }
+    
+    /// Zero extends the source into the larger destination register, as
+    /// `movsx`. A 32 bit source is moved with a 32 bit `mov`, which clears
+    /// the upper half of the destination.
+    pub fn movzx<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.extend("movzx", |size| match size {
+            1 => Some(&MOVZX8),
+            2 => Some(&MOVZX16),
+            4 => Some(&MOVZX32),
+            _ => None
+        }, arg1, arg2)
+    }
+    
+    /// Exchanges two registers of the same size.
+    pub fn xchg<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg()];
+        
+        self.encode("xchg", &args, None, false, |cg, ops| {
+            match (ops[0], ops[1]) {
+                (Arg::Reg(dreg), Arg::Reg(sreg)) => {
+                    cg.check_same_size("xchg", ops, dreg.size(), sreg.size())?;
+                    cg.emit.xchg_reg_reg_size(dreg.reg(), sreg.reg(), dreg.size());
+                }
+                _ => return Err(cg.unsupported("xchg", ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("xchg({:?}, {:?})", arg1, arg2));
+        
+        Ok(())
+    }
+    
+    /// Sign extends AX into DX:AX.
+    pub fn cwd(&mut self) {
+        let pos = self.emit.position();
+        self.note(Note::Inst("cwd"));
+        self.emit.cwd();
+        self.record(pos, format_args!("cwd()"));
+    }
+    
+    /// Sign extends EAX into EDX:EAX.
+    pub fn cdq(&mut self) {
+        let pos = self.emit.position();
+        self.note(Note::Inst("cdq"));
+        self.emit.cdq();
+        self.record(pos, format_args!("cdq()"));
+    }
+    
+    /// Sign extends RAX into RDX:RAX.
+    pub fn cqo(&mut self) {
+        let pos = self.emit.position();
+        self.note(Note::Inst("cqo"));
+        self.emit.cqo();
+        self.record(pos, format_args!("cqo()"));
+    }
+    
+    /// Calls a function. An immediate is the absolute address of the
+    /// function. The call is direct when the function is within reach of
+    /// a rel32 displacement from where the code ends up, and goes through
+    /// a veneer at the end of the code otherwise.
+    pub fn call<A: AsArg>(&mut self, arg: A) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg.as_arg()];
+        
+        self.encode("call", &args, Some(8), false, |cg, ops| {
+            match ops[0] {
+                Arg::Imm(imm) => {
+                    match imm.as_i64() {
+                        Some(address) if imm.size() >= 4 => cg.emit.call_target(FixupTarget::Address(address as u64)),
+                        _ => return Err(cg.error(CodegenErrorKind::InvalidSize, "call", ops))
+                    }
+                }
+                Arg::MemSize(_, size) | Arg::MemBase(_, _, size) if size != 8 => {
+                    return Err(cg.error(CodegenErrorKind::InvalidSize, "call", ops));
+                }
+                Arg::MemSize(mem, _) => cg.emit.call_mem(mem as i32),
+                Arg::MemBase(basereg, disp, _) => cg.emit.call_membase(basereg, disp),
+                Arg::MemIndex(basereg, disp, indexreg, shift, _) => cg.emit.call_memindex(basereg, disp, indexreg, shift),
+                Arg::Reg(reg) => cg.emit.call_reg(reg.reg()),
+                _ => return Err(cg.unsupported("call", ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("call({:?})", arg));
+        
+        Ok(())
     }
     
     pub fn ret(&mut self) {
+        let pos = self.emit.position();
+        self.note(Note::Ret);
         self.emit.ret();
+        self.record(pos, format_args!("ret()"));
+    }
+    
+    /// Marks the code generated from here on as coming from a line of a
+    /// source file. The lines are kept in the `CodeBuffer` and reported
+    /// to profilers.
+    pub fn set_line(&mut self, file: &str, line: u32) {
+        let pos = self.emit.position();
+        self.emit.line(file, line);
+        self.record(pos, format_args!("set_line({:?}, {})", file, line));
+    }
+    
+    /// Creates a new label that can be used as a branch target before it
+    /// is bound.
+    pub fn new_label(&mut self) -> Label {
+        self.emit.new_label()
+    }
+    
+    /// Binds the label to the current position.
+    pub fn bind(&mut self, label: Label) {
+        let pos = self.emit.position();
+        self.note(Note::Bind(label));
+        self.emit.bind(label);
+        self.record(pos, format_args!("bind({:?})", label));
+    }
+    
+    /// Loads the address of the label with a RIP relative `lea`, which
+    /// does not need a relocation.
+    pub fn lea_label(&mut self, dst: SizedReg, label: Label) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [dst.as_arg()];
+        
+        self.encode("lea_label", &args, Some(8), false, |cg, ops| {
+            match ops[0] {
+                Arg::Reg(reg) if reg.size() == 8 => {
+                    cg.emit.lea_membase_size(reg.reg(), Reg::RIP, 0, 8);
+                    cg.emit.rip_fixup(RelocKind::Rel32, FixupTarget::Label(label));
+                }
+                _ => return Err(cg.unsupported("lea_label", ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("lea_label({:?}, {:?})", dst, label));
+        Ok(())
+    }
+    
+    /// Loads the absolute address of the label with a 64 bit immediate,
+    /// which is relocated when the code is installed.
+    pub fn mov_label(&mut self, dst: SizedReg, label: Label) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [dst.as_arg()];
+        
+        self.encode("mov_label", &args, Some(8), false, |cg, ops| {
+            match ops[0] {
+                Arg::Reg(reg) if reg.size() == 8 => cg.emit.mov_reg_target(reg.reg(), FixupTarget::Label(label)),
+                _ => return Err(cg.unsupported("mov_label", ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("mov_label({:?}, {:?})", dst, label));
+        Ok(())
+    }
+    
+    /// Loads the address of a symbol, which is resolved when the code is
+    /// installed.
+    pub fn mov_symbol(&mut self, dst: SizedReg, name: &str) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [dst.as_arg()];
+        
+        self.encode("mov_symbol", &args, Some(8), false, |cg, ops| {
+            match ops[0] {
+                Arg::Reg(reg) if reg.size() == 8 => cg.emit.mov_reg_target(reg.reg(), FixupTarget::Symbol(name.to_string())),
+                _ => return Err(cg.unsupported("mov_symbol", ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("mov_symbol({:?}, {:?})", dst, name));
+        Ok(())
+    }
+    
+    /// Calls a symbol, which is resolved when the code is installed. Like
+    /// calls to absolute addresses, the call goes through a veneer when
+    /// the symbol is out of reach.
+    pub fn call_symbol(&mut self, name: &str) {
+        let pos = self.emit.position();
+        self.note(Note::Inst("call"));
+        self.emit.call_target(FixupTarget::Symbol(name.to_string()));
+        self.record(pos, format_args!("call_symbol({:?})", name));
+    }
+    
+    pub fn jmp(&mut self, label: Label) {
+        let pos = self.emit.position();
+        self.note(Note::Jump(label));
+        self.emit.jmp_label(label);
+        self.record(pos, format_args!("jmp({:?})", label));
+    }
+    
+    /// Jumps to the label if the condition is met.
+    pub fn jcc(&mut self, cond: Cond, label: Label) {
+        let pos = self.emit.position();
+        self.note(Note::Branch(label));
+        self.emit.branch_label(cond, label);
+        self.record(pos, format_args!("jcc({:?}, {:?})", cond, label));
+    }
+    
+    /// Sets the byte operand to 1 if the condition is met and to 0 otherwise.
+    pub fn setcc<A: AsArg>(&mut self, cond: Cond, arg: A) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg.as_arg()];
+        
+        self.encode("setcc", &args, Some(1), false, |cg, ops| {
+            match ops[0] {
+                Arg::Reg(reg) => {
+                    if reg.size() != 1 {
+                        return Err(cg.error(CodegenErrorKind::InvalidSize, "setcc", ops));
+                    }
+                    cg.emit.set_reg(cond, reg.reg());
+                }
+                Arg::MemSize(_, size) if size != 1 => {
+                    return Err(cg.error(CodegenErrorKind::InvalidSize, "setcc", ops));
+                }
+                Arg::MemSize(mem, _) => cg.emit.set_mem(cond, mem as i32),
+                Arg::MemBase(basereg, disp, _) => cg.emit.set_membase(cond, basereg, disp),
+                Arg::MemIndex(basereg, disp, indexreg, shift, _) => cg.emit.set_memindex(cond, basereg, disp, indexreg, shift),
+                _ => return Err(cg.unsupported("setcc", ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("setcc({:?}, {:?})", cond, arg));
+        
+        Ok(())
+    }
+    
+    /// Moves the source into the destination register if the condition is
+    /// met. Byte sized operands are not supported.
+    pub fn cmov<A1: AsArg, A2: AsArg>(&mut self, cond: Cond, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg()];
+        
+        self.encode("cmov", &args, Codegen::implied_size(&args), false, |cg, ops| {
+            if let Arg::Reg(dreg) = ops[0] {
+                if dreg.size() == 1 {
+                    return Err(cg.error(CodegenErrorKind::InvalidSize, "cmov", ops));
+                }
+            }
+            
+            match (ops[0], ops[1]) {
+                (Arg::Reg(dreg), Arg::Reg(sreg)) => {
+                    cg.check_same_size("cmov", ops, dreg.size(), sreg.size())?;
+                    cg.emit.cmov_reg_reg_size(cond, dreg.reg(), sreg.reg(), dreg.size());
+                }
+                (Arg::Reg(dreg), Arg::MemSize(mem, size)) => {
+                    cg.check_same_size("cmov", ops, dreg.size(), size)?;
+                    cg.emit.cmov_reg_mem_size(cond, dreg.reg(), mem as i32, dreg.size());
+                }
+                (Arg::Reg(dreg), Arg::MemBase(basereg, disp, size)) => {
+                    cg.check_same_size("cmov", ops, dreg.size(), size)?;
+                    cg.emit.cmov_reg_membase_size(cond, dreg.reg(), basereg, disp, dreg.size());
+                }
+                (Arg::Reg(dreg), Arg::MemIndex(basereg, disp, indexreg, shift, size)) => {
+                    cg.check_same_size("cmov", ops, dreg.size(), size)?;
+                    cg.emit.cmov_reg_memindex_size(cond, dreg.reg(), basereg, disp, indexreg, shift, dreg.size());
+                }
+                _ => return Err(cg.unsupported("cmov", ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("cmov({:?}, {:?}, {:?})", cond, arg1, arg2));
+        
+        Ok(())
+    }
+    
+    /*
+     * Emits an SSE instruction with the register r in the reg field and
+     * rm as the register or memory operand. p1 is the mandatory prefix, or
+     * 0 if there is none, and opc the opcode byte that follows 0x0f. A
+     * size of 8 sets REX.W.
+     */
+    fn sse_emit(&mut self, p1: u8, opc: u8, r: Reg, rm: Arg, size: i32) {
+        match (p1, rm) {
+            (0, Arg::Xmm(reg)) => self.emit.xmm2_reg_reg(0x0f, opc, r, reg),
+            (0, Arg::MemSize(mem, _)) => self.emit.xmm2_reg_mem(0x0f, opc, r, mem as i32),
+            (0, Arg::MemBase(basereg, disp, _)) => self.emit.xmm2_reg_membase(0x0f, opc, r, basereg, disp),
+            (0, Arg::MemIndex(basereg, disp, indexreg, shift, _)) => self.emit.xmm2_reg_memindex(0x0f, opc, r, basereg, disp, indexreg, shift),
+            (_, Arg::Xmm(reg)) => self.emit.p1_xmm2_reg_reg_size(p1, 0x0f, opc, r, reg, size),
+            (_, Arg::Reg(reg)) => self.emit.p1_xmm2_reg_reg_size(p1, 0x0f, opc, r, reg.reg(), size),
+            (_, Arg::MemSize(mem, _)) => self.emit.p1_xmm2_reg_mem_size(p1, 0x0f, opc, r, mem as i32, size),
+            (_, Arg::MemBase(basereg, disp, _)) => self.emit.p1_xmm2_reg_membase_size(p1, 0x0f, opc, r, basereg, disp, size),
+            (_, Arg::MemIndex(basereg, disp, indexreg, shift, _)) => {
+                self.emit.p1_xmm2_reg_memindex_size(p1, 0x0f, opc, r, basereg, disp, indexreg, shift, size);
+            }
+            _ => panic!("not an SSE operand")
+        }
+    }
+    
+    /*
+     * Checks that a MemSize operand has the size the instruction reads or
+     * writes. The size of other memory operands is not checked.
+     */
+    fn check_mem_size(&self, instruction: &'static str, args: &[Arg], size: i32) -> Result<(), CodegenError> {
+        for arg in args {
+            if let Arg::MemSize(_, mem_size) = *arg {
+                self.check_same_size(instruction, args, mem_size, size)?;
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /*
+     * An SSE instruction with an XMM destination and an XMM or memory
+     * source of the given size.
+     */
+    fn sse<A1: AsArg, A2: AsArg>(&mut self, instruction: &'static str, p1: u8, opc: u8, size: i32, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg()];
+        
+        self.encode(instruction, &args, Some(size), false, |cg, ops| {
+            cg.check_mem_size(instruction, ops, size)?;
+            
+            match (ops[0], ops[1]) {
+                (Arg::Xmm(dreg), src) if src.is_xmm_rm() => cg.sse_emit(p1, opc, dreg, src, 0),
+                _ => return Err(cg.unsupported(instruction, ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("{}({:?}, {:?})", instruction, arg1, arg2));
+        
+        Ok(())
+    }
+    
+    /*
+     * An SSE move between XMM registers and memory of the given size.
+     * opc loads the XMM register; the opcode that follows it stores it.
+     */
+    fn sse_mov<A1: AsArg, A2: AsArg>(&mut self, instruction: &'static str, p1: u8, opc: u8, size: i32, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg()];
+        
+        self.encode(instruction, &args, Some(size), false, |cg, ops| {
+            cg.check_mem_size(instruction, ops, size)?;
+            
+            match (ops[0], ops[1]) {
+                (Arg::Xmm(dreg), src) if src.is_xmm_rm() => cg.sse_emit(p1, opc, dreg, src, 0),
+                (dst, Arg::Xmm(sreg)) if dst.is_mem() => cg.sse_emit(p1, opc + 1, sreg, dst, 0),
+                _ => return Err(cg.unsupported(instruction, ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("{}({:?}, {:?})", instruction, arg1, arg2));
+        
+        Ok(())
+    }
+    
+    /*
+     * movd and movq: moves between XMM registers and general purpose
+     * registers or memory of the given size.
+     */
+    fn sse_movx<A1: AsArg, A2: AsArg>(&mut self, instruction: &'static str, size: i32, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg()];
+        
+        self.encode(instruction, &args, Some(size), false, |cg, ops| {
+            cg.check_mem_size(instruction, ops, size)?;
+            
+            for op in ops {
+                if let Arg::Reg(reg) = *op {
+                    cg.check_same_size(instruction, ops, reg.size(), size)?;
+                }
+            }
+            
+            match (ops[0], ops[1]) {
+                (Arg::Xmm(dreg), src) if src.is_gpr_rm() => cg.sse_emit(0x66, 0x6e, dreg, src, size),
+                (dst, Arg::Xmm(sreg)) if dst.is_gpr_rm() => cg.sse_emit(0x66, 0x7e, sreg, dst, size),
+                _ => return Err(cg.unsupported(instruction, ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("{}({:?}, {:?})", instruction, arg1, arg2));
+        
+        Ok(())
+    }
+    
+    /*
+     * Conversion of a 32 or 64 bit integer to a float. The integer size is
+     * that of the source operand.
+     */
+    fn sse_cvtsi<A1: AsArg, A2: AsArg>(&mut self, instruction: &'static str, p1: u8, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg()];
+        
+        self.encode(instruction, &args, None, false, |cg, ops| {
+            match (ops[0], ops[1]) {
+                (Arg::Xmm(dreg), src) if src.is_gpr_rm() => {
+                    let size = src.operand().size.unwrap_or(0);
+                    if !(size == 4 || size == 8) {
+                        return Err(cg.error(CodegenErrorKind::InvalidSize, instruction, ops));
+                    }
+                    cg.sse_emit(p1, 0x2a, dreg, src, size);
+                }
+                _ => return Err(cg.unsupported(instruction, ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("{}({:?}, {:?})", instruction, arg1, arg2));
+        
+        Ok(())
+    }
+    
+    /*
+     * Conversion of a float of the given size to a 32 or 64 bit integer.
+     * The integer size is that of the destination register.
+     */
+    fn sse_cvt2si<A1: AsArg, A2: AsArg>(&mut self, instruction: &'static str, p1: u8, opc: u8, size: i32, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg()];
+        
+        self.encode(instruction, &args, Some(size), false, |cg, ops| {
+            cg.check_mem_size(instruction, ops, size)?;
+            
+            match (ops[0], ops[1]) {
+                (Arg::Reg(dreg), src) if src.is_xmm_rm() => {
+                    if !(dreg.size() == 4 || dreg.size() == 8) {
+                        return Err(cg.error(CodegenErrorKind::InvalidSize, instruction, ops));
+                    }
+                    cg.sse_emit(p1, opc, dreg.reg(), src, dreg.size());
+                }
+                _ => return Err(cg.unsupported(instruction, ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("{}({:?}, {:?})", instruction, arg1, arg2));
+        
+        Ok(())
+    }
+    
+    /*
+     * roundss and roundsd.
+     */
+    fn sse_round<A1: AsArg, A2: AsArg>(&mut self, instruction: &'static str, size: i32, arg1: A1, arg2: A2, mode: Round) -> Result<(), CodegenError> {
+        let pos = self.emit.position();
+        let args = [arg1.as_arg(), arg2.as_arg()];
+        let double = size == 8;
+        let imm = mode as i32;
+        
+        self.encode(instruction, &args, Some(size), false, |cg, ops| {
+            cg.check_mem_size(instruction, ops, size)?;
+            
+            match (ops[0], ops[1], double) {
+                (Arg::Xmm(dreg), Arg::Xmm(sreg), false) => cg.emit.roundss_reg_reg(dreg, sreg, imm),
+                (Arg::Xmm(dreg), Arg::Xmm(sreg), true) => cg.emit.roundsd_reg_reg(dreg, sreg, imm),
+                (Arg::Xmm(dreg), Arg::MemSize(mem, _), false) => cg.emit.roundss_reg_mem(dreg, mem as i32, imm),
+                (Arg::Xmm(dreg), Arg::MemSize(mem, _), true) => cg.emit.roundsd_reg_mem(dreg, mem as i32, imm),
+                (Arg::Xmm(dreg), Arg::MemBase(basereg, disp, _), false) => cg.emit.roundss_reg_membase(dreg, basereg, disp, imm),
+                (Arg::Xmm(dreg), Arg::MemBase(basereg, disp, _), true) => cg.emit.roundsd_reg_membase(dreg, basereg, disp, imm),
+                (Arg::Xmm(dreg), Arg::MemIndex(basereg, disp, indexreg, shift, _), false) => cg.emit.roundss_reg_memindex(dreg, basereg, disp, indexreg, shift, imm),
+                (Arg::Xmm(dreg), Arg::MemIndex(basereg, disp, indexreg, shift, _), true) => cg.emit.roundsd_reg_memindex(dreg, basereg, disp, indexreg, shift, imm),
+                _ => return Err(cg.unsupported(instruction, ops))
+            }
+            
+            Ok(())
+        })?;
+        
+        self.record(pos, format_args!("{}({:?}, {:?}, {:?})", instruction, arg1, arg2, mode));
+        
+        Ok(())
+    }
+    
+    pub fn addss<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("addss", 0xf3, 0x58, 4, arg1, arg2)
+    }
+    
+    pub fn addsd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("addsd", 0xf2, 0x58, 8, arg1, arg2)
+    }
+    
+    pub fn subss<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("subss", 0xf3, 0x5c, 4, arg1, arg2)
+    }
+    
+    pub fn subsd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("subsd", 0xf2, 0x5c, 8, arg1, arg2)
+    }
+    
+    pub fn mulss<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("mulss", 0xf3, 0x59, 4, arg1, arg2)
+    }
+    
+    pub fn mulsd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("mulsd", 0xf2, 0x59, 8, arg1, arg2)
+    }
+    
+    pub fn divss<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("divss", 0xf3, 0x5e, 4, arg1, arg2)
+    }
+    
+    pub fn divsd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("divsd", 0xf2, 0x5e, 8, arg1, arg2)
+    }
+    
+    pub fn sqrtss<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("sqrtss", 0xf3, 0x51, 4, arg1, arg2)
+    }
+    
+    pub fn sqrtsd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("sqrtsd", 0xf2, 0x51, 8, arg1, arg2)
+    }
+    
+    pub fn minss<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("minss", 0xf3, 0x5d, 4, arg1, arg2)
+    }
+    
+    pub fn minsd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("minsd", 0xf2, 0x5d, 8, arg1, arg2)
+    }
+    
+    pub fn maxss<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("maxss", 0xf3, 0x5f, 4, arg1, arg2)
+    }
+    
+    pub fn maxsd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("maxsd", 0xf2, 0x5f, 8, arg1, arg2)
+    }
+    
+    /// Bitwise and of all 128 bits. With a constant pool mask this clears
+    /// sign bits.
+    pub fn andps<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("andps", 0, 0x54, 16, arg1, arg2)
+    }
+    
+    pub fn andpd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("andpd", 0x66, 0x54, 16, arg1, arg2)
+    }
+    
+    pub fn orps<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("orps", 0, 0x56, 16, arg1, arg2)
+    }
+    
+    pub fn orpd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("orpd", 0x66, 0x56, 16, arg1, arg2)
+    }
+    
+    /// Bitwise exclusive or of all 128 bits. With a constant pool mask this
+    /// flips sign bits.
+    pub fn xorps<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("xorps", 0, 0x57, 16, arg1, arg2)
+    }
+    
+    pub fn xorpd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("xorpd", 0x66, 0x57, 16, arg1, arg2)
+    }
+    
+    /// Compares two floats and sets ZF, PF and CF; PF is set if either is
+    /// a NaN.
+    pub fn ucomiss<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("ucomiss", 0, 0x2e, 4, arg1, arg2)
+    }
+    
+    pub fn ucomisd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("ucomisd", 0x66, 0x2e, 8, arg1, arg2)
+    }
+    
+    /// Like `ucomiss`, but quiet NaNs also raise an invalid operation
+    /// exception.
+    pub fn comiss<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("comiss", 0, 0x2f, 4, arg1, arg2)
+    }
+    
+    pub fn comisd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("comisd", 0x66, 0x2f, 8, arg1, arg2)
+    }
+    
+    pub fn cvtss2sd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("cvtss2sd", 0xf3, 0x5a, 4, arg1, arg2)
+    }
+    
+    pub fn cvtsd2ss<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse("cvtsd2ss", 0xf2, 0x5a, 8, arg1, arg2)
+    }
+    
+    /// Converts a 32 or 64 bit integer register or memory operand to a
+    /// float. Memory operands need an explicit size.
+    pub fn cvtsi2ss<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse_cvtsi("cvtsi2ss", 0xf3, arg1, arg2)
+    }
+    
+    pub fn cvtsi2sd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse_cvtsi("cvtsi2sd", 0xf2, arg1, arg2)
+    }
+    
+    /// Converts a float to a 32 or 64 bit integer register, rounding as
+    /// set in MXCSR.
+    pub fn cvtss2si<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse_cvt2si("cvtss2si", 0xf3, 0x2d, 4, arg1, arg2)
+    }
+    
+    pub fn cvtsd2si<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse_cvt2si("cvtsd2si", 0xf2, 0x2d, 8, arg1, arg2)
+    }
+    
+    /// Converts a float to a 32 or 64 bit integer register, truncating.
+    pub fn cvttss2si<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse_cvt2si("cvttss2si", 0xf3, 0x2c, 4, arg1, arg2)
+    }
+    
+    pub fn cvttsd2si<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse_cvt2si("cvttsd2si", 0xf2, 0x2c, 8, arg1, arg2)
+    }
+    
+    /// Rounds a float to an integral value. Needs SSE 4.1.
+    pub fn roundss<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2, mode: Round) -> Result<(), CodegenError> {
+        self.sse_round("roundss", 4, arg1, arg2, mode)
+    }
+    
+    pub fn roundsd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2, mode: Round) -> Result<(), CodegenError> {
+        self.sse_round("roundsd", 8, arg1, arg2, mode)
+    }
+    
+    /// Moves a float between XMM registers or between an XMM register and
+    /// memory. Loading from memory clears the rest of the register.
+    pub fn movss<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse_mov("movss", 0xf3, 0x10, 4, arg1, arg2)
+    }
+    
+    pub fn movsd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse_mov("movsd", 0xf2, 0x10, 8, arg1, arg2)
+    }
+    
+    /// Moves all 128 bits. Memory operands must be 16 byte aligned.
+    pub fn movaps<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse_mov("movaps", 0, 0x28, 16, arg1, arg2)
+    }
+    
+    /// Moves all 128 bits without alignment requirement.
+    pub fn movups<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse_mov("movups", 0, 0x10, 16, arg1, arg2)
+    }
+    
+    /// Moves 32 bits between an XMM register and a general purpose
+    /// register or memory.
+    pub fn movd<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse_movx("movd", 4, arg1, arg2)
+    }
+    
+    /// Moves 64 bits between an XMM register and a general purpose
+    /// register or memory, for instance to reinterpret an f64 as its bit
+    /// pattern.
+    pub fn movq<A1: AsArg, A2: AsArg>(&mut self, arg1: A1, arg2: A2) -> Result<(), CodegenError> {
+        self.sse_movx("movq", 8, arg1, arg2)
+    }
+}
+
+/*
+ * The emitters of an instruction with one operand, by operand kind.
+ */
+struct Unary {
+    reg: fn(&mut Emit, Reg, i32),
+    mem: fn(&mut Emit, i32, i32),
+    membase: fn(&mut Emit, Reg, i32, i32),
+    memindex: fn(&mut Emit, Reg, i32, Reg, u8, i32)
+}
+
+const INC: Unary = Unary {
+    reg: Emit::inc_reg_size,
+    mem: Emit::inc_mem_size,
+    membase: Emit::inc_membase_size,
+    memindex: Emit::inc_memindex_size
+};
+
+const DEC: Unary = Unary {
+    reg: Emit::dec_reg_size,
+    mem: Emit::dec_mem_size,
+    membase: Emit::dec_membase_size,
+    memindex: Emit::dec_memindex_size
+};
+
+const NEG: Unary = Unary {
+    reg: Emit::neg_reg_size,
+    mem: Emit::neg_mem_size,
+    membase: Emit::neg_membase_size,
+    memindex: Emit::neg_memindex_size
+};
+
+const NOT: Unary = Unary {
+    reg: Emit::not_reg_size,
+    mem: Emit::not_mem_size,
+    membase: Emit::not_membase_size,
+    memindex: Emit::not_memindex_size
+};
+
+const MUL: Unary = Unary {
+    reg: |emit, reg, size| emit.mul_reg_issigned_size(reg, false, size),
+    mem: |emit, mem, size| emit.mul_mem_issigned_size(mem, false, size),
+    membase: |emit, basereg, disp, size| emit.mul_membase_issigned_size(basereg, disp, false, size),
+    memindex: |emit, basereg, disp, indexreg, shift, size| emit.mul_memindex_issigned_size(basereg, disp, indexreg, shift, false, size)
+};
+
+const IMUL_WIDE: Unary = Unary {
+    reg: |emit, reg, size| emit.mul_reg_issigned_size(reg, true, size),
+    mem: |emit, mem, size| emit.mul_mem_issigned_size(mem, true, size),
+    membase: |emit, basereg, disp, size| emit.mul_membase_issigned_size(basereg, disp, true, size),
+    memindex: |emit, basereg, disp, indexreg, shift, size| emit.mul_memindex_issigned_size(basereg, disp, indexreg, shift, true, size)
+};
+
+const DIV: Unary = Unary {
+    reg: Emit::div_reg_size,
+    mem: Emit::div_mem_size,
+    membase: Emit::div_membase_size,
+    memindex: Emit::div_memindex_size
+};
+
+const IDIV: Unary = Unary {
+    reg: Emit::idiv_reg_size,
+    mem: Emit::idiv_mem_size,
+    membase: Emit::idiv_membase_size,
+    memindex: Emit::idiv_memindex_size
+};
+
+/*
+ * The emitters of an instruction with a register destination and a
+ * register or memory source, by source kind.
+ */
+struct Unary2 {
+    reg: fn(&mut Emit, Reg, Reg, i32),
+    mem: fn(&mut Emit, Reg, i32, i32),
+    membase: fn(&mut Emit, Reg, Reg, i32, i32),
+    memindex: fn(&mut Emit, Reg, Reg, i32, Reg, u8, i32)
+}
+
+const MOVSX8: Unary2 = Unary2 {
+    reg: Emit::movsx8_reg_reg_size,
+    mem: Emit::movsx8_reg_mem_size,
+    membase: Emit::movsx8_reg_membase_size,
+    memindex: Emit::movsx8_reg_memindex_size
+};
+
+const MOVSX16: Unary2 = Unary2 {
+    reg: Emit::movsx16_reg_reg_size,
+    mem: Emit::movsx16_reg_mem_size,
+    membase: Emit::movsx16_reg_membase_size,
+    memindex: Emit::movsx16_reg_memindex_size
+};
+
+const MOVSX32: Unary2 = Unary2 {
+    reg: Emit::movsx32_reg_reg_size,
+    mem: Emit::movsx32_reg_mem_size,
+    membase: Emit::movsx32_reg_membase_size,
+    memindex: Emit::movsx32_reg_memindex_size
+};
+
+const MOVZX8: Unary2 = Unary2 {
+    reg: Emit::movzx8_reg_reg_size,
+    mem: Emit::movzx8_reg_mem_size,
+    membase: Emit::movzx8_reg_membase_size,
+    memindex: Emit::movzx8_reg_memindex_size
+};
+
+const MOVZX16: Unary2 = Unary2 {
+    reg: Emit::movzx16_reg_reg_size,
+    mem: Emit::movzx16_reg_mem_size,
+    membase: Emit::movzx16_reg_membase_size,
+    memindex: Emit::movzx16_reg_memindex_size
+};
+
+const MOVZX32: Unary2 = Unary2 {
+    reg: |emit, dreg, sreg, _| emit.mov_reg_reg_size(dreg, sreg, 4),
+    mem: |emit, dreg, mem, _| emit.mov_reg_mem_size(dreg, mem as i64, 4),
+    membase: |emit, dreg, basereg, disp, _| emit.mov_reg_membase_size(dreg, basereg, disp, 4),
+    memindex: |emit, dreg, basereg, disp, indexreg, shift, _| emit.mov_reg_memindex_size(dreg, basereg, disp, indexreg, shift, 4)
+};
+
+impl Default for Codegen {
+    fn default() -> Codegen {
+        Codegen::new()
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub enum Arg {
     Reg(SizedReg),
+    Xmm(Reg),
     Imm(Imm),
     Mem(i64),
     MemSize(i64, i32),
     MemBase(Reg, i32, i32),
-    MemIndex(Reg, i32, Reg, u8, i32)
+    MemIndex(Reg, i32, Reg, u8, i32),
+    Const(usize, i32),
+    VReg(VReg),
+    VMemBase(VReg, i32, i32)
+}
+
+impl Arg {
+    fn is_mem(self) -> bool {
+        matches!(self, Arg::MemSize(..) | Arg::MemBase(..) | Arg::MemIndex(..))
+    }
+    
+    fn is_xmm_rm(self) -> bool {
+        matches!(self, Arg::Xmm(_)) || self.is_mem()
+    }
+    
+    fn is_gpr_rm(self) -> bool {
+        matches!(self, Arg::Reg(_)) || self.is_mem()
+    }
+    
+    fn is_virtual(self) -> bool {
+        matches!(self, Arg::VReg(_) | Arg::VMemBase(..))
+    }
+    
+    /*
+     * Whether the register is part of the operand.
+     */
+    fn uses(self, reg: Reg) -> bool {
+        match self {
+            Arg::Reg(sreg) => sreg.reg() == reg,
+            Arg::Xmm(xreg) => xreg == reg,
+            Arg::MemBase(basereg, _, _) => basereg == reg,
+            Arg::MemIndex(basereg, _, indexreg, _, _) => basereg == reg || indexreg == reg,
+            _ => false
+        }
+    }
+    
+    fn operand(self) -> Operand {
+        let (kind, size) = match self {
+            Arg::Reg(reg) => (OperandKind::Reg, Some(reg.size())),
+            Arg::Xmm(_) => (OperandKind::Xmm, Some(16)),
+            Arg::Imm(imm) => (OperandKind::Imm, Some(imm.size())),
+            Arg::Mem(_) => (OperandKind::Mem, None),
+            Arg::MemSize(_, size) => (OperandKind::Mem, Some(size)),
+            Arg::MemBase(_, _, size) => (OperandKind::MemBase, Some(size)),
+            Arg::MemIndex(_, _, _, _, size) => (OperandKind::MemIndex, Some(size)),
+            Arg::Const(_, size) => (OperandKind::MemBase, Some(size)),
+            Arg::VReg(vreg) if vreg.is_xmm() => (OperandKind::Xmm, Some(16)),
+            Arg::VReg(vreg) => (OperandKind::Reg, Some(vreg.size())),
+            Arg::VMemBase(_, _, size) => (OperandKind::MemBase, Some(size))
+        };
+        
+        Operand {
+            kind,
+            size
+        }
+    }
 }
 
-pub trait AsArg : Copy {
+pub trait AsArg : Copy + fmt::Debug {
     fn as_arg(self) -> Arg;
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub enum Imm {
     U8(u8),
     I8(i8),
@@ -195,23 +1974,44 @@ pub enum Imm {
 }
 
 impl Imm {
-    fn as_i32(self) -> i32 {
+    fn as_i32(self) -> Option<i32> {
+        match self {
+            Imm::U8(value) => Some(value as i32),
+            Imm::I8(value) => Some(value as i32),
+            Imm::U16(value) => Some(value as i32),
+            Imm::I16(value) => Some(value as i32),
+            Imm::U32(value) => Some(value as i32),
+            Imm::I32(value) => Some(value),
+            Imm::U64(value) if value as i64 == value as i32 as i64 => Some(value as i32),
+            Imm::I64(value) if value == value as i32 as i64 => Some(value as i32),
+            _ => None
+        }
+    }
+    
+    /*
+     * The value of an integer immediate, zero extended if it is unsigned
+     * and sign extended if it is signed.
+     */
+    fn as_i64(self) -> Option<i64> {
         match self {
-            Imm::U8(value) => value as i32,
-            Imm::I8(value) => value as i32,
-            Imm::U16(value) => value as i32,
-            Imm::I16(value) => value as i32,
-            Imm::U32(value) => value as i32,
-            Imm::I32(value) => value,
-            _ => jit_assert!()
+            Imm::U8(value) => Some(value as i64),
+            Imm::U16(value) => Some(value as i64),
+            Imm::U32(value) => Some(value as i64),
+            Imm::U64(value) => Some(value as i64),
+            Imm::I64(value) => Some(value),
+            _ => self.as_i32().map(|value| value as i64)
         }
     }
     
-    fn as_i64(self) -> i64 {
+    /*
+     * The bit pattern of a floating point immediate as an integer
+     * immediate of the same size.
+     */
+    fn float_bits(self) -> Option<Imm> {
         match self {
-            Imm::U64(value) => value as i64,
-            Imm::I64(value) => value,
-            _ => self.as_i32() as i64
+            Imm::F32(value) => Some(Imm::U32(value.to_bits())),
+            Imm::F64(value) => Some(Imm::U64(value.to_bits())),
+            _ => None
         }
     }
     
@@ -225,19 +2025,33 @@ impl Imm {
     }
 }
 
-#[derive(Copy, Clone)]
+/// The rounding mode of `roundss` and `roundsd`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Round {
+    Nearest = 0,
+    Down = 1,
+    Up = 2,
+    Zero = 3
+}
+
+#[derive(Copy, Clone, Debug)]
 pub struct Mem(pub u64);
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct MemSize(pub u64, pub i32);
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct MemBase(pub SizedReg, pub i32);
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct MemIndex(pub SizedReg, pub i32, pub SizedReg, pub u8);
 
-#[derive(Copy, Clone)]
+/// A constant in the constant pool, created by `Codegen::const_f64`,
+/// `const_u64` or `const_bytes`.
+#[derive(Copy, Clone, Debug)]
+pub struct Const(usize, i32);
+
+#[derive(Copy, Clone, Debug)]
 pub enum SizedReg {
     AL,
     AX,
@@ -305,18 +2119,41 @@ pub enum SizedReg {
     RSP,
     IP,
     EIP,
-    RIP
+    RIP,
+    XMM0,
+    XMM1,
+    XMM2,
+    XMM3,
+    XMM4,
+    XMM5,
+    XMM6,
+    XMM7,
+    XMM8,
+    XMM9,
+    XMM10,
+    XMM11,
+    XMM12,
+    XMM13,
+    XMM14,
+    XMM15
 }
 
 pub mod prologue {
+    pub use codegen::{Label, Cond, CodegenError};
     pub use super::{Arg, AsArg, Imm, Codegen, Mem, MemSize, MemBase};
-    pub use super::{MemIndex, Reg, SizedReg};
+    pub use super::{MemIndex, Const, Reg, Round, SizedReg, CallConv, Frame, VReg, VMemBase};
     pub use super::SizedReg::*;
     
     type M = Mem;
     type MB = MemBase;
     type MI = MemIndex;
 
+    impl AsArg for Arg {
+        fn as_arg(self) -> Arg {
+            self
+        }
+    }
+    
     impl AsArg for Imm {
         fn as_arg(self) -> Arg {
             Arg::Imm(self)
@@ -341,6 +2178,12 @@ pub mod prologue {
         }
     }
     
+    impl AsArg for Const {
+        fn as_arg(self) -> Arg {
+            Arg::Const(self.0, self.1)
+        }
+    }
+    
     impl AsArg for MemIndex {
         fn as_arg(self) -> Arg {
             Arg::MemIndex(self.0.reg(), self.1, self.2.reg(), self.3, self.0.size())
@@ -409,7 +2252,11 @@ pub mod prologue {
 
     impl AsArg for SizedReg {
         fn as_arg(self) -> Arg {
-            Arg::Reg(self)
+            if self.is_xmm() {
+                Arg::Xmm(self.reg())
+            } else {
+                Arg::Reg(self)
+            }
         }
     }
     
@@ -432,7 +2279,23 @@ pub mod prologue {
                 SizedReg::SIL | SizedReg::SI | SizedReg::ESI | SizedReg::RSI => Reg::RSI,
                 SizedReg::DIL | SizedReg::DI | SizedReg::EDI | SizedReg::RDI => Reg::RDI,
                 SizedReg::SPL | SizedReg::SP | SizedReg::ESP | SizedReg::RSP => Reg::RSP,
-                SizedReg::IP | SizedReg::EIP | SizedReg::RIP => Reg::RIP
+                SizedReg::IP | SizedReg::EIP | SizedReg::RIP => Reg::RIP,
+                SizedReg::XMM0 => Reg::XMM0,
+                SizedReg::XMM1 => Reg::XMM1,
+                SizedReg::XMM2 => Reg::XMM2,
+                SizedReg::XMM3 => Reg::XMM3,
+                SizedReg::XMM4 => Reg::XMM4,
+                SizedReg::XMM5 => Reg::XMM5,
+                SizedReg::XMM6 => Reg::XMM6,
+                SizedReg::XMM7 => Reg::XMM7,
+                SizedReg::XMM8 => Reg::XMM8,
+                SizedReg::XMM9 => Reg::XMM9,
+                SizedReg::XMM10 => Reg::XMM10,
+                SizedReg::XMM11 => Reg::XMM11,
+                SizedReg::XMM12 => Reg::XMM12,
+                SizedReg::XMM13 => Reg::XMM13,
+                SizedReg::XMM14 => Reg::XMM14,
+                SizedReg::XMM15 => Reg::XMM15
             }
         }
         
@@ -453,8 +2316,17 @@ pub mod prologue {
                 SizedReg::RAX | SizedReg::RBX | SizedReg::RCX | SizedReg::RDX | SizedReg::R8 | SizedReg::R9 |
                 SizedReg::R10 | SizedReg::R11 | SizedReg::R12 | SizedReg::R13 | SizedReg::R14 | SizedReg::R15 |
                 SizedReg::RBP | SizedReg::RSI | SizedReg::RDI | SizedReg::RSP | SizedReg::RIP
-                    => 8
+                    => 8,
+                SizedReg::XMM0 | SizedReg::XMM1 | SizedReg::XMM2 | SizedReg::XMM3 | SizedReg::XMM4 |
+                SizedReg::XMM5 | SizedReg::XMM6 | SizedReg::XMM7 | SizedReg::XMM8 | SizedReg::XMM9 |
+                SizedReg::XMM10 | SizedReg::XMM11 | SizedReg::XMM12 | SizedReg::XMM13 | SizedReg::XMM14 |
+                SizedReg::XMM15
+                    => 16
             }
         }
+        
+        pub fn is_xmm(self) -> bool {
+            self.size() == 16
+        }
     }
 }
@@ -0,0 +1,1129 @@
+//! Virtual registers and the linear scan allocator that assigns them
+//! physical registers or stack slots.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use codegen::{CodegenError, Label};
+use super::{Codegen, Arg, AsArg, Reg, SizedReg, CallConv, Frame};
+use super::abi::gpr;
+use super::emit::Emit;
+
+/// A virtual register, created by `Codegen::new_vreg` or `new_vreg_xmm`.
+/// In the body of `Codegen::function` it can be used wherever a register
+/// of its size is accepted, and is assigned a physical register or a
+/// stack slot for the whole function.
+#[derive(Copy, Clone, PartialEq)]
+pub struct VReg {
+    index: u32,
+    size: i32
+}
+
+impl VReg {
+    pub fn size(self) -> i32 {
+        self.size
+    }
+    
+    pub fn is_xmm(self) -> bool {
+        self.size == 16
+    }
+    
+    /// The same general purpose virtual register as an operand of 1, 2, 4
+    /// or 8 bytes, like EAX is of RAX.
+    pub fn sized(self, size: i32) -> VReg {
+        assert!(!self.is_xmm() && matches!(size, 1 | 2 | 4 | 8), "invalid size {} for {:?}", size, self);
+        
+        VReg {
+            index: self.index,
+            size
+        }
+    }
+}
+
+impl fmt::Debug for VReg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let suffix = match self.size {
+            1 => "b",
+            2 => "w",
+            4 => "d",
+            8 => "",
+            _ => "x"
+        };
+        
+        write!(f, "v{}{}", self.index, suffix)
+    }
+}
+
+/// A memory operand at a displacement from the address in a general
+/// purpose virtual register, like `MemBase`. The size of the operand is
+/// the size of the virtual register, which holds the address in all of
+/// its 64 bits.
+#[derive(Copy, Clone, Debug)]
+pub struct VMemBase(pub VReg, pub i32);
+
+impl AsArg for VReg {
+    fn as_arg(self) -> Arg {
+        Arg::VReg(self)
+    }
+}
+
+impl AsArg for VMemBase {
+    fn as_arg(self) -> Arg {
+        Arg::VMemBase(self.0, self.1, self.0.size())
+    }
+}
+
+/*
+ * Locations are numbered with the physical registers first, by their
+ * number in Reg, followed by the virtual registers of the function.
+ */
+const PHYS: usize = Reg::XMM15 as usize + 1;
+
+/*
+ * The registers virtual registers are assigned. R10, R11, XMM14 and XMM15
+ * are kept for the operands of instructions that cannot take a spilled
+ * virtual register from memory, and RSP and RBP hold the frame.
+ */
+const GPRS: [Reg; 12] = [
+    Reg::RAX, Reg::RCX, Reg::RDX, Reg::RSI, Reg::RDI, Reg::R8,
+    Reg::R9, Reg::RBX, Reg::R12, Reg::R13, Reg::R14, Reg::R15
+];
+const XMMS: [Reg; 14] = [
+    Reg::XMM0, Reg::XMM1, Reg::XMM2, Reg::XMM3, Reg::XMM4, Reg::XMM5, Reg::XMM6,
+    Reg::XMM7, Reg::XMM8, Reg::XMM9, Reg::XMM10, Reg::XMM11, Reg::XMM12, Reg::XMM13
+];
+const GPR_TEMPS: [Reg; 2] = [Reg::R10, Reg::R11];
+const XMM_TEMPS: [Reg; 2] = [Reg::XMM14, Reg::XMM15];
+
+const SIZED_XMMS: [SizedReg; 16] = [
+    SizedReg::XMM0, SizedReg::XMM1, SizedReg::XMM2, SizedReg::XMM3,
+    SizedReg::XMM4, SizedReg::XMM5, SizedReg::XMM6, SizedReg::XMM7,
+    SizedReg::XMM8, SizedReg::XMM9, SizedReg::XMM10, SizedReg::XMM11,
+    SizedReg::XMM12, SizedReg::XMM13, SizedReg::XMM14, SizedReg::XMM15
+];
+
+/*
+ * Every spilled virtual register gets a slot that can hold an XMM
+ * register.
+ */
+const SLOT_SIZE: i32 = 16;
+
+fn is_xmm(reg: Reg) -> bool {
+    reg as usize >= Reg::XMM0 as usize
+}
+
+fn sized(reg: Reg) -> SizedReg {
+    if is_xmm(reg) {
+        SIZED_XMMS[reg as usize - Reg::XMM0 as usize]
+    } else {
+        gpr(reg, 8)
+    }
+}
+
+/*
+ * The operand of a register in place of a virtual register of the size.
+ */
+fn register(reg: Reg, size: i32) -> Arg {
+    if is_xmm(reg) {
+        Arg::Xmm(reg)
+    } else {
+        Arg::Reg(gpr(reg, size))
+    }
+}
+
+/*
+ * The operand of a virtual register in the first pass, which only has
+ * to encode like it. RBP and XMM15 are never assigned.
+ */
+fn placeholder(arg: Arg) -> Arg {
+    match arg {
+        Arg::VReg(vreg) if vreg.is_xmm() => Arg::Xmm(Reg::XMM15),
+        Arg::VReg(vreg) => Arg::Reg(gpr(Reg::RBP, vreg.size)),
+        Arg::VMemBase(_, disp, size) => Arg::MemBase(Reg::RBP, disp, size),
+        arg => arg
+    }
+}
+
+/*
+ * The location of a base or index register, which may also be NONE or
+ * NOBASEREG.
+ */
+fn physical(reg: Reg) -> Option<usize> {
+    if (reg as usize) < PHYS {
+        Some(reg as usize)
+    } else {
+        None
+    }
+}
+
+fn caller_saved(conv: CallConv) -> Vec<usize> {
+    (0..PHYS)
+        .filter(|&loc| loc != Reg::RSP as usize && loc != Reg::RBP as usize && loc != Reg::RIP as usize)
+        .filter(|&loc| !conv.callee_saved().iter().any(|reg| reg.reg() as usize == loc))
+        .collect()
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Role {
+    Use,
+    Def,
+    UseDef
+}
+
+/*
+ * How an instruction accesses the register operand at index. Only the
+ * first operand is written, except by xchg. Writes of less than 32 bits
+ * keep the rest of a general purpose register, and the scalar XMM
+ * instructions are taken to write the whole register.
+ */
+fn role(instruction: &str, index: usize, size: i32) -> Role {
+    if instruction == "xchg" {
+        return Role::UseDef;
+    }
+    if index > 0 {
+        return Role::Use;
+    }
+    
+    match instruction {
+        "cmp" | "test" | "push" | "call" | "call_native" | "mul" | "imul_wide" | "div" | "idiv" |
+        "ucomiss" | "ucomisd" | "comiss" | "comisd" => Role::Use,
+        "mov" | "movzx" | "movsx" | "lea" | "lea_label" | "mov_label" | "mov_symbol" | "pop" |
+        "movss" | "movsd" | "movaps" | "movups" | "movd" | "movq" | "sqrtss" | "sqrtsd" |
+        "roundss" | "roundsd" | "cvtss2sd" | "cvtsd2ss" | "cvtsi2ss" | "cvtsi2sd" |
+        "cvtss2si" | "cvtsd2si" | "cvttss2si" | "cvttsd2si" if size >= 4 => Role::Def,
+        _ => Role::UseDef
+    }
+}
+
+/*
+ * Control flow and instructions without operands, which the first pass
+ * is told about besides the instructions that are encoded.
+ */
+pub(super) enum Note {
+    Inst(&'static str),
+    Jump(Label),
+    Branch(Label),
+    Bind(Label),
+    Ret
+}
+
+#[derive(Copy, Clone, Default)]
+enum Flow {
+    #[default]
+    Next,
+    Jump(usize),
+    Branch(usize),
+    Bind(usize),
+    Ret
+}
+
+/*
+ * The locations an instruction reads and writes. Weak uses are physical
+ * registers that calls and returns read if the function wrote them, like
+ * argument registers. Clobbers are written with values nobody reads.
+ */
+#[derive(Default)]
+struct Inst {
+    uses: Vec<usize>,
+    defs: Vec<usize>,
+    weak: Vec<usize>,
+    clobbers: Vec<usize>,
+    flow: Flow
+}
+
+struct Block {
+    start: usize,
+    end: usize,
+    succs: Vec<usize>
+}
+
+/*
+ * A set of locations.
+ */
+#[derive(Clone, PartialEq)]
+struct Set(Vec<u64>);
+
+impl Set {
+    fn new(size: usize) -> Set {
+        Set(vec![0; size.div_ceil(64)])
+    }
+    
+    fn contains(&self, i: usize) -> bool {
+        self.0[i / 64] & (1 << (i % 64)) != 0
+    }
+    
+    fn insert(&mut self, i: usize) {
+        self.0[i / 64] |= 1 << (i % 64);
+    }
+    
+    fn remove(&mut self, i: usize) {
+        self.0[i / 64] &= !(1 << (i % 64));
+    }
+    
+    fn union(&mut self, other: &Set) {
+        for (word, &other) in self.0.iter_mut().zip(other.0.iter()) {
+            *word |= other;
+        }
+    }
+    
+    fn subtract(&mut self, other: &Set) {
+        for (word, &other) in self.0.iter_mut().zip(other.0.iter()) {
+            *word &= !other;
+        }
+    }
+    
+    fn elements(&self) -> Vec<usize> {
+        (0..self.0.len() * 64).filter(|&i| self.contains(i)).collect()
+    }
+}
+
+/*
+ * Whether two live ranges, from the instruction that writes the value to
+ * the last one that reads it, need different registers. A value may be
+ * written by the instruction that reads another one for the last time.
+ */
+fn overlaps(a: (usize, usize), b: (usize, usize)) -> bool {
+    (a.0 < b.1 && b.0 < a.1) || a.0 == b.0
+}
+
+/*
+ * What the first pass finds out about a function.
+ */
+pub(super) struct Analysis {
+    conv: CallConv,
+    first: u32,
+    insts: Vec<Inst>,
+    xmm: Vec<bool>,
+    call_args: Option<Vec<Reg>>
+}
+
+#[derive(Copy, Clone)]
+enum Location {
+    Reg(Reg),
+    Slot(i32)
+}
+
+/*
+ * Where the second pass puts the virtual registers. The slots are
+ * displacements from RBP.
+ */
+pub(super) struct Assignment {
+    first: u32,
+    locations: Vec<Option<Location>>
+}
+
+pub(super) enum Alloc {
+    Analyze(Analysis),
+    Rewrite(Assignment)
+}
+
+struct Allocation {
+    locations: Vec<Option<Location>>,
+    saved: Vec<SizedReg>,
+    slots: i32
+}
+
+impl Analysis {
+    fn new(conv: CallConv, first: u32) -> Analysis {
+        Analysis {
+            conv,
+            first,
+            insts: Vec::new(),
+            xmm: Vec::new(),
+            call_args: None
+        }
+    }
+    
+    fn vreg(&mut self, vreg: VReg) -> usize {
+        let index = vreg.index.checked_sub(self.first).expect("virtual register of another function") as usize;
+        
+        if index >= self.xmm.len() {
+            self.xmm.resize(index + 1, false);
+        }
+        self.xmm[index] = vreg.is_xmm();
+        
+        PHYS + index
+    }
+    
+    fn instruction(&mut self, instruction: &'static str, args: &[Arg]) {
+        let mut inst = Inst::default();
+        
+        for (i, &arg) in args.iter().enumerate() {
+            let (loc, size) = match arg {
+                Arg::Reg(reg) => (reg.reg() as usize, reg.size()),
+                Arg::Xmm(reg) => (reg as usize, 16),
+                Arg::VReg(vreg) => (self.vreg(vreg), vreg.size),
+                Arg::MemBase(basereg, _, _) => {
+                    inst.uses.extend(physical(basereg));
+                    continue;
+                }
+                Arg::MemIndex(basereg, _, indexreg, _, _) => {
+                    inst.uses.extend(physical(basereg).into_iter().chain(physical(indexreg)));
+                    continue;
+                }
+                Arg::VMemBase(basereg, _, _) => {
+                    inst.uses.push(self.vreg(basereg));
+                    continue;
+                }
+                _ => continue
+            };
+            
+            match role(instruction, i, size) {
+                Role::Use => inst.uses.push(loc),
+                Role::Def => inst.defs.push(loc),
+                Role::UseDef => {
+                    inst.uses.push(loc);
+                    inst.defs.push(loc);
+                }
+            }
+        }
+        
+        self.implicit(instruction, &mut inst);
+        
+        self.insts.push(inst);
+    }
+    
+    /*
+     * Adds the registers an instruction accesses without naming them.
+     */
+    fn implicit(&mut self, instruction: &str, inst: &mut Inst) {
+        let (rax, rdx) = (Reg::RAX as usize, Reg::RDX as usize);
+        
+        match instruction {
+            "mul" | "imul_wide" => {
+                inst.uses.push(rax);
+                inst.defs.extend_from_slice(&[rax, rdx]);
+            }
+            "div" | "idiv" => {
+                inst.uses.extend_from_slice(&[rax, rdx]);
+                inst.defs.extend_from_slice(&[rax, rdx]);
+            }
+            "cwd" | "cdq" | "cqo" => {
+                inst.uses.push(rax);
+                inst.defs.push(rdx);
+            }
+            "call" => {
+                match self.call_args.take() {
+                    Some(regs) => inst.uses.extend(regs.iter().map(|&reg| reg as usize)),
+                    None => {
+                        inst.weak.push(rax);
+                        inst.weak.extend(self.conv.int_args().iter().chain(self.conv.float_args()).map(|reg| reg.reg() as usize));
+                    }
+                }
+                inst.clobbers = caller_saved(self.conv);
+            }
+            _ => {}
+        }
+    }
+    
+    fn note(&mut self, note: Note) {
+        let mut inst = Inst::default();
+        
+        match note {
+            Note::Inst(instruction) => self.implicit(instruction, &mut inst),
+            Note::Jump(label) => inst.flow = Flow::Jump(label.0),
+            Note::Branch(label) => inst.flow = Flow::Branch(label.0),
+            Note::Bind(label) => inst.flow = Flow::Bind(label.0),
+            Note::Ret => {
+                inst.weak = [Reg::RAX, Reg::RDX, Reg::XMM0, Reg::XMM1].iter().map(|&reg| reg as usize).collect();
+                inst.flow = Flow::Ret;
+            }
+        }
+        
+        self.insts.push(inst);
+    }
+    
+    /*
+     * Splits the instructions into basic blocks. A block starts where a
+     * label is bound and after a jump, branch or return.
+     */
+    fn blocks(&self) -> Vec<Block> {
+        let count = self.insts.len();
+        let mut leaders = vec![false; count + 1];
+        leaders[0] = true;
+        
+        for (i, inst) in self.insts.iter().enumerate() {
+            match inst.flow {
+                Flow::Bind(_) => leaders[i] = true,
+                Flow::Jump(_) | Flow::Branch(_) | Flow::Ret => leaders[i + 1] = true,
+                Flow::Next => {}
+            }
+        }
+        
+        let starts = (0..count).filter(|&i| leaders[i]).collect::<Vec<_>>();
+        let mut labels = HashMap::new();
+        
+        for (b, &start) in starts.iter().enumerate() {
+            if let Flow::Bind(label) = self.insts[start].flow {
+                labels.insert(label, b);
+            }
+        }
+        
+        starts.iter().enumerate().map(|(b, &start)| {
+            let end = starts.get(b + 1).cloned().unwrap_or(count);
+            let next = if b + 1 < starts.len() { Some(b + 1) } else { None };
+            let succs = match self.insts[end - 1].flow {
+                Flow::Jump(label) => labels.get(&label).cloned().into_iter().collect(),
+                Flow::Branch(label) => labels.get(&label).cloned().into_iter().chain(next).collect(),
+                Flow::Ret => Vec::new(),
+                _ => next.into_iter().collect()
+            };
+            
+            Block {
+                start,
+                end,
+                succs
+            }
+        }).collect()
+    }
+    
+    /*
+     * Turns the weak uses into uses where the register may have been
+     * written before, on any path to the instruction.
+     */
+    fn resolve_weak(&mut self, blocks: &[Block]) {
+        let mut written = vec![Set::new(PHYS); blocks.len()];
+        let mut changed = true;
+        
+        while changed {
+            changed = false;
+            
+            for (b, block) in blocks.iter().enumerate() {
+                let mut out = written[b].clone();
+                
+                for inst in &self.insts[block.start..block.end] {
+                    for &loc in inst.defs.iter().filter(|&&loc| loc < PHYS) {
+                        out.insert(loc);
+                    }
+                }
+                
+                for &succ in &block.succs {
+                    let mut written_in = written[succ].clone();
+                    written_in.union(&out);
+                    
+                    if written_in != written[succ] {
+                        written[succ] = written_in;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        
+        for (b, block) in blocks.iter().enumerate() {
+            let mut out = written[b].clone();
+            
+            for inst in &mut self.insts[block.start..block.end] {
+                let weak = mem::take(&mut inst.weak);
+                inst.uses.extend(weak.into_iter().filter(|&loc| out.contains(loc)));
+                
+                for &loc in inst.defs.iter().filter(|&&loc| loc < PHYS) {
+                    out.insert(loc);
+                }
+            }
+        }
+    }
+    
+    /*
+     * Computes where every location is live, as a list of ranges from the
+     * instruction that writes a value, or the start of a block it is live
+     * into, to the last instruction that reads it.
+     */
+    fn live_ranges(&self, blocks: &[Block], size: usize) -> Vec<Vec<(usize, usize)>> {
+        let mut gen = vec![Set::new(size); blocks.len()];
+        let mut kill = vec![Set::new(size); blocks.len()];
+        
+        for (b, block) in blocks.iter().enumerate() {
+            for inst in &self.insts[block.start..block.end] {
+                for &loc in &inst.uses {
+                    if !kill[b].contains(loc) {
+                        gen[b].insert(loc);
+                    }
+                }
+                for &loc in inst.defs.iter().chain(&inst.clobbers) {
+                    kill[b].insert(loc);
+                }
+            }
+        }
+        
+        let mut live_in = vec![Set::new(size); blocks.len()];
+        let mut live_out = vec![Set::new(size); blocks.len()];
+        let mut changed = true;
+        
+        while changed {
+            changed = false;
+            
+            for (b, block) in blocks.iter().enumerate().rev() {
+                let mut out = Set::new(size);
+                for &succ in &block.succs {
+                    out.union(&live_in[succ]);
+                }
+                
+                let mut live = out.clone();
+                live.subtract(&kill[b]);
+                live.union(&gen[b]);
+                
+                if live != live_in[b] {
+                    live_in[b] = live;
+                    changed = true;
+                }
+                live_out[b] = out;
+            }
+        }
+        
+        let mut ranges = vec![Vec::new(); size];
+        let mut end = vec![0; size];
+        
+        for (b, block) in blocks.iter().enumerate() {
+            let mut live = live_out[b].clone();
+            
+            for loc in live.elements() {
+                end[loc] = block.end - 1;
+            }
+            
+            for i in (block.start..block.end).rev() {
+                let inst = &self.insts[i];
+                
+                for &loc in inst.defs.iter().chain(&inst.clobbers) {
+                    if live.contains(loc) {
+                        ranges[loc].push((i, end[loc]));
+                        live.remove(loc);
+                    } else {
+                        ranges[loc].push((i, i));
+                    }
+                }
+                
+                for &loc in &inst.uses {
+                    if !live.contains(loc) {
+                        live.insert(loc);
+                        end[loc] = i;
+                    }
+                }
+            }
+            
+            for loc in live.elements() {
+                ranges[loc].push((block.start, end[loc]));
+            }
+        }
+        
+        ranges
+    }
+    
+    /*
+     * Assigns the virtual registers in the order their live intervals
+     * start. Each gets a register that is free for its whole interval,
+     * preferably a caller saved one. When there is none, the register of
+     * the conflicting interval that ends last is taken over if that ends
+     * after the new one, and the loser of the two is spilled.
+     */
+    fn allocate(mut self, count: usize) -> Allocation {
+        let size = PHYS + count;
+        let blocks = self.blocks();
+        
+        self.resolve_weak(&blocks);
+        let ranges = self.live_ranges(&blocks, size);
+        
+        let intervals = (0..count).map(|v| {
+            let ranges = &ranges[PHYS + v];
+            let start = ranges.iter().map(|range| range.0).min();
+            let end = ranges.iter().map(|range| range.1).max();
+            start.and_then(|start| end.map(|end| (start, end)))
+        }).collect::<Vec<_>>();
+        
+        let mut order = (0..count).filter(|&v| intervals[v].is_some()).collect::<Vec<_>>();
+        order.sort_by_key(|&v| intervals[v]);
+        
+        let callee_saved = |reg: Reg| self.conv.callee_saved().iter().any(|saved| saved.reg() == reg);
+        let preferred = |regs: &[Reg]| -> Vec<Reg> {
+            regs.iter().filter(|&&reg| !callee_saved(reg))
+                .chain(regs.iter().filter(|&&reg| callee_saved(reg)))
+                .cloned().collect()
+        };
+        let gprs = preferred(&GPRS);
+        let xmms = preferred(&XMMS);
+        
+        let mut locations = vec![None; count];
+        let mut holders = vec![Vec::new(); PHYS];
+        let mut slots = 0;
+        
+        for &v in &order {
+            let interval = intervals[v].unwrap();
+            let regs = if self.xmm[v] { &xmms } else { &gprs };
+            let fixed = |reg: Reg| ranges[reg as usize].iter().any(|&range| overlaps(range, interval));
+            let conflicts = |holders: &Vec<usize>| holders.iter().filter(|&&w| overlaps(intervals[w].unwrap(), interval)).cloned().collect::<Vec<_>>();
+            
+            let free = regs.iter().cloned().find(|&reg| !fixed(reg) && conflicts(&holders[reg as usize]).is_empty());
+            
+            let reg = match free {
+                Some(reg) => Some(reg),
+                None => {
+                    let victim = regs.iter().cloned()
+                        .filter(|&reg| !fixed(reg))
+                        .filter_map(|reg| match conflicts(&holders[reg as usize])[..] {
+                            [w] if intervals[w].unwrap().1 > interval.1 => Some((reg, w)),
+                            _ => None
+                        })
+                        .max_by_key(|&(_, w)| intervals[w].unwrap().1);
+                    
+                    victim.map(|(reg, w)| {
+                        holders[reg as usize].retain(|&holder| holder != w);
+                        locations[w] = Some(Location::Slot(slots));
+                        slots += 1;
+                        reg
+                    })
+                }
+            };
+            
+            match reg {
+                Some(reg) => {
+                    holders[reg as usize].push(v);
+                    locations[v] = Some(Location::Reg(reg));
+                }
+                None => {
+                    locations[v] = Some(Location::Slot(slots));
+                    slots += 1;
+                }
+            }
+        }
+        
+        /*
+         * Besides the registers that are assigned, the callee saved
+         * registers the body writes itself are saved, and the XMM
+         * temporaries when an XMM virtual register is spilled.
+         */
+        
+        let written = |reg: Reg| self.insts.iter().any(|inst| inst.defs.contains(&(reg as usize)));
+        let spilled_xmm = (0..count).any(|v| self.xmm[v] && matches!(locations[v], Some(Location::Slot(_))));
+        
+        let saved = self.conv.callee_saved().iter()
+            .filter(|saved| {
+                let reg = saved.reg();
+                !holders[reg as usize].is_empty() || written(reg) || (spilled_xmm && XMM_TEMPS.contains(&reg))
+            })
+            .cloned()
+            .collect();
+        
+        Allocation {
+            locations,
+            saved,
+            slots
+        }
+    }
+}
+
+impl Assignment {
+    fn location(&self, vreg: VReg) -> Location {
+        vreg.index.checked_sub(self.first)
+            .and_then(|index| self.locations.get(index as usize).cloned())
+            .and_then(|location| location)
+            .expect("virtual register that the function did not use the first time")
+    }
+}
+
+impl Codegen {
+    /// Creates a general purpose virtual register of 1, 2, 4 or 8 bytes.
+    /// Use `VReg::sized` for operands of other sizes.
+    pub fn new_vreg(&mut self, size: i32) -> VReg {
+        assert!(matches!(size, 1 | 2 | 4 | 8), "invalid size {} for a virtual register", size);
+        
+        self.vregs += 1;
+        
+        VReg {
+            index: self.vregs - 1,
+            size
+        }
+    }
+    
+    /// Creates an XMM virtual register.
+    pub fn new_vreg_xmm(&mut self) -> VReg {
+        self.vregs += 1;
+        
+        VReg {
+            index: self.vregs - 1,
+            size: 16
+        }
+    }
+    
+    /// Generates a function whose body uses virtual registers. The body
+    /// is called twice and must generate the same code both times: the
+    /// first time to find out where the virtual registers are live, and
+    /// the second time to generate the code with the physical register or
+    /// stack slot assigned to each of them. Virtual registers and labels
+    /// used in the body must be created in it.
+    ///
+    /// The frame is set up as by `prologue` with `locals` bytes of
+    /// locals, and also saves the callee saved registers that are
+    /// assigned or that the body writes itself, and holds the spill slots.
+    /// The body returns with `epilogue`.
+    ///
+    /// Physical registers can be used alongside the virtual registers,
+    /// for instance to pass arguments, and are never assigned while they
+    /// hold a value the code reads later. A `call` reads the argument
+    /// registers, and `ret` RAX, RDX, XMM0 and XMM1, if the body wrote
+    /// them before. Calls clobber the caller saved registers, as do
+    /// `div`, `mul` and `cqo` the accumulator registers. A shift count
+    /// in a virtual register is moved to CL first. Virtual registers are
+    /// never assigned R10, R11, XMM14 and XMM15, which hold spilled
+    /// operands that an instruction cannot take from memory.
+    pub fn function<F>(&mut self, conv: CallConv, locals: i32, body: F) -> Result<(), CodegenError>
+        where F: Fn(&mut Codegen, &Frame) -> Result<(), CodegenError>
+    {
+        assert!(self.alloc.is_none(), "function inside a function");
+        
+        let first = self.vregs;
+        
+        /*
+         * The first pass generates its code into a stream that is thrown
+         * away again.
+         */
+        
+        let emit = mem::replace(&mut self.emit, Emit::new());
+        let calls = self.calls.take();
+        self.alloc = Some(Box::new(Alloc::Analyze(Analysis::new(conv, first))));
+        
+        let result = self.prologue(conv, &[], locals).and_then(|frame| body(self, &frame));
+        
+        let alloc = self.alloc.take();
+        self.emit = emit;
+        self.calls = calls;
+        result?;
+        
+        let analysis = match alloc.map(|alloc| *alloc) {
+            Some(Alloc::Analyze(analysis)) => analysis,
+            _ => unreachable!()
+        };
+        let count = self.vregs - first;
+        let allocation = analysis.allocate(count as usize);
+        
+        self.vregs = first;
+        
+        let frame = self.prologue(conv, &allocation.saved, locals + allocation.slots * SLOT_SIZE)?;
+        let locations = allocation.locations.iter().map(|&location| match location {
+            Some(Location::Slot(slot)) => Some(Location::Slot(frame.local(locals + slot * SLOT_SIZE))),
+            location => location
+        }).collect();
+        
+        self.alloc = Some(Box::new(Alloc::Rewrite(Assignment {
+            first,
+            locations
+        })));
+        
+        let result = body(self, &frame);
+        
+        self.alloc = None;
+        result?;
+        
+        assert!(self.vregs - first == count, "the body of a function created other virtual registers the second time");
+        
+        Ok(())
+    }
+    
+    /*
+     * Tells the first pass about control flow or an instruction without
+     * operands.
+     */
+    pub(super) fn note(&mut self, note: Note) {
+        if let Some(Alloc::Analyze(analysis)) = self.alloc.as_deref_mut() {
+            analysis.note(note);
+        }
+    }
+    
+    /*
+     * Encodes an instruction of a function with virtual registers. The
+     * first pass records the locations the instruction accesses and
+     * encodes it with placeholders; the second pass encodes it with the
+     * assigned locations.
+     */
+    pub(super) fn encode_virtual<F>(&mut self, instruction: &'static str, args: &[Arg], size: Option<i32>, far: bool, emit: &F) -> Result<(), CodegenError>
+        where F: Fn(&mut Codegen, &[Arg]) -> Result<(), CodegenError>
+    {
+        let mut args = args.to_vec();
+        
+        let count = match instruction {
+            "shl" | "shr" | "sar" | "rol" | "ror" | "rcl" | "rcr" => Some(1),
+            "shld" | "shrd" => Some(2),
+            _ => None
+        };
+        
+        if let Some(i) = count {
+            if let Arg::VReg(vreg) = args[i] {
+                self.mov(gpr(Reg::RCX, vreg.size), vreg)?;
+                args[i] = Arg::Reg(SizedReg::CL);
+            }
+        }
+        
+        let ops = match self.alloc.as_deref_mut() {
+            Some(Alloc::Analyze(analysis)) => {
+                analysis.instruction(instruction, &args);
+                args.iter().map(|&arg| placeholder(arg)).collect::<Vec<_>>()
+            }
+            _ => return self.rewrite(instruction, &args, size, far, emit)
+        };
+        
+        self.encode_args(instruction, &ops, size, far, emit)
+    }
+    
+    /*
+     * Encodes an instruction with the virtual registers replaced by their
+     * locations. Spilled general purpose registers are tried as memory
+     * operands first, unless the instruction writes them with an
+     * immediate, whose size may not be theirs, or is an xchg, which locks
+     * memory. Otherwise spilled registers are loaded into temporary
+     * registers, and stored again if the instruction writes them.
+     */
+    fn rewrite<F>(&mut self, instruction: &'static str, args: &[Arg], size: Option<i32>, far: bool, emit: &F) -> Result<(), CodegenError>
+        where F: Fn(&mut Codegen, &[Arg]) -> Result<(), CodegenError>
+    {
+        let pos = self.emit.position();
+        let locate = |cg: &Codegen, vreg: VReg| match cg.alloc.as_deref() {
+            Some(Alloc::Rewrite(assignment)) => assignment.location(vreg),
+            _ => unreachable!()
+        };
+        
+        let imm = args.iter().any(|arg| matches!(arg, Arg::Imm(_)));
+        let direct = args.iter().enumerate().map(|(i, &arg)| match arg {
+            Arg::VReg(vreg) => match locate(self, vreg) {
+                Location::Reg(reg) => Some(register(reg, vreg.size)),
+                Location::Slot(disp) if !vreg.is_xmm() && instruction != "xchg" && (!imm || role(instruction, i, vreg.size) == Role::Use) => {
+                    Some(Arg::MemBase(Reg::RBP, disp, vreg.size))
+                }
+                Location::Slot(_) => None
+            },
+            Arg::VMemBase(basereg, disp, size) => match locate(self, basereg) {
+                Location::Reg(reg) => Some(Arg::MemBase(reg, disp, size)),
+                Location::Slot(_) => None
+            },
+            arg => Some(arg)
+        }).collect::<Option<Vec<_>>>();
+        
+        let spilled = args.iter().any(|&arg| match arg {
+            Arg::VReg(vreg) | Arg::VMemBase(vreg, _, _) => matches!(locate(self, vreg), Location::Slot(_)),
+            _ => false
+        });
+        
+        if let Some(ops) = direct {
+            match self.encode_args(instruction, &ops, size, far, emit) {
+                Err(_) if spilled => {}
+                result => return result
+            }
+        }
+        
+        let mut gprs = GPR_TEMPS.iter().cloned();
+        let mut xmms = XMM_TEMPS.iter().cloned();
+        let mut ops = Vec::with_capacity(args.len());
+        let mut stores = Vec::new();
+        
+        for (i, &arg) in args.iter().enumerate() {
+            let op = match arg {
+                Arg::VReg(vreg) => match locate(self, vreg) {
+                    Location::Reg(reg) => register(reg, vreg.size),
+                    Location::Slot(disp) => {
+                        let temp = if vreg.is_xmm() { xmms.next() } else { gprs.next() };
+                        let temp = match temp {
+                            Some(temp) => temp,
+                            None => {
+                                self.emit.truncate(pos);
+                                return Err(self.unsupported(instruction, args));
+                            }
+                        };
+                        let role = role(instruction, i, vreg.size);
+                        
+                        if role != Role::Def {
+                            self.load_slot(temp, disp);
+                        }
+                        if role != Role::Use {
+                            stores.push((temp, disp));
+                        }
+                        
+                        register(temp, vreg.size)
+                    }
+                },
+                Arg::VMemBase(basereg, disp, size) => match locate(self, basereg) {
+                    Location::Reg(reg) => Arg::MemBase(reg, disp, size),
+                    Location::Slot(slot) => match gprs.next() {
+                        Some(temp) => {
+                            self.load_slot(temp, slot);
+                            Arg::MemBase(temp, disp, size)
+                        }
+                        None => {
+                            self.emit.truncate(pos);
+                            return Err(self.unsupported(instruction, args));
+                        }
+                    }
+                },
+                arg => arg
+            };
+            
+            ops.push(op);
+        }
+        
+        if let Err(err) = self.encode_args(instruction, &ops, size, far, emit) {
+            self.emit.truncate(pos);
+            return Err(CodegenError {
+                operands: args.iter().map(|arg| arg.operand()).collect(),
+                ..err
+            });
+        }
+        
+        for (temp, disp) in stores {
+            self.store_slot(disp, temp);
+        }
+        
+        Ok(())
+    }
+    
+    fn load_slot(&mut self, reg: Reg, disp: i32) {
+        if is_xmm(reg) {
+            self.emit.movups_reg_membase(reg, Reg::RBP, disp);
+        } else {
+            self.emit.mov_reg_membase_size(reg, Reg::RBP, disp, 8);
+        }
+    }
+    
+    fn store_slot(&mut self, disp: i32, reg: Reg) {
+        if is_xmm(reg) {
+            self.emit.movups_membase_reg(Reg::RBP, disp, reg);
+        } else {
+            self.emit.mov_membase_reg_size(Reg::RBP, disp, reg, 8);
+        }
+    }
+    
+    /*
+     * Replaces the virtual registers among the arguments of a native call
+     * by their locations, where spilled XMM registers are read as 64 bit
+     * memory operands. The first pass records that the call reads them.
+     */
+    pub(super) fn call_args(&mut self, args: &[Arg]) -> Result<Vec<Arg>, CodegenError> {
+        if let Some(Alloc::Analyze(analysis)) = self.alloc.as_deref_mut() {
+            analysis.instruction("call_native", args);
+            return Ok(args.iter().map(|&arg| placeholder(arg)).collect());
+        }
+        
+        let assignment = match self.alloc.as_deref() {
+            Some(Alloc::Rewrite(assignment)) => assignment,
+            _ => return Ok(args.to_vec())
+        };
+        
+        args.iter().map(|&arg| match arg {
+            Arg::VReg(vreg) => Ok(match assignment.location(vreg) {
+                Location::Reg(reg) => register(reg, vreg.size),
+                Location::Slot(disp) => Arg::MemBase(Reg::RBP, disp, if vreg.is_xmm() { 8 } else { vreg.size })
+            }),
+            Arg::VMemBase(basereg, disp, size) => match assignment.location(basereg) {
+                Location::Reg(reg) => Ok(Arg::MemBase(reg, disp, size)),
+                Location::Slot(_) => Err(self.unsupported("call_native", args))
+            },
+            arg => Ok(arg)
+        }).collect()
+    }
+    
+    /*
+     * Tells the first pass which argument registers the next call reads.
+     */
+    pub(super) fn note_call_args(&mut self, regs: Vec<Reg>) {
+        if let Some(Alloc::Analyze(analysis)) = self.alloc.as_deref_mut() {
+            analysis.call_args = Some(regs);
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use codegen::x86_64::prologue::*;
+    
+    extern "C" fn mix(x: i64, y: i64) -> i64 {
+        x.wrapping_mul(3) - y
+    }
+    
+    /*
+     * Keeps more values live across a loop and a native call than there
+     * are registers, and divides and shifts by virtual registers.
+     */
+    #[test]
+    fn pressure() {
+        const COUNT: i64 = 24;
+        
+        let mut cg = Codegen::new();
+        cg.function(CallConv::host(), 0, |cg, frame| {
+            let (a, i, sum) = (cg.new_vreg(8), cg.new_vreg(8), cg.new_vreg(8));
+            let values = (0..COUNT).map(|_| cg.new_vreg(8)).collect::<Vec<_>>();
+            let (top, done) = (cg.new_label(), cg.new_label());
+            
+            cg.mov(a, CallConv::host().int_args()[0])?;
+            for (k, &value) in values.iter().enumerate() {
+                cg.imul_imm(value, a, k as i32)?;
+            }
+            
+            cg.mov(i, 0)?;
+            cg.bind(top);
+            cg.cmp(i, 4)?;
+            cg.jcc(Cond::GE, done);
+            for &value in &values {
+                cg.add(value, i)?;
+            }
+            cg.inc(i)?;
+            cg.jmp(top);
+            cg.bind(done);
+            
+            cg.call_native(CallConv::host(), mix as extern "C" fn(i64, i64) -> i64 as usize as u64, &[values[1].as_arg(), values[2].as_arg()], Some(sum.as_arg()))?;
+            
+            cg.mov(RAX, values[3])?;
+            cg.cqo();
+            cg.idiv(values[1])?;
+            cg.add(sum, RAX)?;
+            cg.shl(values[4], values[2].sized(1))?;
+            
+            for &value in &values {
+                cg.add(sum, value)?;
+            }
+            cg.mov(RAX, sum)?;
+            cg.epilogue(frame)
+        }).unwrap();
+        
+        let f = unsafe { cg.build_fn::<fn(i64) -> i64>().unwrap() };
+        
+        for &a in &[1i64, 3, -7] {
+            let mut values = (0..COUNT).map(|k| a * k + 6).collect::<Vec<_>>();
+            let mut sum = mix(values[1], values[2]) + values[3] / values[1];
+            values[4] <<= values[2] & 63;
+            sum += values.iter().sum::<i64>();
+            
+            assert_eq!(f.call((a,)), sum);
+        }
+    }
+    
+    /*
+     * Callee saved registers that the body writes itself are saved like
+     * the ones that are assigned.
+     */
+    #[test]
+    fn callee_saved() {
+        let mut cg = Codegen::new();
+        cg.function(CallConv::host(), 0, |cg, frame| {
+            let sum = cg.new_vreg(8);
+            
+            cg.mov(RBX, 100)?;
+            cg.mov(R12, 200)?;
+            cg.mov(sum, RBX)?;
+            cg.add(sum, R12)?;
+            cg.mov(RAX, sum)?;
+            cg.epilogue(frame)
+        }).unwrap();
+        
+        let inner = unsafe { cg.build_fn::<fn() -> i64>().unwrap() };
+        
+        let mut cg = Codegen::new();
+        let frame = cg.prologue(CallConv::host(), &[RBX, R12], 0).unwrap();
+        cg.mov(RBX, 1).unwrap();
+        cg.mov(R12, 2).unwrap();
+        cg.call_native(CallConv::host(), unsafe { inner.ptr() } as u64, &[], Some(Arg::Reg(RAX))).unwrap();
+        cg.shl(RBX, 4).unwrap();
+        cg.add(RAX, RBX).unwrap();
+        cg.add(RAX, R12).unwrap();
+        cg.epilogue(&frame).unwrap();
+        
+        let outer = unsafe { cg.build_fn::<fn() -> i64>().unwrap() };
+        
+        assert_eq!(inner.call(()), 300);
+        assert_eq!(outer.call(()), 318);
+    }
+}
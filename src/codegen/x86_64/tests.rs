@@ -0,0 +1,1417 @@
+//! Encoding conformance tests for `Emit`.
+//!
+//! Every case pairs an instruction in Intel syntax with the emitter call
+//! that should produce it. The expected bytes live in `encodings.txt`, which
+//! was produced by assembling the instruction column with GNU as. To add
+//! cases, add their instructions to the file and assemble the column again;
+//! `encodings` names every case that has no reference encoding yet.
+//!
+//! `sign_extended_immediates` checks the immediates `Codegen` accepts for
+//! 64 bit destinations.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use codegen::Cond;
+use super::emit::{Emit, Reg, AluOp, ShiftOp, DoubleShiftOp};
+
+const ENCODINGS : &str = include_str!("encodings.txt");
+
+const GPRS : [Reg; 16] = [
+    Reg::RAX, Reg::RCX, Reg::RDX, Reg::RBX, Reg::RSP, Reg::RBP, Reg::RSI, Reg::RDI,
+    Reg::R8, Reg::R9, Reg::R10, Reg::R11, Reg::R12, Reg::R13, Reg::R14, Reg::R15
+];
+
+const XMMS : [Reg; 16] = [
+    Reg::XMM0, Reg::XMM1, Reg::XMM2, Reg::XMM3, Reg::XMM4, Reg::XMM5, Reg::XMM6, Reg::XMM7,
+    Reg::XMM8, Reg::XMM9, Reg::XMM10, Reg::XMM11, Reg::XMM12, Reg::XMM13, Reg::XMM14, Reg::XMM15
+];
+
+/// Registers that can be used as an index; RSP has no index encoding.
+const INDEXES : [Reg; 15] = [
+    Reg::RAX, Reg::RCX, Reg::RDX, Reg::RBX, Reg::RBP, Reg::RSI, Reg::RDI, Reg::R8,
+    Reg::R9, Reg::R10, Reg::R11, Reg::R12, Reg::R13, Reg::R14, Reg::R15
+];
+
+const GPR_NAMES : [[&str; 16]; 4] = [
+    ["al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil",
+     "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b"],
+    ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di",
+     "r8w", "r9w", "r10w", "r11w", "r12w", "r13w", "r14w", "r15w"],
+    ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi",
+     "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d"],
+    ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi",
+     "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"]
+];
+
+const ALU_OPS : [(AluOp, &str); 8] = [
+    (AluOp::Add, "add"), (AluOp::Or, "or"), (AluOp::Adc, "adc"), (AluOp::Sbb, "sbb"),
+    (AluOp::And, "and"), (AluOp::Sub, "sub"), (AluOp::XOr, "xor"), (AluOp::Cmp, "cmp")
+];
+
+const SHIFT_OPS : [(ShiftOp, &str); 7] = [
+    (ShiftOp::Rol, "rol"), (ShiftOp::Ror, "ror"), (ShiftOp::Rcl, "rcl"), (ShiftOp::Rcr, "rcr"),
+    (ShiftOp::Shl, "shl"), (ShiftOp::Shr, "shr"), (ShiftOp::Sar, "sar")
+];
+
+const DOUBLE_SHIFT_OPS : [(DoubleShiftOp, &str); 2] = [
+    (DoubleShiftOp::Shld, "shld"), (DoubleShiftOp::Shrd, "shrd")
+];
+
+const CONDS : [(Cond, &str); 16] = [
+    (Cond::O, "o"), (Cond::NO, "no"), (Cond::B, "b"), (Cond::AE, "ae"),
+    (Cond::E, "e"), (Cond::NE, "ne"), (Cond::BE, "be"), (Cond::A, "a"),
+    (Cond::S, "s"), (Cond::NS, "ns"), (Cond::P, "p"), (Cond::NP, "np"),
+    (Cond::L, "l"), (Cond::GE, "ge"), (Cond::LE, "le"), (Cond::G, "g")
+];
+
+const SIZES : [i32; 4] = [1, 2, 4, 8];
+
+fn gpr(reg: Reg, size: i32) -> &'static str {
+    let index = GPRS.iter().position(|&r| r == reg).unwrap();
+    
+    match size {
+        1 => GPR_NAMES[0][index],
+        2 => GPR_NAMES[1][index],
+        4 => GPR_NAMES[2][index],
+        _ => GPR_NAMES[3][index]
+    }
+}
+
+fn xmm(reg: Reg) -> String {
+    format!("xmm{}", reg.value())
+}
+
+fn pick<T: Copy>(items: &[T], i: usize) -> T {
+    items[i % items.len()]
+}
+
+/// Picks from `items` such that every base register of `addrs` is combined
+/// with every item, not only the displacements that share an index.
+fn spread<T: Copy>(items: &[T], i: usize) -> T {
+    items[(i + i / 4) % items.len()]
+}
+
+/// A memory operand in one of the addressing forms of the emitter.
+#[derive(Copy, Clone)]
+enum Addr {
+    Regp(Reg),
+    Mem(i32),
+    MemBase(Reg, i32),
+    MemIndex(Reg, i32, Reg, u8)
+}
+
+impl Addr {
+    fn is_memindex(&self) -> bool {
+        matches!(*self, Addr::MemIndex(..))
+    }
+    
+    fn text(&self, ptr: &str) -> String {
+        fn disp(disp: i32) -> String {
+            if disp < 0 {
+                format!("-{:#x}", -(disp as i64))
+            } else if disp > 0 {
+                format!("+{:#x}", disp)
+            } else {
+                String::new()
+            }
+        }
+        
+        let base = |reg: Reg| if reg == Reg::RIP { "rip" } else { gpr(reg, 8) };
+        
+        let addr = match *self {
+            Addr::Regp(reg) => base(reg).to_string(),
+            Addr::Mem(mem) => format!("{:#x}", mem),
+            Addr::MemBase(reg, d) => format!("{}{}", base(reg), disp(d)),
+            Addr::MemIndex(Reg::NOBASEREG, d, index, shift) => {
+                format!("{}*{}{}", gpr(index, 8), 1 << shift, disp(d))
+            }
+            Addr::MemIndex(reg, d, index, shift) => {
+                format!("{}+{}*{}{}", base(reg), gpr(index, 8), 1 << shift, disp(d))
+            }
+        };
+        
+        if ptr.is_empty() {
+            format!("[{}]", addr)
+        } else {
+            format!("{} ptr [{}]", ptr, addr)
+        }
+    }
+}
+
+/// Every addressing form: each register as pointer, absolute addresses,
+/// each base register with no, 8 bit and 32 bit displacements (including
+/// the RSP/R12 SIB and RBP/R13 displacement special cases), RIP relative
+/// and base plus scaled index with every index register and scale.
+fn addrs() -> Vec<Addr> {
+    let mut addrs = Vec::new();
+    
+    for &reg in GPRS.iter() {
+        addrs.push(Addr::Regp(reg));
+    }
+    
+    addrs.push(Addr::Mem(0x1000));
+    addrs.push(Addr::Mem(0x7ffffff0));
+    
+    for &reg in GPRS.iter() {
+        for &disp in [0, 8, -128, 0x1000].iter() {
+            addrs.push(Addr::MemBase(reg, disp));
+        }
+    }
+    
+    addrs.push(Addr::MemBase(Reg::RIP, 0x10));
+    
+    for (i, &reg) in GPRS.iter().enumerate() {
+        addrs.push(Addr::MemIndex(reg, 0, pick(&INDEXES, i * 2), (i % 4) as u8));
+        addrs.push(Addr::MemIndex(reg, pick(&[8, -128, 0x1000], i), pick(&INDEXES, i * 2 + 1), ((i + 1) % 4) as u8));
+    }
+    
+    addrs.push(Addr::MemIndex(Reg::NOBASEREG, 0x10, Reg::RCX, 2));
+    addrs.push(Addr::MemIndex(Reg::NOBASEREG, 0, Reg::R13, 3));
+    
+    addrs
+}
+
+/// A sample of `addrs` with the special cases of every addressing form,
+/// for the emitters that share their addressing code with others.
+fn few_addrs() -> Vec<Addr> {
+    vec![
+        Addr::Regp(Reg::RAX), Addr::Regp(Reg::RSP), Addr::Regp(Reg::RBP),
+        Addr::Regp(Reg::R9), Addr::Regp(Reg::R12), Addr::Regp(Reg::R13),
+        Addr::Mem(0x1000),
+        Addr::MemBase(Reg::RBX, 0), Addr::MemBase(Reg::RSP, 8), Addr::MemBase(Reg::RBP, 0),
+        Addr::MemBase(Reg::R12, 0), Addr::MemBase(Reg::R13, -128), Addr::MemBase(Reg::R15, 0x1000),
+        Addr::MemBase(Reg::RIP, 0x10),
+        Addr::MemIndex(Reg::RAX, 0, Reg::RCX, 0), Addr::MemIndex(Reg::RBP, 0, Reg::R8, 1),
+        Addr::MemIndex(Reg::R12, 8, Reg::R13, 2), Addr::MemIndex(Reg::R13, 0x1000, Reg::R12, 3),
+        Addr::MemIndex(Reg::RSP, -128, Reg::RBP, 0), Addr::MemIndex(Reg::NOBASEREG, 0x10, Reg::R9, 2)
+    ]
+}
+
+fn ptr(size: i32) -> &'static str {
+    match size {
+        1 => "byte",
+        2 => "word",
+        4 => "dword",
+        8 => "qword",
+        10 => "tbyte",
+        _ => "xmmword"
+    }
+}
+
+struct Suite {
+    cases: Vec<(String, Vec<u8>)>
+}
+
+impl Suite {
+    fn case<F: FnOnce(&mut Emit)>(&mut self, asm: String, emit: F) {
+        let mut e = Emit::new();
+        emit(&mut e);
+        let (code, _) = e.layout(&[]).unwrap();
+        self.cases.push((asm, code));
+    }
+    
+    /// Register to register forms of the general purpose instructions:
+    /// every register as either operand, in each of the given sizes.
+    fn reg_reg<F>(&mut self, mnemonic: &str, sizes: &[i32], ssize: Option<i32>, emit: F)
+        where F: Fn(&mut Emit, Reg, Reg, i32)
+    {
+        for &size in sizes {
+            for i in 0..16 {
+                let dreg = GPRS[i];
+                let sreg = GPRS[(i * 3 + 2) % 16];
+                self.case(format!("{} {}, {}", mnemonic, gpr(dreg, size), gpr(sreg, ssize.unwrap_or(size))), |e| emit(e, dreg, sreg, size));
+            }
+        }
+    }
+}
+
+fn alu(s: &mut Suite) {
+    for &(op, name) in ALU_OPS.iter() {
+        s.reg_reg(&format!("{{load}} {}", name), &SIZES, None, |e, dreg, sreg, size| e.alu_reg_reg_size(op, dreg, sreg, size));
+    }
+    
+    for (i, addr) in addrs().into_iter().enumerate() {
+        let (op, name) = pick(&ALU_OPS, i);
+        let size = spread(&SIZES, i);
+        let reg = pick(&GPRS, i * 5 + 3);
+        
+        s.case(format!("{} {}, {}", name, addr.text(ptr(size)), gpr(reg, size)), |e| match addr {
+            Addr::Regp(base) => e.alu_regp_reg_size(op, base, reg, size),
+            Addr::Mem(mem) => e.alu_mem_reg_size(op, mem, reg, size),
+            Addr::MemBase(base, disp) => e.alu_membase_reg_size(op, base, disp, reg, size),
+            Addr::MemIndex(base, disp, index, shift) => e.alu_memindex_reg_size(op, base, disp, index, shift, reg, size)
+        });
+        
+        let (op, name) = pick(&ALU_OPS, i + 3);
+        
+        s.case(format!("{} {}, {}", name, gpr(reg, size), addr.text(ptr(size))), |e| match addr {
+            Addr::Regp(base) => e.alu_reg_regp_size(op, reg, base, size),
+            Addr::Mem(mem) => e.alu_reg_mem_size(op, reg, mem, size),
+            Addr::MemBase(base, disp) => e.alu_reg_membase_size(op, reg, base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.alu_reg_memindex_size(op, reg, base, disp, index, shift, size)
+        });
+        
+        let imm = match size {
+            1 => pick(&[1, -128, 127], i),
+            2 => pick(&[1, -128, 0x1234], i),
+            _ => pick(&[1, -128, 0x12345678, -0x1000], i)
+        };
+        
+        s.case(format!("{} {}, {}", name, addr.text(ptr(size)), imm), |e| match addr {
+            Addr::Regp(base) => e.alu_regp_imm_size(op, base, imm, size),
+            Addr::Mem(mem) => e.alu_mem_imm_size(op, mem, imm, size),
+            Addr::MemBase(base, disp) => e.alu_membase_imm_size(op, base, disp, imm, size),
+            Addr::MemIndex(base, disp, index, shift) => e.alu_memindex_imm_size(op, base, disp, index, shift, imm, size)
+        });
+    }
+    
+    for &(op, name) in ALU_OPS.iter() {
+        for &size in SIZES.iter() {
+            let imms : &[i32] = match size {
+                1 => &[1, -128, 127],
+                2 => &[1, -128, 0x1234],
+                _ => &[1, -128, 0x12345678, -0x1000]
+            };
+            
+            for (i, &imm) in imms.iter().enumerate() {
+                for &reg in [Reg::RAX, pick(&GPRS, i * 5 + 1 + size as usize)].iter() {
+                    s.case(format!("{} {}, {}", name, gpr(reg, size), imm), |e| e.alu_reg_imm_size(op, reg, imm, size));
+                }
+            }
+        }
+    }
+}
+
+fn unary(s: &mut Suite) {
+    type UnaryOp = (&'static str, fn(&mut Emit, Reg, i32), fn(&mut Emit, Addr, i32));
+    
+    let ops : [UnaryOp; 8] = [
+        ("dec", |e, reg, size| e.dec_reg_size(reg, size), |e, addr, size| match addr {
+            Addr::Regp(base) => e.dec_regp_size(base, size),
+            Addr::Mem(mem) => e.dec_mem_size(mem, size),
+            Addr::MemBase(base, disp) => e.dec_membase_size(base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.dec_memindex_size(base, disp, index, shift, size)
+        }),
+        ("inc", |e, reg, size| e.inc_reg_size(reg, size), |e, addr, size| match addr {
+            Addr::Regp(base) => e.inc_regp_size(base, size),
+            Addr::Mem(mem) => e.inc_mem_size(mem, size),
+            Addr::MemBase(base, disp) => e.inc_membase_size(base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.inc_memindex_size(base, disp, index, shift, size)
+        }),
+        ("div", |e, reg, size| e.div_reg_size(reg, size), |e, addr, size| match addr {
+            Addr::Regp(base) => e.div_regp_size(base, size),
+            Addr::Mem(mem) => e.div_mem_size(mem, size),
+            Addr::MemBase(base, disp) => e.div_membase_size(base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.div_memindex_size(base, disp, index, shift, size)
+        }),
+        ("idiv", |e, reg, size| e.idiv_reg_size(reg, size), |e, addr, size| match addr {
+            Addr::Regp(base) => e.idiv_regp_size(base, size),
+            Addr::Mem(mem) => e.idiv_mem_size(mem, size),
+            Addr::MemBase(base, disp) => e.idiv_membase_size(base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.idiv_memindex_size(base, disp, index, shift, size)
+        }),
+        ("mul", |e, reg, size| e.mul_reg_issigned_size(reg, false, size), |e, addr, size| match addr {
+            Addr::Regp(base) => e.mul_regp_issigned_size(base, false, size),
+            Addr::Mem(mem) => e.mul_mem_issigned_size(mem, false, size),
+            Addr::MemBase(base, disp) => e.mul_membase_issigned_size(base, disp, false, size),
+            Addr::MemIndex(base, disp, index, shift) => e.mul_memindex_issigned_size(base, disp, index, shift, false, size)
+        }),
+        ("imul", |e, reg, size| e.mul_reg_issigned_size(reg, true, size), |e, addr, size| match addr {
+            Addr::Regp(base) => e.mul_regp_issigned_size(base, true, size),
+            Addr::Mem(mem) => e.mul_mem_issigned_size(mem, true, size),
+            Addr::MemBase(base, disp) => e.mul_membase_issigned_size(base, disp, true, size),
+            Addr::MemIndex(base, disp, index, shift) => e.mul_memindex_issigned_size(base, disp, index, shift, true, size)
+        }),
+        ("neg", |e, reg, size| e.neg_reg_size(reg, size), |e, addr, size| match addr {
+            Addr::Regp(base) => e.neg_regp_size(base, size),
+            Addr::Mem(mem) => e.neg_mem_size(mem, size),
+            Addr::MemBase(base, disp) => e.neg_membase_size(base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.neg_memindex_size(base, disp, index, shift, size)
+        }),
+        ("not", |e, reg, size| e.not_reg_size(reg, size), |e, addr, size| match addr {
+            Addr::Regp(base) => e.not_regp_size(base, size),
+            Addr::Mem(mem) => e.not_mem_size(mem, size),
+            Addr::MemBase(base, disp) => e.not_membase_size(base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.not_memindex_size(base, disp, index, shift, size)
+        })
+    ];
+    
+    for &(name, reg_op, addr_op) in ops.iter() {
+        for &size in SIZES.iter() {
+            for &reg in GPRS.iter() {
+                s.case(format!("{} {}", name, gpr(reg, size)), |e| reg_op(e, reg, size));
+            }
+        }
+        
+        for (i, addr) in few_addrs().into_iter().enumerate() {
+            let size = spread(&SIZES, i);
+            s.case(format!("{} {}", name, addr.text(ptr(size))), |e| addr_op(e, addr, size));
+        }
+    }
+}
+
+fn shift(s: &mut Suite) {
+    for &(op, name) in SHIFT_OPS.iter() {
+        for &size in SIZES.iter() {
+            for (i, &reg) in GPRS.iter().enumerate() {
+                let imm = pick(&[1, 5], i);
+                s.case(format!("{} {}, {}", name, gpr(reg, size), imm), |e| e.shift_reg_imm_size(op, reg, imm, size));
+                s.case(format!("{} {}, cl", name, gpr(reg, size)), |e| e.shift_reg_size(op, reg, size));
+            }
+        }
+    }
+    
+    for (i, addr) in few_addrs().into_iter().enumerate() {
+        let (op, name) = pick(&SHIFT_OPS, i);
+        let size = spread(&SIZES, i);
+        let imm = pick(&[1, 5, 31], i);
+        
+        s.case(format!("{} {}, {}", name, addr.text(ptr(size)), imm), |e| match addr {
+            Addr::Regp(base) => e.shift_regp_imm_size(op, base, imm, size),
+            Addr::Mem(mem) => e.shift_mem_imm_size(op, mem, imm, size),
+            Addr::MemBase(base, disp) => e.shift_membase_imm_size(op, base, disp, imm, size),
+            Addr::MemIndex(base, disp, index, shift) => e.shift_memindex_imm_size(op, base, disp, index, shift, imm, size)
+        });
+        
+        s.case(format!("{} {}, cl", name, addr.text(ptr(size))), |e| match addr {
+            Addr::Regp(base) => e.shift_regp_size(op, base, size),
+            Addr::Mem(mem) => e.shift_mem_size(op, mem, size),
+            Addr::MemBase(base, disp) => e.shift_membase_size(op, base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.shift_memindex_size(op, base, disp, index, shift, size)
+        });
+    }
+}
+
+fn double_shift(s: &mut Suite) {
+    for &(op, name) in DOUBLE_SHIFT_OPS.iter() {
+        for &size in [2, 4, 8].iter() {
+            for (i, &reg) in GPRS.iter().enumerate() {
+                let sreg = GPRS[(i * 5 + 3) % 16];
+                let imm = pick(&[1, 7, 63], i);
+                s.case(format!("{} {}, {}, {}", name, gpr(reg, size), gpr(sreg, size), imm), |e| e.double_shift_reg_reg_imm_size(op, reg, sreg, imm, size));
+                s.case(format!("{} {}, {}, cl", name, gpr(reg, size), gpr(sreg, size)), |e| e.double_shift_reg_reg_size(op, reg, sreg, size));
+            }
+        }
+    }
+    
+    for (i, addr) in few_addrs().into_iter().enumerate() {
+        let (op, name) = pick(&DOUBLE_SHIFT_OPS, i);
+        let size = spread(&[2, 4, 8], i);
+        let sreg = pick(&GPRS, i * 7);
+        let imm = pick(&[1, 5, 31], i);
+        
+        s.case(format!("{} {}, {}, {}", name, addr.text(ptr(size)), gpr(sreg, size), imm), |e| match addr {
+            Addr::Regp(base) => e.double_shift_regp_reg_imm_size(op, base, sreg, imm, size),
+            Addr::Mem(mem) => e.double_shift_mem_reg_imm_size(op, mem, sreg, imm, size),
+            Addr::MemBase(base, disp) => e.double_shift_membase_reg_imm_size(op, base, disp, sreg, imm, size),
+            Addr::MemIndex(base, disp, index, shift) => e.double_shift_memindex_reg_imm_size(op, base, disp, index, shift, sreg, imm, size)
+        });
+        
+        s.case(format!("{} {}, {}, cl", name, addr.text(ptr(size)), gpr(sreg, size)), |e| match addr {
+            Addr::Regp(base) => e.double_shift_regp_reg_size(op, base, sreg, size),
+            Addr::Mem(mem) => e.double_shift_mem_reg_size(op, mem, sreg, size),
+            Addr::MemBase(base, disp) => e.double_shift_membase_reg_size(op, base, disp, sreg, size),
+            Addr::MemIndex(base, disp, index, shift) => e.double_shift_memindex_reg_size(op, base, disp, index, shift, sreg, size)
+        });
+    }
+}
+
+fn test_ops(s: &mut Suite) {
+    s.reg_reg("test", &SIZES, None, |e, dreg, sreg, size| e.test_reg_reg_size(dreg, sreg, size));
+    
+    for &size in SIZES.iter() {
+        let imm = if size == 1 { 0x7f } else { 0x1234 };
+        
+        for &reg in GPRS.iter() {
+            s.case(format!("test {}, {}", gpr(reg, size), imm), |e| e.test_reg_imm_size(reg, imm, size));
+        }
+    }
+    
+    for (i, addr) in few_addrs().into_iter().enumerate() {
+        let size = spread(&SIZES, i);
+        let reg = pick(&GPRS, i * 5 + 3);
+        let imm = if size == 1 { -2 } else { 0x1234 };
+        
+        s.case(format!("test {}, {}", addr.text(ptr(size)), gpr(reg, size)), |e| match addr {
+            Addr::Regp(base) => e.test_regp_reg_size(base, reg, size),
+            Addr::Mem(mem) => e.test_mem_reg_size(mem, reg, size),
+            Addr::MemBase(base, disp) => e.test_membase_reg_size(base, disp, reg, size),
+            Addr::MemIndex(base, disp, index, shift) => e.test_memindex_reg_size(base, disp, index, shift, reg, size)
+        });
+        
+        s.case(format!("test {}, {}", addr.text(ptr(size)), imm), |e| match addr {
+            Addr::Regp(base) => e.test_regp_imm_size(base, imm, size),
+            Addr::Mem(mem) => e.test_mem_imm_size(mem, imm, size),
+            Addr::MemBase(base, disp) => e.test_membase_imm_size(base, disp, imm, size),
+            Addr::MemIndex(base, disp, index, shift) => e.test_memindex_imm_size(base, disp, index, shift, imm, size)
+        });
+    }
+}
+
+fn imul(s: &mut Suite) {
+    s.reg_reg("imul", &[2, 4, 8], None, |e, dreg, sreg, size| e.imul_reg_reg_size(dreg, sreg, size));
+    
+    for &size in [2, 4, 8].iter() {
+        for i in 0..16 {
+            let dreg = GPRS[i];
+            let sreg = GPRS[(i * 7 + 3) % 16];
+            let imm = pick(&[5, -128, 0x1234], i);
+            s.case(format!("imul {}, {}, {}", gpr(dreg, size), gpr(sreg, size), imm), |e| e.imul_reg_reg_imm_size(dreg, sreg, imm, size));
+        }
+    }
+    
+    for (i, addr) in few_addrs().into_iter().enumerate() {
+        let size = spread(&[2, 4, 8], i);
+        let reg = pick(&GPRS, i * 5 + 3);
+        let imm = pick(&[5, -128, 0x1234], i);
+        
+        s.case(format!("imul {}, {}", gpr(reg, size), addr.text(ptr(size))), |e| match addr {
+            Addr::Regp(base) => e.imul_reg_regp_size(reg, base, size),
+            Addr::Mem(mem) => e.imul_reg_mem_size(reg, mem, size),
+            Addr::MemBase(base, disp) => e.imul_reg_membase_size(reg, base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.imul_reg_memindex_size(reg, base, disp, index, shift, size)
+        });
+        
+        s.case(format!("imul {}, {}, {}", gpr(reg, size), addr.text(ptr(size)), imm), |e| match addr {
+            Addr::Regp(base) => e.imul_reg_regp_imm_size(reg, base, imm, size),
+            Addr::Mem(mem) => e.imul_reg_mem_imm_size(reg, mem, imm, size),
+            Addr::MemBase(base, disp) => e.imul_reg_membase_imm_size(reg, base, disp, imm, size),
+            Addr::MemIndex(base, disp, index, shift) => e.imul_reg_memindex_imm_size(reg, base, disp, index, shift, imm, size)
+        });
+    }
+    
+    s.case("cwd".to_string(), |e| e.cwd());
+    s.case("cdq".to_string(), |e| e.cdq());
+    s.case("cqo".to_string(), |e| e.cqo());
+}
+
+fn lea(s: &mut Suite) {
+    for (i, addr) in addrs().into_iter().enumerate() {
+        let size = spread(&[2, 4, 8], i);
+        let reg = pick(&GPRS, i * 5 + 3);
+        
+        s.case(format!("lea {}, {}", gpr(reg, size), addr.text("")), |e| match addr {
+            Addr::Regp(base) => e.lea_membase_size(reg, base, 0, size),
+            Addr::Mem(mem) => e.lea_mem_size(reg, mem, size),
+            Addr::MemBase(base, disp) => e.lea_membase_size(reg, base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.lea_memindex_size(reg, base, disp, index, shift, size)
+        });
+    }
+}
+
+fn mov(s: &mut Suite) {
+    s.reg_reg("{load} mov", &SIZES, None, |e, dreg, sreg, size| e.mov_reg_reg_size(dreg, sreg, size));
+    
+    for &size in SIZES.iter() {
+        let imms : &[i64] = match size {
+            1 => &[0x7f, -1],
+            2 => &[0x1234, -1],
+            4 => &[0x12345678, -1],
+            _ => &[0x12345678, -1, 0x123456789a, -0x123456789a]
+        };
+        
+        for (i, &reg) in GPRS.iter().enumerate() {
+            let imm = pick(imms, i);
+            let mnemonic = if imm != imm as i32 as i64 { "movabs" } else { "mov" };
+            s.case(format!("{} {}, {}", mnemonic, gpr(reg, size), imm), |e| e.mov_reg_imm_size(reg, imm, size));
+        }
+        
+        for &reg in [Reg::RAX, Reg::RCX, Reg::R9].iter() {
+            let mem = if reg == Reg::RAX { 0x1122334455667788 } else { 0x1000 };
+            let mnemonic = if reg == Reg::RAX { "movabs" } else { "mov" };
+            s.case(format!("{} {}, {} ptr [{:#x}]", mnemonic, gpr(reg, size), ptr(size), mem), |e| e.mov_reg_mem_size(reg, mem, size));
+            s.case(format!("{} {} ptr [{:#x}], {}", mnemonic, ptr(size), mem, gpr(reg, size)), |e| e.mov_mem_reg_size(mem, reg, size));
+        }
+    }
+    
+    for (i, addr) in addrs().into_iter().enumerate() {
+        let size = spread(&SIZES, i);
+        let reg = pick(&GPRS, i * 5 + 3);
+        let imm = match size {
+            1 => -2,
+            2 => 0x1234,
+            _ => pick(&[0x12345678, -1], i)
+        };
+        
+        s.case(format!("mov {}, {}", addr.text(ptr(size)), gpr(reg, size)), |e| match addr {
+            Addr::Regp(base) => e.mov_regp_reg_size(base, reg, size),
+            Addr::Mem(mem) => e.mov_mem_reg_size(mem as i64, reg, size),
+            Addr::MemBase(base, disp) => e.mov_membase_reg_size(base, disp, reg, size),
+            Addr::MemIndex(base, disp, index, shift) => e.mov_memindex_reg_size(base, disp, index, shift, reg, size)
+        });
+        
+        s.case(format!("mov {}, {}", gpr(reg, size), addr.text(ptr(size))), |e| match addr {
+            Addr::Regp(base) => e.mov_reg_regp_size(reg, base, size),
+            Addr::Mem(mem) => e.mov_reg_mem_size(reg, mem as i64, size),
+            Addr::MemBase(base, disp) => e.mov_reg_membase_size(reg, base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.mov_reg_memindex_size(reg, base, disp, index, shift, size)
+        });
+        
+        s.case(format!("mov {}, {}", addr.text(ptr(size)), imm), |e| match addr {
+            Addr::Regp(base) => e.mov_regp_imm_size(base, imm, size),
+            Addr::Mem(mem) => e.mov_mem_imm_size(mem, imm, size),
+            Addr::MemBase(base, disp) => e.mov_membase_imm_size(base, disp, imm, size),
+            Addr::MemIndex(base, disp, index, shift) => e.mov_memindex_imm_size(base, disp, index, shift, imm, size)
+        });
+    }
+}
+
+fn movx(s: &mut Suite) {
+    s.reg_reg("movsx", &[2, 4, 8], Some(1), |e, dreg, sreg, size| e.movsx8_reg_reg_size(dreg, sreg, size));
+    s.reg_reg("movsx", &[4, 8], Some(2), |e, dreg, sreg, size| e.movsx16_reg_reg_size(dreg, sreg, size));
+    s.reg_reg("movsxd", &[8], Some(4), |e, dreg, sreg, size| e.movsx32_reg_reg_size(dreg, sreg, size));
+    s.reg_reg("movzx", &[2, 4, 8], Some(1), |e, dreg, sreg, size| e.movzx8_reg_reg_size(dreg, sreg, size));
+    s.reg_reg("movzx", &[4, 8], Some(2), |e, dreg, sreg, size| e.movzx16_reg_reg_size(dreg, sreg, size));
+    
+    for (i, addr) in few_addrs().into_iter().enumerate() {
+        let reg = pick(&GPRS, i * 5 + 3);
+        
+        let size = spread(&[2, 4, 8], i);
+        s.case(format!("movsx {}, {}", gpr(reg, size), addr.text("byte")), |e| match addr {
+            Addr::Regp(base) => e.movsx8_reg_regp_size(reg, base, size),
+            Addr::Mem(mem) => e.movsx8_reg_mem_size(reg, mem, size),
+            Addr::MemBase(base, disp) => e.movsx8_reg_membase_size(reg, base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.movsx8_reg_memindex_size(reg, base, disp, index, shift, size)
+        });
+        s.case(format!("movzx {}, {}", gpr(reg, size), addr.text("byte")), |e| match addr {
+            Addr::Regp(base) => e.movzx8_reg_regp_size(reg, base, size),
+            Addr::Mem(mem) => e.movzx8_reg_mem_size(reg, mem, size),
+            Addr::MemBase(base, disp) => e.movzx8_reg_membase_size(reg, base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.movzx8_reg_memindex_size(reg, base, disp, index, shift, size)
+        });
+        
+        let size = spread(&[4, 8], i);
+        s.case(format!("movsx {}, {}", gpr(reg, size), addr.text("word")), |e| match addr {
+            Addr::Regp(base) => e.movsx16_reg_regp_size(reg, base, size),
+            Addr::Mem(mem) => e.movsx16_reg_mem_size(reg, mem, size),
+            Addr::MemBase(base, disp) => e.movsx16_reg_membase_size(reg, base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.movsx16_reg_memindex_size(reg, base, disp, index, shift, size)
+        });
+        s.case(format!("movzx {}, {}", gpr(reg, size), addr.text("word")), |e| match addr {
+            Addr::Regp(base) => e.movzx16_reg_regp_size(reg, base, size),
+            Addr::Mem(mem) => e.movzx16_reg_mem_size(reg, mem, size),
+            Addr::MemBase(base, disp) => e.movzx16_reg_membase_size(reg, base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.movzx16_reg_memindex_size(reg, base, disp, index, shift, size)
+        });
+        
+        s.case(format!("movsxd {}, {}", gpr(reg, 8), addr.text("dword")), |e| match addr {
+            Addr::Regp(base) => e.movsx32_reg_regp_size(reg, base, 8),
+            Addr::Mem(mem) => e.movsx32_reg_mem_size(reg, mem, 8),
+            Addr::MemBase(base, disp) => e.movsx32_reg_membase_size(reg, base, disp, 8),
+            Addr::MemIndex(base, disp, index, shift) => e.movsx32_reg_memindex_size(reg, base, disp, index, shift, 8)
+        });
+    }
+}
+
+fn cond(s: &mut Suite) {
+    for &(cond, name) in CONDS.iter() {
+        s.reg_reg(&format!("cmov{}", name), &[2, 4, 8], None, |e, dreg, sreg, size| e.cmov_reg_reg_size(cond, dreg, sreg, size));
+        
+        for &reg in GPRS.iter() {
+            s.case(format!("set{} {}", name, gpr(reg, 1)), |e| e.set_reg(cond, reg));
+        }
+    }
+    
+    for (i, addr) in few_addrs().into_iter().enumerate() {
+        let (cond, name) = pick(&CONDS, i);
+        let size = spread(&[2, 4, 8], i);
+        let reg = pick(&GPRS, i * 5 + 3);
+        
+        s.case(format!("cmov{} {}, {}", name, gpr(reg, size), addr.text(ptr(size))), |e| match addr {
+            Addr::Regp(base) => e.cmov_reg_regp_size(cond, reg, base, size),
+            Addr::Mem(mem) => e.cmov_reg_mem_size(cond, reg, mem, size),
+            Addr::MemBase(base, disp) => e.cmov_reg_membase_size(cond, reg, base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.cmov_reg_memindex_size(cond, reg, base, disp, index, shift, size)
+        });
+        
+        s.case(format!("set{} {}", name, addr.text("byte")), |e| match addr {
+            Addr::Regp(base) => e.set_membase(cond, base, 0),
+            Addr::Mem(mem) => e.set_mem(cond, mem),
+            Addr::MemBase(base, disp) => e.set_membase(cond, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.set_memindex(cond, base, disp, index, shift)
+        });
+    }
+}
+
+fn stack(s: &mut Suite) {
+    for &size in [2, 8].iter() {
+        for &reg in GPRS.iter() {
+            s.case(format!("push {}", gpr(reg, size)), |e| e.push_reg_size(reg, size));
+            s.case(format!("pop {}", gpr(reg, size)), |e| e.pop_reg_size(reg, size));
+        }
+    }
+    
+    for (i, addr) in few_addrs().into_iter().enumerate() {
+        let size = spread(&[2, 8], i);
+        
+        s.case(format!("push {}", addr.text(ptr(size))), |e| match addr {
+            Addr::Regp(base) => e.push_regp_size(base, size),
+            Addr::Mem(mem) => e.push_mem_size(mem, size),
+            Addr::MemBase(base, disp) => e.push_membase_size(base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.push_memindex_size(base, disp, index, shift, size)
+        });
+        
+        s.case(format!("pop {}", addr.text(ptr(size))), |e| match addr {
+            Addr::Regp(base) => e.pop_regp_size(base, size),
+            Addr::Mem(mem) => e.pop_mem_size(mem, size),
+            Addr::MemBase(base, disp) => e.pop_membase_size(base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.pop_memindex_size(base, disp, index, shift, size)
+        });
+    }
+    
+    for &imm in [5, -128, 0x12345678].iter() {
+        s.case(format!("push {}", imm), |e| e.push_imm(imm));
+    }
+    
+    s.case("pushw 0x1234".to_string(), |e| e.push_imm_size(0x1234, 2));
+    s.case("push 0x12345678".to_string(), |e| e.push_imm_size(0x12345678, 4));
+}
+
+fn branch(s: &mut Suite) {
+    for &reg in GPRS.iter() {
+        s.case(format!("call {}", gpr(reg, 8)), |e| e.call_reg(reg));
+        s.case(format!("jmp {}", gpr(reg, 8)), |e| e.jmp_reg(reg));
+    }
+    
+    for addr in few_addrs() {
+        s.case(format!("call {}", addr.text("qword")), |e| match addr {
+            Addr::Regp(base) => e.call_regp(base),
+            Addr::Mem(mem) => e.call_mem(mem),
+            Addr::MemBase(base, disp) => e.call_membase(base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.call_memindex(base, disp, index, shift)
+        });
+        
+        s.case(format!("jmp {}", addr.text("qword")), |e| match addr {
+            Addr::Regp(base) => e.jmp_regp(base),
+            Addr::Mem(mem) => e.jmp_mem(mem),
+            Addr::MemBase(base, disp) => e.jmp_membase(base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.jmp_memindex(base, disp, index, shift)
+        });
+    }
+    
+    s.case("ret".to_string(), |e| e.ret());
+}
+
+fn xchg(s: &mut Suite) {
+    s.reg_reg("{load} xchg", &SIZES, None, |e, dreg, sreg, size| e.xchg_reg_reg_size(dreg, sreg, size));
+    
+    for &size in SIZES.iter() {
+        for &(dreg, sreg) in [(Reg::RAX, Reg::RAX), (Reg::RAX, Reg::R8), (Reg::R9, Reg::RAX)].iter() {
+            // The assembler drops the Rex prefix of the nop xchg rax, rax.
+            if size == 8 && dreg == sreg {
+                continue;
+            }
+            s.case(format!("{{load}} xchg {}, {}", gpr(dreg, size), gpr(sreg, size)), |e| e.xchg_reg_reg_size(dreg, sreg, size));
+        }
+    }
+}
+
+fn sse(s: &mut Suite) {
+    type XmmOp = (&'static str, &'static str, fn(&mut Emit, Reg, Reg), fn(&mut Emit, Reg, Addr));
+    
+    let ops : [XmmOp; 30] = [
+        ("movaps", "xmmword", |e, d, s| e.movaps_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.movaps_reg_regp(d, base),
+            Addr::Mem(mem) => e.movaps_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.movaps_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.movaps_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("movups", "xmmword", |e, d, s| e.movups_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.movups_reg_regp(d, base),
+            Addr::Mem(mem) => e.movups_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.movups_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.movups_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("movss", "dword", |e, d, s| e.movss_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.movss_reg_regp(d, base),
+            Addr::Mem(mem) => e.movss_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.movss_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.movss_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("movsd", "qword", |e, d, s| e.movsd_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.movsd_reg_regp(d, base),
+            Addr::Mem(mem) => e.movsd_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.movsd_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.movsd_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("cvtss2sd", "dword", |e, d, s| e.cvtss2sd_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.cvtss2sd_reg_regp(d, base),
+            Addr::Mem(mem) => e.cvtss2sd_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.cvtss2sd_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.cvtss2sd_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("cvtsd2ss", "qword", |e, d, s| e.cvtsd2ss_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.cvtsd2ss_reg_regp(d, base),
+            Addr::Mem(mem) => e.cvtsd2ss_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.cvtsd2ss_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.cvtsd2ss_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("comiss", "dword", |e, d, s| e.comiss_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.comiss_reg_regp(d, base),
+            Addr::Mem(mem) => e.comiss_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.comiss_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.comiss_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("comisd", "qword", |e, d, s| e.comisd_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.comisd_reg_regp(d, base),
+            Addr::Mem(mem) => e.comisd_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.comisd_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.comisd_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("ucomiss", "dword", |e, d, s| e.ucomiss_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.ucomiss_reg_regp(d, base),
+            Addr::Mem(mem) => e.ucomiss_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.ucomiss_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.ucomiss_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("ucomisd", "qword", |e, d, s| e.ucomisd_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.ucomisd_reg_regp(d, base),
+            Addr::Mem(mem) => e.ucomisd_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.ucomisd_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.ucomisd_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("addss", "dword", |e, d, s| e.addss_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.addss_reg_regp(d, base),
+            Addr::Mem(mem) => e.addss_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.addss_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.addss_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("subss", "dword", |e, d, s| e.subss_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.subss_reg_regp(d, base),
+            Addr::Mem(mem) => e.subss_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.subss_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.subss_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("mulss", "dword", |e, d, s| e.mulss_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.mulss_reg_regp(d, base),
+            Addr::Mem(mem) => e.mulss_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.mulss_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.mulss_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("divss", "dword", |e, d, s| e.divss_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.divss_reg_regp(d, base),
+            Addr::Mem(mem) => e.divss_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.divss_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.divss_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("maxss", "dword", |e, d, s| e.maxss_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.maxss_reg_regp(d, base),
+            Addr::Mem(mem) => e.maxss_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.maxss_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.maxss_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("minss", "dword", |e, d, s| e.minss_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.minss_reg_regp(d, base),
+            Addr::Mem(mem) => e.minss_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.minss_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.minss_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("sqrtss", "dword", |e, d, s| e.sqrtss_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.sqrtss_reg_regp(d, base),
+            Addr::Mem(mem) => e.sqrtss_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.sqrtss_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.sqrtss_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("addsd", "qword", |e, d, s| e.addsd_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.addsd_reg_regp(d, base),
+            Addr::Mem(mem) => e.addsd_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.addsd_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.addsd_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("subsd", "qword", |e, d, s| e.subsd_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.subsd_reg_regp(d, base),
+            Addr::Mem(mem) => e.subsd_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.subsd_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.subsd_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("mulsd", "qword", |e, d, s| e.mulsd_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.mulsd_reg_regp(d, base),
+            Addr::Mem(mem) => e.mulsd_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.mulsd_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.mulsd_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("divsd", "qword", |e, d, s| e.divsd_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.divsd_reg_regp(d, base),
+            Addr::Mem(mem) => e.divsd_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.divsd_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.divsd_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("maxsd", "qword", |e, d, s| e.maxsd_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.maxsd_reg_regp(d, base),
+            Addr::Mem(mem) => e.maxsd_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.maxsd_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.maxsd_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("minsd", "qword", |e, d, s| e.minsd_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.minsd_reg_regp(d, base),
+            Addr::Mem(mem) => e.minsd_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.minsd_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.minsd_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("sqrtsd", "qword", |e, d, s| e.sqrtsd_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.sqrtsd_reg_regp(d, base),
+            Addr::Mem(mem) => e.sqrtsd_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.sqrtsd_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.sqrtsd_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("andps", "xmmword", |e, d, s| e.andps_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.andps_reg_regp(d, base),
+            Addr::Mem(mem) => e.andps_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.andps_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.andps_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("orps", "xmmword", |e, d, s| e.orps_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.orps_reg_regp(d, base),
+            Addr::Mem(mem) => e.orps_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.orps_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.orps_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("xorps", "xmmword", |e, d, s| e.xorps_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.xorps_reg_regp(d, base),
+            Addr::Mem(mem) => e.xorps_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.xorps_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.xorps_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("andpd", "xmmword", |e, d, s| e.andpd_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.andpd_reg_regp(d, base),
+            Addr::Mem(mem) => e.andpd_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.andpd_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.andpd_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("orpd", "xmmword", |e, d, s| e.orpd_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.orpd_reg_regp(d, base),
+            Addr::Mem(mem) => e.orpd_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.orpd_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.orpd_reg_memindex(d, base, disp, index, shift)
+        }),
+        ("xorpd", "xmmword", |e, d, s| e.xorpd_reg_reg(d, s), |e, d, addr| match addr {
+            Addr::Regp(base) => e.xorpd_reg_regp(d, base),
+            Addr::Mem(mem) => e.xorpd_reg_mem(d, mem),
+            Addr::MemBase(base, disp) => e.xorpd_reg_membase(d, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.xorpd_reg_memindex(d, base, disp, index, shift)
+        })
+    ];
+    
+    for (n, &(name, ptr, reg_op, addr_op)) in ops.iter().enumerate() {
+        for i in 0..16 {
+            let dreg = XMMS[i];
+            let sreg = XMMS[(i * 7 + 3 + n) % 16];
+            s.case(format!("{} {}, {}", name, xmm(dreg), xmm(sreg)), |e| reg_op(e, dreg, sreg));
+        }
+        
+        for (i, addr) in few_addrs().into_iter().enumerate() {
+            let reg = pick(&XMMS, i * 5 + n);
+            s.case(format!("{} {}, {}", name, xmm(reg), addr.text(ptr)), |e| addr_op(e, reg, addr));
+        }
+    }
+    
+    for (i, addr) in few_addrs().into_iter().enumerate() {
+        let reg = pick(&XMMS, i * 5 + 3);
+        
+        s.case(format!("movaps {}, {}", addr.text("xmmword"), xmm(reg)), |e| match addr {
+            Addr::Regp(base) => e.movaps_regp_reg(base, reg),
+            Addr::Mem(mem) => e.movaps_mem_reg(mem, reg),
+            Addr::MemBase(base, disp) => e.movaps_membase_reg(base, disp, reg),
+            Addr::MemIndex(base, disp, index, shift) => e.movaps_memindex_reg(base, disp, index, shift, reg)
+        });
+        s.case(format!("movups {}, {}", addr.text("xmmword"), xmm(reg)), |e| match addr {
+            Addr::Regp(base) => e.movups_regp_reg(base, reg),
+            Addr::Mem(mem) => e.movups_mem_reg(mem, reg),
+            Addr::MemBase(base, disp) => e.movups_membase_reg(base, disp, reg),
+            Addr::MemIndex(base, disp, index, shift) => e.movups_memindex_reg(base, disp, index, shift, reg)
+        });
+        s.case(format!("movss {}, {}", addr.text("dword"), xmm(reg)), |e| match addr {
+            Addr::Regp(base) => e.movss_regp_reg(base, reg),
+            Addr::Mem(mem) => e.movss_mem_reg(mem, reg),
+            Addr::MemBase(base, disp) => e.movss_membase_reg(base, disp, reg),
+            Addr::MemIndex(base, disp, index, shift) => e.movss_memindex_reg(base, disp, index, shift, reg)
+        });
+        s.case(format!("movsd {}, {}", addr.text("qword"), xmm(reg)), |e| match addr {
+            Addr::Regp(base) => e.movsd_regp_reg(base, reg),
+            Addr::Mem(mem) => e.movsd_mem_reg(mem, reg),
+            Addr::MemBase(base, disp) => e.movsd_membase_reg(base, disp, reg),
+            Addr::MemIndex(base, disp, index, shift) => e.movsd_memindex_reg(base, disp, index, shift, reg)
+        });
+        
+        s.case(format!("movd {}, {}", xmm(reg), addr.text("dword")), |e| match addr {
+            Addr::Regp(base) => e.movd_xreg_regp(reg, base),
+            Addr::Mem(mem) => e.movd_xreg_mem(reg, mem),
+            Addr::MemBase(base, disp) => e.movd_xreg_membase(reg, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.movd_xreg_memindex(reg, base, disp, index, shift)
+        });
+        s.case(format!("movd {}, {}", addr.text("dword"), xmm(reg)), |e| match addr {
+            Addr::Regp(base) => e.movd_regp_xreg(base, reg),
+            Addr::Mem(mem) => e.movd_mem_xreg(mem, reg),
+            Addr::MemBase(base, disp) => e.movd_membase_xreg(base, disp, reg),
+            Addr::MemIndex(base, disp, index, shift) => e.movd_memindex_xreg(base, disp, index, shift, reg)
+        });
+        s.case(format!("rex.w movd {}, {}", xmm(reg), addr.text("dword")), |e| match addr {
+            Addr::Regp(base) => e.movq_xreg_regp(reg, base),
+            Addr::Mem(mem) => e.movq_xreg_mem(reg, mem),
+            Addr::MemBase(base, disp) => e.movq_xreg_membase(reg, base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.movq_xreg_memindex(reg, base, disp, index, shift)
+        });
+        s.case(format!("rex.w movd {}, {}", addr.text("dword"), xmm(reg)), |e| match addr {
+            Addr::Regp(base) => e.movq_regp_xreg(base, reg),
+            Addr::Mem(mem) => e.movq_mem_xreg(mem, reg),
+            Addr::MemBase(base, disp) => e.movq_membase_xreg(base, disp, reg),
+            Addr::MemIndex(base, disp, index, shift) => e.movq_memindex_xreg(base, disp, index, shift, reg)
+        });
+        
+        let mode = pick(&[0, 1, 2, 3], i);
+        s.case(format!("roundss {}, {}, {}", xmm(reg), addr.text("dword"), mode), |e| match addr {
+            Addr::Regp(base) => e.roundss_reg_regp(reg, base, mode),
+            Addr::Mem(mem) => e.roundss_reg_mem(reg, mem, mode),
+            Addr::MemBase(base, disp) => e.roundss_reg_membase(reg, base, disp, mode),
+            Addr::MemIndex(base, disp, index, shift) => e.roundss_reg_memindex(reg, base, disp, index, shift, mode)
+        });
+        s.case(format!("roundsd {}, {}, {}", xmm(reg), addr.text("qword"), mode), |e| match addr {
+            Addr::Regp(base) => e.roundsd_reg_regp(reg, base, mode),
+            Addr::Mem(mem) => e.roundsd_reg_mem(reg, mem, mode),
+            Addr::MemBase(base, disp) => e.roundsd_reg_membase(reg, base, disp, mode),
+            Addr::MemIndex(base, disp, index, shift) => e.roundsd_reg_memindex(reg, base, disp, index, shift, mode)
+        });
+        
+        s.case(format!("ldmxcsr {}", addr.text("dword")), |e| match addr {
+            Addr::Regp(base) => e.ldmxcsr_regp(base),
+            Addr::Mem(mem) => e.ldmxcsr_mem(mem),
+            Addr::MemBase(base, disp) => e.ldmxcsr_membase(base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.ldmxcsr_memindex(base, disp, index, shift)
+        });
+        s.case(format!("stmxcsr {}", addr.text("dword")), |e| match addr {
+            Addr::Regp(base) => e.stmxcsr_regp(base),
+            Addr::Mem(mem) => e.stmxcsr_mem(mem),
+            Addr::MemBase(base, disp) => e.stmxcsr_membase(base, disp),
+            Addr::MemIndex(base, disp, index, shift) => e.stmxcsr_memindex(base, disp, index, shift)
+        });
+        
+        let size = spread(&[4, 8], i);
+        let gpreg = pick(&GPRS, i * 3 + 1);
+        
+        s.case(format!("cvtsi2ss {}, {}", xmm(reg), addr.text(ptr(size))), |e| match addr {
+            Addr::Regp(base) => e.cvtsi2ss_reg_regp_size(reg, base, size),
+            Addr::Mem(mem) => e.cvtsi2ss_reg_mem_size(reg, mem, size),
+            Addr::MemBase(base, disp) => e.cvtsi2ss_reg_membase_size(reg, base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.cvtsi2ss_reg_memindex_size(reg, base, disp, index, shift, size)
+        });
+        s.case(format!("cvtsi2sd {}, {}", xmm(reg), addr.text(ptr(size))), |e| match addr {
+            Addr::Regp(base) => e.cvtsi2sd_reg_regp_size(reg, base, size),
+            Addr::Mem(mem) => e.cvtsi2sd_reg_mem_size(reg, mem, size),
+            Addr::MemBase(base, disp) => e.cvtsi2sd_reg_membase_size(reg, base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.cvtsi2sd_reg_memindex_size(reg, base, disp, index, shift, size)
+        });
+        s.case(format!("cvtss2si {}, {}", gpr(gpreg, size), addr.text("dword")), |e| match addr {
+            Addr::Regp(base) => e.cvtss2si_reg_regp_size(gpreg, base, size),
+            Addr::Mem(mem) => e.cvtss2si_reg_mem_size(gpreg, mem, size),
+            Addr::MemBase(base, disp) => e.cvtss2si_reg_membase_size(gpreg, base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.cvtss2si_reg_memindex_size(gpreg, base, disp, index, shift, size)
+        });
+        s.case(format!("cvttss2si {}, {}", gpr(gpreg, size), addr.text("dword")), |e| match addr {
+            Addr::Regp(base) => e.cvttss2si_reg_regp_size(gpreg, base, size),
+            Addr::Mem(mem) => e.cvttss2si_reg_mem_size(gpreg, mem, size),
+            Addr::MemBase(base, disp) => e.cvttss2si_reg_membase_size(gpreg, base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.cvttss2si_reg_memindex_size(gpreg, base, disp, index, shift, size)
+        });
+        s.case(format!("cvtsd2si {}, {}", gpr(gpreg, size), addr.text("qword")), |e| match addr {
+            Addr::Regp(base) => e.cvtsd2si_reg_regp_size(gpreg, base, size),
+            Addr::Mem(mem) => e.cvtsd2si_reg_mem_size(gpreg, mem, size),
+            Addr::MemBase(base, disp) => e.cvtsd2si_reg_membase_size(gpreg, base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.cvtsd2si_reg_memindex_size(gpreg, base, disp, index, shift, size)
+        });
+        s.case(format!("cvttsd2si {}, {}", gpr(gpreg, size), addr.text("qword")), |e| match addr {
+            Addr::Regp(base) => e.cvttsd2si_reg_regp_size(gpreg, base, size),
+            Addr::Mem(mem) => e.cvttsd2si_reg_mem_size(gpreg, mem, size),
+            Addr::MemBase(base, disp) => e.cvttsd2si_reg_membase_size(gpreg, base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.cvttsd2si_reg_memindex_size(gpreg, base, disp, index, shift, size)
+        });
+    }
+    
+    for &size in [4, 8].iter() {
+        for i in 0..16 {
+            let reg = XMMS[i];
+            let gpreg = GPRS[(i * 7 + 3) % 16];
+            
+            s.case(format!("cvtsi2ss {}, {}", xmm(reg), gpr(gpreg, size)), |e| e.cvtsi2ss_reg_reg_size(reg, gpreg, size));
+            s.case(format!("cvtsi2sd {}, {}", xmm(reg), gpr(gpreg, size)), |e| e.cvtsi2sd_reg_reg_size(reg, gpreg, size));
+            s.case(format!("cvtss2si {}, {}", gpr(gpreg, size), xmm(reg)), |e| e.cvtss2si_reg_reg_size(gpreg, reg, size));
+            s.case(format!("cvttss2si {}, {}", gpr(gpreg, size), xmm(reg)), |e| e.cvttss2si_reg_reg_size(gpreg, reg, size));
+            s.case(format!("cvtsd2si {}, {}", gpr(gpreg, size), xmm(reg)), |e| e.cvtsd2si_reg_reg_size(gpreg, reg, size));
+            s.case(format!("cvttsd2si {}, {}", gpr(gpreg, size), xmm(reg)), |e| e.cvttsd2si_reg_reg_size(gpreg, reg, size));
+            
+            if size == 4 {
+                s.case(format!("movd {}, {}", xmm(reg), gpr(gpreg, 4)), |e| e.movd_xreg_reg(reg, gpreg));
+                s.case(format!("movd {}, {}", gpr(gpreg, 4), xmm(reg)), |e| e.movd_reg_xreg(gpreg, reg));
+            } else {
+                s.case(format!("movq {}, {}", xmm(reg), gpr(gpreg, 8)), |e| e.movq_xreg_reg(reg, gpreg));
+                s.case(format!("movq {}, {}", gpr(gpreg, 8), xmm(reg)), |e| e.movq_reg_xreg(gpreg, reg));
+            }
+        }
+    }
+    
+    for i in 0..16 {
+        let dreg = XMMS[i];
+        let sreg = XMMS[(i * 7 + 3) % 16];
+        let mode = (i % 4) as i32;
+        
+        s.case(format!("roundss {}, {}, {}", xmm(dreg), xmm(sreg), mode), |e| e.roundss_reg_reg(dreg, sreg, mode));
+        s.case(format!("roundsd {}, {}, {}", xmm(dreg), xmm(sreg), mode), |e| e.roundsd_reg_reg(dreg, sreg, mode));
+    }
+}
+
+fn fpu(s: &mut Suite) {
+    for (i, addr) in few_addrs().into_iter().enumerate() {
+        let size = spread(&[4, 8, 10], i);
+        
+        s.case(format!("fld {}", addr.text(ptr(size))), |e| match addr {
+            Addr::Regp(base) => e.fld_regp_size(base, size),
+            Addr::Mem(mem) => e.fld_mem_size(mem, size),
+            Addr::MemBase(base, disp) => e.fld_membase_size(base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.fld_memindex_size(base, disp, index, shift, size)
+        });
+        s.case(format!("fstp {}", addr.text(ptr(size))), |e| match addr {
+            Addr::Regp(base) => e.fstp_regp_size(base, size),
+            Addr::Mem(mem) => e.fstp_mem_size(mem, size),
+            Addr::MemBase(base, disp) => e.fstp_membase_size(base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.fstp_memindex_size(base, disp, index, shift, size)
+        });
+        
+        let size = spread(&[4, 8], i);
+        
+        s.case(format!("fst {}", addr.text(ptr(size))), |e| match addr {
+            Addr::Regp(base) => e.fst_regp_size(base, size),
+            Addr::Mem(mem) => e.fst_mem_size(mem, size),
+            Addr::MemBase(base, disp) => e.fst_membase_size(base, disp, size),
+            Addr::MemIndex(base, disp, index, shift) => e.fst_memindex_size(base, disp, index, shift, size)
+        });
+        
+        if addr.is_memindex() {
+            continue;
+        }
+        
+        let size = spread(&[2, 4, 8], i);
+        
+        s.case(format!("fild {}", addr.text(ptr(size))), |e| match addr {
+            Addr::Regp(base) => e.fild_membase_size(base, 0, size),
+            Addr::Mem(mem) => e.fild_mem_size(mem, size),
+            Addr::MemBase(base, disp) => e.fild_membase_size(base, disp, size),
+            Addr::MemIndex(..) => unreachable!()
+        });
+        s.case(format!("fistp {}", addr.text(ptr(size))), |e| match addr {
+            Addr::Regp(base) => e.fistp_regp_size(base, size),
+            Addr::Mem(mem) => e.fistp_mem_size(mem, size),
+            Addr::MemBase(base, disp) => e.fistp_membase_size(base, disp, size),
+            Addr::MemIndex(..) => unreachable!()
+        });
+        s.case(format!("fisttp {}", addr.text(ptr(size))), |e| match addr {
+            Addr::Regp(base) => e.fisttp_regp_size(base, size),
+            Addr::Mem(mem) => e.fisttp_mem_size(mem, size),
+            Addr::MemBase(base, disp) => e.fisttp_membase_size(base, disp, size),
+            Addr::MemIndex(..) => unreachable!()
+        });
+        s.case(format!("fnstcw {}", addr.text("word")), |e| match addr {
+            Addr::Regp(base) => e.fnstcw_membase(base, 0),
+            Addr::Mem(mem) => e.fnstcw(mem),
+            Addr::MemBase(base, disp) => e.fnstcw_membase(base, disp),
+            Addr::MemIndex(..) => unreachable!()
+        });
+        s.case(format!("fldcw {}", addr.text("word")), |e| match addr {
+            Addr::Regp(base) => e.fldcw_membase(base, 0),
+            Addr::Mem(mem) => e.fldcw(mem),
+            Addr::MemBase(base, disp) => e.fldcw_membase(base, disp),
+            Addr::MemIndex(..) => unreachable!()
+        });
+    }
+    
+    s.case("frndint".to_string(), |e| e.frndint());
+    s.case("fabs".to_string(), |e| e.fabs());
+    s.case("fchs".to_string(), |e| e.fchs());
+}
+
+fn suite() -> Suite {
+    let mut s = Suite { cases: Vec::new() };
+    
+    alu(&mut s);
+    unary(&mut s);
+    shift(&mut s);
+    double_shift(&mut s);
+    test_ops(&mut s);
+    imul(&mut s);
+    lea(&mut s);
+    mov(&mut s);
+    movx(&mut s);
+    cond(&mut s);
+    stack(&mut s);
+    branch(&mut s);
+    xchg(&mut s);
+    sse(&mut s);
+    fpu(&mut s);
+    
+    s
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    
+    for (i, byte) in bytes.iter().enumerate() {
+        write!(text, "{}{:02x}", if i == 0 { "" } else { " " }, byte).unwrap();
+    }
+    
+    text
+}
+
+fn golden() -> HashMap<&'static str, &'static str> {
+    ENCODINGS.lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut parts = line.split('\t');
+            let asm = parts.next().unwrap();
+            let bytes = parts.next().expect("missing bytes in encodings.txt");
+            (asm, bytes)
+        })
+        .collect()
+}
+
+#[test]
+fn encodings() {
+    let golden = golden();
+    let cases = suite().cases;
+    let mut failures = Vec::new();
+    
+    for (asm, code) in cases.iter() {
+        let actual = hex(code);
+        
+        match golden.get(asm.as_str()) {
+            Some(&expected) if expected == actual => {}
+            Some(&expected) => failures.push(format!("{}: expected {}, got {}", asm, expected, actual)),
+            None => failures.push(format!("{}: no reference encoding, got {}", asm, actual))
+        }
+    }
+    
+    assert!(failures.is_empty(), "{} of {} encodings differ:\n{}", failures.len(), cases.len(), failures.join("\n"));
+    let distinct = cases.iter().map(|(asm, _)| asm.as_str()).collect::<HashSet<_>>();
+    assert_eq!(golden.len(), distinct.len(), "encodings.txt has entries without a test case");
+}
+
+/*
+ * A 64 bit destination sign extends a 32 bit immediate, so `Codegen`
+ * rejects unsigned immediates whose value would change and encodes the
+ * ones that keep it.
+ */
+#[test]
+fn sign_extended_immediates() {
+    use codegen::CodegenErrorKind;
+    use super::prologue::*;
+    
+    let mut cg = Codegen::new();
+    let rejected = [
+        cg.add(RAX, 0x8000_0000u64),
+        cg.test(RAX, 0x8000_0000u32),
+        cg.test(MemBase(RSP, 8), 0xffff_ffffu64),
+        cg.imul_imm(RAX, RCX, 0x8000_0000u32),
+        cg.mov(MemBase(RSP, 8), 0x8000_0000u64),
+        cg.mov(MemBase(RSP, 8), 0x8000_0000u32)
+    ];
+    
+    for result in rejected.iter() {
+        assert_eq!(result.as_ref().map_err(|err| err.kind), Err(CodegenErrorKind::ImmOutOfRange));
+    }
+    
+    cg.test(RAX, 0x7fff_ffffu32).unwrap();
+    cg.imul_imm(RAX, RCX, u64::MAX).unwrap();
+    cg.mov(MemBase(ESP, 8), 0x8000_0000u32).unwrap();
+    
+    assert_eq!(hex(cg.finish().unwrap().code()), "48 a9 ff ff ff 7f 48 6b c1 ff c7 44 24 08 00 00 00 80");
+}
+
+/*
+ * An immediate is moved at the size of the register it is moved into,
+ * sign extended when it is signed and zero extended when it is not.
+ */
+#[test]
+fn mov_destination_size() {
+    use codegen::CodegenErrorKind;
+    use super::prologue::*;
+    
+    let mut cg = Codegen::new();
+    let rejected = [
+        cg.mov(AL, 0x1234_5678u32),
+        cg.mov(EAX, 1u64),
+        cg.mov(EAX, MemBase(RCX, 0)),
+        cg.mov(MemIndex(RCX, 0, RDX, 0), AX)
+    ];
+    
+    for result in rejected.iter() {
+        assert_eq!(result.as_ref().map_err(|err| err.kind), Err(CodegenErrorKind::SizeMismatch));
+    }
+    
+    cg.mov(RAX, -1i32).unwrap();
+    cg.mov(RAX, 5u8).unwrap();
+    cg.mov(EAX, -2i8).unwrap();
+    cg.mov(AX, 0xffu8).unwrap();
+    
+    assert_eq!(
+        hex(cg.finish().unwrap().code()),
+        "48 c7 c0 ff ff ff ff 48 c7 c0 05 00 00 00 b8 fe ff ff ff 66 b8 ff 00"
+    );
+}
+
+/*
+ * The packed SSE instructions read 16 bytes of memory, which no other
+ * instruction accepts.
+ */
+#[test]
+fn packed_memory_operands() {
+    use codegen::CodegenErrorKind;
+    use super::prologue::*;
+    
+    let mut cg = Codegen::new();
+    
+    assert_eq!(cg.mov(MemSize(0x1000, 16), RAX).map_err(|err| err.kind), Err(CodegenErrorKind::InvalidSize));
+    assert_eq!(cg.addsd(XMM0, MemSize(0x1000, 16)).map_err(|err| err.kind), Err(CodegenErrorKind::SizeMismatch));
+    
+    cg.andps(XMM0, MemSize(0x1000, 16)).unwrap();
+    cg.movups(MemSize(0x1000, 16), XMM1).unwrap();
+    
+    assert_eq!(hex(cg.finish().unwrap().code()), "0f 54 04 25 00 10 00 00 0f 11 0c 25 00 10 00 00");
+}
+
+/*
+ * An f64 immediate that does not fit a sign extended 32 bit immediate is
+ * stored to 8 bytes of memory as two halves.
+ */
+#[test]
+fn split_f64() {
+    use codegen::CodegenErrorKind;
+    use super::prologue::*;
+    
+    let mut cg = Codegen::new();
+    let rejected = [
+        (cg.mov(MemBase(EAX, 0), 1.5f64), CodegenErrorKind::SizeMismatch),
+        (cg.mov(MemSize(0x1000, 4), 1.5f64), CodegenErrorKind::SizeMismatch),
+        (cg.mov(MemIndex(ECX, 0, RDX, 3), 1.5f64), CodegenErrorKind::SizeMismatch),
+        (cg.mov(MemBase(RAX, i32::MAX), 1.5f64), CodegenErrorKind::UnsupportedOperands),
+        (cg.mov(MemIndex(RCX, i32::MAX - 3, RDX, 3), 1.5f64), CodegenErrorKind::UnsupportedOperands),
+        (cg.mov(MemSize(i64::MAX as u64 - 3, 8), 1.5f64), CodegenErrorKind::UnsupportedOperands)
+    ];
+    
+    for &(ref result, kind) in rejected.iter() {
+        assert_eq!(result.as_ref().map_err(|err| err.kind), Err(kind));
+    }
+    
+    cg.mov(MemBase(RAX, 8), 1.5f64).unwrap();
+    
+    assert_eq!(hex(cg.finish().unwrap().code()), "c7 40 08 00 00 00 00 c7 40 0c 00 00 f8 3f");
+}
+
+/*
+ * push always writes 8 bytes and sign extends its immediate, which picks
+ * the imm8 or imm32 form by value.
+ */
+#[test]
+fn push_immediates() {
+    use codegen::CodegenErrorKind;
+    use super::prologue::*;
+    
+    let mut cg = Codegen::new();
+    
+    assert_eq!(cg.push(0x8000_0000u32).map_err(|err| err.kind), Err(CodegenErrorKind::ImmOutOfRange));
+    assert_eq!(cg.push(0x1234u16).map_err(|err| err.kind), Err(CodegenErrorKind::InvalidSize));
+    
+    cg.push(200u8).unwrap();
+    cg.push(-1i32).unwrap();
+    cg.push(0x1234_5678u64).unwrap();
+    
+    assert_eq!(hex(cg.finish().unwrap().code()), "68 c8 00 00 00 6a ff 68 78 56 34 12");
+}
+
+/*
+ * The size of an operand in bytes, or None for an immediate.
+ */
+fn operand_size(operand: &str) -> Option<i32> {
+    let sizes = [("byte ", 1), ("word ", 2), ("dword ", 4), ("qword ", 8), ("xmmword ", 16)];
+    
+    if let Some(&(_, size)) = sizes.iter().find(|&&(prefix, _)| operand.starts_with(prefix)) {
+        return Some(size);
+    }
+    
+    match GPR_NAMES.iter().position(|names| names.contains(&operand)) {
+        Some(i) => Some(1 << i),
+        None if operand.starts_with("xmm") => Some(16),
+        None => None
+    }
+}
+
+fn number(operand: &str) -> Option<i64> {
+    let (negative, digits) = match operand.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, operand)
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()? as i64,
+        None => digits.parse::<i64>().ok()?
+    };
+    
+    Some(if negative { value.wrapping_neg() } else { value })
+}
+
+/*
+ * Brings an instruction in the syntax of encodings.txt or of the
+ * disassembler to one form. The encoding selectors of the assembler and
+ * the RIP relative targets of the disassembler are dropped, immediates
+ * are written in hex truncated to the size of the first operand, and the
+ * operands of xchg, which can be encoded either way round, are sorted.
+ */
+fn canonical(text: &str) -> String {
+    let text = text.split("  #").next().unwrap();
+    let text = text.strip_prefix("{load} ").unwrap_or(text);
+    let text = match text.strip_prefix("rex.w movd ") {
+        Some(operands) => format!("movq {}", operands.replace("dword ptr", "qword ptr")),
+        None => text.to_string()
+    };
+    
+    let (mnemonic, operands) = match text.find(' ') {
+        Some(i) => (&text[..i], &text[i + 1..]),
+        None => return text
+    };
+    let mut operands = operands.split(", ").map(|operand| operand.to_string()).collect::<Vec<_>>();
+    let size = operand_size(&operands[0]).unwrap_or(8);
+    
+    for operand in operands.iter_mut() {
+        if let Some(value) = number(operand) {
+            *operand = match size {
+                1 => format!("{:#x}", value as u8),
+                2 => format!("{:#x}", value as u16),
+                4 => format!("{:#x}", value as u32),
+                _ => format!("{:#x}", value)
+            };
+        }
+    }
+    
+    if mnemonic == "xchg" {
+        operands.sort();
+    }
+    
+    format!("{} {}", mnemonic, operands.join(", "))
+}
+
+/*
+ * Decodes every reference encoding and compares the result with the
+ * instruction it was assembled from.
+ */
+#[test]
+fn disassemble_encodings() {
+    let mut failures = Vec::new();
+    
+    for (asm, bytes) in golden() {
+        let code = bytes.split(' ').map(|byte| u8::from_str_radix(byte, 16).unwrap()).collect::<Vec<_>>();
+        let instructions = super::disasm::disassemble(&code);
+        let text = instructions.iter().map(|instruction| instruction.text.as_str()).collect::<Vec<_>>().join("; ");
+        
+        if instructions.len() != 1 || canonical(&text) != canonical(asm) {
+            failures.push(format!("{}: {} decodes as {}", asm, bytes, text));
+        }
+    }
+    
+    failures.sort();
+    assert!(failures.is_empty(), "{} instructions decode differently:\n{}", failures.len(), failures.join("\n"));
+}
@@ -1,9 +1,7 @@
 extern crate rjs_jit;
 
 use rjs_jit::codegen::x86_64::prologue::*;
-use std::mem::transmute;
 
-type CallbackFn = extern "C" fn() -> u64;
 type ExternalFn = extern "C" fn(a: u64, b: u64) -> u64;
 
 extern "C" fn callback(a: u64, b: u64) -> u64 {
@@ -14,54 +12,32 @@ extern "C" fn my_fn(a: u64, b: u64) -> u64 {
     callback(a + 10, b + 10) - 10
 }
 
-fn main() {
+fn main() -> Result<(), CodegenError> {
     println!("Hello, world!");
     
     my_fn(3, 5);
     
     let mut gen = Codegen::new();
+    let conv = CallConv::host();
+    let args = conv.int_args();
     
     // Prolog
     
-    gen.push(RBP);
-    gen.mov(RBP, RSP);
+    let frame = gen.prologue(conv, &[], 0)?;
     
-    /* ** SIMPLE ADD **
-    // Add the values
+    // Call the function with our own arguments
     
-    gen.add(RDX, RCX);
-    
-    // Write the result
-    
-    gen.mov(RAX, RDX);
-    */
-    
-    // Reserve arguments space (minimum of 0x20 for shadow stack)
-    
-    gen.sub(RSP, 0x20);
-    
-    // Call the function
-    
-    gen.mov(RAX, callback as u64);
-    gen.call(RAX);
-    
-    // Pop arguments
-    
-    gen.add(RSP, 0x20);
+    gen.call_native(conv, callback as ExternalFn as usize as u64, &[args[0].as_arg(), args[1].as_arg()], None)?;
     
     // Epilog
     
-    gen.mov(RSP, RBP);
-    gen.pop(RBP);
+    gen.epilogue(&frame)?;
     
-    gen.ret();
-    
-    unsafe {
-        let jit_fn = gen.build();
-        let f : ExternalFn = transmute(jit_fn.ptr());
-        let result = f(3, 5);
-        println!("Result {}", result);
-    }
+    let jit_fn = unsafe { gen.build_fn::<fn(u64, u64) -> u64>()? };
+    let result = jit_fn.call((3, 5));
+    println!("Result {}", result);
     
     println!("Success");
+    
+    Ok(())
 }